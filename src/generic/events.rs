@@ -6,30 +6,171 @@ use std::{
 use bevy::ecs::{entity::Entity, event::Event};
 use bytes::Bytes;
 
+use crate::{
+    net::{
+        blocklist::{BlockReason, Cidr},
+        stream::ConnectionState,
+    },
+    protocol::{mcpe::ServerStatus, reliability::Reliability},
+};
+
 /// RakNetEvent contains various variants that are useful in debugging various
 /// RakNet connection stages and to receive and send a RakNet Game Packet batch.
-#[derive(Event)]
+#[derive(Event, Debug, Clone)]
 pub enum RakNetEvent {
     ConnectionRequest(SocketAddr),
+    PongReceived(SocketAddr, ServerStatus),
+    ServerStatusChanged(SocketAddr, ServerStatus),
+    ServerLatencyChanged(SocketAddr, Duration),
+    LanServerDiscovered(SocketAddr, ServerStatus),
     ConnectionEstablished(SocketAddr, Entity),
     MalformedPackets(Entity),
+    /// The datagram that the connection could not decode, with the error it failed with. It is only written while the
+    /// malformed packets are dumped, so that the interop issues with specific clients can be diagnosed.
+    MalformedPacketDump(Entity, Vec<u8>, String),
+    SplitLimitExceeded(Entity),
+    /// The connection buffered the provided number of bytes, which exceeds the maximum buffered bytes of a connection.
+    BufferLimitExceeded(Entity, usize),
     DuplicateLogin(Entity),
     Timeout(Entity),
     Ping(Entity, u64),
     Latency(Entity, Duration),
-    Disconnect(Entity),
+    Disconnect(Entity, DisconnectReason),
+    DisconnectRequest(Entity, DisconnectReason),
+    StateChanged(Entity, ConnectionState),
     IncompatibleProtocol(Entity, u8),
     LastActivity(Entity, Instant),
     IncomingBatch(Entity, Vec<u8>),
     OutgoingBatch(Entity, Vec<u8>),
+    /// A game packet of the provided size was received from or sent to the connection, which exceeds the maximum game
+    /// packet size. The packet was dropped.
+    PacketTooLarge(Entity, usize),
+    /// The quality of the connection crossed one of the quality thresholds of the RakNetConfig. It is written once when
+    /// the issue starts and again only after the connection has recovered from it.
+    QualityWarning(Entity, QualityIssue),
+    Broadcast(Vec<u8>, Reliability),
+    Multicast(Vec<Entity>, Vec<u8>, Reliability),
+    /// The connection of the entity asked the NatCoordinator for a punchthrough to the peer with the provided GUID.
+    NatPunchthroughRequest(Entity, i64),
+    /// The coordinator of the connection of the entity introduced the peer with the provided address and GUID, the
+    /// punching starts after the provided delay.
+    NatConnectAtTime(Entity, SocketAddr, i64, Duration),
+    /// The transport of the connection of the entity can exchange datagrams with the peer at the provided address.
+    NatPunchthroughSucceeded(Entity, SocketAddr, i64),
+    /// The punchthrough to the peer with the provided GUID requested through the connection of the entity failed.
+    NatPunchthroughFailed(Entity, i64, NatFailure),
+    /// The system at the provided address advertised itself with the provided payload, without being connected.
+    SystemAdvertised(SocketAddr, Vec<u8>),
+    /// The provided address was blocked from a server for the provided duration.
+    AddressBlocked(SocketAddr, Duration, BlockReason),
+    /// The provided address was blocked from a server again, for the provided number of times, and its block was
+    /// escalated to the provided duration. An AddressBlocked event follows it.
+    BlockEscalated(SocketAddr, u32, Duration),
+    /// The provided address was unblocked from a server, either by the App or because its block expired.
+    AddressUnblocked(SocketAddr),
+    /// All the addresses within the provided range were blocked from a server for the provided duration.
+    RangeBlocked(Cidr, Duration, BlockReason),
+    /// The provided range was unblocked from a server, either by the App or because its block expired.
+    RangeUnblocked(Cidr),
+    /// The offline handshake of the provided address was refused by a ConnectionFilter and dropped without a reply.
+    ConnectionFiltered(SocketAddr),
+    /// The client with the provided address and GUID was refused as it is not on the enabled Whitelist of the server.
+    NotWhitelisted(SocketAddr, i64),
+    /// The client with the provided address connected with the provided GUID, which is already used by the provided
+    /// connection. Either of them is disconnected according to the DuplicateLoginPolicy of the RakNetConfig.
+    DuplicateGuid(SocketAddr, i64, Entity),
+    /// The client with the provided address and GUID was refused as the Maintenance of the server is enabled.
+    RefusedForMaintenance(SocketAddr, i64),
+    /// The provided address asked to connect and is waiting for the approval of the App, with its remote address that
+    /// differs if it is a trusted proxy and its GUID. It is refused unless approved within the approval timeout.
+    ApprovalRequested(SocketAddr, SocketAddr, i64),
+    /// Approves the connection of the provided address that is waiting for the approval of the App.
+    ApproveConnection(SocketAddr),
+    /// Refuses the connection of the provided address that is waiting for the approval of the App.
+    DenyConnection(SocketAddr),
+}
+
+/// ProxyEvent can be used for moving the downstream clients of a NetworkProxy between its backends.
+#[derive(Event, Debug)]
+pub enum ProxyEvent {
+    /// Moves the downstream client of the entity to the provided backend without the client reconnecting. The login
+    /// of the client is replayed to the backend, which must not encrypt the connections.
+    Transfer(Entity, String),
+    /// The downstream client of the entity has been logged in to the provided backend after a transfer or a reconnect.
+    Transferred(Entity, String),
+    /// The provided backend of the downstream client of the entity refused the connection or timed out.
+    UpstreamFailed(Entity, String),
+    /// The downstream client of the entity is being moved to the provided fallback backend after its backend failed.
+    FallbackAttempt(Entity, String),
+    /// The downstream client of the entity is being reconnected to the provided backend, the number is the attempt.
+    ReconnectAttempt(Entity, String, u32),
+}
+
+/// DisconnectReason describes why a RakNet connection was disconnected. It is carried by the DisconnectRequest
+/// and the resulting Disconnect events so that the disconnections can be logged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The other end of the connection sent a disconnect notification.
+    ClosedByPeer,
+    /// The disconnection was requested by the App without any specific reason.
+    Requested,
+    /// The other end of the connection exceeded the limits on the split messages.
+    SplitLimitExceeded,
+    /// The connection buffered more bytes than a single connection can hold.
+    BufferLimitExceeded,
+    /// The connection did not complete the MCPE login within the login timeout.
+    LoginTimeout,
+    /// The connection did not send any game packet within the idle timeout.
+    Idle,
+    /// The latency of the connection stayed above the maximum latency for longer than the high latency grace.
+    HighLatency,
+    /// The login chain of the client could not be verified against the Mojang root key in online mode.
+    NotAuthenticated,
+    /// A batch of the encrypted connection could not be decrypted or its checksum did not match.
+    DecryptionFailed,
+    /// The address of the other end of the connection was blocked from the server.
+    Blocked(BlockReason),
+    /// Another session of the same client logged into the server.
+    DuplicateLogin,
+    /// The disconnection was requested by the App with a custom reason.
+    Custom(String),
+}
+
+/// QualityIssue describes which quality threshold a RakNet connection crossed, with the value that was measured.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QualityIssue {
+    /// The fraction of the datagrams sent by the other end of the connection that never reached us.
+    PacketLoss(f32),
+    /// The fraction of the datagrams sent to the other end of the connection that had to be retransmitted.
+    Retransmissions(f32),
+    /// The average deviation of the round trip times of the connection from its latency.
+    Jitter(Duration),
+}
+
+/// NatFailure describes why a NAT punchthrough to a peer failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatFailure {
+    /// The peer is not connected to the coordinator.
+    TargetNotConnected,
+    /// A punchthrough between the same peers is already in progress.
+    AlreadyInProgress,
+    /// No datagram of the peer got through before all the attempts were made.
+    TargetUnresponsive,
 }
 
 /// NetworkEvent can be used for handling various Minecraft related Login Process events
 /// and to receive and send a Minecraft (Optionally Compressed & Encrypted) Packet Batch.
-#[derive(Event)]
+#[derive(Event, Debug, Clone)]
 pub enum NetworkEvent {
+    /// The Login packet of the connection was parsed and its LoginData inserted.
     ConnectionRequest(Entity),
+    /// The connection was sent the PlayStatus LoginSuccess and is ready to be spawned.
     ConnectionEstablished(Entity),
+    /// The login chain of the connection could not be verified in online mode.
+    AuthenticationFailed(Entity),
+    /// A game packet of the connection, one of the sub-packets of a decrypted and decompressed RakNetEvent::IncomingBatch.
     IncomingPacket(Entity, Bytes),
+    /// A game packet for the connection. The packets of a connection are framed into a single batch per flush, which
+    /// is compressed and encrypted into a RakNetEvent::OutgoingBatch.
     OutgoingPacket(Entity, Bytes),
 }
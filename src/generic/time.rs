@@ -0,0 +1,144 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Clock is where a TimeSource takes the current time from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Clock {
+    Real,
+    Bevy,
+    Mock,
+}
+
+/// TimeSource is the clock that the time-dependent logic of the connections reads the current time from, such as the
+/// retransmissions, the timeouts and the spam checks. It is the real time by default, it can be driven by the Time
+/// resource of Bevy instead or be a mock that only moves when advanced so that tests are deterministic. Clones share
+/// the same clock.
+#[derive(Debug, Clone)]
+pub struct TimeSource {
+    clock: Clock,
+    start: Instant,
+    elapsed: Arc<AtomicU64>,
+}
+
+impl Default for TimeSource {
+    fn default() -> Self {
+        Self::real()
+    }
+}
+
+impl TimeSource {
+    /// Creates a TimeSource that returns the real time.
+    pub fn real() -> Self {
+        Self::new(Clock::Real)
+    }
+
+    /// Creates a TimeSource that is driven by the elapsed time of the Time resource on every run of the network
+    /// schedule, so the connections see the same time as the rest of the App.
+    pub fn bevy() -> Self {
+        Self::new(Clock::Bevy)
+    }
+
+    /// Creates a TimeSource that is stopped until it is advanced manually.
+    pub fn mock() -> Self {
+        Self::new(Clock::Mock)
+    }
+
+    fn new(clock: Clock) -> Self {
+        Self {
+            clock,
+            start: Instant::now(),
+            elapsed: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Returns the current time of the clock.
+    pub fn now(&self) -> Instant {
+        match self.clock {
+            Clock::Real => Instant::now(),
+            Clock::Bevy | Clock::Mock => {
+                self.start + Duration::from_nanos(self.elapsed.load(Ordering::Relaxed))
+            }
+        }
+    }
+
+    /// Returns the time elapsed on the clock since the provided instant, zero if the instant is in the future.
+    pub fn since(&self, instant: Instant) -> Duration {
+        self.now().saturating_duration_since(instant)
+    }
+
+    /// Moves a mock clock forward by the provided duration. It does nothing on the other clocks.
+    pub fn advance(&self, duration: Duration) {
+        if self.clock == Clock::Mock {
+            self.elapsed
+                .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Waits for the provided duration on the clock. The real clock and the one driven by the Time resource wait for
+    /// it to pass in real time, the latter being moved forward as the Time resource is not updated meanwhile, while
+    /// a mock clock is only advanced.
+    pub fn sleep(&self, duration: Duration) {
+        match self.clock {
+            Clock::Real => thread::sleep(duration),
+            Clock::Bevy => {
+                thread::sleep(duration);
+                self.elapsed
+                    .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+            }
+            Clock::Mock => self.advance(duration),
+        }
+    }
+
+    /// Returns whether the clock is driven by the Time resource.
+    pub fn is_bevy(&self) -> bool {
+        self.clock == Clock::Bevy
+    }
+
+    /// Sets the time elapsed on a clock driven by the Time resource.
+    pub(crate) fn set_elapsed(&self, elapsed: Duration) {
+        self.elapsed
+            .store(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_clock_only_moves_when_advanced() {
+        let clock = TimeSource::mock();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+
+        clock.clone().advance(Duration::from_secs(5));
+        assert_eq!(clock.since(start), Duration::from_secs(5));
+
+        clock.sleep(Duration::from_secs(1));
+        assert_eq!(clock.since(start), Duration::from_secs(6));
+    }
+
+    #[test]
+    fn since_saturates_for_future_instants() {
+        let clock = TimeSource::mock();
+        let future = clock.now() + Duration::from_secs(1);
+        assert_eq!(clock.since(future), Duration::ZERO);
+    }
+
+    #[test]
+    fn advance_does_nothing_on_other_clocks() {
+        let clock = TimeSource::bevy();
+        let start = clock.now();
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(clock.now(), start);
+
+        clock.set_elapsed(Duration::from_secs(2));
+        assert_eq!(clock.since(start), Duration::from_secs(2));
+    }
+}
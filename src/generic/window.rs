@@ -1,80 +1,76 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
+    num::NonZeroU32,
     time::{Duration, Instant},
 };
 
-use crate::protocol::WINDOW_SIZE;
 use bytes::Bytes;
 
 /// SequenceWindow helps in filtering the incoming RakNet datagrams by preventing any datagrams that have
 /// same sequence number or are out of order from reaching our processing side. It maintains a list of acks
 /// and nacks that we should flush by the next tick for the sequences we have received and for those we did
 /// not respectively.
+///
+/// The received sequences are tracked in a ring bitset of window size bits indexed by the sequence number,
+/// so receiving and shifting the window never has to scan through the previously received sequences.
 pub struct SequenceWindow {
     pub start: u32,
-    pub end: u32,
     pub highest: u32,
     pub acks: Vec<u32>,
     pub nacks: Vec<u32>,
+    received: RingBitset,
 }
 
 impl SequenceWindow {
-    /// Creates and returns a new Sequence Window.
-    pub fn new() -> Self {
+    /// Creates and returns a new Sequence Window of the provided size.
+    pub fn new(size: NonZeroU32) -> Self {
         Self {
             start: 0,
-            end: WINDOW_SIZE,
             highest: 0,
-            acks: Vec::with_capacity(WINDOW_SIZE as usize),
-            nacks: Vec::with_capacity(WINDOW_SIZE as usize),
+            acks: Vec::with_capacity(size.get() as usize),
+            nacks: Vec::with_capacity(size.get() as usize),
+            received: RingBitset::new(size),
         }
     }
 
     /// Receives a sequence number and checks if we have received this sequence before or
     /// if it is out of order. It returns true if we should continue processing this datagram.
     pub fn receive(&mut self, seq: u32) -> bool {
-        if seq < self.start || seq > self.end || self.acks.contains(&seq) {
+        if seq < self.start || seq - self.start >= self.received.size || self.received.get(seq) {
             return false;
         }
 
-        self.nacks.retain(|&x| x != seq);
+        self.received.set(seq, true);
         self.acks.push(seq);
 
-        if seq > self.highest {
-            self.highest = seq;
+        if seq >= self.highest {
+            self.highest = seq + 1;
         }
 
-        if seq == self.start {
-            // got a contiguous packet, shift the receive window
-            // this packet might complete a sequence of out-of-order packets, so we incrementally check the indexes
-            // to see how far to shift the window, and stop as soon as we either find a gap or have an empty window
-            while self.acks.contains(&self.start) {
-                self.start += 1;
-                self.end += 1;
-            }
-        } else {
-            // we got a gap - a later packet arrived before earlier ones did.
-            // we add the earlier ones to the nack queue.
-            // if the missing packets arrive before the end of the tick, they'll be removed from nack queue.
-            for i in self.start..seq {
-                if !self.acks.contains(&i) {
-                    self.nacks.push(i);
-                }
-            }
+        // got a contiguous packet, shift the receive window
+        // this packet might complete a sequence of out-of-order packets, so we incrementally check the indexes
+        // to see how far to shift the window, and stop as soon as we either find a gap or have an empty window
+        while self.start < self.highest && self.received.get(self.start) {
+            self.received.set(self.start, false);
+            self.start += 1;
         }
 
         true
     }
 
     /// Shifts the window, this should be called when we a RakNet tick has passed and we should
-    /// stop expecting a certain set of sequences. At this stage, we flush our ACKs and NACKs.
+    /// stop expecting a certain set of sequences. Every sequence below the highest received one that
+    /// has not reached us by now is added to the nack queue. At this stage, we flush our ACKs and NACKs.
     pub fn shift(&mut self) {
-        let diff = self.highest - self.start;
-
-        if diff > 0 {
-            self.start += diff;
-            self.end += diff;
+        for seq in self.start..self.highest {
+            if self.received.get(seq) {
+                self.received.set(seq, false);
+            } else {
+                self.nacks.push(seq);
+            }
         }
+
+        self.start = self.highest;
     }
 }
 
@@ -85,75 +81,140 @@ impl SequenceWindow {
 /// a certain period of time.
 pub struct MessageWindow {
     pub start: u32,
-    pub end: u32,
-    pub indexes: Vec<u32>,
+    received: RingBitset,
 }
 
 impl MessageWindow {
-    /// Creates and returns a new Message Window.
-    pub fn new() -> Self {
+    /// Creates and returns a new Message Window of the provided size.
+    pub fn new(size: NonZeroU32) -> Self {
         Self {
             start: 0,
-            end: WINDOW_SIZE,
-            indexes: Vec::with_capacity(WINDOW_SIZE as usize),
+            received: RingBitset::new(size),
         }
     }
 
     /// Tries to receive a message index and returns whether we should continue processing this datagram or not.
     /// Returns false if a datagram with the provided message index has already reached us before.
     pub fn receive(&mut self, index: u32) -> bool {
-        if index < self.start || index > self.end || self.indexes.contains(&index) {
+        if index < self.start
+            || index - self.start >= self.received.size
+            || self.received.get(index)
+        {
             return false;
         }
 
-        self.indexes.push(index);
+        self.received.set(index, true);
 
-        if index == self.start {
-            while self.indexes.contains(&self.start) {
-                self.indexes.retain(|&x| x != self.start);
-                self.start += 1;
-                self.end += 1;
-            }
+        while self.received.get(self.start) {
+            self.received.set(self.start, false);
+            self.start += 1;
         }
 
         true
     }
 }
 
+/// RingBitset is a fixed size bitset that is indexed by a sequence number or a message index wrapped
+/// around the size of the window.
+struct RingBitset {
+    size: u32,
+    words: Vec<u64>,
+}
+
+impl RingBitset {
+    /// Creates and returns a new Ring Bitset of the provided size with all the bits unset.
+    fn new(size: NonZeroU32) -> Self {
+        Self {
+            size: size.get(),
+            words: vec![0; size.get().div_ceil(64) as usize],
+        }
+    }
+
+    /// Returns whether the bit for the provided index is set.
+    fn get(&self, index: u32) -> bool {
+        let bit = index % self.size;
+        self.words[(bit / 64) as usize] & (1 << (bit % 64)) != 0
+    }
+
+    /// Sets or unsets the bit for the provided index.
+    fn set(&mut self, index: u32, value: bool) {
+        let bit = index % self.size;
+        let word = &mut self.words[(bit / 64) as usize];
+
+        if value {
+            *word |= 1 << (bit % 64);
+        } else {
+            *word &= !(1 << (bit % 64));
+        }
+    }
+}
+
 /// SplitWindow ensures that all the datagrams that are fragmented by the other end of the connection are
 /// unsplit to form a fully encapsulated datagram so it can be processed further like the unsplit datagrams.
+/// Fragments are stored by their index so they may arrive out of order or be duplicated.
 pub struct SplitWindow {
     pub count: u32,
-    pub fragments: Vec<Vec<u8>>,
+    pub received: u32,
+    pub size: usize,
+    pub fragments: Vec<Option<Bytes>>,
+    pub instant: Instant,
+    pub discarded: bool,
 }
 
 impl SplitWindow {
-    /// Creates a new SplitWindow and returns it.
-    pub fn new(count: u32) -> Self {
+    /// Creates a new SplitWindow at the provided time and returns it.
+    pub fn new(count: u32, now: Instant) -> Self {
         Self {
             count,
-            fragments: Vec::with_capacity(count as usize),
+            received: 0,
+            size: 0,
+            fragments: vec![None; count as usize],
+            instant: now,
+            discarded: false,
         }
     }
 
-    /// Tries to receive a fragment. Returns optionally fully encapsulated datagram packet if
-    /// all the fragments have been received.
-    pub fn receive(&mut self, index: u32, fragment: Vec<u8>) -> Option<Vec<u8>> {
-        self.fragments.insert(index as usize, fragment);
+    /// Drops the fragments received so far. The split is kept until it expires so that its remaining fragments are
+    /// ignored instead of starting it again.
+    pub fn discard(&mut self) {
+        self.fragments = Vec::new();
+        self.size = 0;
+        self.discarded = true;
+    }
+
+    /// Tries to receive a fragment at the provided time. Returns optionally fully encapsulated datagram packet if
+    /// all the fragments have been received. Duplicated fragments and fragments with an index
+    /// outside of the split count are ignored.
+    pub fn receive(&mut self, index: u32, fragment: Bytes, now: Instant) -> Option<Vec<u8>> {
+        let slot = self.fragments.get_mut(index as usize)?;
 
-        if self.fragments.capacity() != self.fragments.len() {
+        if slot.is_some() {
             return None;
         }
 
-        let mut buffer = self.fragments.remove(0);
+        self.size += fragment.len();
+        *slot = Some(fragment);
+        self.received += 1;
+        self.instant = now;
 
-        for i in 1..self.fragments.len() {
-            let fragment = self.fragments.remove(i);
+        if self.received != self.count {
+            return None;
+        }
+
+        let mut buffer = Vec::with_capacity(self.size);
+
+        for fragment in self.fragments.drain(..).flatten() {
             buffer.extend_from_slice(&fragment);
         }
 
         Some(buffer)
     }
+
+    /// Returns whether no fragment has been received for this split within the provided duration
+    /// before the provided time and that it should be discarded.
+    pub fn expired(&self, timeout: Duration, now: Instant) -> bool {
+        now.saturating_duration_since(self.instant) > timeout
+    }
 }
 
 /// Record contains information about the datagram that we have sent to the other end of the
@@ -169,7 +230,7 @@ pub struct Record {
 /// Retransmission also occurs from our end if we don't receive an ACK or a NACK for a certain amount of time.
 pub struct RecoveryWindow {
     pub unacknowledged: HashMap<u32, Record>,
-    pub delays: HashMap<Instant, Duration>,
+    pub delays: VecDeque<(Instant, Duration)>,
 }
 
 impl RecoveryWindow {
@@ -177,57 +238,209 @@ impl RecoveryWindow {
     pub fn new() -> Self {
         Self {
             unacknowledged: HashMap::new(),
-            delays: HashMap::new(),
+            delays: VecDeque::new(),
         }
     }
 
-    /// Adds the datagram to the Recovery Window.
-    pub fn add(&mut self, sequence: u32, packet: Bytes) {
+    /// Adds the datagram sent at the provided time to the Recovery Window.
+    pub fn add(&mut self, sequence: u32, packet: Bytes, now: Instant) {
         self.unacknowledged.insert(
             sequence,
             Record {
                 packet,
-                instant: Instant::now(),
+                instant: now,
             },
         );
     }
 
-    /// Removes the datagram from the recovery window.
-    pub fn acknowledge(&mut self, sequence: u32) {
+    /// Removes the datagram acknowledged at the provided time from the recovery window.
+    pub fn acknowledge(&mut self, sequence: u32, now: Instant) {
         if let Some(record) = self.unacknowledged.remove(&sequence) {
-            self.delays.insert(Instant::now(), record.instant.elapsed());
+            self.delays
+                .push_back((now, now.saturating_duration_since(record.instant)));
         }
     }
 
     /// Returns the datagram encoded bytes if the datagram with the provided sequence
     /// exists in the recovery queue.
-    pub fn retransmit(&mut self, sequence: u32) -> Option<Bytes> {
+    pub fn retransmit(&mut self, sequence: u32, now: Instant) -> Option<Bytes> {
         if let Some(record) = self.unacknowledged.remove(&sequence) {
             self.delays
-                .insert(Instant::now(), record.instant.elapsed() * 2);
+                .push_back((now, now.saturating_duration_since(record.instant) * 2));
             return Some(record.packet);
         }
 
         None
     }
 
+    /// Returns the total number of bytes of the datagrams that have not been acknowledged yet.
+    pub fn in_flight_bytes(&self) -> usize {
+        self.unacknowledged
+            .values()
+            .map(|record| record.packet.len())
+            .sum()
+    }
+
     /// Returns the average time taken by the other end of the connection to acknowledge or NACK
-    /// a sequence. This is also known as latency.
-    pub fn rtt(&mut self) -> Duration {
-        let mut total = Duration::from_secs(0);
-        let mut records = 0;
+    /// a sequence within the five seconds before the provided time. This is also known as latency.
+    pub fn rtt(&mut self, now: Instant) -> Duration {
+        // The samples are recorded in the order of their time, so the expired ones are all at the front.
+        while let Some((time, _)) = self.delays.front() {
+            if now.saturating_duration_since(*time).as_secs() <= 5 {
+                break;
+            }
 
-        self.delays.retain(|&time, _| time.elapsed().as_secs() <= 5);
+            self.delays.pop_front();
+        }
 
-        for (_, duration) in self.delays.iter() {
-            total += *duration;
-            records += 1;
+        if self.delays.is_empty() {
+            return Duration::from_secs(0);
         }
 
-        if records != 0 {
-            return total / records;
+        let total: Duration = self.delays.iter().map(|(_, delay)| *delay).sum();
+
+        total / self.delays.len() as u32
+    }
+
+    /// Returns the average deviation of the round trip times within the five seconds before the provided time from
+    /// their average, which is how much the latency of the connection fluctuates.
+    pub fn jitter(&mut self, now: Instant) -> Duration {
+        let rtt = self.rtt(now);
+        if self.delays.is_empty() {
+            return Duration::from_secs(0);
         }
 
-        Duration::from_secs(0)
+        let total: Duration = self
+            .delays
+            .iter()
+            .map(|(_, delay)| delay.abs_diff(rtt))
+            .sum();
+
+        total / self.delays.len() as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    /// Returns the first index at or after the provided start that has not been received yet.
+    fn next_missing(received: &HashSet<u32>, mut start: u32) -> u32 {
+        while received.contains(&start) {
+            start += 1;
+        }
+        start
+    }
+
+    proptest! {
+        #[test]
+        fn message_window_accepts_each_index_once(
+            size in 1u32..256,
+            indices in prop::collection::vec(0u32..1024, 0..512),
+        ) {
+            let mut window = MessageWindow::new(NonZeroU32::new(size).unwrap());
+            let mut received = HashSet::new();
+            let mut start = 0;
+
+            for index in indices {
+                let expected = index >= start && index - start < size && !received.contains(&index);
+
+                prop_assert_eq!(window.receive(index), expected, "index {}", index);
+
+                if expected {
+                    received.insert(index);
+                    start = next_missing(&received, start);
+                }
+
+                prop_assert_eq!(window.start, start);
+            }
+        }
+
+        #[test]
+        fn message_window_rejects_duplicates(
+            size in 1u32..256,
+            indices in prop::collection::vec(0u32..512, 1..256),
+        ) {
+            let mut window = MessageWindow::new(NonZeroU32::new(size).unwrap());
+            let accepted: Vec<u32> = indices.into_iter().filter(|&index| window.receive(index)).collect();
+
+            for index in accepted {
+                prop_assert!(!window.receive(index), "index {} was accepted twice", index);
+            }
+        }
+
+        #[test]
+        fn sequence_window_accepts_each_sequence_once(
+            size in 1u32..256,
+            steps in prop::collection::vec(prop::option::weighted(0.9, 0u32..1024), 0..512),
+        ) {
+            let mut window = SequenceWindow::new(NonZeroU32::new(size).unwrap());
+            let mut received = HashSet::new();
+            let mut start = 0;
+            let mut highest = 0;
+
+            for step in steps {
+                let Some(seq) = step else {
+                    window.shift();
+
+                    let nacks: Vec<u32> = (start..highest).filter(|seq| !received.contains(seq)).collect();
+                    prop_assert_eq!(&window.nacks, &nacks);
+
+                    window.nacks.clear();
+                    start = highest;
+                    continue;
+                };
+
+                let expected = seq >= start && seq - start < size && !received.contains(&seq);
+
+                prop_assert_eq!(window.receive(seq), expected, "sequence {}", seq);
+
+                if expected {
+                    received.insert(seq);
+                    highest = highest.max(seq + 1);
+                    start = next_missing(&received, start);
+
+                    prop_assert_eq!(window.acks.last(), Some(&seq));
+                }
+
+                prop_assert_eq!(window.start, start);
+                prop_assert_eq!(window.highest, highest);
+            }
+        }
+
+        #[test]
+        fn sequence_window_rejects_out_of_window(
+            size in 1u32..256,
+            seq in 0u32..4096,
+        ) {
+            let mut window = SequenceWindow::new(NonZeroU32::new(size).unwrap());
+
+            prop_assert_eq!(window.receive(seq), seq < size);
+            prop_assert!(!window.receive(seq));
+        }
+    }
+
+    #[test]
+    fn recovery_window_keeps_every_sample_of_an_instant() {
+        let mut window = RecoveryWindow::new();
+        let sent = Instant::now();
+
+        window.add(0, Bytes::new(), sent);
+        window.add(1, Bytes::new(), sent + Duration::from_millis(100));
+        window.add(2, Bytes::new(), sent);
+
+        let now = sent + Duration::from_millis(200);
+        window.acknowledge(0, now);
+        window.acknowledge(1, now);
+        window.retransmit(2, now);
+
+        assert_eq!(window.delays.len(), 3);
+        assert_eq!(window.rtt(now), Duration::from_millis(700) / 3);
+        assert_eq!(window.rtt(now + Duration::from_secs(6)), Duration::ZERO);
+        assert!(window.delays.is_empty());
     }
 }
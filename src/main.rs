@@ -1,112 +1,985 @@
 use bevy::{prelude::*, time::common_conditions::on_timer};
-use commons::logger::init_logger;
-use generic::events::{NetworkEvent, RakNetEvent};
-use log::LevelFilter;
+use generic::events::{NetworkEvent, ProxyEvent, RakNetEvent};
 use net::{
-    check_timeout, client_read_udp, connection_tick, flush_batch, flush_receipts, server_read_udp,
+    announce_heartbeat, apply_trace_filter,
+    blocklist::BlockList,
+    check_buffered_memory, check_idle, check_latency, check_login_timeout, check_quality,
+    check_timeout, cleanup_mappings, client_flush_udp, client_login, client_read_udp,
+    client_update_state, compress_outgoing, connection_tick, coordinate_nat_punchthrough,
+    decompress_incoming, disconnect_on_exit, discover_lan, drive_clock, evict_splits, flush_batch,
+    flush_receipts, handle_duplicate_logins, handle_login,
+    heartbeat::{Heartbeat, HeartbeatTarget},
+    lan::{LanDiscovery, LanListener},
+    loopback::LoopbackNetwork,
+    maintenance::Maintenance,
+    nat::NatCoordinator,
+    nat_handshake, nat_punchthrough, networking_running,
+    pcap::PcapWriter,
+    ping::ServerListPinger,
+    ping_server_list,
+    proxy::{
+        Backends, BalanceStrategy, ProxyInterceptor, ProxyInterceptors, ProxyRateLimit,
+        ReconnectPolicy, StatusPassthrough,
+    },
+    proxy_connect, proxy_forward, proxy_handshake, proxy_mirror_status, proxy_reconnect,
+    proxy_transfer, publish_blocks, queue_outgoing,
+    recording::TrafficRecorder,
+    resolve_approvals, save_blocks_on_exit, server_answer_lan, server_flush_udp, server_read_udp,
     server_update_status,
-    socket::{RakSocket, ServerBundle},
+    socket::{RakSocket, ReadBudget, ServerBundle, SocketInfo},
+    socks::Socks5Transport,
+    start_nat_punchthrough,
+    stream::{NetworkInfo, NetworkStatus},
+    sync_online_players,
+    transport::Transport,
+    whitelist::Whitelist,
+    ClientNetState, ConnectionFilters, MessageInterceptors, NetworkSet, NetworkingState,
+    OutgoingQueue, TraceFilter,
+};
+use protocol::{
+    config::RakNetConfig,
+    mcpe::{
+        login::{LoginState, OfflineLogin},
+        BroadcastGamemode, LevelName, MaxPlayers, MinecraftProtocol, MinecraftVersion,
+        OnlinePlayers, OnlinePlayersSync, PortV6, PrimaryMotd, SecondaryMotd, StatusProvider,
+    },
+    HEARTBEAT_INTERVAL, LAN_DISCOVERY_PORT,
+};
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
 };
-use protocol::{mcpe::StatusResource, RAKNET_CHECK_TIMEOUT, RAKNET_TPS};
+
+#[cfg(feature = "simulator")]
+use net::simulator::{NetworkConditions, SimulatedTransport};
+#[cfg(feature = "websocket")]
+use net::websocket::{WebSocketListener, WebSocketTransport};
 
 pub mod generic;
 pub mod net;
 pub mod protocol;
+#[cfg(feature = "testing")]
+pub mod testing;
 
+/// NetworkServer is the plugin that binds a RakNet server on the provided address. The components of the spawned
+/// server entity can be configured through the builder methods, the defaults of ServerBundle are used otherwise.
 pub struct NetworkServer {
     addr: String,
+    guid: Option<i64>,
+    primary_motd: Option<String>,
+    secondary_motd: Option<String>,
+    online_players: Option<u32>,
+    max_players: Option<u32>,
+    gamemode: Option<String>,
+    protocol: Option<u32>,
+    version: Option<String>,
+    level_name: Option<String>,
+    port_v6: Option<u16>,
+    status_provider: Option<Arc<dyn StatusProvider>>,
+    players_sync: Option<i32>,
+    transport: Option<Arc<dyn Transport>>,
+    loopback: Option<LoopbackNetwork>,
+    recorder: Option<TrafficRecorder>,
+    pcap: Option<PcapWriter>,
+    nat_coordinator: bool,
+    whitelist: Option<Whitelist>,
+    maintenance: Option<Maintenance>,
+    #[cfg(feature = "simulator")]
+    conditions: Option<NetworkConditions>,
+    #[cfg(feature = "websocket")]
+    websocket: bool,
 }
 
 impl NetworkServer {
     pub fn new(addr: &str) -> Self {
         Self {
             addr: addr.to_string(),
+            guid: None,
+            primary_motd: None,
+            secondary_motd: None,
+            online_players: None,
+            max_players: None,
+            gamemode: None,
+            protocol: None,
+            version: None,
+            level_name: None,
+            port_v6: None,
+            status_provider: None,
+            players_sync: None,
+            transport: None,
+            loopback: None,
+            recorder: None,
+            pcap: None,
+            nat_coordinator: false,
+            whitelist: None,
+            maintenance: None,
+            #[cfg(feature = "simulator")]
+            conditions: None,
+            #[cfg(feature = "websocket")]
+            websocket: false,
         }
     }
+
+    /// Sets the GUID of the server that is advertised to the clients instead of a random one.
+    pub fn with_guid(mut self, guid: i64) -> Self {
+        self.guid = Some(guid);
+        self
+    }
+
+    /// Sets the primary MOTD of the server.
+    pub fn with_motd(mut self, motd: &str) -> Self {
+        self.primary_motd = Some(motd.to_string());
+        self
+    }
+
+    /// Sets the secondary MOTD of the server.
+    pub fn with_secondary_motd(mut self, motd: &str) -> Self {
+        self.secondary_motd = Some(motd.to_string());
+        self
+    }
+
+    /// Sets the number of online players advertised by the server.
+    pub fn with_online_players(mut self, online_players: u32) -> Self {
+        self.online_players = Some(online_players);
+        self
+    }
+
+    /// Keeps the number of online players advertised by the server equal to the number of established connections
+    /// with the provided offset added, instead of a fixed number.
+    pub fn with_online_players_sync(mut self, offset: i32) -> Self {
+        self.players_sync = Some(offset);
+        self
+    }
+
+    /// Sets the maximum number of players advertised by the server.
+    pub fn with_max_players(mut self, max_players: u32) -> Self {
+        self.max_players = Some(max_players);
+        self
+    }
+
+    /// Sets the gamemode advertised by the server.
+    pub fn with_gamemode(mut self, gamemode: &str) -> Self {
+        self.gamemode = Some(gamemode.to_string());
+        self
+    }
+
+    /// Sets the Minecraft protocol version advertised by the server.
+    pub fn with_protocol(mut self, protocol: u32) -> Self {
+        self.protocol = Some(protocol);
+        self
+    }
+
+    /// Sets the Minecraft version advertised by the server.
+    pub fn with_version(mut self, version: &str) -> Self {
+        self.version = Some(version.to_string());
+        self
+    }
+
+    /// Sets the world name advertised by the server instead of the secondary MOTD.
+    pub fn with_level_name(mut self, level_name: &str) -> Self {
+        self.level_name = Some(level_name.to_string());
+        self
+    }
+
+    /// Sets the IPv6 port advertised by the server.
+    pub fn with_port_v6(mut self, port: u16) -> Self {
+        self.port_v6 = Some(port);
+        self
+    }
+
+    /// Sets the StatusProvider that produces the payload of the Unconnected Pong instead of the MCPE status.
+    pub fn with_status_provider(mut self, provider: impl StatusProvider) -> Self {
+        self.status_provider = Some(Arc::new(provider));
+        self
+    }
+
+    /// Binds the server on the provided LoopbackNetwork instead of a UdpSocket, which lets the clients on the same
+    /// network connect to it without any UDP ports.
+    pub fn with_loopback(mut self, network: &LoopbackNetwork) -> Self {
+        self.loopback = Some(network.clone());
+        self
+    }
+
+    /// Makes the server send and receive its datagrams on the provided transport instead of binding one on its address,
+    /// such as a ReplayTransport.
+    pub fn with_transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Records all the datagrams of the server with the provided TrafficRecorder.
+    pub fn with_recording(mut self, recorder: &TrafficRecorder) -> Self {
+        self.recorder = Some(recorder.clone());
+        self
+    }
+
+    /// Captures all the datagrams of the server into a pcap file with the provided PcapWriter.
+    pub fn with_pcap(mut self, writer: &PcapWriter) -> Self {
+        self.pcap = Some(writer.clone());
+        self
+    }
+
+    /// Makes the server a NatCoordinator that introduces its clients to each other when they request a NAT
+    /// punchthrough, so that they can connect to each other directly.
+    pub fn with_nat_coordinator(mut self) -> Self {
+        self.nat_coordinator = true;
+        self
+    }
+
+    /// Only lets the clients on the provided Whitelist connect to the server, it can be switched off through the
+    /// Whitelist component of the server entity.
+    pub fn with_whitelist(mut self, whitelist: Whitelist) -> Self {
+        self.whitelist = Some(whitelist);
+        self
+    }
+
+    /// Starts the server with the provided Maintenance, it can be switched off through the Maintenance component of
+    /// the server entity once the server is ready for the clients.
+    pub fn with_maintenance(mut self, maintenance: Maintenance) -> Self {
+        self.maintenance = Some(maintenance);
+        self
+    }
+
+    /// Applies the provided NetworkConditions to all the datagrams of the server. It is only available with the
+    /// simulator feature.
+    #[cfg(feature = "simulator")]
+    pub fn with_conditions(mut self, conditions: NetworkConditions) -> Self {
+        self.conditions = Some(conditions);
+        self
+    }
+
+    /// Binds the server on a WebSocketListener instead of a UdpSocket, which tunnels the datagrams of the clients over
+    /// WebSocket connections. It is only available with the websocket feature.
+    #[cfg(feature = "websocket")]
+    pub fn with_websocket(mut self) -> Self {
+        self.websocket = true;
+        self
+    }
+
+    /// Returns whether the server is bound on a UdpSocket, the LAN discovery is only answered then.
+    fn binds_udp(&self) -> bool {
+        #[cfg(feature = "websocket")]
+        if self.websocket {
+            return false;
+        }
+
+        self.transport.is_none() && self.loopback.is_none()
+    }
+
+    /// Binds the transport of the server on its address unless one was provided, wrapped in a SimulatedTransport if
+    /// conditions are set, in a RecordingTransport if it is recorded and in a PcapTransport if it is captured.
+    fn transport(&self) -> Arc<dyn Transport> {
+        let mut transport: Arc<dyn Transport> = match (&self.transport, &self.loopback) {
+            (Some(transport), _) => transport.clone(),
+            (None, Some(network)) => network.bind(&self.addr).unwrap(),
+            #[cfg(feature = "websocket")]
+            (None, None) if self.websocket => {
+                Arc::new(WebSocketListener::bind(&self.addr).unwrap())
+            }
+            (None, None) => RakSocket::new(&self.addr, true).unwrap().transport,
+        };
+
+        #[cfg(feature = "simulator")]
+        if let Some(conditions) = &self.conditions {
+            transport = Arc::new(SimulatedTransport::new(transport, conditions.clone()));
+        }
+
+        if let Some(recorder) = &self.recorder {
+            transport = Arc::new(recorder.attach(transport).unwrap());
+        }
+
+        if let Some(writer) = &self.pcap {
+            transport = Arc::new(writer.attach(transport).unwrap());
+        }
+
+        transport
+    }
+
+    /// Creates the ServerBundle for the server entity with all the configured values applied.
+    fn bundle(&self) -> ServerBundle {
+        let mut bundle = ServerBundle::with_transport(self.transport());
+
+        if let Some(guid) = self.guid {
+            bundle.info.guid = guid;
+        }
+
+        if let Some(motd) = &self.primary_motd {
+            bundle.primary_motd.set(motd);
+        }
+
+        if let Some(motd) = &self.secondary_motd {
+            bundle.secondary_motd.set(motd);
+        }
+
+        if let Some(online_players) = self.online_players {
+            bundle.online_players.set(online_players);
+        }
+
+        if let Some(max_players) = self.max_players {
+            bundle.max_players.set(max_players);
+        }
+
+        if let Some(gamemode) = &self.gamemode {
+            bundle.gamemode.set(gamemode);
+        }
+
+        if let Some(protocol) = self.protocol {
+            bundle.protocol.set(protocol);
+        }
+
+        if let Some(version) = &self.version {
+            bundle.version.set(version);
+        }
+
+        if let Some(level_name) = &self.level_name {
+            bundle.level_name.set(level_name);
+        }
+
+        if let Some(port) = self.port_v6 {
+            bundle.port_v6.set(port);
+        }
+
+        if let Some(whitelist) = &self.whitelist {
+            bundle.whitelist = whitelist.clone();
+        }
+
+        if let Some(maintenance) = &self.maintenance {
+            bundle.maintenance = maintenance.clone();
+        }
+
+        bundle
+    }
 }
 
 impl Plugin for NetworkServer {
     fn build(&self, app: &mut App) {
-        app.add_event::<RakNetEvent>();
-        app.add_event::<NetworkEvent>();
-        app.add_systems(PreUpdate, server_read_udp);
-        app.add_systems(PreUpdate, flush_receipts.run_if(on_timer(RAKNET_TPS)));
-        app.add_systems(PreUpdate, flush_batch.run_if(on_timer(RAKNET_TPS)));
-        app.add_systems(
-            PreUpdate,
-            check_timeout.run_if(on_timer(RAKNET_CHECK_TIMEOUT)),
-        );
-        app.add_systems(PreUpdate, connection_tick);
-        app.add_systems(Update, server_update_status.run_if(on_timer(RAKNET_TPS)));
-        app.world.spawn(ServerBundle::new(&self.addr));
-        app.insert_resource(StatusResource::new());
+        let config = build_common(app);
+        build_server(app);
+
+        let mut bundle = self.bundle();
+        bundle.block_list = block_list(&config);
+        if let Some(provider) = &self.status_provider {
+            bundle.status.set_provider(provider.clone());
+        }
+
+        let addr = bundle.info.addr;
+        let server = app.world.spawn(bundle).id();
+
+        // A server bound to the LAN discovery port on all interfaces already answers the broadcast pings itself.
+        if config.lan_discovery
+            && self.binds_udp()
+            && !(addr.ip().is_unspecified() && addr.port() == LAN_DISCOVERY_PORT)
+        {
+            match LanListener::bind(server) {
+                Ok(lan) => {
+                    app.insert_resource(lan);
+                }
+                Err(e) => warn!("[LAN Error]: {}", e.to_string()),
+            }
+        }
+
+        if let Some(offset) = self.players_sync {
+            app.insert_resource(OnlinePlayersSync::new(offset));
+        }
+
+        if self.nat_coordinator {
+            app.init_resource::<NatCoordinator>();
+        }
     }
 }
 
+/// NetworkClient is the plugin that connects to one or more RakNet servers. Every connection is spawned as its own
+/// client entity with an independent socket, so the RakNet events of each are routed to that entity.
 pub struct NetworkClient {
-    addr: String,
+    addrs: Vec<String>,
+    login: Option<OfflineLogin>,
+    loopback: Option<LoopbackNetwork>,
+    recorder: Option<TrafficRecorder>,
+    pcap: Option<PcapWriter>,
+    nat_hosting: bool,
+    socks5: Option<(String, Option<(String, String)>)>,
+    #[cfg(feature = "simulator")]
+    conditions: Option<NetworkConditions>,
+    #[cfg(feature = "websocket")]
+    websocket: bool,
 }
 
 impl NetworkClient {
     pub fn new(addr: &str) -> Self {
         Self {
-            addr: addr.to_string(),
+            addrs: vec![addr.to_string()],
+            login: None,
+            loopback: None,
+            recorder: None,
+            pcap: None,
+            nat_hosting: false,
+            socks5: None,
+            #[cfg(feature = "simulator")]
+            conditions: None,
+            #[cfg(feature = "websocket")]
+            websocket: false,
         }
     }
+
+    /// Adds another server that the plugin connects to alongside the ones already provided.
+    pub fn with_connection(mut self, addr: &str) -> Self {
+        self.addrs.push(addr.to_string());
+        self
+    }
+
+    /// Logs the client in with the provided offline login once the RakNet connection of each server is established.
+    pub fn with_login(mut self, login: OfflineLogin) -> Self {
+        self.login = Some(login);
+        self
+    }
+
+    /// Connects to the servers bound on the provided LoopbackNetwork instead of over UDP. The handshake blocks while
+    /// the plugin is built, so the App of the server has to be updated on another thread meanwhile.
+    pub fn with_loopback(mut self, network: &LoopbackNetwork) -> Self {
+        self.loopback = Some(network.clone());
+        self
+    }
+
+    /// Records all the datagrams of every connection with the provided TrafficRecorder.
+    pub fn with_recording(mut self, recorder: &TrafficRecorder) -> Self {
+        self.recorder = Some(recorder.clone());
+        self
+    }
+
+    /// Captures all the datagrams of every connection into a pcap file with the provided PcapWriter.
+    pub fn with_pcap(mut self, writer: &PcapWriter) -> Self {
+        self.pcap = Some(writer.clone());
+        self
+    }
+
+    /// Relays the datagrams of every connection through the SOCKS5 proxy at the provided address with UDP ASSOCIATE
+    /// instead of sending them to the servers directly.
+    pub fn with_socks5(mut self, proxy: &str) -> Self {
+        self.socks5 = Some((proxy.to_string(), None));
+        self
+    }
+
+    /// Relays the datagrams of every connection through the SOCKS5 proxy at the provided address, authenticating with
+    /// the provided username and password.
+    pub fn with_socks5_auth(mut self, proxy: &str, username: &str, password: &str) -> Self {
+        self.socks5 = Some((
+            proxy.to_string(),
+            Some((username.to_string(), password.to_string())),
+        ));
+        self
+    }
+
+    /// Adds the systems of a server as well, so that the transport of a connection can be hosted for the punched peer
+    /// once a NAT punchthrough succeeded.
+    pub fn with_nat_hosting(mut self) -> Self {
+        self.nat_hosting = true;
+        self
+    }
+
+    /// Applies the provided NetworkConditions to all the datagrams of every connection. It is only available with the
+    /// simulator feature.
+    #[cfg(feature = "simulator")]
+    pub fn with_conditions(mut self, conditions: NetworkConditions) -> Self {
+        self.conditions = Some(conditions);
+        self
+    }
+
+    /// Connects to the servers over WebSocket instead of UDP, the servers must be bound with a WebSocketListener. It is
+    /// only available with the websocket feature.
+    #[cfg(feature = "websocket")]
+    pub fn with_websocket(mut self) -> Self {
+        self.websocket = true;
+        self
+    }
+
+    /// Binds the transport of a connection to the provided address on any port of the unspecified address of its
+    /// family, or through the SOCKS5 proxy if one is set, wrapped in a SimulatedTransport if conditions are set, in a
+    /// RecordingTransport if it is recorded and in a PcapTransport if it is captured.
+    fn transport(&self, addr: &str) -> io::Result<Arc<dyn Transport>> {
+        let mut transport: Arc<dyn Transport> = match &self.loopback {
+            Some(network) => network.bind_any()?,
+            #[cfg(feature = "websocket")]
+            None if self.websocket => Arc::new(WebSocketTransport::connect(addr)?),
+            None => match &self.socks5 {
+                Some((proxy, credentials)) => Arc::new(Socks5Transport::connect(
+                    proxy,
+                    credentials
+                        .as_ref()
+                        .map(|(username, password)| (username.as_str(), password.as_str())),
+                )?),
+                None => RakSocket::bind_client(addr)?.transport,
+            },
+        };
+
+        #[cfg(feature = "simulator")]
+        if let Some(conditions) = &self.conditions {
+            transport = Arc::new(SimulatedTransport::new(transport, conditions.clone()));
+        }
+
+        if let Some(recorder) = &self.recorder {
+            transport = Arc::new(recorder.attach(transport)?);
+        }
+
+        if let Some(writer) = &self.pcap {
+            transport = Arc::new(writer.attach(transport)?);
+        }
+
+        Ok(transport)
+    }
 }
 
 impl Plugin for NetworkClient {
     fn build(&self, app: &mut App) {
-        app.add_event::<RakNetEvent>();
-        app.add_event::<NetworkEvent>();
-        app.add_systems(PreUpdate, client_read_udp);
-        app.add_systems(PreUpdate, flush_receipts.run_if(on_timer(RAKNET_TPS)));
-        app.add_systems(PreUpdate, flush_batch.run_if(on_timer(RAKNET_TPS)));
-        app.add_systems(
-            PreUpdate,
-            check_timeout.run_if(on_timer(RAKNET_CHECK_TIMEOUT)),
-        );
-        app.add_systems(PreUpdate, connection_tick);
+        build_common(app);
+        build_client(app);
 
-        RakSocket::connect(&self.addr, &mut app.world).unwrap();
+        if self.nat_hosting {
+            build_server(app);
+        }
+
+        for addr in self.addrs.iter() {
+            let entity = self
+                .transport(addr)
+                .and_then(|transport| RakSocket::connect_with(transport, addr, &mut app.world))
+                .unwrap();
+
+            if let Some(login) = &self.login {
+                app.world
+                    .entity_mut(entity)
+                    .insert((login.clone(), LoginState::default()));
+            }
+        }
     }
 }
 
+/// NetworkProxy is the plugin that binds a RakNet server on the provided address and connects every client of it to
+/// one of the backends, forwarding the batches of both connections to each other without decoding them.
 pub struct NetworkProxy {
     addr: String,
+    backends: Vec<(String, u32)>,
+    strategy: BalanceStrategy,
+    passthrough: Option<StatusPassthrough>,
+    fallback: Option<String>,
+    reconnect: Option<ReconnectPolicy>,
+    rate_limit: Option<ProxyRateLimit>,
+    interceptors: Mutex<Vec<Box<dyn ProxyInterceptor>>>,
 }
 
 impl NetworkProxy {
-    pub fn new(addr: &str) -> Self {
+    pub fn new(addr: &str, backend: &str) -> Self {
         Self {
             addr: addr.to_string(),
+            backends: vec![(backend.to_string(), 1)],
+            strategy: BalanceStrategy::default(),
+            passthrough: None,
+            fallback: None,
+            reconnect: None,
+            rate_limit: None,
+            interceptors: Mutex::new(Vec::new()),
         }
     }
+
+    /// Adds another backend with the provided weight that the clients are balanced across.
+    pub fn with_backend(mut self, backend: &str, weight: u32) -> Self {
+        self.backends.push((backend.to_string(), weight));
+        self
+    }
+
+    /// Sets the strategy used to pick the backend of every new client.
+    pub fn with_strategy(mut self, strategy: BalanceStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Mirrors the status of the backends into the status advertised by the proxy.
+    pub fn with_status_passthrough(mut self, passthrough: StatusPassthrough) -> Self {
+        self.passthrough = Some(passthrough);
+        self
+    }
+
+    /// Sets the backend that the clients are moved to when their backend refuses the connection or times out.
+    pub fn with_fallback(mut self, backend: &str) -> Self {
+        self.fallback = Some(backend.to_string());
+        self
+    }
+
+    /// Holds the clients whose backend timed out while they are reconnected to it with the provided policy.
+    pub fn with_reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect = Some(policy);
+        self
+    }
+
+    /// Limits the traffic that every client can push through the proxy towards its backend.
+    pub fn with_rate_limit(mut self, limit: ProxyRateLimit) -> Self {
+        self.rate_limit = Some(limit);
+        self
+    }
+
+    /// Appends the provided interceptor to the chain that the forwarded batches are passed through.
+    pub fn with_interceptor(self, interceptor: impl ProxyInterceptor) -> Self {
+        self.interceptors
+            .lock()
+            .unwrap()
+            .push(Box::new(interceptor));
+        self
+    }
 }
 
 impl Plugin for NetworkProxy {
     fn build(&self, app: &mut App) {
+        let config = build_common(app);
+        build_server(app);
+        build_client(app);
+
+        let mut backends = Backends::new(self.strategy);
+        for (addr, weight) in self.backends.iter() {
+            if let Err(e) = backends.add(addr, *weight) {
+                warn!("[Proxy Error]: {}", e.to_string());
+            }
+        }
+
+        if let Err(e) = backends.set_fallback(self.fallback.as_deref()) {
+            warn!("[Proxy Error]: {}", e.to_string());
+        }
+
+        let mut interceptors = ProxyInterceptors::default();
+        for interceptor in self.interceptors.lock().unwrap().drain(..) {
+            interceptors.add_boxed(interceptor);
+        }
+
+        let mut bundle = ServerBundle::new(&self.addr);
+        bundle.block_list = block_list(&config);
+
+        let server = app.world.spawn(bundle).id();
+        app.insert_resource(backends);
+        app.insert_resource(interceptors);
+
+        if let Some(policy) = &self.reconnect {
+            app.insert_resource(policy.clone());
+        }
+
+        if let Some(limit) = &self.rate_limit {
+            app.insert_resource(limit.clone());
+        }
+
+        if let Some(passthrough) = &self.passthrough {
+            match ServerListPinger::new(passthrough.interval()) {
+                Ok(pinger) => {
+                    app.insert_resource(pinger);
+                    let mut passthrough = passthrough.clone();
+                    passthrough.server = server;
+                    app.insert_resource(passthrough);
+                }
+                Err(e) => warn!("[Proxy Error]: {}", e.to_string()),
+            }
+        }
+
+        app.add_event::<ProxyEvent>();
+        app.add_systems(
+            config.schedule,
+            (
+                proxy_connect,
+                proxy_handshake.before(proxy_forward),
+                proxy_transfer,
+                proxy_forward.after(proxy_transfer).before(connection_tick),
+                proxy_reconnect
+                    .run_if(resource_exists::<ReconnectPolicy>())
+                    .before(proxy_forward),
+                proxy_mirror_status
+                    .run_if(resource_exists::<StatusPassthrough>())
+                    .before(server_update_status),
+            )
+                .in_set(NetworkSet::Process),
+        );
+    }
+}
+
+/// NetworkHeartbeat is the optional plugin that reports the address, MOTD, player counts and version of the servers of
+/// the App to a master server on an interval, so that community server lists can list them. It is added alongside the
+/// NetworkServer plugin.
+pub struct NetworkHeartbeat {
+    target: HeartbeatTarget,
+    interval: Duration,
+    address: Option<String>,
+}
+
+impl NetworkHeartbeat {
+    /// Reports the servers to the master server with a JSON POST request to the provided http:// URL.
+    pub fn http(url: &str) -> Self {
+        Self::new(HeartbeatTarget::Http(url.to_string()))
+    }
+
+    /// Reports the servers to the master server with a JSON datagram sent to the provided address.
+    pub fn udp(addr: SocketAddr) -> Self {
+        Self::new(HeartbeatTarget::Udp(addr))
+    }
+
+    fn new(target: HeartbeatTarget) -> Self {
+        Self {
+            target,
+            interval: HEARTBEAT_INTERVAL,
+            address: None,
+        }
+    }
+
+    /// Sets how often the servers are reported instead of HEARTBEAT_INTERVAL.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets the public address the servers are reported with instead of the address they are bound to, which is
+    /// needed for the servers bound on all the interfaces or behind a NAT.
+    pub fn with_address(mut self, address: &str) -> Self {
+        self.address = Some(address.to_string());
+        self
+    }
+}
+
+impl Plugin for NetworkHeartbeat {
+    fn build(&self, app: &mut App) {
+        let schedule = app
+            .world
+            .get_resource_or_insert_with(RakNetConfig::default)
+            .schedule;
+
+        match Heartbeat::new(self.target.clone(), self.interval, self.address.as_deref()) {
+            Ok(heartbeat) => {
+                app.insert_resource(heartbeat);
+            }
+            Err(e) => warn!("[Heartbeat Error]: {}", e.to_string()),
+        }
+
+        app.add_systems(
+            schedule,
+            announce_heartbeat
+                .run_if(resource_exists::<Heartbeat>())
+                .after(server_update_status)
+                .in_set(NetworkSet::Process),
+        );
+    }
+}
+
+/// Adds the NetworkCore plugin unless another network plugin of the App already did. Returns the RakNetConfig that
+/// the systems were configured with.
+fn build_common(app: &mut App) -> RakNetConfig {
+    add_once(app, NetworkCore);
+    app.world.resource::<RakNetConfig>().clone()
+}
+
+/// Adds the provided internal plugin unless it was already added, so that the systems it adds run only once when an
+/// App has several of the network plugins.
+fn add_once<T: Plugin>(app: &mut App, plugin: T) {
+    if !app.is_plugin_added::<T>() {
+        app.add_plugins(plugin);
+    }
+}
+
+/// NetworkCore is the internal plugin that adds the events, resources and systems that are shared by all the network
+/// plugins.
+struct NetworkCore;
+
+impl Plugin for NetworkCore {
+    fn build(&self, app: &mut App) {
+        let config = app
+            .world
+            .get_resource_or_insert_with(RakNetConfig::default)
+            .clone();
+
         app.add_event::<RakNetEvent>();
         app.add_event::<NetworkEvent>();
-        app.add_systems(PreUpdate, server_read_udp);
-        app.add_systems(PreUpdate, client_read_udp);
-        app.add_systems(PreUpdate, flush_receipts.run_if(on_timer(RAKNET_TPS)));
-        app.add_systems(PreUpdate, flush_batch.run_if(on_timer(RAKNET_TPS)));
+        app.register_type::<NetworkInfo>();
+        app.register_type::<NetworkStatus>();
+        app.register_type::<SocketInfo>();
+        app.init_resource::<ReadBudget>();
+        app.init_resource::<NetworkingState>();
+        app.init_resource::<TraceFilter>();
+        app.init_resource::<OutgoingQueue>();
+        app.init_resource::<MessageInterceptors>();
+        app.configure_sets(
+            config.schedule,
+            (NetworkSet::Receive, NetworkSet::Process, NetworkSet::Flush).chain(),
+        );
+        app.configure_sets(
+            config.schedule,
+            (
+                NetworkSet::Receive.run_if(networking_running),
+                NetworkSet::Process.run_if(networking_running),
+                NetworkSet::Flush.run_if(networking_running),
+            ),
+        );
+        app.add_systems(
+            config.schedule,
+            (
+                queue_outgoing,
+                compress_outgoing
+                    .run_if(on_flush(&config))
+                    .after(queue_outgoing)
+                    .before(connection_tick),
+                decompress_incoming,
+                connection_tick,
+                apply_trace_filter,
+                check_timeout.run_if(on_timer(config.check_interval)),
+                evict_splits.run_if(on_timer(config.check_interval)),
+                check_buffered_memory.run_if(on_timer(config.check_interval)),
+                check_quality.run_if(on_timer(config.check_interval)),
+            )
+                .in_set(NetworkSet::Process),
+        );
+        app.add_systems(
+            config.schedule,
+            (
+                flush_receipts.run_if(on_flush(&config)),
+                flush_batch.run_if(on_flush(&config)),
+                // The flush systems of the servers and of the clients only write the datagrams of their own sockets,
+                // so both of them run whichever plugins were added.
+                server_flush_udp.after(flush_receipts).after(flush_batch),
+                client_flush_udp.after(flush_receipts).after(flush_batch),
+            )
+                .in_set(NetworkSet::Flush),
+        );
+        app.add_systems(config.schedule, drive_clock.before(NetworkSet::Receive));
+        app.add_systems(Last, disconnect_on_exit);
+    }
+}
+
+/// Adds the ServerCore plugin unless another network plugin of the App already did.
+fn build_server(app: &mut App) {
+    add_once(app, ServerCore);
+}
+
+/// ServerCore is the internal plugin that adds the systems that read from and write to the Transport of RakNet
+/// servers.
+struct ServerCore;
+
+impl Plugin for ServerCore {
+    fn build(&self, app: &mut App) {
+        let config = app.world.resource::<RakNetConfig>().clone();
+
+        app.register_type::<PrimaryMotd>();
+        app.register_type::<SecondaryMotd>();
+        app.register_type::<OnlinePlayers>();
+        app.register_type::<MaxPlayers>();
+        app.register_type::<BroadcastGamemode>();
+        app.register_type::<MinecraftProtocol>();
+        app.register_type::<MinecraftVersion>();
+        app.register_type::<LevelName>();
+        app.register_type::<PortV6>();
+        app.init_resource::<ConnectionFilters>();
         app.add_systems(
-            PreUpdate,
-            check_timeout.run_if(on_timer(RAKNET_CHECK_TIMEOUT)),
+            config.schedule,
+            (
+                cleanup_mappings.run_if(on_timer(config.cleanup_interval)),
+                server_read_udp,
+                resolve_approvals,
+            )
+                .chain()
+                .in_set(NetworkSet::Receive),
         );
-        app.add_systems(PreUpdate, connection_tick);
-        app.add_systems(Update, server_update_status.run_if(on_timer(RAKNET_TPS)));
-        app.world.spawn(ServerBundle::new(&self.addr));
-        app.insert_resource(StatusResource::new());
+        app.add_systems(
+            config.schedule,
+            server_answer_lan
+                .run_if(resource_exists::<LanListener>())
+                .in_set(NetworkSet::Receive),
+        );
+        app.add_systems(Last, save_blocks_on_exit);
+        app.add_systems(
+            config.schedule,
+            (
+                // The packets of the login are queued in the same run, ahead of a disconnection behind them.
+                handle_login
+                    .after(decompress_incoming)
+                    .before(queue_outgoing),
+                sync_online_players
+                    .run_if(resource_exists::<OnlinePlayersSync>())
+                    .before(server_update_status),
+                server_update_status,
+                coordinate_nat_punchthrough.run_if(resource_exists::<NatCoordinator>()),
+                publish_blocks.before(connection_tick),
+                check_latency
+                    .run_if(on_timer(config.check_interval))
+                    .before(connection_tick),
+                check_idle
+                    .run_if(on_timer(config.check_interval))
+                    .before(connection_tick),
+                check_login_timeout
+                    .run_if(on_timer(config.check_interval))
+                    .before(connection_tick),
+                handle_duplicate_logins.before(connection_tick),
+            )
+                .in_set(NetworkSet::Process),
+        );
+    }
+}
 
-        RakSocket::connect(&self.addr, &mut app.world).unwrap();
+/// Creates the BlockList of the server spawned by a plugin, loaded from the configured file if it is persisted.
+fn block_list(config: &RakNetConfig) -> BlockList {
+    let mut block_list = BlockList::new(config.clock.clone());
+
+    if let Some(path) = &config.block_list_path {
+        if let Err(e) = block_list.persist_to(path) {
+            warn!("[Block List Error]: {}", e.to_string());
+        }
     }
+
+    block_list
+}
+
+/// Adds the ClientCore plugin unless another network plugin of the App already did.
+fn build_client(app: &mut App) {
+    add_once(app, ClientCore);
+}
+
+/// ClientCore is the internal plugin that adds the systems that read from and write to the Transport of RakNet
+/// clients.
+struct ClientCore;
+
+impl Plugin for ClientCore {
+    fn build(&self, app: &mut App) {
+        let config = app.world.resource::<RakNetConfig>().clone();
+
+        app.add_state::<ClientNetState>();
+        app.add_systems(
+            config.schedule,
+            (
+                client_read_udp,
+                ping_server_list.run_if(resource_exists::<ServerListPinger>()),
+                discover_lan.run_if(resource_exists::<LanDiscovery>()),
+            )
+                .in_set(NetworkSet::Receive),
+        );
+        app.add_systems(
+            config.schedule,
+            (
+                client_update_state.after(connection_tick),
+                client_login
+                    .after(decompress_incoming)
+                    .after(compress_outgoing),
+                start_nat_punchthrough,
+                nat_handshake,
+            )
+                .in_set(NetworkSet::Process),
+        );
+        app.add_systems(config.schedule, nat_punchthrough.in_set(NetworkSet::Flush));
+    }
+}
+
+/// Run condition of the flush systems. They run on every tick of a fixed timestep schedule and on the flush
+/// interval otherwise.
+fn on_flush(config: &RakNetConfig) -> impl FnMut(Res<Time>) -> bool + Clone {
+    let fixed_tick = config.fixed_tick;
+    let mut timer = on_timer(config.flush_interval);
+
+    move |time: Res<Time>| fixed_tick || timer(time)
 }
 
 fn main() {
-    init_logger(LevelFilter::Trace);
+    // The logs are written through a tracing subscriber so that they carry the fields of the connection spans.
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::TRACE)
+        .init();
 
     let mut task_pool_options = TaskPoolOptions::default();
     task_pool_options.io.min_threads = 0;
@@ -120,3 +993,35 @@ fn main() {
         .add_plugins(NetworkServer::new("127.0.0.1:19132"))
         .run();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_systems_are_added_once() {
+        let network = LoopbackNetwork::new();
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(RakNetConfig::default());
+        app.add_plugins(NetworkServer::new("127.0.0.1:19132").with_loopback(&network));
+        app.add_plugins(NetworkProxy::new("127.0.0.1:0", "127.0.0.1:19132"));
+
+        let schedule = app.world.resource::<RakNetConfig>().schedule;
+        let schedules = app.world.resource::<Schedules>();
+        let graph = schedules.get(schedule).unwrap().graph();
+
+        for name in [
+            "connection_tick",
+            "server_flush_udp",
+            "client_flush_udp",
+            "handle_login",
+        ] {
+            let count = graph
+                .systems()
+                .filter(|(_, system, _)| system.name().ends_with(&format!("::{}", name)))
+                .count();
+            assert_eq!(count, 1, "{} was added {} times", name, count);
+        }
+    }
+}
@@ -0,0 +1,806 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    fs,
+    io::{Error, ErrorKind, Result},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bevy::ecs::component::Component;
+use serde_json::{json, Value};
+
+use crate::generic::{events::RakNetEvent, time::TimeSource};
+
+/// BlockReason describes why an address was blocked from a server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockReason {
+    /// The address exceeded the maximum number of messages per second.
+    PacketSpam,
+    /// The address exceeded the maximum number of malformed messages.
+    InvalidPackets,
+    /// The address exceeded the maximum number of unconnected messages per second, such as pings.
+    UnconnectedFlood,
+    /// The address was blocked by the App with a custom reason, such as a ban.
+    Custom(String),
+}
+
+/// BlockEntry is a single address or range on a BlockList, with the time it is blocked until and the reason it was
+/// blocked for.
+#[derive(Debug, Clone)]
+pub struct BlockEntry {
+    /// The time the block expires at, the block is permanent if there is none.
+    pub expiry: Option<Instant>,
+    pub reason: BlockReason,
+}
+
+impl BlockEntry {
+    /// Returns whether the block has not expired at the provided time.
+    pub fn is_active(&self, now: Instant) -> bool {
+        is_active(self.expiry, now)
+    }
+}
+
+/// Offense is a single time an address was blocked by the spam checks of a server, which is remembered to block the
+/// repeat offenders for longer.
+#[derive(Debug, Clone)]
+pub struct Offense {
+    pub at: Instant,
+    pub duration: Duration,
+    pub reason: BlockReason,
+}
+
+fn is_active(expiry: Option<Instant>, now: Instant) -> bool {
+    expiry.map_or(true, |expiry| expiry > now)
+}
+
+/// Cidr is a range of IP addresses in the CIDR notation, such as 203.0.113.0/24. The bits of the address past the
+/// prefix are always zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Creates the range of the addresses that share the first prefix bits of the provided address. The prefix cannot
+    /// exceed 32 bits for IPv4 addresses and 128 bits for IPv6 addresses.
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self> {
+        let width = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        if prefix > width {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "The prefix of the range exceeds the length of its address",
+            ));
+        }
+
+        let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+        let bits = bits(addr) & mask;
+
+        let addr = match addr {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from((bits >> 96) as u32)),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(bits)),
+        };
+
+        Ok(Self { addr, prefix })
+    }
+
+    /// Returns the first address of the range.
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// Returns the number of leading bits shared by all the addresses of the range.
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// Returns whether the provided address is within the range.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        if self.addr.is_ipv4() != ip.is_ipv4() {
+            return false;
+        }
+
+        let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+        bits(ip) & mask == bits(self.addr)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "The range is not in the CIDR notation",
+            )
+        };
+
+        let (addr, prefix) = s.split_once('/').ok_or_else(invalid)?;
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let prefix: u8 = prefix.parse().map_err(|_| invalid())?;
+
+        Self::new(addr, prefix)
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Returns the bits of the provided address aligned to the most significant bit, so that the prefixes of the IPv4 and
+/// IPv6 addresses are read the same way.
+fn bits(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => (u32::from(ip) as u128) << 96,
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+/// TrieNode is a node of the binary prefix trie of the blocked ranges, one level per bit of the addresses. The nodes
+/// at the end of a blocked prefix hold the expiry of its block.
+#[derive(Default)]
+struct TrieNode {
+    children: [Option<Box<TrieNode>>; 2],
+    expiry: Option<Option<Instant>>,
+}
+
+impl TrieNode {
+    fn insert(&mut self, range: Cidr, expiry: Option<Instant>) {
+        let bits = bits(range.addr);
+        let mut node = self;
+
+        for i in 0..range.prefix {
+            let bit = (bits >> (127 - i)) as usize & 1;
+            node = node.children[bit].get_or_insert_with(Default::default);
+        }
+
+        node.expiry = Some(expiry);
+    }
+
+    /// Removes the block of the provided range, the nodes left without any blocks below them are pruned.
+    fn remove(&mut self, bits: u128, depth: u8, prefix: u8) -> bool {
+        if depth == prefix {
+            self.expiry = None;
+        } else {
+            let bit = (bits >> (127 - depth)) as usize & 1;
+
+            if let Some(child) = &mut self.children[bit] {
+                if child.remove(bits, depth + 1, prefix) {
+                    self.children[bit] = None;
+                }
+            }
+        }
+
+        self.expiry.is_none() && self.children.iter().all(Option::is_none)
+    }
+
+    /// Returns whether any of the ranges containing the provided address has an active block. Only as many nodes as
+    /// the length of the longest blocked prefix are visited.
+    fn contains(&self, ip: IpAddr, now: Instant) -> bool {
+        let bits = bits(ip);
+        let mut node = self;
+
+        for depth in 0..128 {
+            if node.expiry.is_some_and(|expiry| is_active(expiry, now)) {
+                return true;
+            }
+
+            let bit = (bits >> (127 - depth)) as usize & 1;
+            match &node.children[bit] {
+                Some(child) => node = child,
+                None => return false,
+            }
+        }
+
+        node.expiry.is_some_and(|expiry| is_active(expiry, now))
+    }
+}
+
+/// BlockList is the component of a server with the addresses whose datagrams are dropped without being read. The spam
+/// checks of the server block the addresses through it, the App can manage the blocks directly as well and can block
+/// whole CIDR ranges. Every change is written as an event and the connections of blocked addresses are disconnected.
+/// A BlockList can be persisted to a JSON file, which it is written back to whenever it changes.
+#[derive(Component, Default)]
+pub struct BlockList {
+    blocked: HashMap<SocketAddr, BlockEntry>,
+    ranges: HashMap<Cidr, BlockEntry>,
+    v4: TrieNode,
+    v6: TrieNode,
+    clock: TimeSource,
+    offenses: HashMap<SocketAddr, Vec<Offense>>,
+    changes: Vec<RakNetEvent>,
+    path: Option<PathBuf>,
+}
+
+impl BlockList {
+    /// Creates an empty BlockList whose blocks expire on the provided clock.
+    pub fn new(clock: TimeSource) -> Self {
+        Self {
+            blocked: HashMap::new(),
+            ranges: HashMap::new(),
+            v4: TrieNode::default(),
+            v6: TrieNode::default(),
+            clock,
+            offenses: HashMap::new(),
+            changes: Vec::new(),
+            path: None,
+        }
+    }
+
+    /// Persists the BlockList to the JSON file at the provided path, loading the unexpired blocks that the file
+    /// already contains. A missing file is created on the next change.
+    pub fn persist_to(&mut self, path: impl Into<PathBuf>) -> Result<()> {
+        let path = path.into();
+        let result = match fs::read_to_string(&path) {
+            Ok(contents) => self.load(&contents),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        };
+
+        self.path = Some(path);
+        result
+    }
+
+    /// Returns the path of the file the BlockList is persisted to if there is any.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Writes the unexpired blocks to the file the BlockList is persisted to, it does nothing if it is not persisted.
+    /// The file is replaced at once so that it is never left half written.
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let now = self.clock.now();
+        let unix_now = SystemTime::now();
+
+        let addresses = self
+            .blocked
+            .iter()
+            .map(|(addr, entry)| ("address", addr.to_string(), entry));
+        let ranges = self
+            .ranges
+            .iter()
+            .map(|(range, entry)| ("range", range.to_string(), entry));
+
+        let blocks: Vec<Value> = addresses
+            .chain(ranges)
+            .filter(|(_, _, entry)| entry.is_active(now))
+            .map(|(key, target, entry)| {
+                // The instants only make sense within this process, the expiries are stored as UNIX timestamps.
+                let expires_at = entry.expiry.and_then(|expiry| {
+                    let at = unix_now.checked_add(expiry.saturating_duration_since(now))?;
+                    Some(at.duration_since(UNIX_EPOCH).ok()?.as_millis() as u64)
+                });
+
+                let (reason, message) = match &entry.reason {
+                    BlockReason::PacketSpam => ("packet_spam", None),
+                    BlockReason::InvalidPackets => ("invalid_packets", None),
+                    BlockReason::UnconnectedFlood => ("unconnected_flood", None),
+                    BlockReason::Custom(message) => ("custom", Some(message.as_str())),
+                };
+
+                let mut block = json!({
+                    "expires_at": expires_at,
+                    "reason": reason,
+                    "message": message,
+                });
+                block[key] = Value::String(target);
+                block
+            })
+            .collect();
+
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+
+        fs::write(&tmp, Value::Array(blocks).to_string())?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Loads the blocks of the provided JSON written by save, the expired blocks are skipped.
+    fn load(&mut self, contents: &str) -> Result<()> {
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_string());
+
+        let value: Value = serde_json::from_str(contents)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        let Value::Array(blocks) = value else {
+            return Err(invalid("The block list file is not a JSON array"));
+        };
+
+        let now = self.clock.now();
+        let unix_now = SystemTime::now();
+
+        for block in blocks.iter() {
+            let range = match block["range"].as_str() {
+                Some(range) => Some(range.parse::<Cidr>()?),
+                None => None,
+            };
+
+            let addr: Option<SocketAddr> = match range {
+                Some(_) => None,
+                None => Some(
+                    block["address"]
+                        .as_str()
+                        .and_then(|addr| addr.parse().ok())
+                        .ok_or_else(|| {
+                            invalid("A blocked address in the block list file is invalid")
+                        })?,
+                ),
+            };
+
+            let expiry = match block["expires_at"].as_u64() {
+                Some(millis) => {
+                    let at = UNIX_EPOCH + Duration::from_millis(millis);
+                    match at.duration_since(unix_now) {
+                        Ok(remaining) => Some(now + remaining),
+                        Err(_) => continue,
+                    }
+                }
+                None => None,
+            };
+
+            let reason = match block["reason"].as_str() {
+                Some("packet_spam") => BlockReason::PacketSpam,
+                Some("invalid_packets") => BlockReason::InvalidPackets,
+                Some("unconnected_flood") => BlockReason::UnconnectedFlood,
+                _ => BlockReason::Custom(block["message"].as_str().unwrap_or_default().to_string()),
+            };
+
+            let entry = BlockEntry { expiry, reason };
+            match (addr, range) {
+                (Some(addr), _) => {
+                    self.blocked.insert(addr, entry);
+                }
+                (None, Some(range)) => {
+                    self.trie(range.addr).insert(range, expiry);
+                    self.ranges.insert(range, entry);
+                }
+                (None, None) => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Blocks the provided address for the provided duration, a duration too long to be represented blocks it
+    /// permanently. An existing block of the address is replaced.
+    pub fn block(&mut self, addr: SocketAddr, duration: Duration, reason: BlockReason) {
+        let expiry = self.clock.now().checked_add(duration);
+
+        self.blocked.insert(
+            addr,
+            BlockEntry {
+                expiry,
+                reason: reason.clone(),
+            },
+        );
+        self.changes
+            .push(RakNetEvent::AddressBlocked(addr, duration, reason));
+    }
+
+    /// Blocks the provided address for an offense against the spam checks. The first offense is blocked for the
+    /// provided duration, the next ones for the durations of the escalation in order and the offenses past the last
+    /// of them are blocked permanently. The blocks of repeat offenders are written as BlockEscalated events too.
+    pub fn block_offender(
+        &mut self,
+        addr: SocketAddr,
+        duration: Duration,
+        escalation: &[Duration],
+        reason: BlockReason,
+    ) {
+        let offenses = self.offenses.entry(addr).or_default();
+        let duration = match offenses.len() {
+            0 => duration,
+            n => escalation.get(n - 1).copied().unwrap_or(Duration::MAX),
+        };
+
+        offenses.push(Offense {
+            at: self.clock.now(),
+            duration,
+            reason: reason.clone(),
+        });
+
+        let count = offenses.len() as u32;
+        if count > 1 {
+            self.changes
+                .push(RakNetEvent::BlockEscalated(addr, count, duration));
+        }
+
+        self.block(addr, duration, reason);
+    }
+
+    /// Returns the offenses of the provided address that are remembered, from the oldest to the latest.
+    pub fn offenses(&self, addr: SocketAddr) -> &[Offense] {
+        self.offenses.get(&addr).map_or(&[], Vec::as_slice)
+    }
+
+    /// Forgets all the offenses of the provided address, so that its next offense is blocked as its first one.
+    /// Returns false if it had none.
+    pub fn forgive(&mut self, addr: SocketAddr) -> bool {
+        self.offenses.remove(&addr).is_some()
+    }
+
+    /// Forgets the offenses of the addresses that have not offended for the provided duration.
+    pub(crate) fn forget_offenses(&mut self, memory: Duration) {
+        let now = self.clock.now();
+
+        self.offenses.retain(|_, offenses| {
+            offenses
+                .last()
+                .is_some_and(|offense| now.saturating_duration_since(offense.at) < memory)
+        });
+    }
+
+    /// Unblocks the provided address. Returns false if the address was not blocked.
+    pub fn unblock(&mut self, addr: SocketAddr) -> bool {
+        if self.blocked.remove(&addr).is_none() {
+            return false;
+        }
+
+        self.changes.push(RakNetEvent::AddressUnblocked(addr));
+        true
+    }
+
+    /// Blocks all the addresses within the provided range for the provided duration, a duration too long to be
+    /// represented blocks them permanently. An existing block of the same range is replaced.
+    pub fn block_range(&mut self, range: Cidr, duration: Duration, reason: BlockReason) {
+        let expiry = self.clock.now().checked_add(duration);
+
+        self.trie(range.addr).insert(range, expiry);
+        self.ranges.insert(
+            range,
+            BlockEntry {
+                expiry,
+                reason: reason.clone(),
+            },
+        );
+        self.changes
+            .push(RakNetEvent::RangeBlocked(range, duration, reason));
+    }
+
+    /// Unblocks the provided range, the addresses within it that are blocked on their own or by another range stay
+    /// blocked. Returns false if the range was not blocked.
+    pub fn unblock_range(&mut self, range: Cidr) -> bool {
+        if self.ranges.remove(&range).is_none() {
+            return false;
+        }
+
+        self.trie(range.addr)
+            .remove(bits(range.addr), 0, range.prefix);
+        self.changes.push(RakNetEvent::RangeUnblocked(range));
+        true
+    }
+
+    /// Returns whether the provided address is blocked on its own or by any of the ranges containing it, the expired
+    /// blocks are not.
+    pub fn is_blocked(&self, addr: SocketAddr) -> bool {
+        let now = self.clock.now();
+
+        if let Some(entry) = self.blocked.get(&addr) {
+            if entry.is_active(now) {
+                return true;
+            }
+        }
+
+        match addr.ip() {
+            IpAddr::V4(_) => self.v4.contains(addr.ip(), now),
+            IpAddr::V6(_) => self.v6.contains(addr.ip(), now),
+        }
+    }
+
+    /// Returns the block of the provided address if there is any, even if it has expired.
+    pub fn get(&self, addr: SocketAddr) -> Option<&BlockEntry> {
+        self.blocked.get(&addr)
+    }
+
+    /// Returns an iterator over all the blocked addresses and their blocks.
+    pub fn iter(&self) -> impl Iterator<Item = (&SocketAddr, &BlockEntry)> {
+        self.blocked.iter()
+    }
+
+    /// Returns an iterator over all the blocked ranges and their blocks.
+    pub fn ranges(&self) -> impl Iterator<Item = (&Cidr, &BlockEntry)> {
+        self.ranges.iter()
+    }
+
+    /// Returns the trie of the blocked ranges of the family of the provided address.
+    fn trie(&mut self, ip: IpAddr) -> &mut TrieNode {
+        match ip {
+            IpAddr::V4(_) => &mut self.v4,
+            IpAddr::V6(_) => &mut self.v6,
+        }
+    }
+
+    /// Evicts the expired blocks and returns the changes made to the BlockList since the last call as events.
+    pub(crate) fn drain_changes(&mut self) -> Vec<RakNetEvent> {
+        let now = self.clock.now();
+        let changes = &mut self.changes;
+
+        self.blocked.retain(|addr, entry| {
+            if entry.is_active(now) {
+                return true;
+            }
+
+            changes.push(RakNetEvent::AddressUnblocked(*addr));
+            false
+        });
+
+        let expired: Vec<Cidr> = self
+            .ranges
+            .iter()
+            .filter(|(_, entry)| !entry.is_active(now))
+            .map(|(range, _)| *range)
+            .collect();
+
+        for range in expired {
+            self.unblock_range(range);
+        }
+
+        std::mem::take(&mut self.changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_parsing() {
+        let range = cidr("203.0.113.77/24");
+        assert_eq!(range.addr(), "203.0.113.0".parse::<IpAddr>().unwrap());
+        assert_eq!(range.prefix(), 24);
+        assert_eq!(range.to_string(), "203.0.113.0/24");
+
+        let range = cidr("2001:db8::1/32");
+        assert_eq!(range.addr(), "2001:db8::".parse::<IpAddr>().unwrap());
+        assert_eq!(cidr("0.0.0.0/0").prefix(), 0);
+        assert_eq!(
+            cidr("10.0.0.1/32").addr(),
+            "10.0.0.1".parse::<IpAddr>().unwrap()
+        );
+
+        for invalid in [
+            "10.0.0.0",
+            "10.0.0.0/33",
+            "10.0.0.0/-1",
+            "10.0.0.0/x",
+            "2001:db8::/129",
+            "not an ip/8",
+        ] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn cidr_contains_only_its_family() {
+        let range = cidr("10.1.0.0/16");
+        assert!(range.contains("10.1.255.3".parse().unwrap()));
+        assert!(!range.contains("10.2.0.0".parse().unwrap()));
+
+        // An IPv6 address whose leading bits match the IPv4 range is not within it, nor is the reverse.
+        assert!(!range.contains("a01::".parse().unwrap()));
+        assert!(!cidr("::/0").contains("10.1.0.0".parse().unwrap()));
+        assert!(cidr("0.0.0.0/0").contains("192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ranges_block_the_addresses_within_them() {
+        let mut list = BlockList::new(TimeSource::mock());
+
+        list.block_range(cidr("10.1.0.0/16"), Duration::MAX, BlockReason::PacketSpam);
+        list.block_range(
+            cidr("2001:db8::/32"),
+            Duration::MAX,
+            BlockReason::PacketSpam,
+        );
+
+        assert!(list.is_blocked(addr("10.1.2.3:19132")));
+        assert!(!list.is_blocked(addr("10.2.2.3:19132")));
+        assert!(list.is_blocked(addr("[2001:db8::5]:19132")));
+        assert!(!list.is_blocked(addr("[2001:db9::5]:19132")));
+    }
+
+    #[test]
+    fn unblocking_a_range_prunes_its_nodes() {
+        let mut list = BlockList::new(TimeSource::mock());
+        let wide = cidr("10.0.0.0/8");
+        let narrow = cidr("10.1.0.0/16");
+
+        list.block_range(wide, Duration::MAX, BlockReason::PacketSpam);
+        list.block_range(narrow, Duration::MAX, BlockReason::PacketSpam);
+
+        // The narrower range stays blocked when the wider one containing it is unblocked.
+        assert!(list.unblock_range(wide));
+        assert!(list.is_blocked(addr("10.1.0.1:19132")));
+        assert!(!list.is_blocked(addr("10.2.0.1:19132")));
+
+        assert!(list.unblock_range(narrow));
+        assert!(!list.unblock_range(narrow));
+        assert!(!list.is_blocked(addr("10.1.0.1:19132")));
+        assert!(list.v4.children.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn blocks_expire_on_the_clock() {
+        let clock = TimeSource::mock();
+        let mut list = BlockList::new(clock.clone());
+
+        list.block(
+            addr("192.0.2.1:19132"),
+            Duration::from_secs(10),
+            BlockReason::PacketSpam,
+        );
+        list.block_range(
+            cidr("198.51.100.0/24"),
+            Duration::from_secs(10),
+            BlockReason::PacketSpam,
+        );
+        assert!(list.is_blocked(addr("192.0.2.1:19132")));
+        assert!(list.is_blocked(addr("198.51.100.9:19132")));
+
+        clock.advance(Duration::from_secs(11));
+        assert!(!list.is_blocked(addr("192.0.2.1:19132")));
+        assert!(!list.is_blocked(addr("198.51.100.9:19132")));
+
+        list.drain_changes();
+        assert_eq!(list.iter().count(), 0);
+        assert_eq!(list.ranges().count(), 0);
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let path = env::temp_dir().join(format!("blocklist-{}.json", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut list = BlockList::new(TimeSource::mock());
+        list.persist_to(&path).unwrap();
+        list.block(
+            addr("192.0.2.1:19132"),
+            Duration::MAX,
+            BlockReason::InvalidPackets,
+        );
+        list.block(
+            addr("[2001:db8::1]:19132"),
+            Duration::from_secs(3600),
+            BlockReason::Custom("banned".to_string()),
+        );
+        list.block(
+            addr("192.0.2.2:19132"),
+            Duration::ZERO,
+            BlockReason::PacketSpam,
+        );
+        list.block_range(
+            cidr("10.0.0.0/8"),
+            Duration::MAX,
+            BlockReason::UnconnectedFlood,
+        );
+        list.save().unwrap();
+
+        let mut loaded = BlockList::new(TimeSource::mock());
+        loaded.persist_to(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let entry = loaded.get(addr("192.0.2.1:19132")).unwrap();
+        assert_eq!(entry.reason, BlockReason::InvalidPackets);
+        assert!(entry.expiry.is_none());
+
+        let entry = loaded.get(addr("[2001:db8::1]:19132")).unwrap();
+        assert_eq!(entry.reason, BlockReason::Custom("banned".to_string()));
+        assert!(entry.expiry.is_some());
+
+        // The expired block is not saved.
+        assert!(loaded.get(addr("192.0.2.2:19132")).is_none());
+        assert!(loaded.is_blocked(addr("10.9.9.9:19132")));
+        assert_eq!(
+            loaded
+                .ranges()
+                .next()
+                .map(|(range, entry)| (*range, entry.reason.clone())),
+            Some((cidr("10.0.0.0/8"), BlockReason::UnconnectedFlood))
+        );
+    }
+
+    #[test]
+    fn load_rejects_malformed_files() {
+        let mut list = BlockList::new(TimeSource::mock());
+
+        assert!(list.load("{}").is_err());
+        assert!(list.load("not json").is_err());
+        assert!(list.load(r#"[{"address": "nowhere"}]"#).is_err());
+        assert!(list.load(r#"[{"range": "10.0.0.0/40"}]"#).is_err());
+    }
+
+    #[test]
+    fn repeat_offenders_are_escalated() {
+        let mut list = BlockList::new(TimeSource::mock());
+        let offender = addr("192.0.2.1:19132");
+        let escalation = [Duration::from_secs(60), Duration::from_secs(600)];
+
+        for _ in 0..4 {
+            list.block_offender(
+                offender,
+                Duration::from_secs(10),
+                &escalation,
+                BlockReason::PacketSpam,
+            );
+        }
+
+        let durations: Vec<Duration> = list
+            .offenses(offender)
+            .iter()
+            .map(|offense| offense.duration)
+            .collect();
+        assert_eq!(
+            durations,
+            [
+                Duration::from_secs(10),
+                Duration::from_secs(60),
+                Duration::from_secs(600),
+                Duration::MAX,
+            ]
+        );
+        assert!(list.get(offender).unwrap().expiry.is_none());
+
+        let escalated: Vec<u32> = list
+            .drain_changes()
+            .into_iter()
+            .filter_map(|event| match event {
+                RakNetEvent::BlockEscalated(_, count, _) => Some(count),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(escalated, [2, 3, 4]);
+    }
+
+    #[test]
+    fn offenses_are_forgotten() {
+        let clock = TimeSource::mock();
+        let mut list = BlockList::new(clock.clone());
+        let offender = addr("192.0.2.1:19132");
+
+        list.block_offender(
+            offender,
+            Duration::from_secs(10),
+            &[],
+            BlockReason::PacketSpam,
+        );
+        clock.advance(Duration::from_secs(30));
+        list.forget_offenses(Duration::from_secs(60));
+        assert_eq!(list.offenses(offender).len(), 1);
+
+        clock.advance(Duration::from_secs(31));
+        list.forget_offenses(Duration::from_secs(60));
+        assert!(list.offenses(offender).is_empty());
+
+        list.block_offender(
+            offender,
+            Duration::from_secs(10),
+            &[],
+            BlockReason::PacketSpam,
+        );
+        assert!(list.forgive(offender));
+        assert!(!list.forgive(offender));
+    }
+}
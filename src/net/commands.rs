@@ -0,0 +1,523 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    net::SocketAddr,
+    sync::{mpsc::Receiver, Arc},
+};
+
+use bevy::ecs::{
+    change_detection::Mut,
+    entity::Entity,
+    system::{EntityCommand, EntityCommands},
+    world::World,
+};
+
+use tracing::{debug, warn};
+
+use binary::datatypes::I64;
+
+use super::{
+    blocklist::BlockList,
+    nat::PendingPunched,
+    proxy::{Backends, PendingUpstream, ProxyLink, ReconnectPolicy, Reconnecting, UpstreamKind},
+    socket::{Handshake, HandshakePool, RakSocket, ServerBundle},
+    stream::{ConnectionState, NetworkInfo, RakStream},
+    transport::{ClosedTransport, Transport},
+};
+
+use crate::{
+    generic::events::{DisconnectReason, ProxyEvent, RakNetEvent},
+    protocol::{
+        compression::{compress_batch, CompressionAlgorithm, CompressionHeader},
+        config::RakNetConfig,
+        encryption::KeyExchange,
+        mcpe::{encode_packet, login::ServerToClientHandshake},
+        message::Message,
+        reliability::Reliability,
+        PROXY_HANDSHAKE_THREADS,
+    },
+};
+
+/// DisconnectConnection is the command that gracefully disconnects a RakNet connection with the provided reason. It
+/// sends a DisconnectRequest event for the entity, which is handled by the connection tick so that the disconnect
+/// notification is flushed by the socket systems before the entity is despawned.
+pub struct DisconnectConnection(pub DisconnectReason);
+
+impl EntityCommand for DisconnectConnection {
+    fn apply(self, id: Entity, world: &mut World) {
+        world.send_event(RakNetEvent::DisconnectRequest(id, self.0));
+    }
+}
+
+/// StartEncryption is the command that performs the server side of the key exchange with the identity public key
+/// of the client. It sends the ServerToClientHandshake packet unencrypted and then inserts the derived
+/// EncryptionKeys on the entity, so that all the following batches are encrypted.
+pub struct StartEncryption(pub String);
+
+impl EntityCommand for StartEncryption {
+    fn apply(self, id: Entity, world: &mut World) {
+        let exchange = KeyExchange::new();
+
+        let (keys, token) = match exchange
+            .derive(&self.0)
+            .and_then(|keys| Ok((keys, exchange.token()?)))
+        {
+            Ok(result) => result,
+            Err(e) => {
+                debug!("[Encryption Error]: {}", e.to_string());
+                world.send_event(RakNetEvent::DisconnectRequest(
+                    id,
+                    DisconnectReason::Custom("Key exchange failed".to_string()),
+                ));
+                return;
+            }
+        };
+
+        let batch = encode_packet(&ServerToClientHandshake { token });
+
+        let algorithm = world
+            .get::<CompressionAlgorithm>(id)
+            .copied()
+            .unwrap_or_default();
+        let header = world.get::<CompressionHeader>(id).is_some();
+        let level = world.resource::<RakNetConfig>().compression_level;
+
+        match compress_batch(&batch, algorithm, level, header) {
+            Ok(batch) => {
+                world.send_event(RakNetEvent::OutgoingBatch(id, batch));
+
+                if let Some(mut entity) = world.get_entity_mut(id) {
+                    entity.insert(keys);
+                }
+            }
+            Err(e) => debug!("[Compression Error]: {}", e.to_string()),
+        }
+    }
+}
+
+/// ConnectUpstream is the command that connects the proxy to the provided backend on behalf of a downstream client.
+/// The upstream connection is paired with the downstream entity through a ProxyLink on both sides right away, while
+/// its handshake with the backend runs on its own thread and is completed by the FinishUpstream command.
+pub struct ConnectUpstream(pub String);
+
+impl EntityCommand for ConnectUpstream {
+    fn apply(self, id: Entity, world: &mut World) {
+        if world.get_entity(id).is_none() {
+            release_backend(&self.0, world);
+            return;
+        }
+
+        let link = ProxyLink::upstream(id, &self.0);
+        let upstream = start_upstream(&self.0, id, link, UpstreamKind::Connect, world);
+
+        world
+            .entity_mut(id)
+            .insert(ProxyLink::downstream(upstream, &self.0));
+    }
+}
+
+/// FinishUpstream is the command that completes the pending upstream connection of the entity with the outcome of its
+/// handshake. If the backend could not be reached, it is marked unhealthy and a new client is connected to the
+/// fallback backend, or disconnected if there is none, a transferred client is disconnected and a held client is kept
+/// for the next attempt.
+pub(crate) struct FinishUpstream(pub UpstreamKind, pub Result<Handshake>);
+
+impl EntityCommand for FinishUpstream {
+    fn apply(self, id: Entity, world: &mut World) {
+        let Some(link) = world.get::<ProxyLink>(id) else {
+            return;
+        };
+
+        let player = link.peer();
+        let backend = link.backend().to_string();
+
+        let e = match self.1 {
+            Ok(handshake) => {
+                world.entity_mut(id).remove::<PendingUpstream>();
+                handshake.insert(id, world);
+
+                if self.0 == UpstreamKind::Reconnect {
+                    if let Some(mut entity) = world.get_entity_mut(player) {
+                        entity.remove::<Reconnecting>();
+                    }
+                }
+                return;
+            }
+            Err(e) => e,
+        };
+
+        debug!("[Proxy Error]: {}", e.to_string());
+        release_backend(&backend, world);
+
+        if self.0 == UpstreamKind::Reconnect {
+            world.despawn(id);
+            retry_reconnect(player, world);
+            return;
+        }
+
+        let config = world.resource::<RakNetConfig>();
+        let (cooldown, now) = (config.backend_cooldown, config.clock.now());
+        let mut fallback = None;
+
+        if let Some(mut backends) = world.get_resource_mut::<Backends>() {
+            backends.mark_unhealthy(&backend, cooldown, now);
+
+            if self.0 == UpstreamKind::Connect {
+                fallback = backends.fallback(&backend).map(str::to_string);
+                if let Some(fallback) = &fallback {
+                    backends.claim(fallback);
+                }
+            }
+        }
+
+        if self.0 == UpstreamKind::Transfer {
+            world.despawn(id);
+            world.send_event(RakNetEvent::DisconnectRequest(
+                player,
+                DisconnectReason::Custom("Transfer failed".to_string()),
+            ));
+            return;
+        }
+
+        world.send_event(ProxyEvent::UpstreamFailed(player, backend));
+
+        match fallback {
+            Some(fallback) => {
+                world.send_event(ProxyEvent::FallbackAttempt(player, fallback.clone()));
+
+                // The pending connection is reused so that the batches queued on it are kept.
+                for entity in [id, player] {
+                    if let Some(mut link) = world.get_mut::<ProxyLink>(entity) {
+                        let peer = link.peer();
+                        link.retarget(peer, &fallback);
+                    }
+                }
+
+                let handle = handshake_backend(&fallback, player, world);
+                world
+                    .entity_mut(id)
+                    .insert(PendingUpstream::new(UpstreamKind::Connect, handle));
+            }
+            None => {
+                world.despawn(id);
+                world.send_event(RakNetEvent::DisconnectRequest(
+                    player,
+                    DisconnectReason::Custom("Backend unavailable".to_string()),
+                ));
+            }
+        }
+    }
+}
+
+/// Queues the handshake with the provided backend on the HandshakePool, forwarding the address of the downstream
+/// client of the entity if the proxy forwarding is enabled and the proxy has a secret to sign it with.
+fn handshake_backend(backend: &str, id: Entity, world: &mut World) -> Receiver<Result<Handshake>> {
+    let secret = world
+        .get_resource::<RakNetConfig>()
+        .filter(|config| config.proxy_forwarding)
+        .and_then(|config| {
+            if config.proxy_secret.is_none() {
+                warn!("[Proxy Error]: Forwarding the client address requires a proxy secret");
+            }
+
+            config.proxy_secret.clone()
+        });
+
+    let forwarded = world
+        .get::<NetworkInfo>(id)
+        .map(|info| info.remote_addr)
+        .zip(secret);
+
+    handshake_pool(world).handshake(backend, forwarded)
+}
+
+/// Returns the HandshakePool of the App, which is created with the configured number of threads the first time.
+fn handshake_pool(world: &mut World) -> Mut<HandshakePool> {
+    let threads = world
+        .get_resource::<RakNetConfig>()
+        .map_or(PROXY_HANDSHAKE_THREADS, |config| {
+            config.proxy_handshake_threads
+        });
+
+    world.get_resource_or_insert_with(|| HandshakePool::new(threads))
+}
+
+/// Spawns the pending upstream connection to the provided backend for the downstream client of the entity with the
+/// provided link and starts its handshake. Returns the ID of the upstream connection.
+fn start_upstream(
+    backend: &str,
+    id: Entity,
+    link: ProxyLink,
+    kind: UpstreamKind,
+    world: &mut World,
+) -> Entity {
+    let handle = handshake_backend(backend, id, world);
+
+    world
+        .spawn((
+            link,
+            ConnectionState::Handshaking,
+            PendingUpstream::new(kind, handle),
+        ))
+        .id()
+}
+
+/// Schedules the next attempt at reconnecting the held downstream client of the entity after the failed one.
+fn retry_reconnect(id: Entity, world: &mut World) {
+    let policy = world.get_resource::<ReconnectPolicy>().cloned();
+    let now = world.resource::<RakNetConfig>().clock.now();
+
+    if let (Some(mut reconnecting), Some(policy)) = (world.get_mut::<Reconnecting>(id), policy) {
+        reconnecting.retry(&policy, now);
+    }
+}
+
+/// Releases the connection counted towards the provided backend.
+fn release_backend(backend: &str, world: &mut World) {
+    if let Some(mut backends) = world.get_resource_mut::<Backends>() {
+        backends.release(backend);
+    }
+}
+
+/// Connects a new upstream connection to the provided backend that replays the recorded login of the downstream client
+/// of the entity and points the client to it. The connection counted towards the backend is released on failure.
+fn replay_upstream(backend: &str, id: Entity, kind: UpstreamKind, world: &mut World) -> Result<()> {
+    let Some(login) = world.get::<ProxyLink>(id).map(|link| link.login()) else {
+        release_backend(backend, world);
+        return Err(Error::new(
+            ErrorKind::Other,
+            "The downstream client is no longer linked",
+        ));
+    };
+
+    let link = ProxyLink::transfer(id, backend, login);
+    let upstream = start_upstream(backend, id, link, kind, world);
+
+    if let Some(mut link) = world.get_mut::<ProxyLink>(id) {
+        link.retarget(upstream, backend);
+    }
+
+    Ok(())
+}
+
+/// TransferUpstream is the command that moves the downstream client of the entity to the provided backend. A new
+/// upstream connection replays the recorded login of the client to the backend, the client is disconnected if the
+/// backend cannot be reached.
+pub struct TransferUpstream(pub String);
+
+impl EntityCommand for TransferUpstream {
+    fn apply(self, id: Entity, world: &mut World) {
+        if let Err(e) = replay_upstream(&self.0, id, UpstreamKind::Transfer, world) {
+            debug!("[Proxy Error]: {}", e.to_string());
+
+            let config = world.resource::<RakNetConfig>();
+            let (cooldown, now) = (config.backend_cooldown, config.clock.now());
+
+            if let Some(mut backends) = world.get_resource_mut::<Backends>() {
+                backends.mark_unhealthy(&self.0, cooldown, now);
+            }
+
+            world.send_event(RakNetEvent::DisconnectRequest(
+                id,
+                DisconnectReason::Custom("Transfer failed".to_string()),
+            ));
+        }
+    }
+}
+
+/// ReconnectUpstream is the command that makes an attempt at reconnecting the held downstream client of the entity to
+/// the provided backend. The client stays held for the next attempt if the backend cannot be reached yet.
+pub struct ReconnectUpstream(pub String);
+
+impl EntityCommand for ReconnectUpstream {
+    fn apply(self, id: Entity, world: &mut World) {
+        if let Err(e) = replay_upstream(&self.0, id, UpstreamKind::Reconnect, world) {
+            debug!("[Proxy Error]: {}", e.to_string());
+            retry_reconnect(id, world);
+        }
+    }
+}
+
+/// RequestPunchthrough is the command that asks the coordinator that the client connection of the entity is connected
+/// to for a NAT punchthrough to the peer with the provided GUID.
+pub struct RequestPunchthrough(pub i64);
+
+impl EntityCommand for RequestPunchthrough {
+    fn apply(self, id: Entity, world: &mut World) {
+        if let Some(mut stream) = world.get_mut::<RakStream>(id) {
+            let msg = Message::NatPunchthroughRequest {
+                target_guid: I64::new(self.0),
+            };
+
+            stream.encode(msg, Reliability::ReliableOrdered);
+        }
+    }
+}
+
+/// Takes the Transport of the client connection of the entity so that it can be used by another socket. The disconnect
+/// notification is written to the coordinator right away, a ClosedTransport is left behind and the entity is despawned
+/// by the resulting Disconnect event.
+fn release_transport(id: Entity, world: &mut World) -> Option<Arc<dyn Transport>> {
+    world.get::<RakSocket>(id)?;
+
+    let mut stream = world.get_mut::<RakStream>(id)?;
+    stream.encode(
+        Message::DisconnectNotification {},
+        Reliability::ReliableOrdered,
+    );
+    stream.try_flush();
+
+    let addr = stream.addr();
+    let datagrams: Vec<_> = std::iter::from_fn(|| stream.poll_outgoing()).collect();
+
+    let mut socket = world.get_mut::<RakSocket>(id)?;
+    let transport = std::mem::replace(&mut socket.transport, Arc::new(ClosedTransport));
+
+    for datagram in datagrams {
+        if let Err(e) = transport.send_to(&datagram, addr) {
+            debug!("[Network Error]: {}", e.to_string());
+        }
+    }
+
+    world.send_event(RakNetEvent::Disconnect(id, DisconnectReason::Requested));
+    Some(transport)
+}
+
+/// ConnectPunched is the command that connects to the peer at the provided address over the Transport of the client
+/// connection of the entity, once a punchthrough to it succeeded. The peer must host its punched transport. The
+/// handshake runs on the HandshakePool and the connection is spawned by the FinishPunched command once it completes.
+pub struct ConnectPunched(pub SocketAddr);
+
+impl EntityCommand for ConnectPunched {
+    fn apply(self, id: Entity, world: &mut World) {
+        let Some(transport) = release_transport(id, world) else {
+            return;
+        };
+
+        let outcome = handshake_pool(world).handshake_with(transport, self.0);
+        world.spawn(PendingPunched::new(outcome));
+    }
+}
+
+/// FinishPunched is the command that completes the pending connection to a punched peer of the entity with the outcome
+/// of its handshake, the entity is despawned if the handshake failed.
+pub(crate) struct FinishPunched(pub Result<Handshake>);
+
+impl EntityCommand for FinishPunched {
+    fn apply(self, id: Entity, world: &mut World) {
+        match self.0 {
+            Ok(handshake) => {
+                world.entity_mut(id).remove::<PendingPunched>();
+                handshake.insert(id, world);
+            }
+            Err(e) => {
+                debug!("[NAT Error]: {}", e.to_string());
+                world.despawn(id);
+            }
+        }
+    }
+}
+
+/// HostPunched is the command that spawns a server on the Transport of the client connection of the entity, once a
+/// punchthrough succeeded, so that the peer can connect to it. The App must run the systems of a server.
+pub struct HostPunched;
+
+impl EntityCommand for HostPunched {
+    fn apply(self, id: Entity, world: &mut World) {
+        if let Some(transport) = release_transport(id, world) {
+            let mut bundle = ServerBundle::with_transport(transport);
+            bundle.block_list = BlockList::new(world.resource::<RakNetConfig>().clock.clone());
+
+            world.spawn(bundle);
+        }
+    }
+}
+
+/// Advertise is the command that sends an AdvertiseSystem message with the provided payload to the provided address
+/// from the socket of the entity, which is either a server or a client connection.
+pub struct Advertise(pub SocketAddr, pub Vec<u8>);
+
+impl EntityCommand for Advertise {
+    fn apply(self, id: Entity, world: &mut World) {
+        if let Some(mut socket) = world.get_mut::<RakSocket>(id) {
+            if let Err(e) = socket.advertise(self.0, &self.1) {
+                debug!("[Network Error]: {}", e.to_string());
+            }
+        }
+    }
+}
+
+/// NetworkCommandsExt extends the EntityCommands with the commands for managing the RakNet connection of an entity.
+pub trait NetworkCommandsExt {
+    /// Gracefully disconnects the RakNet connection of the entity and despawns it once the disconnect notification
+    /// has been flushed.
+    fn disconnect(&mut self) -> &mut Self;
+
+    /// Gracefully disconnects the RakNet connection of the entity with the provided reason.
+    fn disconnect_with(&mut self, reason: DisconnectReason) -> &mut Self;
+
+    /// Starts the encryption of the connection of the entity with the provided identity public key of the client.
+    fn start_encryption(&mut self, client_key: &str) -> &mut Self;
+
+    /// Connects the proxy to the provided backend on behalf of the downstream client of the entity.
+    fn connect_upstream(&mut self, backend: &str) -> &mut Self;
+
+    /// Moves the downstream client of the entity to the provided backend.
+    fn transfer_upstream(&mut self, backend: &str) -> &mut Self;
+
+    /// Attempts to reconnect the held downstream client of the entity to the provided backend.
+    fn reconnect_upstream(&mut self, backend: &str) -> &mut Self;
+
+    /// Asks the coordinator of the client connection of the entity for a NAT punchthrough to the provided peer.
+    fn request_punchthrough(&mut self, guid: i64) -> &mut Self;
+
+    /// Connects to the punched peer at the provided address over the transport of the client connection of the entity.
+    fn connect_punched(&mut self, addr: SocketAddr) -> &mut Self;
+
+    /// Hosts a server on the punched transport of the client connection of the entity.
+    fn host_punched(&mut self) -> &mut Self;
+
+    /// Advertises the provided payload to the provided address from the socket of the entity without a connection.
+    fn advertise(&mut self, addr: SocketAddr, data: &[u8]) -> &mut Self;
+}
+
+impl<'w, 's, 'a> NetworkCommandsExt for EntityCommands<'w, 's, 'a> {
+    fn disconnect(&mut self) -> &mut Self {
+        self.disconnect_with(DisconnectReason::Requested)
+    }
+
+    fn disconnect_with(&mut self, reason: DisconnectReason) -> &mut Self {
+        self.add(DisconnectConnection(reason))
+    }
+
+    fn start_encryption(&mut self, client_key: &str) -> &mut Self {
+        self.add(StartEncryption(client_key.to_string()))
+    }
+
+    fn connect_upstream(&mut self, backend: &str) -> &mut Self {
+        self.add(ConnectUpstream(backend.to_string()))
+    }
+
+    fn transfer_upstream(&mut self, backend: &str) -> &mut Self {
+        self.add(TransferUpstream(backend.to_string()))
+    }
+
+    fn reconnect_upstream(&mut self, backend: &str) -> &mut Self {
+        self.add(ReconnectUpstream(backend.to_string()))
+    }
+
+    fn request_punchthrough(&mut self, guid: i64) -> &mut Self {
+        self.add(RequestPunchthrough(guid))
+    }
+
+    fn connect_punched(&mut self, addr: SocketAddr) -> &mut Self {
+        self.add(ConnectPunched(addr))
+    }
+
+    fn host_punched(&mut self) -> &mut Self {
+        self.add(HostPunched)
+    }
+
+    fn advertise(&mut self, addr: SocketAddr, data: &[u8]) -> &mut Self {
+        self.add(Advertise(addr, data.to_vec()))
+    }
+}
@@ -0,0 +1,174 @@
+use std::{
+    io::{Error, ErrorKind, Read, Result, Write},
+    net::{SocketAddr, TcpStream, UdpSocket},
+    thread,
+    time::Duration,
+};
+
+use bevy::{
+    ecs::system::Resource,
+    time::{Timer, TimerMode},
+};
+use serde_json::json;
+use tracing::debug;
+
+use crate::protocol::{mcpe::ServerStatus, HEARTBEAT_TIMEOUT};
+
+use super::ping::{resolve, unspecified};
+
+/// HeartbeatTarget is the master server that the heartbeats are reported to.
+#[derive(Debug, Clone)]
+pub enum HeartbeatTarget {
+    /// The heartbeats are POSTed as JSON to the provided http:// URL.
+    Http(String),
+    /// The heartbeats are sent as JSON in a single UDP datagram to the provided address.
+    Udp(SocketAddr),
+}
+
+/// Heartbeat is the resource that periodically reports the address, MOTD, player counts and version of the servers of
+/// the App to a master server, so that they are listed by it. The HTTP requests are made on their own threads so that
+/// the App never waits for the master server.
+#[derive(Resource)]
+pub struct Heartbeat {
+    target: HeartbeatTarget,
+    address: Option<String>,
+    timer: Timer,
+    udp: Option<UdpSocket>,
+}
+
+impl Heartbeat {
+    /// Creates a heartbeat reported to the provided target on the provided interval. The servers are reported with the
+    /// provided public address if any, with the address they are bound to otherwise.
+    pub fn new(target: HeartbeatTarget, interval: Duration, address: Option<&str>) -> Result<Self> {
+        let udp = match &target {
+            HeartbeatTarget::Http(url) => {
+                parse_url(url)?;
+                None
+            }
+            HeartbeatTarget::Udp(addr) => {
+                let udp = UdpSocket::bind(unspecified(*addr))?;
+                udp.set_nonblocking(true)?;
+                Some(udp)
+            }
+        };
+
+        // The first heartbeat is reported right away instead of after the first interval.
+        let mut timer = Timer::new(interval, TimerMode::Repeating);
+        timer.set_elapsed(interval);
+
+        Ok(Self {
+            target,
+            address: address.map(str::to_string),
+            timer,
+            udp,
+        })
+    }
+
+    /// Returns the target that the heartbeats are reported to.
+    pub fn target(&self) -> &HeartbeatTarget {
+        &self.target
+    }
+
+    /// Advances the interval of the heartbeat and returns true if the heartbeats are due.
+    pub(crate) fn tick(&mut self, delta: Duration) -> bool {
+        self.timer.tick(delta).just_finished()
+    }
+
+    /// Reports the provided status of the server bound to the provided address.
+    pub(crate) fn report(&self, addr: SocketAddr, status: &ServerStatus) {
+        let body = json!({
+            "address": self.address.clone().unwrap_or_else(|| addr.to_string()),
+            "guid": status.server_guid,
+            "motd": status.motd,
+            "level_name": status.level_name,
+            "online_players": status.online,
+            "max_players": status.max,
+            "protocol": status.protocol,
+            "version": status.version,
+            "gamemode": status.gamemode,
+        })
+        .to_string();
+
+        match (&self.target, &self.udp) {
+            (HeartbeatTarget::Udp(target), Some(udp)) => {
+                if let Err(e) = udp.send_to(body.as_bytes(), target) {
+                    debug!("[Heartbeat Error]: {}", e.to_string());
+                }
+            }
+            (HeartbeatTarget::Http(url), _) => {
+                let url = url.clone();
+
+                thread::spawn(move || {
+                    if let Err(e) = post(&url, &body) {
+                        debug!("[Heartbeat Error]: {}", e.to_string());
+                    }
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Splits the provided http:// URL into the host with its port and the path. HTTPS is not supported.
+fn parse_url(url: &str) -> Result<(String, String)> {
+    let Some(rest) = url.strip_prefix("http://") else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Only http:// URLs are supported by the heartbeat",
+        ));
+    };
+
+    let (host, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+
+    if host.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "The heartbeat URL has no host",
+        ));
+    }
+
+    let host = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+
+    Ok((host, path.to_string()))
+}
+
+/// POSTs the provided JSON body to the provided URL and checks that the master server accepted it.
+fn post(url: &str, body: &str) -> Result<()> {
+    let (host, path) = parse_url(url)?;
+
+    let mut stream = TcpStream::connect_timeout(&resolve(&host)?, HEARTBEAT_TIMEOUT)?;
+    stream.set_read_timeout(Some(HEARTBEAT_TIMEOUT))?;
+    stream.set_write_timeout(Some(HEARTBEAT_TIMEOUT))?;
+
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes())?;
+
+    // Only the status line of the response is read, e.g. "HTTP/1.1 204 No Content".
+    let mut response = [0u8; 64];
+    let len = stream.read(&mut response)?;
+    let status = String::from_utf8_lossy(&response[..len]);
+
+    match status.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(Error::new(
+            ErrorKind::Other,
+            format!(
+                "The master server rejected the heartbeat: {}",
+                status.lines().next().unwrap_or_default()
+            ),
+        )),
+    }
+}
@@ -0,0 +1,170 @@
+use std::{
+    collections::HashMap,
+    io::{Cursor, Result},
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use bevy::{
+    ecs::{entity::Entity, event::EventWriter, system::Resource},
+    time::{Timer, TimerMode},
+};
+use binary::{datatypes::I64, prefixed::Str, Binary};
+use bytes::BytesMut;
+use tracing::debug;
+
+use crate::{
+    generic::events::RakNetEvent,
+    protocol::{
+        binary::Magic,
+        mcpe::{PongStatus, ServerStatus},
+        message::Message,
+        LAN_DISCOVERY_PORT, MAX_MTU_SIZE,
+    },
+};
+
+use super::ping::{read_pong, send_ping};
+
+/// LanListener is the resource that answers the Unconnected Pings broadcast on the LAN discovery port on behalf of a
+/// server that is bound to another address, so that the server is listed as a LAN world by the clients. The pings are
+/// subject to the block list, the rate limits and the connection filters of the server.
+#[derive(Resource)]
+pub struct LanListener {
+    server: Entity,
+    udp: UdpSocket,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
+}
+
+impl LanListener {
+    /// Binds the listener on the LAN discovery port of all the interfaces for the provided server entity.
+    pub fn bind(server: Entity) -> Result<Self> {
+        let udp = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, LAN_DISCOVERY_PORT))?;
+        udp.set_nonblocking(true)?;
+
+        Ok(Self {
+            server,
+            udp,
+            read_buf: BytesMut::zeroed(MAX_MTU_SIZE),
+            write_buf: BytesMut::with_capacity(MAX_MTU_SIZE),
+        })
+    }
+
+    /// Returns the server entity whose status is advertised by the listener.
+    pub fn server(&self) -> Entity {
+        self.server
+    }
+
+    /// Reads the next datagram received on the LAN discovery port and returns its sender and length along with the
+    /// timestamp of the Unconnected Ping it carries, if it is one. Returns None once there are no more datagrams.
+    pub(crate) fn recv(&mut self) -> Option<(SocketAddr, usize, Option<i64>)> {
+        let (len, addr) = self.udp.recv_from(&mut self.read_buf).ok()?;
+
+        let timestamp = match Message::deserialize(&mut Cursor::new(&self.read_buf[..len])) {
+            Ok(Message::UnconnectedPing {
+                send_timestamp,
+                magic: _,
+                client_guid: _,
+            })
+            | Ok(Message::UnconnectedPingOpenConnections {
+                send_timestamp,
+                magic: _,
+                client_guid: _,
+            }) => Some(send_timestamp.0),
+            _ => None,
+        };
+
+        Some((addr, len, timestamp))
+    }
+
+    /// Answers the ping with the provided timestamp received from the provided address with the status of the server.
+    pub(crate) fn answer(
+        &mut self,
+        addr: SocketAddr,
+        send_timestamp: i64,
+        status: &PongStatus,
+        guid: i64,
+    ) {
+        let Some(status) = status.payload(addr) else {
+            return;
+        };
+        let resp = Message::UnconnectedPong {
+            send_timestamp: I64::new(send_timestamp),
+            server_guid: I64::new(guid),
+            magic: Magic,
+            data: Str::new(&status),
+        };
+
+        resp.serialize(&mut self.write_buf);
+        if let Err(e) = self.udp.send_to(&self.write_buf, addr) {
+            debug!("[LAN Error]: {}", e.to_string());
+        }
+        self.write_buf.clear();
+    }
+}
+
+/// LanDiscovery is the resource that broadcasts Unconnected Pings on the LAN discovery port on the provided interval
+/// and collects the servers that respond. The RakNetEvent::LanServerDiscovered event is written whenever a server
+/// responds for the first time or its status changes.
+#[derive(Resource)]
+pub struct LanDiscovery {
+    udp: UdpSocket,
+    guid: i64,
+    timer: Timer,
+    servers: HashMap<SocketAddr, ServerStatus>,
+    buf: BytesMut,
+}
+
+impl LanDiscovery {
+    pub fn new(interval: Duration) -> Result<Self> {
+        let udp = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        udp.set_broadcast(true)?;
+        udp.set_nonblocking(true)?;
+
+        let mut timer = Timer::new(interval, TimerMode::Repeating);
+        timer.set_elapsed(interval);
+
+        Ok(Self {
+            udp,
+            guid: rand::random(),
+            timer,
+            servers: HashMap::new(),
+            buf: BytesMut::zeroed(MAX_MTU_SIZE),
+        })
+    }
+
+    /// Returns the servers discovered so far. The address of a server is the address it responded from with the
+    /// port it advertises in its status.
+    pub fn servers(&self) -> impl Iterator<Item = (&SocketAddr, &ServerStatus)> {
+        self.servers.iter()
+    }
+
+    /// Broadcasts a ping if the interval has elapsed and reads all the pongs received since the last call.
+    pub(crate) fn discover(&mut self, delta: Duration, ev: &mut EventWriter<RakNetEvent>) {
+        if self.timer.tick(delta).just_finished() {
+            let addr = SocketAddr::from((Ipv4Addr::BROADCAST, LAN_DISCOVERY_PORT));
+
+            if let Err(e) = send_ping(&self.udp, addr, 0, self.guid) {
+                debug!("[LAN Error]: {}", e.to_string());
+            }
+        }
+
+        loop {
+            let (len, from) = match self.udp.recv_from(&mut self.buf) {
+                Ok(recv) => recv,
+                Err(_) => break,
+            };
+
+            let Ok((_, _, status)) = read_pong(&self.buf[..len]) else {
+                continue;
+            };
+
+            let addr = SocketAddr::new(from.ip(), status.port_v4.unwrap_or(from.port()));
+
+            if self.servers.get(&addr) != Some(&status) {
+                self.servers.insert(addr, status.clone());
+                ev.send(RakNetEvent::LanServerDiscovered(addr, status));
+            }
+        }
+    }
+}
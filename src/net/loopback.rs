@@ -0,0 +1,123 @@
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind, Result},
+    net::{Ipv4Addr, SocketAddr},
+    str::FromStr,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+};
+
+use crate::protocol::LOOPBACK_EPHEMERAL_PORT;
+
+use super::transport::Transport;
+
+/// LoopbackNetwork is an in-memory network that the LoopbackTransports bound on it exchange their datagrams through,
+/// without binding any UDP ports. The datagrams are delivered in order and are never lost, so the server and client
+/// Apps of a test that share a network can be stepped deterministically. Clones refer to the same network.
+#[derive(Clone, Default)]
+pub struct LoopbackNetwork {
+    endpoints: Arc<Mutex<HashMap<SocketAddr, Sender<(Vec<u8>, SocketAddr)>>>>,
+}
+
+impl LoopbackNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Binds a transport on the provided address of the network.
+    pub fn bind(&self, addr: &str) -> Result<Arc<LoopbackTransport>> {
+        let addr = SocketAddr::from_str(addr).map_err(|_| {
+            Error::new(
+                ErrorKind::Other,
+                "The address is not a valid socket address",
+            )
+        })?;
+
+        let mut endpoints = self.endpoints.lock().unwrap();
+        if endpoints.contains_key(&addr) {
+            return Err(Error::new(
+                ErrorKind::AddrInUse,
+                "The address is already bound on the loopback network",
+            ));
+        }
+
+        Ok(self.register(&mut endpoints, addr))
+    }
+
+    /// Binds a transport on the first free port of the network starting from LOOPBACK_EPHEMERAL_PORT.
+    pub fn bind_any(&self) -> Result<Arc<LoopbackTransport>> {
+        let mut endpoints = self.endpoints.lock().unwrap();
+
+        let addr = (LOOPBACK_EPHEMERAL_PORT..=u16::MAX)
+            .map(|port| SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+            .find(|addr| !endpoints.contains_key(addr))
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::AddrInUse,
+                    "There are no free ports left on the loopback network",
+                )
+            })?;
+
+        Ok(self.register(&mut endpoints, addr))
+    }
+
+    fn register(
+        &self,
+        endpoints: &mut HashMap<SocketAddr, Sender<(Vec<u8>, SocketAddr)>>,
+        addr: SocketAddr,
+    ) -> Arc<LoopbackTransport> {
+        let (sender, receiver) = channel();
+        endpoints.insert(addr, sender);
+
+        Arc::new(LoopbackTransport {
+            addr,
+            network: self.clone(),
+            incoming: Mutex::new(receiver),
+        })
+    }
+}
+
+/// LoopbackTransport is a Transport bound on a LoopbackNetwork. The datagrams sent to an address that nothing is
+/// bound on are dropped like they would be over UDP. The address is released once the transport is dropped.
+pub struct LoopbackTransport {
+    addr: SocketAddr,
+    network: LoopbackNetwork,
+    incoming: Mutex<Receiver<(Vec<u8>, SocketAddr)>>,
+}
+
+impl Transport for LoopbackTransport {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize> {
+        if let Some(endpoint) = self.network.endpoints.lock().unwrap().get(&addr) {
+            let _ = endpoint.send((buf.to_vec(), self.addr));
+        }
+
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let Ok((datagram, from)) = self.incoming.lock().unwrap().try_recv() else {
+            return Err(Error::new(
+                ErrorKind::WouldBlock,
+                "There is no datagram to receive",
+            ));
+        };
+
+        // The datagram is truncated to the size of the buffer like it would be by a UdpSocket.
+        let len = datagram.len().min(buf.len());
+        buf[..len].copy_from_slice(&datagram[..len]);
+
+        Ok((len, from))
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+impl Drop for LoopbackTransport {
+    fn drop(&mut self) {
+        self.network.endpoints.lock().unwrap().remove(&self.addr);
+    }
+}
@@ -0,0 +1,75 @@
+use bevy::ecs::component::Component;
+
+use super::whitelist::Refusal;
+
+/// Maintenance is the component of a server that refuses all the new connections while it is enabled, the established
+/// connections are kept alive so that the server can be drained before a restart. The server can advertise another
+/// MOTD in its pongs during the maintenance. It can be switched on and off at any time.
+#[derive(Component, Debug, Clone)]
+pub struct Maintenance {
+    enabled: bool,
+    motd: Option<String>,
+    refusal: Refusal,
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            motd: None,
+            refusal: Refusal::ServerFull,
+        }
+    }
+}
+
+impl Maintenance {
+    /// Creates a maintenance that is enabled, the new clients are told that the server has no free slots.
+    pub fn new() -> Self {
+        Self {
+            enabled: true,
+            ..Default::default()
+        }
+    }
+
+    /// Sets the MOTD advertised in the pongs of the server while the maintenance is enabled.
+    pub fn with_motd(mut self, motd: &str) -> Self {
+        self.motd = Some(motd.to_string());
+        self
+    }
+
+    /// Sets the message that the refused clients are answered with.
+    pub fn with_refusal(mut self, refusal: Refusal) -> Self {
+        self.refusal = refusal;
+        self
+    }
+
+    /// Enables or disables the maintenance, the new clients can connect again once it is disabled.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Returns whether the maintenance is enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Sets the MOTD advertised during the maintenance, the MOTD of the server is kept if there is none.
+    pub fn set_motd(&mut self, motd: Option<&str>) {
+        self.motd = motd.map(str::to_string);
+    }
+
+    /// Returns the MOTD advertised during the maintenance if there is any.
+    pub fn motd(&self) -> Option<&str> {
+        self.motd.as_deref()
+    }
+
+    /// Sets the message that the refused clients are answered with.
+    pub fn set_refusal(&mut self, refusal: Refusal) {
+        self.refusal = refusal;
+    }
+
+    /// Returns the message that the refused clients are answered with.
+    pub fn refusal(&self) -> Refusal {
+        self.refusal
+    }
+}
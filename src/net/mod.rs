@@ -1,193 +1,2361 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use bevy::app::AppExit;
 use bevy::ecs::{
-    entity::Entity,
+    change_detection::DetectChanges,
+    entity::{Entities, Entity},
     event::{EventReader, EventWriter},
-    system::{Commands, Query, Res, ResMut},
+    query::{Added, Changed, Has, Or, With, Without},
+    schedule::{NextState, State, States, SystemSet},
+    system::{Commands, ParamSet, Query, Res, ResMut, Resource},
 };
-use binary::prefixed::UnsizedBytes;
-use log::debug;
+use bevy::time::Time;
+use binary::{
+    datatypes::{I64, U16},
+    prefixed::UnsizedBytes,
+    Binary,
+};
+use bytes::{Bytes, BytesMut};
+use tracing::{debug, info};
 
 use self::{
-    socket::{Mappings, RakSocket, SocketInfo},
-    stream::{NetworkStatus, RakStream},
+    blocklist::BlockList,
+    commands::{FinishPunched, FinishUpstream, NetworkCommandsExt},
+    heartbeat::Heartbeat,
+    lan::{LanDiscovery, LanListener},
+    maintenance::Maintenance,
+    nat::{NatCoordinator, NatPunchthrough, PendingPunched},
+    ping::ServerListPinger,
+    proxy::{
+        Backends, PendingUpstream, ProxyInterceptors, ProxyLink, ProxyRateLimit, ReconnectBehavior,
+        ReconnectPolicy, Reconnecting, StatusPassthrough,
+    },
+    socket::{read_advertisement, Mappings, RakSocket, ReadBudget, SocketInfo},
+    stream::{ConnectionState, Multicast, NetworkInfo, NetworkStatus, RakStream},
+    whitelist::{Refusal, Whitelist},
 };
 use crate::{
-    generic::events::RakNetEvent,
+    generic::events::{DisconnectReason, NetworkEvent, ProxyEvent, QualityIssue, RakNetEvent},
     protocol::{
+        binary::UDPAddress,
+        compression::{compress_batch, decompress_batch, CompressionAlgorithm, CompressionHeader},
+        config::{DuplicateLoginPolicy, RakNetConfig},
+        encryption::EncryptionKeys,
         mcpe::{
-            BroadcastGamemode, MaxPlayers, MinecraftProtocol, MinecraftVersion, OnlinePlayers,
-            PrimaryMotd, SecondaryMotd, StatusResource,
+            encode_packet,
+            login::{
+                ClientToServerHandshake, Login, LoginData, LoginState, NetworkSettings,
+                OfflineLogin, PlayStatus, RequestNetworkSettings, ServerToClientHandshake, Status,
+                CLIENT_TO_SERVER_HANDSHAKE, LOGIN, NETWORK_SETTINGS, PLAY_STATUS,
+                REQUEST_NETWORK_SETTINGS, SERVER_TO_CLIENT_HANDSHAKE,
+            },
+            packet_id, read_sub_packets, write_sub_packet, BroadcastGamemode, LevelName,
+            MaxPlayers, MinecraftProtocol, MinecraftVersion, OnlinePlayers, OnlinePlayersSync,
+            PongStatus, PortV6, PrimaryMotd, SecondaryMotd, ServerStatus,
         },
         message::Message,
         reliability::Reliability,
-        RAKNET_TIMEOUT,
+        ADVERTISE_SYSTEM_PACKET_ID, COMPRESSION_HEADER_PROTOCOL, COMPRESSION_THRESHOLD,
+        MAX_MTU_SIZE, NAT_PUNCHTHROUGH_DELAY,
     },
 };
-use std::io::Write;
+use std::io::{Cursor, Error, ErrorKind, Write};
 
+pub mod blocklist;
+pub mod commands;
+pub mod heartbeat;
+pub mod lan;
+pub mod loopback;
+pub mod maintenance;
+pub mod nat;
+#[cfg(feature = "egui")]
+pub mod overlay;
+pub mod pcap;
+pub mod peers;
+pub mod ping;
+pub mod proxy;
+pub mod recording;
+#[cfg(feature = "simulator")]
+pub mod simulator;
 pub mod socket;
+pub mod socks;
 pub mod stream;
+#[cfg(feature = "swarm")]
+pub mod swarm;
+pub mod transport;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+pub mod whitelist;
+
+/// NetworkSet contains the labels of the system sets that the systems of the network plugins are put into. They
+/// run in this order in the schedule of RakNetConfig so that user systems can be ordered relative to them, for example
+/// systems reading the incoming batches should run after Process and systems writing outgoing batches before it.
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum NetworkSet {
+    /// Reads the datagrams from the sockets and decodes them.
+    Receive,
+    /// Handles the RakNet events such as the outgoing batches, disconnections and timeouts.
+    Process,
+    /// Flushes the receipts and the datagrams of all the connections to the sockets.
+    Flush,
+}
+
+/// NetworkingState controls whether the network systems are running. While paused, no datagrams are read,
+/// processed or flushed but the connections are kept around, which lets an app suspend the network stack
+/// temporarily (e.g. during a world save) and resume it afterwards.
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkingState {
+    #[default]
+    Running,
+    Paused,
+}
+
+impl NetworkingState {
+    /// Suspends the processing and flushing of the packets of all the connections.
+    pub fn pause(&mut self) {
+        *self = NetworkingState::Paused;
+    }
+
+    /// Resumes the processing and flushing of the packets of all the connections.
+    pub fn resume(&mut self) {
+        *self = NetworkingState::Running;
+    }
+
+    /// Returns true if the network systems are currently running.
+    pub fn is_running(&self) -> bool {
+        *self == NetworkingState::Running
+    }
+}
+
+/// ClientNetState is the lifecycle of the client connections exposed as a Bevy State, so that game code can use the
+/// OnEnter and OnExit schedules and the in_state run conditions. The client is Connected as long as at least one of
+/// its connections is established and Connecting while any of them is still handshaking.
+#[derive(States, Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub enum ClientNetState {
+    #[default]
+    Disconnected,
+    Connecting,
+    Connected,
+}
+
+/// TraceFilter selects the connections whose datagram-level logs are enabled at runtime regardless of the global
+/// level, which allows an operator to trace specific remote addresses or entities on a busy server.
+#[derive(Resource, Debug, Default)]
+pub struct TraceFilter {
+    addrs: HashSet<SocketAddr>,
+    entities: HashSet<Entity>,
+}
+
+impl TraceFilter {
+    /// Enables the datagram-level logs of the connections with the provided remote address.
+    pub fn trace_addr(&mut self, addr: SocketAddr) {
+        self.addrs.insert(addr);
+    }
+
+    /// Disables the datagram-level logs of the connections with the provided remote address.
+    pub fn untrace_addr(&mut self, addr: SocketAddr) {
+        self.addrs.remove(&addr);
+    }
+
+    /// Enables the datagram-level logs of the connection of the provided entity.
+    pub fn trace_entity(&mut self, entity: Entity) {
+        self.entities.insert(entity);
+    }
+
+    /// Disables the datagram-level logs of the connection of the provided entity.
+    pub fn untrace_entity(&mut self, entity: Entity) {
+        self.entities.remove(&entity);
+    }
+
+    /// Disables the datagram-level logs of all the connections.
+    pub fn clear(&mut self) {
+        self.addrs.clear();
+        self.entities.clear();
+    }
+
+    /// Returns true if the datagram-level logs of the provided connection are enabled.
+    pub fn matches(&self, addr: SocketAddr, entity: Entity) -> bool {
+        self.addrs.contains(&addr) || self.entities.contains(&entity)
+    }
+}
+
+/// OutgoingQueue holds the game packets written for each connection since the last flush. They are framed into a
+/// single game packet batch per connection, which is compressed and written as one RakNet batch on the next flush.
+/// The connections can also be disconnected on the next flush, right behind their last batch.
+#[derive(Resource, Debug, Default)]
+pub struct OutgoingQueue {
+    batches: HashMap<Entity, Vec<u8>>,
+    disconnects: Vec<(Entity, DisconnectReason)>,
+}
+
+impl OutgoingQueue {
+    /// Appends the provided game packet to the batch of the provided connection.
+    pub fn push(&mut self, entity: Entity, packet: &[u8]) {
+        write_sub_packet(self.batches.entry(entity).or_default(), packet);
+    }
+
+    /// Requests the disconnection of the provided connection once the batch queued for it has been written.
+    pub fn disconnect_after_flush(&mut self, entity: Entity, reason: DisconnectReason) {
+        self.disconnects.push((entity, reason));
+    }
+
+    /// Removes and returns the batches of all the connections.
+    pub fn drain(&mut self) -> impl Iterator<Item = (Entity, Vec<u8>)> + '_ {
+        self.batches.drain()
+    }
+
+    /// Removes and returns the disconnections requested behind the batches.
+    pub fn drain_disconnects(&mut self) -> impl Iterator<Item = (Entity, DisconnectReason)> + '_ {
+        self.disconnects.drain(..)
+    }
+}
+
+/// MessageAction is what happens to a RakNet message once an interceptor has seen it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageAction {
+    /// The message is passed on to the next interceptor and handled by the stream.
+    Handle,
+    /// The message is ignored as if it was never received and the remaining interceptors do not see it.
+    Veto,
+}
+
+/// MessageInterceptor observes the RakNet messages received on the established connections before the stream acts on
+/// them, it is called with the entity of the connection and the decoded message. Closures can be used as well.
+pub trait MessageInterceptor: Send + Sync + 'static {
+    fn on_message(&mut self, entity: Entity, message: &Message) -> MessageAction;
+}
+
+impl<F> MessageInterceptor for F
+where
+    F: FnMut(Entity, &Message) -> MessageAction + Send + Sync + 'static,
+{
+    fn on_message(&mut self, entity: Entity, message: &Message) -> MessageAction {
+        self(entity, message)
+    }
+}
+
+/// MessageInterceptors is the chain of interceptors that the received RakNet messages are passed through in the order
+/// the interceptors were added, which lets user code collect analytics or handle experimental messages.
+#[derive(Resource, Default)]
+pub struct MessageInterceptors {
+    interceptors: Vec<Box<dyn MessageInterceptor>>,
+}
+
+impl MessageInterceptors {
+    /// Appends the provided interceptor to the end of the chain.
+    pub fn add(&mut self, interceptor: impl MessageInterceptor) {
+        self.interceptors.push(Box::new(interceptor));
+    }
+
+    /// Appends the provided boxed interceptor to the end of the chain.
+    pub fn add_boxed(&mut self, interceptor: Box<dyn MessageInterceptor>) {
+        self.interceptors.push(interceptor);
+    }
+
+    /// Returns whether there are no interceptors in the chain.
+    pub fn is_empty(&self) -> bool {
+        self.interceptors.is_empty()
+    }
+
+    /// Passes the provided message through the chain on behalf of the connection of the entity. Returns false if one
+    /// of the interceptors vetoed it.
+    pub(crate) fn intercept(&mut self, entity: Entity, message: &Message) -> bool {
+        self.interceptors
+            .iter_mut()
+            .all(|interceptor| interceptor.on_message(entity, message) == MessageAction::Handle)
+    }
+}
+
+/// ConnectionFilter decides whether the offline handshake of the provided address is answered. It is called before
+/// any reply is sent, which lets user code wire in GeoIP checks or reputation lists. Closures can be used as well.
+pub trait ConnectionFilter: Send + Sync + 'static {
+    fn allow(&mut self, addr: SocketAddr) -> bool;
+}
+
+impl<F> ConnectionFilter for F
+where
+    F: FnMut(SocketAddr) -> bool + Send + Sync + 'static,
+{
+    fn allow(&mut self, addr: SocketAddr) -> bool {
+        self(addr)
+    }
+}
+
+/// ConnectionFilters is the chain of filters that the offline handshakes of the servers are passed through in the
+/// order the filters were added. The handshakes refused by any of them are dropped without a reply.
+#[derive(Resource, Default)]
+pub struct ConnectionFilters {
+    filters: Vec<Box<dyn ConnectionFilter>>,
+}
+
+impl ConnectionFilters {
+    /// Appends the provided filter to the end of the chain.
+    pub fn add(&mut self, filter: impl ConnectionFilter) {
+        self.filters.push(Box::new(filter));
+    }
+
+    /// Appends the provided boxed filter to the end of the chain.
+    pub fn add_boxed(&mut self, filter: Box<dyn ConnectionFilter>) {
+        self.filters.push(filter);
+    }
+
+    /// Returns whether there are no filters in the chain.
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// Passes the handshake of the provided address through the chain. Returns false if one of the filters refused it.
+    pub(crate) fn allow(&mut self, addr: SocketAddr) -> bool {
+        self.filters.iter_mut().all(|filter| filter.allow(addr))
+    }
+}
+
+/// Run condition of all the network system sets that is active while the NetworkingState is Running.
+pub fn networking_running(state: Res<NetworkingState>) -> bool {
+    state.is_running()
+}
+
+/// This system is responsible for driving the clock of the RakNetConfig with the elapsed time of the Time resource
+/// when the clock is driven by Bevy.
+pub fn drive_clock(time: Res<Time>, config: Res<RakNetConfig>) {
+    if config.clock.is_bevy() {
+        config.clock.set_elapsed(time.elapsed());
+    }
+}
 
 /// This system is responsible for checking any outlived connections and sends a timeout to the connections
 /// that don't respond for more than a specific time period.
-pub fn check_timeout(query: Query<(Entity, &NetworkStatus)>, mut ev: EventWriter<RakNetEvent>) {
+pub fn check_timeout(
+    query: Query<(Entity, &NetworkStatus)>,
+    mut ev: EventWriter<RakNetEvent>,
+    config: Res<RakNetConfig>,
+) {
     for (entity, status) in query.iter() {
-        if status.last_activity.elapsed().as_millis() > RAKNET_TIMEOUT {
+        if config.clock.since(status.last_activity) > config.timeout {
             ev.send(RakNetEvent::Timeout(entity))
         }
     }
 }
 
-/// This system is responsible for building the MCPE Status that is sent in the Unconnected Pong message.
-pub fn server_update_status(
-    query: Query<(
-        &PrimaryMotd,
-        &SecondaryMotd,
-        &OnlinePlayers,
-        &MaxPlayers,
-        &MinecraftProtocol,
-        &MinecraftVersion,
-        &BroadcastGamemode,
-        &SocketInfo,
-    )>,
-    mut status: ResMut<StatusResource>,
-) {
-    let query = query.get_single().unwrap();
-    status.bytes.clear();
-
-    if let Err(e) = write!(
-        &mut status.bytes,
-        "MCPE;{};{};{};{};{};{};{};{};1;{};",
-        query.0.get(),
-        query.4.get(),
-        query.5.get(),
-        query.2.get(),
-        query.3.get(),
-        query.7.guid,
-        query.1.get(),
-        query.6.get(),
-        query.7.addr.port()
-    ) {
-        debug!("[Status Error]: {}", e.to_string());
+/// This system is responsible for disconnecting the connections that buffer more bytes than the maximum buffered bytes
+/// of a connection, so that a single peer cannot make the App hold arbitrary amounts of memory.
+pub fn check_buffered_memory(
+    query: Query<(Entity, &RakStream)>,
+    mut ev: EventWriter<RakNetEvent>,
+    config: Res<RakNetConfig>,
+) {
+    for (entity, stream) in query.iter() {
+        if matches!(
+            stream.state(),
+            ConnectionState::Disconnecting | ConnectionState::Closed
+        ) {
+            continue;
+        }
+
+        let bytes = stream.buffered_bytes();
+        if bytes > config.max_buffered_bytes {
+            ev.send(RakNetEvent::BufferLimitExceeded(entity, bytes));
+            ev.send(RakNetEvent::DisconnectRequest(
+                entity,
+                DisconnectReason::BufferLimitExceeded,
+            ));
+        }
+    }
+}
+
+/// This system is responsible for measuring the quality of the connections and warning about the ones whose packet
+/// loss, retransmissions or jitter rise above the quality thresholds. The issues that were not measured on a check, as
+/// too few datagrams were exchanged, are considered to still be the same as on the previous one.
+pub fn check_quality(
+    mut query: Query<(Entity, &mut RakStream)>,
+    mut ev: EventWriter<RakNetEvent>,
+    config: Res<RakNetConfig>,
+) {
+    for (entity, mut stream) in query.iter_mut() {
+        if stream.state() != ConnectionState::Connected {
+            continue;
+        }
+
+        let quality = stream.measure_quality();
+        let checks = [
+            quality.packet_loss.map(|loss| {
+                (
+                    QualityIssue::PacketLoss(loss),
+                    config
+                        .packet_loss_threshold
+                        .is_some_and(|threshold| loss > threshold),
+                )
+            }),
+            quality.retransmissions.map(|retransmissions| {
+                (
+                    QualityIssue::Retransmissions(retransmissions),
+                    config
+                        .retransmission_threshold
+                        .is_some_and(|threshold| retransmissions > threshold),
+                )
+            }),
+            Some((
+                QualityIssue::Jitter(quality.jitter),
+                config
+                    .jitter_threshold
+                    .is_some_and(|threshold| quality.jitter > threshold),
+            )),
+        ];
+
+        for (issue, crossed) in checks.into_iter().flatten() {
+            if stream.track_issue(issue, crossed) {
+                ev.send(RakNetEvent::QualityWarning(entity, issue));
+            }
+        }
+    }
+}
+
+/// This system is responsible for disconnecting the connections of the servers that have not completed the MCPE login
+/// within the login timeout since their RakNet connection was established, if the MCPE login is enabled.
+pub fn check_login_timeout(
+    query: Query<(Entity, &NetworkStatus, &ConnectionState, &LoginState), Without<RakSocket>>,
+    mut ev: EventWriter<RakNetEvent>,
+    config: Res<RakNetConfig>,
+) {
+    let Some(login_timeout) = config.login_timeout.filter(|_| config.mcpe_login) else {
         return;
+    };
+
+    for (entity, status, state, login) in query.iter() {
+        if *state == ConnectionState::Connected
+            && *login != LoginState::LoggedIn
+            && config.clock.since(status.state_changed_at) > login_timeout
+        {
+            debug!(
+                "[Network] Entity ID {:?} has not logged in within the login timeout",
+                entity.index(),
+            );
+
+            ev.send(RakNetEvent::DisconnectRequest(
+                entity,
+                DisconnectReason::LoginTimeout,
+            ));
+        }
     }
 }
 
-/// This system is responsible for reading for any messages from the UdpSocket. It handles all the Unconnected Messages
-/// and internal Connected Messages immediately while it writes an event for any Game Packets received.
-pub fn server_read_udp(
-    mut query: Query<&mut RakStream>,
-    mut server: Query<(&mut RakSocket, &mut Mappings, &SocketInfo)>,
+/// This system is responsible for disconnecting the connections of the servers that have not sent any game packet
+/// within the idle timeout, whether or not they are still alive.
+pub fn check_idle(
+    query: Query<(Entity, &NetworkStatus, &ConnectionState), Without<RakSocket>>,
     mut ev: EventWriter<RakNetEvent>,
-    mut commands: Commands,
-    status: Res<StatusResource>,
+    config: Res<RakNetConfig>,
 ) {
-    let (mut socket, mut mappings, info) = server.get_single_mut().unwrap();
-    let status = match std::str::from_utf8(&status.bytes) {
-        Ok(status) => status,
-        Err(e) => {
-            debug!("[Status Error]: {}", e.to_string());
-            return;
+    let Some(idle_timeout) = config.idle_timeout else {
+        return;
+    };
+
+    for (entity, status, state) in query.iter() {
+        if *state == ConnectionState::Connected
+            && config.clock.since(status.last_game_packet) > idle_timeout
+        {
+            debug!(
+                "[Network] Entity ID {:?} has not sent any game packet for too long",
+                entity.index(),
+            );
+
+            ev.send(RakNetEvent::DisconnectRequest(
+                entity,
+                DisconnectReason::Idle,
+            ));
         }
+    }
+}
+
+/// This system is responsible for disconnecting the connections of the servers whose latency has stayed above the
+/// maximum latency for longer than the high latency grace.
+pub fn check_latency(
+    mut query: Query<(Entity, &mut NetworkStatus, &ConnectionState), Without<RakSocket>>,
+    mut ev: EventWriter<RakNetEvent>,
+    config: Res<RakNetConfig>,
+) {
+    let Some(max_latency) = config.max_latency else {
+        return;
     };
 
-    let udp = socket.udp.clone();
-    if let Ok((len, addr)) = udp.recv_from(&mut socket.read_buf) {
-        if socket.is_blocked(addr, &mut mappings) {
-            return;
+    let now = config.clock.now();
+
+    for (entity, mut status, state) in query.iter_mut() {
+        if *state != ConnectionState::Connected || status.latency <= max_latency {
+            if status.high_latency_since.is_some() {
+                status.high_latency_since = None;
+            }
+
+            continue;
         }
 
-        if socket.check_packet_spam(addr, &mut mappings) {
-            return;
+        let since = *status.high_latency_since.get_or_insert(now);
+        if now.saturating_duration_since(since) >= config.high_latency_grace {
+            debug!(
+                "[Network] Entity ID {:?} has had a latency of {:?} for too long",
+                entity.index(),
+                status.latency,
+            );
+
+            ev.send(RakNetEvent::DisconnectRequest(
+                entity,
+                DisconnectReason::HighLatency,
+            ));
         }
+    }
+}
+
+/// This system is responsible for enabling the datagram-level logs of the connections selected by the TraceFilter.
+pub fn apply_trace_filter(filter: Res<TraceFilter>, mut query: Query<(Entity, &mut RakStream)>) {
+    for (entity, mut stream) in query.iter_mut() {
+        let traced = filter.matches(stream.addr(), entity);
 
-        if socket.handle_connected_message(addr, len, &mut query, &mut ev, &mut mappings) {
-            return;
+        if stream.is_traced() != traced {
+            stream.set_traced(traced);
         }
+    }
+}
 
-        if let Err(e) = socket.handle_unconnected_message(
-            addr,
-            len,
-            status,
-            &mut commands,
-            &mut ev,
-            &info,
-            &mut mappings,
-        ) {
-            socket.check_invalid_packets(addr, &mut mappings);
-            debug!("[Network Error]: {}", e.to_string());
+/// This system is responsible for discarding the split messages that were abandoned by the other end of the
+/// connection before all of their fragments were received.
+pub fn evict_splits(mut query: Query<&mut RakStream>) {
+    for mut stream in query.iter_mut() {
+        stream.evict_splits();
+    }
+}
+
+/// This system is responsible for evicting the expired and idle entries from the server mappings and the offenses that
+/// are no longer remembered from the BlockLists, so that they don't keep growing with every address that ever sent a
+/// message to the server.
+pub fn cleanup_mappings(
+    mut server: Query<(&mut Mappings, Option<&mut BlockList>)>,
+    streams: Query<&RakStream>,
+    config: Res<RakNetConfig>,
+) {
+    for (mut mappings, block_list) in server.iter_mut() {
+        mappings.cleanup(&config, |entity| streams.contains(entity));
+
+        if let Some(mut block_list) = block_list {
+            block_list.forget_offenses(config.offense_memory);
         }
     }
 }
 
-/// This system is responsible for reading for any messages from the UdpSocket. It handles all the Unconnected Messages
-/// and internal Connected Messages immediately while it writes an event for any Game Packets received.
-pub fn client_read_udp(
-    mut client: Query<(Entity, &mut RakSocket, &mut RakStream)>,
-    mut ev: EventWriter<RakNetEvent>,
+/// This system is responsible for keeping the OnlinePlayers of the server equal to the number of established
+/// connections with the offset of the OnlinePlayersSync applied.
+pub fn sync_online_players(
+    connections: Query<&ConnectionState, Without<RakSocket>>,
+    mut server: Query<&mut OnlinePlayers>,
+    sync: Res<OnlinePlayersSync>,
 ) {
-    let (entity, mut socket, mut stream) = client.get_single_mut().unwrap();
+    let count = connections
+        .iter()
+        .filter(|state| **state == ConnectionState::Connected)
+        .count();
+    let players = sync.players(count);
 
-    let udp = socket.udp.clone();
-    if let Ok(len) = udp.recv(&mut socket.read_buf) {
-        if let Err(e) = stream.decode(&socket.read_buf[..len], &mut ev, entity) {
-            debug!("[Network Error]: {}", e.to_string());
+    for mut online in server.iter_mut() {
+        if online.get() != players {
+            online.set(players);
         }
     }
 }
 
-/// This system is responsible for flushing receipts for those sequence numbers that we did receive ACK
-/// and for those we didn't (NACK).
-pub fn flush_receipts(mut query: Query<&mut RakStream>) {
-    for mut stream in query.iter_mut() {
-        stream.flush_receipts();
+/// This system is responsible for building the MCPE Status of every server that is sent in the Unconnected Pong
+/// message. The status is only rebuilt when one of the components it is built from has changed.
+pub fn server_update_status(
+    mut query: Query<
+        (
+            &PrimaryMotd,
+            &Maintenance,
+            &SecondaryMotd,
+            &OnlinePlayers,
+            &MaxPlayers,
+            &MinecraftProtocol,
+            &MinecraftVersion,
+            &BroadcastGamemode,
+            &SocketInfo,
+            &LevelName,
+            &PortV6,
+            &mut PongStatus,
+        ),
+        Or<(
+            Changed<PrimaryMotd>,
+            Changed<Maintenance>,
+            Changed<SecondaryMotd>,
+            Changed<OnlinePlayers>,
+            Changed<MaxPlayers>,
+            Changed<MinecraftProtocol>,
+            Changed<MinecraftVersion>,
+            Changed<BroadcastGamemode>,
+            Changed<SocketInfo>,
+            Changed<LevelName>,
+            Changed<PortV6>,
+        )>,
+    >,
+) {
+    for (
+        primary_motd,
+        maintenance,
+        secondary_motd,
+        online_players,
+        max_players,
+        protocol,
+        version,
+        gamemode,
+        info,
+        level_name,
+        port_v6,
+        mut status,
+    ) in query.iter_mut()
+    {
+        status.bytes.clear();
+
+        let motd = match maintenance.motd() {
+            Some(motd) if maintenance.is_enabled() => motd,
+            _ => primary_motd.get(),
+        };
+
+        let level_name = match level_name.get() {
+            "" => secondary_motd.get(),
+            name => name,
+        };
+
+        if let Err(e) = write!(
+            &mut status.bytes,
+            "MCPE;{};{};{};{};{};{};{};{};1;{};{};",
+            motd,
+            protocol.get(),
+            version.get(),
+            online_players.get(),
+            max_players.get(),
+            info.guid,
+            level_name,
+            gamemode.get(),
+            info.addr.port(),
+            port_v6.get()
+        ) {
+            debug!("[Status Error]: {}", e.to_string());
+        }
     }
 }
 
-/// This system is responsible for flushing of datagrams that we have written so far for all connections
-/// to the other end of the connection.
-pub fn flush_batch(mut query: Query<&mut RakStream>) {
-    for mut stream in query.iter_mut() {
-        stream.try_flush();
+/// This system is responsible for reporting the status of all the servers to the master server of the Heartbeat on its
+/// interval.
+pub fn announce_heartbeat(
+    mut heartbeat: ResMut<Heartbeat>,
+    servers: Query<(&SocketInfo, &PongStatus)>,
+    time: Res<Time>,
+) {
+    if !heartbeat.tick(time.delta()) {
+        return;
+    }
+
+    for (info, status) in servers.iter() {
+        match ServerStatus::parse(&String::from_utf8_lossy(&status.bytes)) {
+            Ok(status) => heartbeat.report(info.addr, &status),
+            Err(e) => debug!("[Heartbeat Error]: {}", e.to_string()),
+        }
     }
 }
 
-/// This system is responsible for checking the connection states, updating latencies, pings, etc.
-pub fn connection_tick(
-    mut ev: EventReader<RakNetEvent>,
+/// This system is responsible for reading for any messages from the Transports of all the servers. It handles all the
+/// Unconnected Messages and internal Connected Messages immediately while it writes an event for any Game Packets
+/// received. Each server is read within its own ReadBudget.
+pub fn server_read_udp(
+    mut query: Query<&mut RakStream>,
+    mut server: Query<(
+        &mut RakSocket,
+        &mut Mappings,
+        &mut BlockList,
+        &Whitelist,
+        &Maintenance,
+        &SocketInfo,
+        &PongStatus,
+    )>,
+    mut ev: EventWriter<RakNetEvent>,
+    mut interceptors: ResMut<MessageInterceptors>,
+    mut filters: ResMut<ConnectionFilters>,
     mut commands: Commands,
-    mut query: Query<(&mut NetworkStatus, &mut RakStream)>,
+    entities: &Entities,
+    budget: Res<ReadBudget>,
+    config: Res<RakNetConfig>,
 ) {
-    for event in ev.read() {
-        match event {
-            RakNetEvent::Disconnect(entity) => {
-                debug!(
-                    "[Network] Entity ID {:?} has been disconnected from the server",
-                    entity.index(),
-                );
+    for (mut socket, mut mappings, mut block_list, whitelist, maintenance, info, status) in
+        server.iter_mut()
+    {
+        let transport = socket.transport.clone();
+        let mut datagrams = 0;
+        let mut bytes = 0;
 
-                commands.entity(*entity).despawn();
+        while datagrams < budget.max_datagrams && bytes < budget.max_bytes {
+            let (len, addr) = match transport.recv_from(&mut socket.read_buf) {
+                Ok(recv) => recv,
+                Err(_) => break,
+            };
+
+            datagrams += 1;
+            bytes += len;
+
+            if block_list.is_blocked(addr) {
+                continue;
             }
-            RakNetEvent::Latency(entity, latency) => {
-                let (mut status, _) = query.get_mut(*entity).unwrap();
-                status.latency = *latency;
+
+            // The connections and the unconnected messages are rate limited apart, the recently closed connections
+            // are still rate limited as connections.
+            let spam = match mappings.connection(&addr).is_some() || mappings.is_closed(&addr) {
+                true => socket.check_packet_spam(addr, &mut mappings, &mut block_list, &config),
+                false => {
+                    socket.check_unconnected_spam(addr, &mut mappings, &mut block_list, &config)
+                }
+            };
+
+            if spam {
+                continue;
             }
-            RakNetEvent::Ping(entity, ping) => {
-                let (mut status, _) = query.get_mut(*entity).unwrap();
-                status.ping = *ping;
+
+            if socket.handle_connected_message(
+                addr,
+                len,
+                &mut query,
+                &mut ev,
+                &mut interceptors,
+                &mut mappings,
+                entities,
+                &config,
+            ) {
+                continue;
             }
-            RakNetEvent::LastActivity(entity, last_activity) => {
-                let (mut status, _) = query.get_mut(*entity).unwrap();
-                status.last_activity = *last_activity;
+
+            if let Err(e) = socket.handle_unconnected_message(
+                addr,
+                len,
+                status,
+                &mut commands,
+                &mut ev,
+                info,
+                &mut mappings,
+                &mut filters,
+                whitelist,
+                maintenance,
+                &config,
+            ) {
+                socket.check_invalid_packets(addr, &mut mappings, &mut block_list, &config);
+                debug!("[Network Error]: {}", e.to_string());
             }
-            RakNetEvent::OutgoingBatch(entity, bytes) => {
-                let (_, mut conn) = query.get_mut(*entity).unwrap();
-                let message = Message::GamePacket {
-                    data: UnsizedBytes::new(&bytes),
-                };
+        }
+    }
+}
+
+/// This system is responsible for answering the connections waiting for the approval of the App with the decisions of
+/// the App, the connections that were not approved within the approval timeout are refused.
+pub fn resolve_approvals(
+    mut events: ParamSet<(EventReader<RakNetEvent>, EventWriter<RakNetEvent>)>,
+    mut server: Query<(&mut RakSocket, &mut Mappings, &SocketInfo)>,
+    mut commands: Commands,
+    config: Res<RakNetConfig>,
+) {
+    let decisions: Vec<(SocketAddr, bool)> = events
+        .p0()
+        .read()
+        .filter_map(|event| match event {
+            RakNetEvent::ApproveConnection(addr) => Some((*addr, true)),
+            RakNetEvent::DenyConnection(addr) => Some((*addr, false)),
+            _ => None,
+        })
+        .collect();
+
+    let mut ev = events.p1();
 
-                conn.encode(message, Reliability::ReliableOrdered);
+    for (mut socket, mut mappings, info) in server.iter_mut() {
+        for (addr, approved) in decisions.iter() {
+            let Some(pending) = mappings.take_approval(addr) else {
+                continue;
+            };
+
+            let result = match approved {
+                true => socket.accept(
+                    *addr,
+                    pending,
+                    &mut commands,
+                    &mut ev,
+                    info,
+                    &mut mappings,
+                    &config,
+                ),
+                false => socket.refuse(*addr, info, Refusal::Banned),
+            };
+
+            if let Err(e) = result {
+                debug!("[Network Error]: {}", e.to_string());
             }
-            _ => {}
+        }
+
+        for addr in mappings.expired_approvals(config.clock.now(), config.approval_timeout) {
+            debug!(
+                "[Network] The connection of {} was not approved in time",
+                addr
+            );
+
+            if let Err(e) = socket.refuse(addr, info, Refusal::Banned) {
+                debug!("[Network Error]: {}", e.to_string());
+            }
+        }
+    }
+}
+
+/// This system is responsible for enforcing the DuplicateLoginPolicy on the clients that start another handshake from
+/// the address of a live connection, either by disconnecting the existing connection so that the handshake can go
+/// through or by refusing the handshake.
+pub fn handle_duplicate_logins(
+    mut events: ParamSet<(EventReader<RakNetEvent>, EventWriter<RakNetEvent>)>,
+    mut server: Query<(&mut RakSocket, &Mappings, &SocketInfo)>,
+    streams: Query<&RakStream>,
+    config: Res<RakNetConfig>,
+) {
+    let mut requests = Vec::new();
+
+    for event in events.p0().read() {
+        let RakNetEvent::DuplicateLogin(entity) = event else {
+            continue;
+        };
+
+        let Ok(stream) = streams.get(*entity) else {
+            continue;
+        };
+
+        match config.duplicate_login_policy {
+            DuplicateLoginPolicy::KickExisting => {
+                // The retries of the handshake keep coming until the connection is despawned.
+                if matches!(
+                    stream.state(),
+                    ConnectionState::Disconnecting | ConnectionState::Closed
+                ) {
+                    continue;
+                }
+
+                requests.push(RakNetEvent::DisconnectRequest(
+                    *entity,
+                    DisconnectReason::DuplicateLogin,
+                ));
+            }
+            DuplicateLoginPolicy::RejectNew => {
+                let addr = stream.addr();
+
+                for (mut socket, mappings, info) in server.iter_mut() {
+                    if mappings.connection(&addr) != Some(*entity) {
+                        continue;
+                    }
+
+                    if let Err(e) = socket.refuse(addr, info, Refusal::AlreadyConnected) {
+                        debug!("[Network Error]: {}", e.to_string());
+                    }
+                }
+            }
+            DuplicateLoginPolicy::AllowBoth => {}
+        }
+    }
+
+    let mut ev = events.p1();
+
+    for request in requests {
+        ev.send(request);
+    }
+}
+
+/// This system is responsible for writing the changes made to the BlockLists of all the servers as events, for
+/// disconnecting the connections of the addresses that were blocked and for saving the persisted BlockLists that
+/// changed.
+pub fn publish_blocks(
+    mut server: Query<(&mut BlockList, &Mappings)>,
+    mut ev: EventWriter<RakNetEvent>,
+) {
+    for (mut block_list, mappings) in server.iter_mut() {
+        let changes = block_list.drain_changes();
+        if changes.is_empty() {
+            continue;
+        }
+
+        if let Err(e) = block_list.save() {
+            debug!("[Block List Error]: {}", e.to_string());
+        }
+
+        for change in changes {
+            match &change {
+                RakNetEvent::AddressBlocked(addr, duration, reason) => {
+                    info!(%addr, ?duration, ?reason, "Blocked address");
+
+                    if let Some(entity) = mappings.connection(addr) {
+                        ev.send(RakNetEvent::DisconnectRequest(
+                            entity,
+                            DisconnectReason::Blocked(reason.clone()),
+                        ));
+                    }
+                }
+                RakNetEvent::RangeBlocked(range, duration, reason) => {
+                    info!(%range, ?duration, ?reason, "Blocked range");
+
+                    for (addr, entity) in mappings.connections() {
+                        if range.contains(addr.ip()) {
+                            ev.send(RakNetEvent::DisconnectRequest(
+                                *entity,
+                                DisconnectReason::Blocked(reason.clone()),
+                            ));
+                        }
+                    }
+                }
+                RakNetEvent::BlockEscalated(addr, offenses, duration) => {
+                    info!(%addr, offenses, ?duration, "Escalated the block of a repeat offender");
+                }
+                RakNetEvent::AddressUnblocked(addr) => info!(%addr, "Unblocked address"),
+                RakNetEvent::RangeUnblocked(range) => info!(%range, "Unblocked range"),
+                _ => {}
+            }
+
+            ev.send(change);
+        }
+    }
+}
+
+/// This system is responsible for saving the persisted BlockLists of all the servers when the App exits, so that
+/// the blocks changed since the last run of the network schedule are not lost.
+pub fn save_blocks_on_exit(mut exit: EventReader<AppExit>, server: Query<&BlockList>) {
+    if exit.is_empty() {
+        return;
+    }
+
+    exit.clear();
+
+    for block_list in server.iter() {
+        if let Err(e) = block_list.save() {
+            debug!("[Block List Error]: {}", e.to_string());
+        }
+    }
+}
+
+/// This system is responsible for answering the Unconnected Pings broadcast on the LAN discovery port with the status
+/// of the server. The pings go through the block list, the unconnected rate limit, the connection filters and the
+/// pong rate limit of the server, and the datagrams read in a single run are bounded by the ReadBudget.
+pub fn server_answer_lan(
+    mut lan: ResMut<LanListener>,
+    mut server: Query<(
+        &mut RakSocket,
+        &mut Mappings,
+        &mut BlockList,
+        &SocketInfo,
+        &PongStatus,
+    )>,
+    mut filters: ResMut<ConnectionFilters>,
+    budget: Res<ReadBudget>,
+    config: Res<RakNetConfig>,
+) {
+    let Ok((mut socket, mut mappings, mut block_list, info, status)) = server.get_mut(lan.server())
+    else {
+        return;
+    };
+
+    let mut datagrams = 0;
+    let mut bytes = 0;
+
+    while datagrams < budget.max_datagrams && bytes < budget.max_bytes {
+        let Some((addr, len, timestamp)) = lan.recv() else {
+            break;
+        };
+
+        datagrams += 1;
+        bytes += len;
+
+        if block_list.is_blocked(addr)
+            || socket.check_unconnected_spam(addr, &mut mappings, &mut block_list, &config)
+        {
+            continue;
+        }
+
+        let Some(timestamp) = timestamp else {
+            continue;
+        };
+
+        if !filters.allow(addr) || !socket.allow_pong(addr, &mut mappings, &config) {
+            continue;
+        }
+
+        lan.answer(addr, timestamp, status, info.guid);
+    }
+}
+
+/// This system is responsible for introducing the connections of the server that request a NAT punchthrough to their
+/// target. Both are sent the address and GUID of each other, the requester is told if the target is not connected or
+/// a punchthrough between them is already in progress.
+pub fn coordinate_nat_punchthrough(
+    mut ev: EventReader<RakNetEvent>,
+    mut coordinator: ResMut<NatCoordinator>,
+    mut query: Query<(Entity, &NetworkInfo, &ConnectionState, &mut RakStream)>,
+    config: Res<RakNetConfig>,
+) {
+    for event in ev.read() {
+        let RakNetEvent::NatPunchthroughRequest(entity, target_guid) = event else {
+            continue;
+        };
+
+        let Ok((_, info, _, _)) = query.get(*entity) else {
+            continue;
+        };
+        let guid = info.guid;
+
+        let target = query
+            .iter()
+            .find(|(other, info, state, _)| {
+                other != entity
+                    && info.guid == *target_guid
+                    && **state == ConnectionState::Connected
+            })
+            .map(|(other, ..)| other);
+
+        let Some(target) = target else {
+            if let Ok((.., mut stream)) = query.get_mut(*entity) {
+                let msg = Message::NatTargetNotConnected {
+                    target_guid: I64::new(*target_guid),
+                };
+                stream.encode(msg, Reliability::ReliableOrdered);
+            }
+            continue;
+        };
+
+        if !coordinator.begin(guid, *target_guid, config.clock.now()) {
+            if let Ok((.., mut stream)) = query.get_mut(*entity) {
+                let msg = Message::NatAlreadyInProgress {
+                    target_guid: I64::new(*target_guid),
+                };
+                stream.encode(msg, Reliability::ReliableOrdered);
+            }
+            continue;
+        }
+
+        let Ok([(_, requester_info, _, mut requester), (_, peer_info, _, mut peer)]) =
+            query.get_many_mut([*entity, target])
+        else {
+            continue;
+        };
+
+        let delay = U16::new(NAT_PUNCHTHROUGH_DELAY.as_millis() as u16);
+
+        requester.encode(
+            Message::NatConnectAtTime {
+                delay: delay.clone(),
+                peer_address: UDPAddress(peer_info.remote_addr),
+                peer_guid: I64::new(peer_info.guid),
+            },
+            Reliability::ReliableOrdered,
+        );
+        peer.encode(
+            Message::NatConnectAtTime {
+                delay,
+                peer_address: UDPAddress(requester_info.remote_addr),
+                peer_guid: I64::new(requester_info.guid),
+            },
+            Reliability::ReliableOrdered,
+        );
+    }
+}
+
+/// This system is responsible for reading for any messages from the Transports of all the client connections. It handles
+/// the internal Connected Messages immediately while it writes an event for any Game Packets received. Each client
+/// connection is read within its own ReadBudget, the datagrams of other addresses are only handled by its
+/// NatPunchthrough.
+pub fn client_read_udp(
+    mut clients: Query<(
+        Entity,
+        &mut RakSocket,
+        &SocketInfo,
+        &mut RakStream,
+        Option<&mut NatPunchthrough>,
+    )>,
+    mut ev: EventWriter<RakNetEvent>,
+    mut interceptors: ResMut<MessageInterceptors>,
+    budget: Res<ReadBudget>,
+    config: Res<RakNetConfig>,
+) {
+    for (entity, mut socket, info, mut stream, mut nat) in clients.iter_mut() {
+        let transport = socket.transport.clone();
+        let mut datagrams = 0;
+        let mut bytes = 0;
+
+        while datagrams < budget.max_datagrams && bytes < budget.max_bytes {
+            let (len, addr) = match transport.recv_from(&mut socket.read_buf) {
+                Ok(recv) => recv,
+                Err(_) => break,
+            };
+
+            datagrams += 1;
+            bytes += len;
+
+            if addr != stream.addr() {
+                if socket.read_buf[..len].first() == Some(&ADVERTISE_SYSTEM_PACKET_ID) {
+                    if let Err(e) = read_advertisement(addr, &socket.read_buf[..len], &mut ev) {
+                        debug!("[Network Error]: {}", e.to_string());
+                    }
+                } else if let Some(nat) = nat.as_mut() {
+                    nat.receive(
+                        entity,
+                        &*transport,
+                        info.guid,
+                        addr,
+                        &socket.read_buf[..len],
+                        &mut ev,
+                    );
+                }
+
+                continue;
+            }
+
+            if let Err(e) =
+                stream.decode(&socket.read_buf[..len], &mut ev, &mut interceptors, entity)
+            {
+                debug!("[Network Error]: {}", e.to_string());
+
+                if config.dump_malformed_packets {
+                    ev.send(RakNetEvent::MalformedPacketDump(
+                        entity,
+                        socket.read_buf[..len].to_vec(),
+                        e.to_string(),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// This system is responsible for pinging the servers of the ServerListPinger on its interval and for reading their
+/// pongs.
+pub fn ping_server_list(
+    mut pinger: ResMut<ServerListPinger>,
+    mut ev: EventWriter<RakNetEvent>,
+    time: Res<Time>,
+) {
+    pinger.refresh(time.delta());
+    pinger.receive(&mut ev);
+}
+
+/// This system is responsible for broadcasting the Unconnected Pings of the LanDiscovery on its interval and for
+/// reading the pongs of the servers on the LAN.
+pub fn discover_lan(
+    mut lan: ResMut<LanDiscovery>,
+    mut ev: EventWriter<RakNetEvent>,
+    time: Res<Time>,
+) {
+    lan.discover(time.delta(), &mut ev);
+}
+
+/// This system is responsible for starting the NatPunchthrough of the client connections whose coordinator introduced
+/// them to a peer.
+pub fn start_nat_punchthrough(
+    mut ev: EventReader<RakNetEvent>,
+    mut commands: Commands,
+    mut query: Query<&mut NatPunchthrough>,
+    config: Res<RakNetConfig>,
+) {
+    let mut inserted = HashMap::new();
+
+    for event in ev.read() {
+        let RakNetEvent::NatConnectAtTime(entity, peer, guid, delay) = event else {
+            continue;
+        };
+
+        let now = config.clock.now();
+
+        match query.get_mut(*entity) {
+            Ok(mut nat) => nat.start(*peer, *guid, *delay, now),
+            Err(_) => inserted
+                .entry(*entity)
+                .or_insert_with(NatPunchthrough::default)
+                .start(*peer, *guid, *delay, now),
+        }
+    }
+
+    for (entity, nat) in inserted {
+        if let Some(mut entity) = commands.get_entity(entity) {
+            entity.insert(nat);
+        }
+    }
+}
+
+/// This system is responsible for completing the connections to the punched peers whose handshakes on the
+/// HandshakePool have finished.
+pub fn nat_handshake(mut query: Query<(Entity, &mut PendingPunched)>, mut commands: Commands) {
+    for (entity, mut pending) in query.iter_mut() {
+        if let Some(outcome) = pending.poll() {
+            commands.entity(entity).add(FinishPunched(outcome));
+        }
+    }
+}
+
+/// This system is responsible for sending the due punches of the client connections to their peers from their
+/// Transports and for failing the punchthroughs that ran out of attempts.
+pub fn nat_punchthrough(
+    mut query: Query<(Entity, &RakSocket, &SocketInfo, &mut NatPunchthrough)>,
+    mut ev: EventWriter<RakNetEvent>,
+    config: Res<RakNetConfig>,
+) {
+    for (entity, socket, info, mut nat) in query.iter_mut() {
+        nat.punch(
+            entity,
+            &*socket.transport,
+            info.guid,
+            config.clock.now(),
+            &mut ev,
+        );
+    }
+}
+
+/// This system is responsible for driving the ClientNetState from the connection states of all the client connections.
+pub fn client_update_state(
+    clients: Query<&ConnectionState, With<RakSocket>>,
+    state: Res<State<ClientNetState>>,
+    mut next_state: ResMut<NextState<ClientNetState>>,
+) {
+    let mut new_state = ClientNetState::Disconnected;
+
+    for conn_state in clients.iter() {
+        match conn_state {
+            ConnectionState::Connected => {
+                new_state = ClientNetState::Connected;
+                break;
+            }
+            ConnectionState::Handshaking | ConnectionState::AwaitingConnectionRequest => {
+                new_state = ClientNetState::Connecting;
+            }
+            _ => {}
+        }
+    }
+
+    if *state.get() != new_state {
+        next_state.set(new_state);
+    }
+}
+
+/// This system is responsible for writing the datagrams queued by the connections of the server
+/// to the Transport.
+pub fn server_flush_udp(
+    mut query: Query<&mut RakStream>,
+    mut server: Query<(&mut RakSocket, &Mappings)>,
+) {
+    for (mut socket, mappings) in server.iter_mut() {
+        socket.flush_connections(&mut query, mappings);
+    }
+}
+
+/// This system is responsible for writing the datagrams queued by each client connection to its Transport.
+pub fn client_flush_udp(mut client: Query<(&RakSocket, &mut RakStream)>) {
+    for (socket, mut stream) in client.iter_mut() {
+        while let Some(datagram) = stream.poll_outgoing() {
+            if let Err(e) = socket.transport.send_to(&datagram, stream.addr()) {
+                debug!("[Network Error]: {}", e.to_string());
+            }
+        }
+    }
+}
+
+/// This system is responsible for gracefully disconnecting all the connections when the App exits. The disconnect
+/// notifications are written to the sockets right away and the sockets are read for the receipts of the other ends
+/// until everything has been acknowledged or the exit grace period of the RakNetConfig has elapsed on its clock.
+pub fn disconnect_on_exit(
+    mut exit: EventReader<AppExit>,
+    mut streams: Query<(Entity, &mut RakStream, Option<&RakSocket>)>,
+    servers: Query<(&RakSocket, &Mappings), Without<RakStream>>,
+    mut ev: EventWriter<RakNetEvent>,
+    mut interceptors: ResMut<MessageInterceptors>,
+    config: Res<RakNetConfig>,
+) {
+    if exit.is_empty() {
+        return;
+    }
+
+    exit.clear();
+
+    for (entity, mut stream, _) in streams.iter_mut() {
+        stream.disconnect(&mut ev, entity);
+    }
+
+    let deadline = config.clock.now() + config.exit_grace;
+    let mut buf = [0u8; MAX_MTU_SIZE];
+
+    loop {
+        for (entity, mut stream, socket) in streams.iter_mut() {
+            stream.flush_receipts();
+            stream.try_flush();
+
+            if let Some(socket) = socket {
+                while let Some(datagram) = stream.poll_outgoing() {
+                    if let Err(e) = socket.transport.send_to(&datagram, stream.addr()) {
+                        debug!("[Network Error]: {}", e.to_string());
+                    }
+                }
+
+                while let Ok((len, addr)) = socket.transport.recv_from(&mut buf) {
+                    if addr != stream.addr() {
+                        continue;
+                    }
+
+                    let _ = stream.decode(&buf[..len], &mut ev, &mut interceptors, entity);
+                }
+            }
+        }
+
+        for (socket, mappings) in servers.iter() {
+            for (addr, entity) in mappings.connections() {
+                if let Ok((_, mut stream, _)) = streams.get_mut(*entity) {
+                    while let Some(datagram) = stream.poll_outgoing() {
+                        if let Err(e) = socket.transport.send_to(&datagram, *addr) {
+                            debug!("[Network Error]: {}", e.to_string());
+                        }
+                    }
+                }
+            }
+
+            while let Ok((len, addr)) = socket.transport.recv_from(&mut buf) {
+                if let Some(entity) = mappings.connection(&addr) {
+                    if let Ok((_, mut stream, _)) = streams.get_mut(entity) {
+                        let _ = stream.decode(&buf[..len], &mut ev, &mut interceptors, entity);
+                    }
+                }
+            }
+        }
+
+        if config.clock.now() >= deadline || streams.iter().all(|(_, s, _)| s.is_acknowledged()) {
+            break;
+        }
+
+        config.clock.sleep(Duration::from_millis(1));
+    }
+}
+
+/// This system is responsible for flushing receipts for those sequence numbers that we did receive ACK
+/// and for those we didn't (NACK).
+pub fn flush_receipts(mut query: Query<(Entity, &mut RakStream)>) {
+    for (entity, mut stream) in query.iter_mut() {
+        let _span = stream.span(entity).entered();
+        stream.flush_receipts();
+    }
+}
+
+/// This system is responsible for flushing of datagrams that we have written so far for all connections
+/// to the other end of the connection.
+pub fn flush_batch(mut query: Query<(Entity, &mut RakStream)>) {
+    for (entity, mut stream) in query.iter_mut() {
+        let _span = stream.span(entity).entered();
+        stream.try_flush();
+    }
+}
+
+/// This system is responsible for queueing the outgoing game packets into the batches of the respective connections.
+pub fn queue_outgoing(mut network: EventReader<NetworkEvent>, mut queue: ResMut<OutgoingQueue>) {
+    for event in network.read() {
+        if let NetworkEvent::OutgoingPacket(entity, bytes) = event {
+            queue.push(*entity, bytes);
+        }
+    }
+}
+
+/// This system is responsible for compressing, and encrypting if the connection has EncryptionKeys, the queued
+/// game packet batches and writing them as RakNet batches to the respective connections. The disconnections requested
+/// behind the batches are written after them, so that the batches are sent ahead of the disconnect notifications.
+pub fn compress_outgoing(
+    mut queue: ResMut<OutgoingQueue>,
+    mut ev: EventWriter<RakNetEvent>,
+    mut query: Query<(
+        Option<&CompressionAlgorithm>,
+        Has<CompressionHeader>,
+        Option<&mut EncryptionKeys>,
+    )>,
+    config: Res<RakNetConfig>,
+) {
+    for (entity, bytes) in queue.drain() {
+        let Ok((algorithm, header, keys)) = query.get_mut(entity) else {
+            continue;
+        };
+
+        let algorithm = algorithm.copied().unwrap_or_default();
+
+        match compress_batch(&bytes, algorithm, config.compression_level, header) {
+            Ok(batch) => match keys {
+                Some(mut keys) => ev.send(RakNetEvent::OutgoingBatch(entity, keys.encrypt(batch))),
+                None => ev.send(RakNetEvent::OutgoingBatch(entity, batch)),
+            },
+            Err(e) => debug!("[Compression Error]: {}", e.to_string()),
+        }
+    }
+
+    for (entity, reason) in queue.drain_disconnects() {
+        ev.send(RakNetEvent::DisconnectRequest(entity, reason));
+    }
+}
+
+/// This system is responsible for decrypting, if the connection has EncryptionKeys, and decompressing the incoming
+/// RakNet batches and writing each of their sub-packets as a game packet for the respective connections. The
+/// connections whose batches fail to decrypt are disconnected.
+pub fn decompress_incoming(
+    mut events: ParamSet<(EventReader<RakNetEvent>, EventWriter<RakNetEvent>)>,
+    mut network: EventWriter<NetworkEvent>,
+    mut query: Query<
+        (
+            Option<&CompressionAlgorithm>,
+            Has<CompressionHeader>,
+            Option<&mut EncryptionKeys>,
+        ),
+        Without<ProxyLink>,
+    >,
+    config: Res<RakNetConfig>,
+) {
+    let mut failed = Vec::new();
+
+    for event in events.p0().read() {
+        if let RakNetEvent::IncomingBatch(entity, bytes) = event {
+            let Ok((algorithm, header, keys)) = query.get_mut(*entity) else {
+                continue;
+            };
+
+            let algorithm = algorithm.copied().unwrap_or_default();
+
+            let batch = match keys {
+                // The disconnection was already requested when the first batch failed to decrypt.
+                Some(keys) if keys.has_failed() => continue,
+                Some(mut keys) => match keys.decrypt(bytes.to_vec()) {
+                    Ok(batch) => batch,
+                    Err(e) => {
+                        debug!("[Encryption Error]: {}", e.to_string());
+                        failed.push(RakNetEvent::DisconnectRequest(
+                            *entity,
+                            DisconnectReason::DecryptionFailed,
+                        ));
+                        continue;
+                    }
+                },
+                None => bytes.to_vec(),
+            };
+
+            let batch = match decompress_batch(&batch, algorithm, config.max_batch_size, header) {
+                Ok(batch) => batch,
+                Err(e) => {
+                    debug!("[Compression Error]: {}", e.to_string());
+                    continue;
+                }
+            };
+
+            match read_sub_packets(&batch) {
+                Ok(packets) => network.send_batch(packets.into_iter().map(|packet| {
+                    NetworkEvent::IncomingPacket(*entity, Bytes::copy_from_slice(packet))
+                })),
+                Err(e) => debug!("[Batch Error]: {}", e.to_string()),
+            }
+        }
+    }
+
+    events.p1().send_batch(failed);
+}
+
+/// This system is responsible for advancing the MCPE login sequence of the connections as the packets of the sequence
+/// are received. It answers the network settings request, parses and verifies the Login packet, starts the encryption
+/// and writes a ConnectionEstablished event once the client has been sent the PlayStatus LoginSuccess. The clients
+/// that fail the verification are sent the PlayStatus LoginFailedClient before they are disconnected.
+pub fn handle_login(
+    mut network: ParamSet<(EventReader<NetworkEvent>, EventWriter<NetworkEvent>)>,
+    mut ev: EventWriter<RakNetEvent>,
+    mut queue: ResMut<OutgoingQueue>,
+    mut commands: Commands,
+    mut query: Query<&mut LoginState>,
+    config: Res<RakNetConfig>,
+) {
+    let mut results = Vec::new();
+
+    for event in network.p0().read() {
+        let NetworkEvent::IncomingPacket(entity, bytes) = event else {
+            continue;
+        };
+
+        let Ok(mut state) = query.get_mut(*entity) else {
+            continue;
+        };
+
+        match (*state, packet_id(bytes)) {
+            (LoginState::AwaitingNetworkSettings, Some(REQUEST_NETWORK_SETTINGS)) => {
+                let request =
+                    match RequestNetworkSettings::deserialize(&mut Cursor::new(&bytes[..])) {
+                        Ok(request) => request,
+                        Err(e) => {
+                            debug!("[Login Error]: {}", e.to_string());
+                            ev.send(RakNetEvent::DisconnectRequest(
+                                *entity,
+                                DisconnectReason::Custom("Invalid network settings".to_string()),
+                            ));
+                            continue;
+                        }
+                    };
+
+                let settings = NetworkSettings {
+                    compression_threshold: COMPRESSION_THRESHOLD,
+                    compression_algorithm: config.compression_algorithm.id(),
+                    client_throttle: false,
+                    client_throttle_threshold: 0,
+                    client_throttle_scalar: 0.0,
+                };
+
+                ev.send(RakNetEvent::OutgoingBatch(
+                    *entity,
+                    encode_packet(&settings),
+                ));
+                commands
+                    .entity(*entity)
+                    .insert(config.compression_algorithm);
+
+                if request.protocol >= COMPRESSION_HEADER_PROTOCOL {
+                    commands.entity(*entity).insert(CompressionHeader);
+                }
+
+                *state = LoginState::AwaitingLogin;
+            }
+            (LoginState::AwaitingLogin, Some(LOGIN)) => {
+                let data = match read_login(bytes, &config) {
+                    Ok(Some(data)) => data,
+                    Ok(None) => {
+                        results.extend(login_failed(*entity));
+                        continue;
+                    }
+                    Err(e) => {
+                        debug!("[Login Error]: {}", e.to_string());
+                        ev.send(RakNetEvent::DisconnectRequest(
+                            *entity,
+                            DisconnectReason::Custom("Invalid login".to_string()),
+                        ));
+                        continue;
+                    }
+                };
+
+                results.push(NetworkEvent::ConnectionRequest(*entity));
+
+                if config.encryption {
+                    commands
+                        .entity(*entity)
+                        .start_encryption(&data.identity_public_key)
+                        .insert(data);
+                    *state = LoginState::AwaitingHandshake;
+                } else {
+                    commands.entity(*entity).insert(data);
+                    results.extend(login_success(*entity));
+                    *state = LoginState::LoggedIn;
+                }
+            }
+            (LoginState::AwaitingHandshake, Some(CLIENT_TO_SERVER_HANDSHAKE)) => {
+                results.extend(login_success(*entity));
+                *state = LoginState::LoggedIn;
+            }
+            _ => {}
+        }
+    }
+
+    for event in results {
+        if let NetworkEvent::AuthenticationFailed(entity) = event {
+            queue.disconnect_after_flush(entity, DisconnectReason::NotAuthenticated);
+        }
+
+        network.p1().send(event);
+    }
+}
+
+/// This system is responsible for driving the client connections with an OfflineLogin through the MCPE login
+/// sequence. It requests the network settings once the RakNet connection is established, sends the Login packet,
+/// completes the encryption handshake and writes a ConnectionEstablished event once the login succeeded.
+pub fn client_login(
+    mut ev: EventReader<RakNetEvent>,
+    mut network: ParamSet<(EventReader<NetworkEvent>, EventWriter<NetworkEvent>)>,
+    mut commands: Commands,
+    mut query: Query<(&OfflineLogin, &mut LoginState)>,
+) {
+    let mut results = Vec::new();
+
+    for event in ev.read() {
+        if let RakNetEvent::StateChanged(entity, ConnectionState::Connected) = event {
+            if let Ok((login, _)) = query.get(*entity) {
+                let request = RequestNetworkSettings {
+                    protocol: login.protocol(),
+                };
+
+                results.push(outgoing_packet(*entity, &request));
+            }
+        }
+    }
+
+    for event in network.p0().read() {
+        let NetworkEvent::IncomingPacket(entity, bytes) = event else {
+            continue;
+        };
+
+        let Ok((login, mut state)) = query.get_mut(*entity) else {
+            continue;
+        };
+
+        let mut buf = Cursor::new(&bytes[..]);
+
+        match (*state, packet_id(bytes)) {
+            (LoginState::AwaitingNetworkSettings, Some(NETWORK_SETTINGS)) => {
+                let algorithm = NetworkSettings::deserialize(&mut buf).and_then(|settings| {
+                    CompressionAlgorithm::from_id(settings.compression_algorithm).ok_or(Error::new(
+                        ErrorKind::Other,
+                        "Unknown compression algorithm",
+                    ))
+                });
+
+                match algorithm.and_then(|algorithm| Ok((algorithm, login.login()?))) {
+                    Ok((algorithm, packet)) => {
+                        commands.entity(*entity).insert(algorithm);
+
+                        if login.protocol() >= COMPRESSION_HEADER_PROTOCOL {
+                            commands.entity(*entity).insert(CompressionHeader);
+                        }
+
+                        results.push(outgoing_packet(*entity, &packet));
+                        *state = LoginState::AwaitingLogin;
+                    }
+                    Err(e) => {
+                        debug!("[Login Error]: {}", e.to_string());
+                        commands.entity(*entity).disconnect();
+                    }
+                }
+            }
+            (LoginState::AwaitingLogin, Some(SERVER_TO_CLIENT_HANDSHAKE)) => {
+                let keys = ServerToClientHandshake::deserialize(&mut buf).and_then(|handshake| {
+                    EncryptionKeys::from_handshake(login.secret(), &handshake.token)
+                });
+
+                match keys {
+                    Ok(keys) => {
+                        commands.entity(*entity).insert(keys);
+                        results.push(outgoing_packet(*entity, &ClientToServerHandshake));
+                        *state = LoginState::AwaitingHandshake;
+                    }
+                    Err(e) => {
+                        debug!("[Login Error]: {}", e.to_string());
+                        commands.entity(*entity).disconnect();
+                    }
+                }
+            }
+            (LoginState::AwaitingLogin | LoginState::AwaitingHandshake, Some(PLAY_STATUS)) => {
+                match PlayStatus::deserialize(&mut buf).map(|packet| packet.status) {
+                    Ok(Status::LoginSuccess) => {
+                        results.push(NetworkEvent::ConnectionEstablished(*entity));
+                        *state = LoginState::LoggedIn;
+                    }
+                    Ok(status) => {
+                        commands
+                            .entity(*entity)
+                            .disconnect_with(DisconnectReason::Custom(format!("{:?}", status)));
+                    }
+                    Err(e) => debug!("[Login Error]: {}", e.to_string()),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for event in results {
+        network.p1().send(event);
+    }
+}
+
+/// Parses the provided Login packet and verifies its chain in online mode. Returns None if the chain is not signed by
+/// Mojang.
+fn read_login(packet: &[u8], config: &RakNetConfig) -> std::io::Result<Option<LoginData>> {
+    let login = Login::deserialize(&mut Cursor::new(packet))?;
+
+    if config.online_mode && !login.verify()? {
+        return Ok(None);
+    }
+
+    LoginData::parse(&login).map(Some)
+}
+
+/// Returns the events that send the PlayStatus LoginSuccess to the provided connection and establish it.
+fn login_success(entity: Entity) -> [NetworkEvent; 2] {
+    let status = PlayStatus {
+        status: Status::LoginSuccess,
+    };
+
+    [
+        outgoing_packet(entity, &status),
+        NetworkEvent::ConnectionEstablished(entity),
+    ]
+}
+
+/// Returns the events that send the PlayStatus LoginFailedClient to the provided connection and report that it could
+/// not be authenticated.
+fn login_failed(entity: Entity) -> [NetworkEvent; 2] {
+    let status = PlayStatus {
+        status: Status::LoginFailedClient,
+    };
+
+    [
+        outgoing_packet(entity, &status),
+        NetworkEvent::AuthenticationFailed(entity),
+    ]
+}
+
+/// Returns the event that writes the provided game packet to the provided connection.
+fn outgoing_packet<'a>(entity: Entity, packet: &impl Binary<'a>) -> NetworkEvent {
+    let mut buf = Vec::new();
+    packet.serialize(&mut buf);
+
+    NetworkEvent::OutgoingPacket(entity, buf.into())
+}
+
+/// This system is responsible for connecting every new downstream client of the proxy to the backend picked by the
+/// Backends. The downstream clients are not logged in by the proxy, their login sequence is forwarded to the backend
+/// along with everything else.
+pub fn proxy_connect(
+    query: Query<Entity, (Added<RakStream>, Without<RakSocket>)>,
+    mut backends: ResMut<Backends>,
+    mut commands: Commands,
+    config: Res<RakNetConfig>,
+) {
+    for entity in query.iter() {
+        let mut entity = commands.entity(entity);
+        entity.remove::<LoginState>();
+
+        match backends.select(config.clock.now()) {
+            Some(backend) => entity.connect_upstream(&backend),
+            None => {
+                entity.disconnect_with(DisconnectReason::Custom("No backend available".to_string()))
+            }
+        };
+    }
+}
+
+/// This system is responsible for completing the pending upstream connections of the proxy whose handshakes with the
+/// backends have finished on their own threads. The pending connections that were detached by a transfer or whose
+/// downstream client has gone away meanwhile are dropped, along with the connection counted towards their backend.
+pub fn proxy_handshake(
+    mut query: Query<(Entity, &mut PendingUpstream, Option<&ProxyLink>)>,
+    downstream: Query<(), With<RakStream>>,
+    mut backends: ResMut<Backends>,
+    mut commands: Commands,
+) {
+    for (entity, mut pending, link) in query.iter_mut() {
+        let Some(link) = link else {
+            commands.entity(entity).despawn();
+            continue;
+        };
+
+        if downstream.get(link.peer()).is_err() {
+            backends.release(link.backend());
+            commands.entity(entity).despawn();
+            continue;
+        }
+
+        if let Some(outcome) = pending.poll() {
+            commands
+                .entity(entity)
+                .add(FinishUpstream(pending.kind(), outcome));
+        }
+    }
+}
+
+/// This system is responsible for forwarding the batches received on either side of the proxy to the other side, the
+/// batches of the downstream clients are checked against the ProxyRateLimit, then all the batches are passed through
+/// the ProxyInterceptors and held back until the receiving connection has been established
+/// and, after a transfer, until the login has been replayed to the new backend. When one side is disconnected the
+/// other side is disconnected with the same reason, while a client whose backend timed out is held for reconnecting
+/// if there is a ReconnectPolicy or transferred to the fallback backend if there is one.
+pub fn proxy_forward(
+    mut events: ParamSet<(EventReader<RakNetEvent>, EventWriter<RakNetEvent>)>,
+    mut transfers: EventWriter<ProxyEvent>,
+    mut links: Query<(Entity, &mut ProxyLink, &ConnectionState)>,
+    mut backends: ResMut<Backends>,
+    mut interceptors: ResMut<ProxyInterceptors>,
+    policy: Option<Res<ReconnectPolicy>>,
+    limit: Option<Res<ProxyRateLimit>>,
+    mut commands: Commands,
+    config: Res<RakNetConfig>,
+) {
+    let mut limited = HashSet::new();
+    let mut batches = Vec::new();
+    let mut replays = Vec::new();
+    let mut requests = Vec::new();
+    let mut outgoing = Vec::new();
+
+    for event in events.p0().read() {
+        match event {
+            RakNetEvent::IncomingBatch(entity, bytes) => {
+                if let Ok((_, mut link, _)) = links.get_mut(*entity) {
+                    if link.replay().is_some() {
+                        replays.push((*entity, bytes.clone()));
+                        continue;
+                    }
+
+                    if let Some(limit) = &limit {
+                        if !link.is_upstream()
+                            && !link.allow(bytes.len(), limit, config.clock.now())
+                        {
+                            if limit.disconnect && limited.insert(*entity) {
+                                requests.push(RakNetEvent::DisconnectRequest(
+                                    *entity,
+                                    DisconnectReason::Custom("Rate limit exceeded".to_string()),
+                                ));
+                            }
+                            continue;
+                        }
+                    }
+
+                    let batch = match interceptors.is_empty() {
+                        true => Some(bytes.clone()),
+                        false => {
+                            let player = match link.is_upstream() {
+                                true => link.peer(),
+                                false => *entity,
+                            };
+
+                            interceptors.intercept(player, link.is_upstream(), bytes)
+                        }
+                    };
+
+                    if let Some(batch) = batch {
+                        link.record(&batch);
+                        batches.push((link.peer(), batch));
+                    }
+                }
+            }
+            RakNetEvent::Disconnect(entity, reason) => {
+                if let Ok((_, link, _)) = links.get(*entity) {
+                    if link.is_upstream() {
+                        backends.release(link.backend());
+
+                        if let (Some(policy), DisconnectReason::ClosedByPeer) = (&policy, reason) {
+                            let player = link.peer();
+                            let backend = link.backend().to_string();
+
+                            if policy.on_close {
+                                if let Ok((_, downstream, _)) = links.get(player) {
+                                    if downstream.can_transfer() {
+                                        transfers.send(ProxyEvent::UpstreamFailed(
+                                            player,
+                                            backend.clone(),
+                                        ));
+                                        outgoing.extend(hold_downstream(
+                                            player,
+                                            downstream,
+                                            &backend,
+                                            policy,
+                                            &config,
+                                            &mut commands,
+                                        ));
+                                        continue;
+                                    }
+                                }
+                            }
+                        }
+                    }
+
+                    requests.push(RakNetEvent::DisconnectRequest(link.peer(), reason.clone()));
+                }
+            }
+            RakNetEvent::Timeout(entity) => {
+                let Ok((_, link, state)) = links.get(*entity) else {
+                    continue;
+                };
+
+                if !link.is_upstream() || *state != ConnectionState::Connected {
+                    continue;
+                }
+
+                let player = link.peer();
+                let backend = link.backend().to_string();
+
+                backends.mark_unhealthy(&backend, config.backend_cooldown, config.clock.now());
+                transfers.send(ProxyEvent::UpstreamFailed(player, backend.clone()));
+
+                let transferable = links
+                    .get(player)
+                    .map_or(false, |(_, link, _)| link.can_transfer());
+
+                if let (Some(policy), true) = (&policy, transferable) {
+                    backends.release(&backend);
+                    commands.entity(*entity).remove::<ProxyLink>().disconnect();
+
+                    if let Ok((_, downstream, _)) = links.get(player) {
+                        outgoing.extend(hold_downstream(
+                            player,
+                            downstream,
+                            &backend,
+                            policy,
+                            &config,
+                            &mut commands,
+                        ));
+                    }
+                    continue;
+                }
+
+                match backends.fallback(&backend) {
+                    Some(fallback) if transferable => {
+                        transfers.send(ProxyEvent::FallbackAttempt(player, fallback.to_string()));
+                        transfers.send(ProxyEvent::Transfer(player, fallback.to_string()));
+                    }
+                    _ => requests.push(RakNetEvent::DisconnectRequest(
+                        *entity,
+                        DisconnectReason::Custom("Backend timed out".to_string()),
+                    )),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for (peer, batch) in batches {
+        if let Ok((_, mut link, _)) = links.get_mut(peer) {
+            link.queue(batch);
+        }
+    }
+
+    for (entity, batch) in replays {
+        let Ok((_, mut link, _)) = links.get_mut(entity) else {
+            continue;
+        };
+
+        let peer = link.peer();
+        let Some(replay) = link.replay() else {
+            continue;
+        };
+
+        match replay.handle(&batch, &config) {
+            Ok(outcome) => {
+                if let Some(batch) = outcome.upstream {
+                    outgoing.push(RakNetEvent::OutgoingBatch(entity, batch));
+                }
+
+                if let Some(batch) = outcome.downstream {
+                    outgoing.push(RakNetEvent::OutgoingBatch(peer, batch));
+                }
+
+                if outcome.done {
+                    link.finish_replay();
+                    transfers.send(ProxyEvent::Transferred(peer, link.backend().to_string()));
+                }
+            }
+            Err(e) => {
+                debug!("[Proxy Error]: {}", e.to_string());
+                requests.push(RakNetEvent::DisconnectRequest(
+                    peer,
+                    DisconnectReason::Custom("Transfer failed".to_string()),
+                ));
+            }
+        }
+    }
+
+    for (entity, mut link, state) in links.iter_mut() {
+        if *state != ConnectionState::Connected {
+            continue;
+        }
+
+        match link.replay() {
+            Some(replay) => {
+                if let Some(batch) = replay.start() {
+                    outgoing.push(RakNetEvent::OutgoingBatch(entity, batch));
+                }
+            }
+            None => {
+                for batch in link.drain() {
+                    outgoing.push(RakNetEvent::OutgoingBatch(entity, batch));
+                }
+            }
+        }
+    }
+
+    let mut ev = events.p1();
+    ev.send_batch(outgoing);
+    ev.send_batch(requests);
+}
+
+/// Holds the downstream client of the proxy whose backend went away until it is reconnected to it. Returns the batch
+/// that notifies the client if the ReconnectPolicy asks for it and its compression algorithm is known.
+fn hold_downstream(
+    player: Entity,
+    link: &ProxyLink,
+    backend: &str,
+    policy: &ReconnectPolicy,
+    config: &RakNetConfig,
+    commands: &mut Commands,
+) -> Option<RakNetEvent> {
+    commands
+        .entity(player)
+        .insert(Reconnecting::new(backend, policy, config.clock.now()));
+
+    let ReconnectBehavior::Notify(packet) = &policy.behavior else {
+        return None;
+    };
+
+    let mut batch = Vec::new();
+    write_sub_packet(&mut batch, packet);
+
+    match compress_batch(
+        &batch,
+        link.algorithm()?,
+        config.compression_level,
+        link.compression_header(),
+    ) {
+        Ok(batch) => Some(RakNetEvent::OutgoingBatch(player, batch)),
+        Err(e) => {
+            debug!("[Proxy Error]: {}", e.to_string());
+            None
+        }
+    }
+}
+
+/// This system is responsible for retrying the upstream connections of the downstream clients held by the proxy on the
+/// backoff of the ReconnectPolicy. The clients that run out of attempts are moved to the fallback backend if there is
+/// one and disconnected otherwise.
+pub fn proxy_reconnect(
+    mut query: Query<(Entity, &mut Reconnecting)>,
+    policy: Res<ReconnectPolicy>,
+    mut backends: ResMut<Backends>,
+    mut transfers: EventWriter<ProxyEvent>,
+    mut commands: Commands,
+    config: Res<RakNetConfig>,
+) {
+    for (entity, mut reconnecting) in query.iter_mut() {
+        if !reconnecting.is_due(config.clock.now()) {
+            continue;
+        }
+
+        let backend = reconnecting.backend().to_string();
+
+        if reconnecting.attempts() >= policy.max_attempts {
+            commands.entity(entity).remove::<Reconnecting>();
+
+            match backends.fallback(&backend).map(str::to_string) {
+                Some(fallback) => {
+                    transfers.send(ProxyEvent::FallbackAttempt(entity, fallback.clone()));
+                    backends.claim(&fallback);
+                    commands.entity(entity).transfer_upstream(&fallback);
+                }
+                None => {
+                    commands
+                        .entity(entity)
+                        .disconnect_with(DisconnectReason::Custom(
+                            "Backend unavailable".to_string(),
+                        ));
+                }
+            }
+            continue;
+        }
+
+        reconnecting.schedule(&policy, config.clock.now());
+        transfers.send(ProxyEvent::ReconnectAttempt(
+            entity,
+            backend.clone(),
+            reconnecting.attempts(),
+        ));
+        backends.claim(&backend);
+        commands.entity(entity).reconnect_upstream(&backend);
+    }
+}
+
+/// This system is responsible for moving the downstream clients of the proxy to the backends of the Transfer events.
+/// The current upstream connection is detached from the client and disconnected before the new one is connected.
+pub fn proxy_transfer(
+    mut transfers: EventReader<ProxyEvent>,
+    links: Query<&ProxyLink>,
+    mut backends: ResMut<Backends>,
+    mut commands: Commands,
+) {
+    for event in transfers.read() {
+        let ProxyEvent::Transfer(entity, backend) = event else {
+            continue;
+        };
+
+        let Ok(link) = links.get(*entity) else {
+            continue;
+        };
+
+        if !link.can_transfer() {
+            debug!("[Proxy Error]: The client has not been logged in to be transferred");
+            continue;
+        }
+
+        if let Ok(upstream) = links.get(link.peer()) {
+            backends.release(upstream.backend());
+        }
+
+        commands
+            .entity(link.peer())
+            .remove::<ProxyLink>()
+            .disconnect();
+
+        backends.claim(backend);
+        commands.entity(*entity).transfer_upstream(backend);
+    }
+}
+
+/// This system is responsible for pinging the backends of the proxy through the ServerListPinger and for mirroring
+/// their merged status into the components of the proxy server whenever the status of one of them changes.
+pub fn proxy_mirror_status(
+    mut events: EventReader<RakNetEvent>,
+    mut passthrough: ResMut<StatusPassthrough>,
+    mut pinger: ResMut<ServerListPinger>,
+    backends: Res<Backends>,
+    mut server: Query<(
+        &mut PrimaryMotd,
+        &mut LevelName,
+        &mut OnlinePlayers,
+        &mut MaxPlayers,
+        &mut MinecraftProtocol,
+        &mut MinecraftVersion,
+        &mut BroadcastGamemode,
+    )>,
+    config: Res<RakNetConfig>,
+) {
+    if backends.is_changed() {
+        let passthrough = passthrough.as_mut();
+
+        for backend in backends.iter() {
+            if passthrough.addrs.contains_key(backend.addr()) {
+                continue;
+            }
+
+            match pinger.add(backend.addr()) {
+                Ok(addr) => {
+                    passthrough.addrs.insert(backend.addr().to_string(), addr);
+                }
+                Err(e) => debug!("[Proxy Error]: {}", e.to_string()),
+            }
+        }
+
+        passthrough.addrs.retain(|backend, addr| {
+            let exists = backends.get(backend).is_some();
+            if !exists {
+                pinger.remove(*addr);
+            }
+
+            exists
+        });
+    }
+
+    let changed = events
+        .read()
+        .any(|event| matches!(event, RakNetEvent::ServerStatusChanged(_, _)));
+
+    if !changed {
+        return;
+    }
+
+    let statuses = backends
+        .iter()
+        .filter(|backend| backend.is_healthy(config.clock.now()))
+        .filter_map(|backend| passthrough.addrs.get(backend.addr()))
+        .filter_map(|addr| pinger.get(*addr))
+        .filter_map(|entry| entry.status.as_ref());
+
+    let Some(status) = passthrough.merge(statuses) else {
+        return;
+    };
+
+    let Ok((
+        mut motd,
+        mut level_name,
+        mut online,
+        mut max,
+        mut protocol,
+        mut version,
+        mut gamemode,
+    )) = server.get_mut(passthrough.server)
+    else {
+        return;
+    };
+
+    if motd.get() != status.motd {
+        motd.set(&status.motd);
+    }
+
+    if level_name.get() != status.level_name {
+        level_name.set(&status.level_name);
+    }
+
+    if online.get() != status.online {
+        online.set(status.online);
+    }
+
+    if max.get() != status.max {
+        max.set(status.max);
+    }
+
+    if protocol.get() != status.protocol {
+        protocol.set(status.protocol);
+    }
+
+    if version.get() != status.version {
+        version.set(&status.version);
+    }
+
+    if gamemode.get() != status.gamemode {
+        gamemode.set(&status.gamemode);
+    }
+}
+
+/// This system is responsible for checking the connection states, updating latencies, pings, etc.
+pub fn connection_tick(
+    mut events: ParamSet<(EventReader<RakNetEvent>, EventWriter<RakNetEvent>)>,
+    mut commands: Commands,
+    mut query: Query<(Entity, &mut NetworkStatus, &mut RakStream)>,
+    mut states: Query<&mut ConnectionState>,
+    config: Res<RakNetConfig>,
+) {
+    let mut requests = Vec::new();
+    let mut rejected = Vec::new();
+
+    for event in events.p0().read() {
+        match event {
+            RakNetEvent::Disconnect(entity, reason) => {
+                debug!(
+                    "[Network] Entity ID {:?} has been disconnected from the server: {:?}",
+                    entity.index(),
+                    reason,
+                );
+
+                commands.entity(*entity).despawn();
+            }
+            RakNetEvent::Latency(entity, latency) => {
+                if let Ok((_, mut status, _)) = query.get_mut(*entity) {
+                    status.latency = *latency;
+                }
+            }
+            RakNetEvent::Ping(entity, ping) => {
+                if let Ok((_, mut status, _)) = query.get_mut(*entity) {
+                    status.ping = *ping;
+                }
+            }
+            RakNetEvent::IncomingBatch(entity, _) => {
+                if let Ok((_, mut status, _)) = query.get_mut(*entity) {
+                    status.last_game_packet = config.clock.now();
+                }
+            }
+            RakNetEvent::LastActivity(entity, last_activity) => {
+                if let Ok((_, mut status, _)) = query.get_mut(*entity) {
+                    status.last_activity = *last_activity;
+                }
+            }
+            RakNetEvent::StateChanged(entity, state) => {
+                // The connections spawned during this tick don't have their components inserted yet, they are
+                // spawned with the state the event refers to anyway.
+                if let Ok(mut current) = states.get_mut(*entity) {
+                    *current = *state;
+                }
+
+                if let Ok((_, mut status, _)) = query.get_mut(*entity) {
+                    status.state_changed_at = config.clock.now();
+                }
+            }
+            RakNetEvent::OutgoingBatch(entity, bytes) => {
+                if bytes.len() > config.max_game_packet_size {
+                    debug!(
+                        "[Network] Dropped an outgoing batch of {} bytes for Entity ID {:?}",
+                        bytes.len(),
+                        entity.index(),
+                    );
+
+                    rejected.push(RakNetEvent::PacketTooLarge(*entity, bytes.len()));
+                    continue;
+                }
+
+                // The connection may have been despawned by a Disconnect within the same tick, the batch is dropped.
+                if let Ok((_, _, mut conn)) = query.get_mut(*entity) {
+                    let message = Message::GamePacket {
+                        data: UnsizedBytes::new(&bytes),
+                    };
+
+                    conn.encode(message, Reliability::ReliableOrdered);
+                }
+            }
+            RakNetEvent::Broadcast(bytes, reliability) => {
+                if bytes.len() > config.max_game_packet_size {
+                    debug!(
+                        "[Network] Dropped a broadcast game packet of {} bytes",
+                        bytes.len()
+                    );
+
+                    rejected.extend(
+                        query
+                            .iter()
+                            .filter(|(_, _, conn)| conn.state() == ConnectionState::Connected)
+                            .map(|(entity, _, _)| RakNetEvent::PacketTooLarge(entity, bytes.len())),
+                    );
+                    continue;
+                }
+
+                // The game packet is serialized and split once and the fragments are encoded on every established connection.
+                let mut buf = BytesMut::new();
+                let message = Message::GamePacket {
+                    data: UnsizedBytes::new(&bytes),
+                };
+                message.serialize(&mut buf);
+
+                let mut multicast = Multicast::new(&buf, reliability.clone());
+
+                for (_, _, mut conn) in query.iter_mut() {
+                    if conn.state() == ConnectionState::Connected {
+                        multicast.encode(&mut conn);
+                    }
+                }
+            }
+            RakNetEvent::Multicast(entities, bytes, reliability) => {
+                if bytes.len() > config.max_game_packet_size {
+                    debug!(
+                        "[Network] Dropped a multicast game packet of {} bytes for {} entities",
+                        bytes.len(),
+                        entities.len(),
+                    );
+
+                    rejected.extend(
+                        entities
+                            .iter()
+                            .map(|entity| RakNetEvent::PacketTooLarge(*entity, bytes.len())),
+                    );
+                    continue;
+                }
+
+                // The game packet is serialized and split once and the fragments are encoded on the provided
+                // connections that have been established.
+                let mut buf = BytesMut::new();
+                let message = Message::GamePacket {
+                    data: UnsizedBytes::new(&bytes),
+                };
+                message.serialize(&mut buf);
+
+                let mut multicast = Multicast::new(&buf, reliability.clone());
+
+                for entity in entities.iter() {
+                    if let Ok((_, _, mut conn)) = query.get_mut(*entity) {
+                        if conn.state() == ConnectionState::Connected {
+                            multicast.encode(&mut conn);
+                        }
+                    }
+                }
+            }
+            RakNetEvent::DisconnectRequest(entity, reason) => {
+                requests.push((*entity, reason.clone()));
+            }
+            _ => {}
+        }
+    }
+
+    events.p1().send_batch(rejected);
+
+    // The disconnect notifications are queued after all the events have been read, the resulting Disconnect events
+    // despawn the entities in the next tick once the notifications have been flushed.
+    let mut ev = events.p1();
+
+    for (entity, reason) in requests {
+        if let Ok((_, _, mut stream)) = query.get_mut(entity) {
+            stream.disconnect(&mut ev, entity);
+            ev.send(RakNetEvent::Disconnect(entity, reason));
         }
     }
 }
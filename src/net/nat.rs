@@ -0,0 +1,209 @@
+use std::{
+    collections::HashMap,
+    io::{Cursor, Result},
+    net::SocketAddr,
+    sync::mpsc::Receiver,
+    time::{Duration, Instant},
+};
+
+use bevy::ecs::{component::Component, entity::Entity, event::EventWriter, system::Resource};
+use binary::{
+    datatypes::{Bool, I64},
+    Binary,
+};
+use bytes::BytesMut;
+use tracing::debug;
+
+use crate::{
+    generic::events::{NatFailure, RakNetEvent},
+    protocol::{
+        binary::Magic, message::Message, NAT_COORDINATOR_TIMEOUT, NAT_PUNCH_ATTEMPTS,
+        NAT_PUNCH_INTERVAL,
+    },
+};
+
+use super::{
+    socket::{poll_handshake, Handshake},
+    transport::Transport,
+};
+
+/// NatCoordinator is the resource of a server that introduces its connected clients to each other so that they can
+/// punch through their NATs. Every pair of peers is introduced at most once within NAT_COORDINATOR_TIMEOUT.
+#[derive(Resource, Default)]
+pub struct NatCoordinator {
+    in_progress: HashMap<(i64, i64), Instant>,
+}
+
+impl NatCoordinator {
+    /// Marks a punchthrough between the peers with the provided GUIDs as in progress. Returns false if one between
+    /// them is already in progress.
+    pub fn begin(&mut self, guid: i64, other: i64, now: Instant) -> bool {
+        self.in_progress
+            .retain(|_, started| now.saturating_duration_since(*started) < NAT_COORDINATOR_TIMEOUT);
+
+        let key = (guid.min(other), guid.max(other));
+        if self.in_progress.contains_key(&key) {
+            return false;
+        }
+
+        self.in_progress.insert(key, now);
+        true
+    }
+}
+
+/// PunchSession is a punchthrough to a single peer that has not got through yet.
+struct PunchSession {
+    peer: SocketAddr,
+    guid: i64,
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+/// NatPunchthrough is the component of a client connection that punches through the NAT to the peers introduced by
+/// its coordinator. The punches are sent from the transport of the connection, so that the address the coordinator
+/// has seen for it is the one that the peers can reach once the punchthrough succeeds.
+#[derive(Component, Default)]
+pub struct NatPunchthrough {
+    sessions: Vec<PunchSession>,
+    punched: HashMap<i64, SocketAddr>,
+    write_buf: BytesMut,
+}
+
+impl NatPunchthrough {
+    /// Starts punching to the peer with the provided address and GUID after the provided delay. A punchthrough to the
+    /// same peer that is still in progress is restarted.
+    pub fn start(&mut self, peer: SocketAddr, guid: i64, delay: Duration, now: Instant) {
+        self.sessions.retain(|session| session.guid != guid);
+        self.punched.remove(&guid);
+
+        self.sessions.push(PunchSession {
+            peer,
+            guid,
+            attempts: 0,
+            next_attempt: now + delay,
+        });
+    }
+
+    /// Returns the address of the peer with the provided GUID if the punchthrough to it has succeeded.
+    pub fn punched(&self, guid: i64) -> Option<SocketAddr> {
+        self.punched.get(&guid).copied()
+    }
+
+    /// Handles a datagram received on the transport of the connection from an address other than the coordinator.
+    /// The punch of a peer completes its punchthrough and is answered unless it is an answer itself, so that the peer
+    /// learns that its punches get through as well.
+    pub fn receive(
+        &mut self,
+        entity: Entity,
+        transport: &dyn Transport,
+        own_guid: i64,
+        addr: SocketAddr,
+        buf: &[u8],
+        ev: &mut EventWriter<RakNetEvent>,
+    ) {
+        let mut reader = Cursor::new(buf);
+        let Ok(Message::NatEstablish {
+            magic: _,
+            sender_guid,
+            reply,
+        }) = Message::deserialize(&mut reader)
+        else {
+            return;
+        };
+
+        let guid = sender_guid.0;
+
+        if let Some(index) = self.sessions.iter().position(|s| s.guid == guid) {
+            // The peer may be seen from another port than the one the coordinator introduced it with.
+            self.sessions.remove(index);
+            self.punched.insert(guid, addr);
+            ev.send(RakNetEvent::NatPunchthroughSucceeded(entity, addr, guid));
+        }
+
+        if reply.0 || self.punched.get(&guid) != Some(&addr) {
+            return;
+        }
+
+        if let Err(e) = self.send(transport, own_guid, addr, true) {
+            debug!("[NAT Error]: {}", e.to_string());
+        }
+    }
+
+    /// Sends the punches that are due to the peers and fails the punchthroughs that have run out of attempts.
+    pub fn punch(
+        &mut self,
+        entity: Entity,
+        transport: &dyn Transport,
+        own_guid: i64,
+        now: Instant,
+        ev: &mut EventWriter<RakNetEvent>,
+    ) {
+        let mut sessions = std::mem::take(&mut self.sessions);
+
+        sessions.retain_mut(|session| {
+            if now < session.next_attempt {
+                return true;
+            }
+
+            if session.attempts >= NAT_PUNCH_ATTEMPTS {
+                ev.send(RakNetEvent::NatPunchthroughFailed(
+                    entity,
+                    session.guid,
+                    NatFailure::TargetUnresponsive,
+                ));
+                return false;
+            }
+
+            if let Err(e) = self.send(transport, own_guid, session.peer, false) {
+                debug!("[NAT Error]: {}", e.to_string());
+            }
+
+            session.attempts += 1;
+            session.next_attempt = now + NAT_PUNCH_INTERVAL;
+            true
+        });
+
+        self.sessions = sessions;
+    }
+
+    /// Sends a punch to the provided address, the reply flag is set on the answers to the punches of the peers.
+    fn send(
+        &mut self,
+        transport: &dyn Transport,
+        own_guid: i64,
+        addr: SocketAddr,
+        reply: bool,
+    ) -> Result<()> {
+        let msg = Message::NatEstablish {
+            magic: Magic,
+            sender_guid: I64::new(own_guid),
+            reply: Bool::new(reply),
+        };
+
+        msg.serialize(&mut self.write_buf);
+        let result = transport.send_to(&self.write_buf, addr);
+        self.write_buf.clear();
+
+        result.map(|_| ())
+    }
+}
+
+/// PendingPunched is the component of a connection to a punched peer whose offline handshake runs on the
+/// HandshakePool, so that the App is not blocked meanwhile. The connection is inserted on the entity once it completes.
+#[derive(Component)]
+pub struct PendingPunched {
+    outcome: Option<Receiver<Result<Handshake>>>,
+}
+
+impl PendingPunched {
+    pub(crate) fn new(outcome: Receiver<Result<Handshake>>) -> Self {
+        Self {
+            outcome: Some(outcome),
+        }
+    }
+
+    /// Returns the outcome of the handshake the first time it is called once the handshake has completed.
+    pub(crate) fn poll(&mut self) -> Option<Result<Handshake>> {
+        poll_handshake(&mut self.outcome)
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::VecDeque;
+
+use bevy::{
+    app::{App, Plugin, Update},
+    ecs::{
+        entity::Entity,
+        event::EventReader,
+        schedule::IntoSystemConfigs,
+        system::{Query, Res, ResMut, Resource},
+    },
+};
+use bevy_egui::{egui, EguiContexts, EguiPlugin};
+
+use crate::generic::events::RakNetEvent;
+
+use super::stream::{NetworkInfo, NetworkStatus, RakStream};
+
+/// This is the number of the most recent RakNet events that are shown by the overlay.
+const RECENT_EVENTS: usize = 32;
+
+/// This is the maximum number of characters of a RakNet event that are shown by the overlay.
+const EVENT_WIDTH: usize = 96;
+
+/// NetworkDebugOverlay is the plugin that renders a live table of all the RakNet connections along with the most recent
+/// RakNet events in an egui window. It is only available with the egui feature.
+pub struct NetworkDebugOverlay;
+
+impl Plugin for NetworkDebugOverlay {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<EguiPlugin>() {
+            app.add_plugins(EguiPlugin);
+        }
+
+        app.init_resource::<RecentEvents>();
+        app.add_systems(Update, (record_events, render_overlay).chain());
+    }
+}
+
+/// RecentEvents contains the textual representation of the most recent RakNet events shown by the overlay.
+#[derive(Resource, Default)]
+pub struct RecentEvents(VecDeque<String>);
+
+/// This system is responsible for recording the RakNet events that are shown by the overlay.
+fn record_events(mut ev: EventReader<RakNetEvent>, mut recent: ResMut<RecentEvents>) {
+    for event in ev.read() {
+        if recent.0.len() == RECENT_EVENTS {
+            recent.0.pop_front();
+        }
+
+        let line = format!("{:?}", event).chars().take(EVENT_WIDTH).collect();
+        recent.0.push_back(line);
+    }
+}
+
+/// This system is responsible for rendering the connections table and the recent RakNet events.
+fn render_overlay(
+    mut contexts: EguiContexts,
+    query: Query<(Entity, &NetworkInfo, &NetworkStatus, &RakStream)>,
+    recent: Res<RecentEvents>,
+) {
+    egui::Window::new("RakNet").show(contexts.ctx_mut(), |ui| {
+        egui::Grid::new("raknet_connections")
+            .striped(true)
+            .show(ui, |ui| {
+                ui.label("Entity");
+                ui.label("Address");
+                ui.label("Ping");
+                ui.label("Latency");
+                ui.label("Packet Loss");
+                ui.label("In-Flight");
+                ui.end_row();
+
+                for (entity, info, status, stream) in query.iter() {
+                    ui.label(format!("{:?}", entity));
+                    ui.label(info.remote_addr.to_string());
+                    ui.label(format!("{} ms", status.ping));
+                    ui.label(format!("{:?}", status.latency));
+                    ui.label(format!("{:.1}%", stream.packet_loss() * 100.0));
+                    ui.label(format!("{} B", stream.in_flight_bytes()));
+                    ui.end_row();
+                }
+            });
+
+        ui.separator();
+        ui.heading("Recent Events");
+
+        for line in recent.0.iter().rev() {
+            ui.label(line);
+        }
+    });
+}
@@ -0,0 +1,203 @@
+use std::{
+    fs::File,
+    io::{Result, Write},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use byteorder::{WriteBytesExt, BE, LE};
+use tracing::debug;
+
+use crate::protocol::{PCAP_LINKTYPE_RAW, PCAP_MAGIC, PCAP_SNAPSHOT_LENGTH};
+
+use super::transport::Transport;
+
+/// UDP_PROTOCOL is the number of the UDP protocol in the IP headers.
+const UDP_PROTOCOL: u8 = 17;
+
+/// PcapWriter writes the datagrams of the transports it is attached to into a pcap file as raw IP packets with their
+/// UDP headers, so that a session can be opened in Wireshark and dissected as RakNet. Every packet is written as soon
+/// as it is captured. Clones write to the same file.
+#[derive(Clone)]
+pub struct PcapWriter {
+    file: Arc<Mutex<File>>,
+}
+
+impl PcapWriter {
+    /// Creates the pcap file at the provided path, truncating it if it already exists.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let mut file = File::create(path)?;
+
+        let mut header = Vec::with_capacity(24);
+        header.write_u32::<LE>(PCAP_MAGIC)?;
+        header.write_u16::<LE>(2)?;
+        header.write_u16::<LE>(4)?;
+        header.write_i32::<LE>(0)?;
+        header.write_u32::<LE>(0)?;
+        header.write_u32::<LE>(PCAP_SNAPSHOT_LENGTH)?;
+        header.write_u32::<LE>(PCAP_LINKTYPE_RAW)?;
+        file.write_all(&header)?;
+
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    /// Wraps the provided transport so that all the datagrams sent and received through it are captured.
+    pub fn attach(&self, inner: Arc<dyn Transport>) -> Result<PcapTransport> {
+        Ok(PcapTransport {
+            local_addr: inner.local_addr()?,
+            inner,
+            writer: self.clone(),
+        })
+    }
+
+    /// Writes the provided datagram as a UDP packet from the source address to the destination address.
+    fn capture(&self, src: SocketAddr, dst: SocketAddr, datagram: &[u8]) {
+        let packet = udp_packet(src, dst, datagram);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let mut buf = Vec::with_capacity(packet.len() + 16);
+        buf.write_u32::<LE>(timestamp.as_secs() as u32).unwrap();
+        buf.write_u32::<LE>(timestamp.subsec_micros()).unwrap();
+        buf.write_u32::<LE>(packet.len() as u32).unwrap();
+        buf.write_u32::<LE>(packet.len() as u32).unwrap();
+        buf.extend_from_slice(&packet);
+
+        if let Err(e) = self.file.lock().unwrap().write_all(&buf) {
+            debug!("[Pcap Error]: {}", e.to_string());
+        }
+    }
+}
+
+/// Builds the IP packet carrying the provided datagram in a UDP packet. The packet is IPv6 if either of the addresses
+/// is, in which case an IPv4 address is mapped to IPv6.
+fn udp_packet(src: SocketAddr, dst: SocketAddr, datagram: &[u8]) -> Vec<u8> {
+    let udp_len = (8 + datagram.len()) as u16;
+    let mut packet = Vec::with_capacity(48 + datagram.len());
+
+    let (src_ip, dst_ip) = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            packet.write_u8(0x45).unwrap();
+            packet.write_u8(0).unwrap();
+            packet.write_u16::<BE>(20 + udp_len).unwrap();
+            packet.write_u16::<BE>(0).unwrap();
+            packet.write_u16::<BE>(0x4000).unwrap();
+            packet.write_u8(64).unwrap();
+            packet.write_u8(UDP_PROTOCOL).unwrap();
+            packet.write_u16::<BE>(0).unwrap();
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+
+            let checksum = checksum(&[&packet]);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+            (src.octets().to_vec(), dst.octets().to_vec())
+        }
+        (src, dst) => {
+            let src = match src {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            let dst = match dst {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+
+            packet.write_u32::<BE>(0x60000000).unwrap();
+            packet.write_u16::<BE>(udp_len).unwrap();
+            packet.write_u8(UDP_PROTOCOL).unwrap();
+            packet.write_u8(64).unwrap();
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+
+            (src.octets().to_vec(), dst.octets().to_vec())
+        }
+    };
+
+    let mut header = Vec::with_capacity(8);
+    header.write_u16::<BE>(src.port()).unwrap();
+    header.write_u16::<BE>(dst.port()).unwrap();
+    header.write_u16::<BE>(udp_len).unwrap();
+    header.write_u16::<BE>(0).unwrap();
+
+    // The checksum of UDP covers a pseudo-header made of the addresses, the protocol and the length of the packet.
+    let mut pseudo = Vec::with_capacity(40);
+    pseudo.extend_from_slice(&src_ip);
+    pseudo.extend_from_slice(&dst_ip);
+    match src_ip.len() {
+        4 => {
+            pseudo.write_u8(0).unwrap();
+            pseudo.write_u8(UDP_PROTOCOL).unwrap();
+            pseudo.write_u16::<BE>(udp_len).unwrap();
+        }
+        _ => {
+            pseudo.write_u32::<BE>(udp_len as u32).unwrap();
+            pseudo.extend_from_slice(&[0, 0, 0, UDP_PROTOCOL]);
+        }
+    }
+
+    let checksum = match checksum(&[&pseudo, &header, datagram]) {
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    header[6..8].copy_from_slice(&checksum.to_be_bytes());
+
+    packet.extend_from_slice(&header);
+    packet.extend_from_slice(datagram);
+    packet
+}
+
+/// Returns the internet checksum of the provided parts as if they were a single buffer.
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    let mut odd = None;
+
+    for byte in parts.iter().flat_map(|part| part.iter()) {
+        match odd.take() {
+            Some(high) => sum += u16::from_be_bytes([high, *byte]) as u32,
+            None => odd = Some(*byte),
+        }
+    }
+
+    if let Some(high) = odd {
+        sum += u16::from_be_bytes([high, 0]) as u32;
+    }
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+/// PcapTransport is a Transport that captures all the datagrams going through the transport it wraps.
+pub struct PcapTransport {
+    inner: Arc<dyn Transport>,
+    local_addr: SocketAddr,
+    writer: PcapWriter,
+}
+
+impl Transport for PcapTransport {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize> {
+        let len = self.inner.send_to(buf, addr)?;
+        self.writer.capture(self.local_addr, addr, buf);
+
+        Ok(len)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let (len, addr) = self.inner.recv_from(buf)?;
+        self.writer.capture(addr, self.local_addr, &buf[..len]);
+
+        Ok((len, addr))
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
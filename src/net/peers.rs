@@ -0,0 +1,80 @@
+use std::net::SocketAddr;
+
+use bevy::ecs::{
+    entity::Entity,
+    system::{Query, SystemParam},
+};
+
+use super::stream::{ConnectionState, NetworkInfo, NetworkStatus};
+
+/// Peer is a read-only view over the network components of a single RakNet connection.
+pub struct Peer<'a> {
+    pub entity: Entity,
+    pub info: &'a NetworkInfo,
+    pub status: &'a NetworkStatus,
+    pub state: ConnectionState,
+}
+
+/// NetworkPeers is a SystemParam wrapping the queries over the RakNet connections so that user systems can look up the
+/// connections without declaring the network components themselves.
+#[derive(SystemParam)]
+pub struct NetworkPeers<'w, 's> {
+    query: Query<
+        'w,
+        's,
+        (
+            Entity,
+            &'static NetworkInfo,
+            &'static NetworkStatus,
+            &'static ConnectionState,
+        ),
+    >,
+}
+
+impl<'w, 's> NetworkPeers<'w, 's> {
+    /// Returns the connection of the provided entity if the entity is a RakNet connection.
+    pub fn get(&self, entity: Entity) -> Option<Peer<'_>> {
+        self.query
+            .get(entity)
+            .ok()
+            .map(|(entity, info, status, state)| Peer {
+                entity,
+                info,
+                status,
+                state: *state,
+            })
+    }
+
+    /// Returns an iterator over all the RakNet connections regardless of their state.
+    pub fn iter(&self) -> impl Iterator<Item = Peer<'_>> {
+        self.query.iter().map(|(entity, info, status, state)| Peer {
+            entity,
+            info,
+            status,
+            state: *state,
+        })
+    }
+
+    /// Returns an iterator over the RakNet connections that have been established.
+    pub fn iter_connected(&self) -> impl Iterator<Item = Peer<'_>> {
+        self.iter()
+            .filter(|peer| peer.state == ConnectionState::Connected)
+    }
+
+    /// Returns the remote address of the connection of the provided entity.
+    pub fn addr_of(&self, entity: Entity) -> Option<SocketAddr> {
+        self.get(entity).map(|peer| peer.info.remote_addr)
+    }
+
+    /// Returns the entity of the connection with the provided remote address.
+    pub fn entity_of(&self, addr: SocketAddr) -> Option<Entity> {
+        self.iter()
+            .find(|peer| peer.info.remote_addr == addr)
+            .map(|peer| peer.entity)
+    }
+
+    /// Returns the number of the RakNet connections that have been established.
+    pub fn connected_count(&self) -> usize {
+        self.iter_connected().count()
+    }
+}
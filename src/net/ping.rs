@@ -0,0 +1,247 @@
+use std::{
+    collections::HashMap,
+    io::{Cursor, Error, ErrorKind, Result},
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use bevy::{
+    ecs::{event::EventWriter, system::Resource},
+    time::{Timer, TimerMode},
+};
+use binary::{datatypes::I64, Binary};
+use bytes::BytesMut;
+use tracing::debug;
+
+use crate::{
+    generic::events::RakNetEvent,
+    protocol::{binary::Magic, mcpe::ServerStatus, message::Message, MAX_MTU_SIZE},
+};
+
+/// Pong is the response of a server to an Unconnected Ping along with the round trip time it took to receive it.
+#[derive(Debug, Clone)]
+pub struct Pong {
+    pub addr: SocketAddr,
+    pub server_guid: i64,
+    pub status: ServerStatus,
+    pub rtt: Duration,
+}
+
+/// Sends an Unconnected Ping to the provided address and waits up to the provided timeout for the Unconnected Pong
+/// without establishing a connection. It blocks the calling thread, so systems should run it on a task pool.
+pub fn ping(addr: &str, timeout: Duration) -> Result<Pong> {
+    let addr = resolve(addr)?;
+    let udp = UdpSocket::bind(unspecified(addr))?;
+    let mut buf = BytesMut::zeroed(MAX_MTU_SIZE);
+
+    let started = Instant::now();
+    send_ping(&udp, addr, 0, rand::random())?;
+
+    loop {
+        let remaining = timeout.saturating_sub(started.elapsed());
+        if remaining.is_zero() {
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                "The server did not respond to the Unconnected Ping",
+            ));
+        }
+
+        udp.set_read_timeout(Some(remaining))?;
+
+        let (len, from) = match udp.recv_from(&mut buf) {
+            Ok(recv) => recv,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(e) => return Err(e),
+        };
+
+        if from != addr {
+            continue;
+        }
+
+        if let Ok((_, server_guid, status)) = read_pong(&buf[..len]) {
+            return Ok(Pong {
+                addr,
+                server_guid,
+                status,
+                rtt: started.elapsed(),
+            });
+        }
+    }
+}
+
+/// Resolves the provided address to the first socket address it refers to.
+pub(crate) fn resolve(addr: &str) -> Result<SocketAddr> {
+    addr.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::Other, "The address did not resolve"))
+}
+
+/// Returns the unspecified address of the same family as the provided address to bind a socket on any port.
+pub(crate) fn unspecified(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+    }
+}
+
+/// Sends an Unconnected Ping with the provided timestamp, which is echoed back by the server in its pong.
+pub(crate) fn send_ping(
+    udp: &UdpSocket,
+    addr: SocketAddr,
+    timestamp: i64,
+    guid: i64,
+) -> Result<()> {
+    let msg = Message::UnconnectedPing {
+        send_timestamp: I64::new(timestamp),
+        magic: Magic,
+        client_guid: I64::new(guid),
+    };
+
+    let mut buf = BytesMut::with_capacity(64);
+    msg.serialize(&mut buf);
+    udp.send_to(&buf, addr)?;
+
+    Ok(())
+}
+
+/// Reads an Unconnected Pong from the provided buffer and returns the echoed timestamp, the GUID of the server and
+/// its parsed status.
+pub(crate) fn read_pong(buf: &[u8]) -> Result<(i64, i64, ServerStatus)> {
+    match Message::deserialize(&mut Cursor::new(buf))? {
+        Message::UnconnectedPong {
+            send_timestamp,
+            server_guid,
+            magic: _,
+            data,
+        } => Ok((
+            send_timestamp.0,
+            server_guid.0,
+            ServerStatus::parse(data.0)?,
+        )),
+        _ => Err(Error::new(
+            ErrorKind::Other,
+            "Expected UnconnectedPong message from the server",
+        )),
+    }
+}
+
+/// ServerEntry is the last known status and latency of a server tracked by the ServerListPinger, both of which are
+/// None until the server responds for the first time.
+#[derive(Debug, Clone, Default)]
+pub struct ServerEntry {
+    pub status: Option<ServerStatus>,
+    pub latency: Option<Duration>,
+}
+
+/// ServerListPinger is the resource that pings a list of servers from a single socket on the provided interval. It is
+/// the backend of a server list, the RakNetEvent::ServerStatusChanged and ServerLatencyChanged events are written
+/// whenever a pong differs from the last one received from the same server.
+#[derive(Resource)]
+pub struct ServerListPinger {
+    udp: UdpSocket,
+    guid: i64,
+    started: Instant,
+    timer: Timer,
+    servers: HashMap<SocketAddr, ServerEntry>,
+    buf: BytesMut,
+}
+
+impl ServerListPinger {
+    pub fn new(interval: Duration) -> Result<Self> {
+        let udp = UdpSocket::bind("0.0.0.0:0")?;
+        udp.set_nonblocking(true)?;
+
+        let mut timer = Timer::new(interval, TimerMode::Repeating);
+        timer.set_elapsed(interval);
+
+        Ok(Self {
+            udp,
+            guid: rand::random(),
+            started: Instant::now(),
+            timer,
+            servers: HashMap::new(),
+            buf: BytesMut::zeroed(MAX_MTU_SIZE),
+        })
+    }
+
+    /// Starts tracking the provided address and returns the socket address it resolved to.
+    pub fn add(&mut self, addr: &str) -> Result<SocketAddr> {
+        let addr = resolve(addr)?;
+
+        if !addr.is_ipv4() {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "Only IPv4 servers can be tracked by the ServerListPinger",
+            ));
+        }
+
+        self.servers.entry(addr).or_default();
+        Ok(addr)
+    }
+
+    /// Stops tracking the provided address.
+    pub fn remove(&mut self, addr: SocketAddr) {
+        self.servers.remove(&addr);
+    }
+
+    /// Returns the last known status and latency of the provided address.
+    pub fn get(&self, addr: SocketAddr) -> Option<&ServerEntry> {
+        self.servers.get(&addr)
+    }
+
+    /// Returns all the tracked servers.
+    pub fn iter(&self) -> impl Iterator<Item = (&SocketAddr, &ServerEntry)> {
+        self.servers.iter()
+    }
+
+    /// Pings all the tracked servers if the interval has elapsed. The time since the pinger was created is sent as the
+    /// timestamp so that the latency can be measured from the echoed value.
+    pub(crate) fn refresh(&mut self, delta: Duration) {
+        if !self.timer.tick(delta).just_finished() {
+            return;
+        }
+
+        let timestamp = self.started.elapsed().as_millis() as i64;
+
+        for addr in self.servers.keys() {
+            if let Err(e) = send_ping(&self.udp, *addr, timestamp, self.guid) {
+                debug!("[Ping Error]: {}", e.to_string());
+            }
+        }
+    }
+
+    /// Reads all the pongs received since the last call and writes an event for every status or latency that changed.
+    pub(crate) fn receive(&mut self, ev: &mut EventWriter<RakNetEvent>) {
+        loop {
+            let (len, addr) = match self.udp.recv_from(&mut self.buf) {
+                Ok(recv) => recv,
+                Err(_) => break,
+            };
+
+            let Some(entry) = self.servers.get_mut(&addr) else {
+                continue;
+            };
+
+            let (timestamp, _, status) = match read_pong(&self.buf[..len]) {
+                Ok(pong) => pong,
+                Err(e) => {
+                    debug!("[Ping Error]: {}", e.to_string());
+                    continue;
+                }
+            };
+
+            let now = self.started.elapsed().as_millis() as i64;
+            let latency = Duration::from_millis(now.saturating_sub(timestamp).max(0) as u64);
+
+            if entry.status.as_ref() != Some(&status) {
+                entry.status = Some(status.clone());
+                ev.send(RakNetEvent::ServerStatusChanged(addr, status));
+            }
+
+            if entry.latency != Some(latency) {
+                entry.latency = Some(latency);
+                ev.send(RakNetEvent::ServerLatencyChanged(addr, latency));
+            }
+        }
+    }
+}
@@ -0,0 +1,827 @@
+use std::{
+    collections::HashMap,
+    io::{Cursor, Error, ErrorKind, Result},
+    net::SocketAddr,
+    str::FromStr,
+    sync::mpsc::Receiver,
+    time::{Duration, Instant},
+};
+
+use bevy::ecs::{component::Component, entity::Entity, system::Resource};
+use binary::Binary;
+use bytes::BytesMut;
+
+use super::socket::{poll_handshake, Handshake};
+
+use crate::protocol::{
+    compression::{compress_batch, decompress_batch, CompressionAlgorithm},
+    config::RakNetConfig,
+    mcpe::{
+        login::{
+            NetworkSettings, PlayStatus, RequestNetworkSettings, Status, NETWORK_SETTINGS,
+            PLAY_STATUS, REQUEST_NETWORK_SETTINGS, SERVER_TO_CLIENT_HANDSHAKE,
+        },
+        packet_id, read_sub_packets, write_sub_packet, ServerStatus,
+    },
+    COMPRESSION_HEADER_PROTOCOL,
+};
+
+/// The number of batches a client sends before it is logged in, which are the RequestNetworkSettings and the Login.
+const LOGIN_BATCHES: usize = 2;
+
+/// BalanceStrategy decides which of the healthy backends a new downstream client of the proxy is connected to.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BalanceStrategy {
+    /// The backends take turns in proportion to their weights.
+    #[default]
+    RoundRobin,
+    /// The backend with the fewest connections relative to its weight is picked.
+    LeastConnections,
+}
+
+/// Backend is a game server behind the proxy along with its weight and the number of clients connected to it.
+#[derive(Debug, Clone)]
+pub struct Backend {
+    addr: String,
+    weight: u32,
+    connections: usize,
+    current_weight: i64,
+    unhealthy_until: Option<Instant>,
+}
+
+impl Backend {
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    pub fn weight(&self) -> u32 {
+        self.weight
+    }
+
+    /// Returns the number of downstream clients that are connected to the backend.
+    pub fn connections(&self) -> usize {
+        self.connections
+    }
+
+    /// Returns whether the backend can be selected at the provided time, unhealthy backends are skipped until their
+    /// cooldown elapses.
+    pub fn is_healthy(&self, now: Instant) -> bool {
+        self.unhealthy_until.map_or(true, |until| now >= until)
+    }
+}
+
+/// Backends is the registry of the game servers behind the proxy. Every new downstream client is connected to the
+/// backend picked by the BalanceStrategy, a backend that cannot be reached is marked unhealthy for a while.
+#[derive(Resource, Debug, Default)]
+pub struct Backends {
+    backends: Vec<Backend>,
+    strategy: BalanceStrategy,
+    fallback: Option<String>,
+}
+
+impl Backends {
+    pub fn new(strategy: BalanceStrategy) -> Self {
+        Self {
+            backends: Vec::new(),
+            strategy,
+            fallback: None,
+        }
+    }
+
+    /// Adds a backend with the provided weight, a weight of zero is treated as one. Returns an error if the address is
+    /// not a valid socket address.
+    pub fn add(&mut self, addr: &str, weight: u32) -> Result<()> {
+        parse_backend(addr)?;
+
+        self.backends.push(Backend {
+            addr: addr.to_string(),
+            weight: weight.max(1),
+            connections: 0,
+            current_weight: 0,
+            unhealthy_until: None,
+        });
+        Ok(())
+    }
+
+    /// Removes the backend with the provided address.
+    pub fn remove(&mut self, addr: &str) {
+        self.backends.retain(|backend| backend.addr != addr);
+    }
+
+    /// Returns the backend with the provided address.
+    pub fn get(&self, addr: &str) -> Option<&Backend> {
+        self.backends.iter().find(|backend| backend.addr == addr)
+    }
+
+    /// Returns all the backends.
+    pub fn iter(&self) -> impl Iterator<Item = &Backend> {
+        self.backends.iter()
+    }
+
+    /// Sets the strategy used to pick the backends of the new connections.
+    pub fn set_strategy(&mut self, strategy: BalanceStrategy) {
+        self.strategy = strategy;
+    }
+
+    /// Sets the backend that the clients are moved to when their backend fails, such as a lobby. It does not have to be
+    /// one of the balanced backends. Returns an error if the address is not a valid socket address.
+    pub fn set_fallback(&mut self, addr: Option<&str>) -> Result<()> {
+        if let Some(addr) = addr {
+            parse_backend(addr)?;
+        }
+
+        self.fallback = addr.map(str::to_string);
+        Ok(())
+    }
+
+    /// Returns the fallback backend unless it is the provided failed backend itself.
+    pub fn fallback(&self, failed: &str) -> Option<&str> {
+        self.fallback
+            .as_deref()
+            .filter(|fallback| *fallback != failed)
+    }
+
+    /// Marks the backend with the provided address unhealthy for the provided duration from the provided time.
+    pub fn mark_unhealthy(&mut self, addr: &str, duration: Duration, now: Instant) {
+        if let Some(backend) = self
+            .backends
+            .iter_mut()
+            .find(|backend| backend.addr == addr)
+        {
+            backend.unhealthy_until = Some(now + duration);
+        }
+    }
+
+    /// Marks the backend with the provided address healthy again.
+    pub fn mark_healthy(&mut self, addr: &str) {
+        if let Some(backend) = self
+            .backends
+            .iter_mut()
+            .find(|backend| backend.addr == addr)
+        {
+            backend.unhealthy_until = None;
+        }
+    }
+
+    /// Picks a healthy backend for a new connection and counts the connection towards it. Returns None if there is no
+    /// healthy backend at the provided time.
+    pub fn select(&mut self, now: Instant) -> Option<String> {
+        let index = match self.strategy {
+            BalanceStrategy::RoundRobin => self.select_round_robin(now),
+            BalanceStrategy::LeastConnections => self.select_least_connections(now),
+        }?;
+
+        let backend = &mut self.backends[index];
+        backend.connections += 1;
+        Some(backend.addr.clone())
+    }
+
+    /// Counts a connection towards the backend with the provided address without picking it.
+    pub fn claim(&mut self, addr: &str) {
+        if let Some(backend) = self
+            .backends
+            .iter_mut()
+            .find(|backend| backend.addr == addr)
+        {
+            backend.connections += 1;
+        }
+    }
+
+    /// Releases a connection that was counted towards the backend with the provided address.
+    pub fn release(&mut self, addr: &str) {
+        if let Some(backend) = self
+            .backends
+            .iter_mut()
+            .find(|backend| backend.addr == addr)
+        {
+            backend.connections = backend.connections.saturating_sub(1);
+        }
+    }
+
+    /// Picks the backend with the smooth weighted round robin, which spreads the turns of heavier backends evenly.
+    fn select_round_robin(&mut self, now: Instant) -> Option<usize> {
+        let mut total = 0;
+        let mut selected: Option<(usize, i64)> = None;
+
+        for (index, backend) in self.backends.iter_mut().enumerate() {
+            if !backend.is_healthy(now) {
+                continue;
+            }
+
+            backend.current_weight += backend.weight as i64;
+            total += backend.weight as i64;
+
+            if selected.map_or(true, |(_, best)| backend.current_weight > best) {
+                selected = Some((index, backend.current_weight));
+            }
+        }
+
+        let (index, _) = selected?;
+        self.backends[index].current_weight -= total;
+        Some(index)
+    }
+
+    /// Picks the healthy backend with the fewest connections relative to its weight.
+    fn select_least_connections(&self, now: Instant) -> Option<usize> {
+        self.backends
+            .iter()
+            .enumerate()
+            .filter(|(_, backend)| backend.is_healthy(now))
+            .min_by(|(_, a), (_, b)| {
+                let left = a.connections as u64 * b.weight as u64;
+                let right = b.connections as u64 * a.weight as u64;
+                left.cmp(&right)
+            })
+            .map(|(index, _)| index)
+    }
+}
+
+/// Parses the provided address of a backend, which the upstream connections are made to.
+fn parse_backend(addr: &str) -> Result<SocketAddr> {
+    SocketAddr::from_str(addr).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("The backend {} is not a valid socket address", addr),
+        )
+    })
+}
+
+/// InterceptAction is what the proxy does with a batch once an interceptor has seen it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterceptAction {
+    /// The batch, including any changes made to it, is passed on.
+    Forward,
+    /// The batch is discarded and the remaining interceptors do not see it.
+    Drop,
+}
+
+/// ProxyInterceptor can inspect, rewrite or drop the game packet batches forwarded by the proxy in either direction.
+/// The batches are seen as they are on the wire, so they are compressed and cannot be read if the backend encrypts
+/// the connection.
+pub trait ProxyInterceptor: Send + Sync + 'static {
+    /// Called for every batch the downstream client of the entity sends towards its backend.
+    fn on_downstream_batch(&mut self, _entity: Entity, _batch: &mut BytesMut) -> InterceptAction {
+        InterceptAction::Forward
+    }
+
+    /// Called for every batch the backend sends towards the downstream client of the entity.
+    fn on_upstream_batch(&mut self, _entity: Entity, _batch: &mut BytesMut) -> InterceptAction {
+        InterceptAction::Forward
+    }
+}
+
+/// ProxyInterceptors is the chain of interceptors that the forwarded batches are passed through in the order the
+/// interceptors were added.
+#[derive(Resource, Default)]
+pub struct ProxyInterceptors {
+    interceptors: Vec<Box<dyn ProxyInterceptor>>,
+}
+
+impl ProxyInterceptors {
+    /// Appends the provided interceptor to the end of the chain.
+    pub fn add(&mut self, interceptor: impl ProxyInterceptor) {
+        self.interceptors.push(Box::new(interceptor));
+    }
+
+    /// Appends the provided boxed interceptor to the end of the chain.
+    pub fn add_boxed(&mut self, interceptor: Box<dyn ProxyInterceptor>) {
+        self.interceptors.push(interceptor);
+    }
+
+    /// Returns whether there are no interceptors in the chain.
+    pub fn is_empty(&self) -> bool {
+        self.interceptors.is_empty()
+    }
+
+    /// Passes the provided batch through the chain on behalf of the downstream client of the entity. Returns None if
+    /// one of the interceptors dropped it.
+    pub(crate) fn intercept(
+        &mut self,
+        entity: Entity,
+        upstream: bool,
+        batch: &[u8],
+    ) -> Option<Vec<u8>> {
+        let mut batch = BytesMut::from(batch);
+
+        for interceptor in self.interceptors.iter_mut() {
+            let action = match upstream {
+                true => interceptor.on_upstream_batch(entity, &mut batch),
+                false => interceptor.on_downstream_batch(entity, &mut batch),
+            };
+
+            if action == InterceptAction::Drop {
+                return None;
+            }
+        }
+
+        Some(batch.to_vec())
+    }
+}
+
+/// ProxyRateLimit bounds the traffic that a single downstream client can push through the proxy towards its backend
+/// within one second. The batches above either limit are dropped, the client is also disconnected if asked to.
+#[derive(Resource, Debug, Clone)]
+pub struct ProxyRateLimit {
+    pub max_batches_per_sec: u32,
+    pub max_bytes_per_sec: usize,
+    /// Whether the client is disconnected as soon as it exceeds either limit.
+    pub disconnect: bool,
+}
+
+impl Default for ProxyRateLimit {
+    fn default() -> Self {
+        Self {
+            max_batches_per_sec: 200,
+            max_bytes_per_sec: 1024 * 1024,
+            disconnect: false,
+        }
+    }
+}
+
+/// ReconnectBehavior is what the downstream clients experience while the proxy reconnects them to their backend.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ReconnectBehavior {
+    /// The clients are kept connected without being sent anything, the batches they send are dropped.
+    #[default]
+    Hold,
+    /// The clients are sent the provided game packet once their backend goes away, such as a Text packet asking them
+    /// to wait. It is compressed with the algorithm the client negotiated with the backend.
+    Notify(Vec<u8>),
+}
+
+/// ReconnectPolicy makes the proxy hold the downstream clients whose backend timed out while it retries connecting
+/// them to it with an exponential backoff, so that a brief restart of the backend does not disconnect them. The
+/// clients are moved to the fallback backend or disconnected once the attempts run out.
+#[derive(Resource, Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// The number of attempts after which the client is given up on.
+    pub max_attempts: u32,
+    /// The delay before the first attempt, which doubles after every attempt.
+    pub initial_backoff: Duration,
+    /// The upper bound of the delay between two attempts.
+    pub max_backoff: Duration,
+    /// Whether the clients are also held when their backend closes the connection, which holds the kicked clients too.
+    pub on_close: bool,
+    pub behavior: ReconnectBehavior,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+            on_close: false,
+            behavior: ReconnectBehavior::Hold,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Returns the delay before the provided attempt, starting from zero for the first one.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_backoff)
+    }
+}
+
+/// Reconnecting is the component of a downstream client that the proxy holds while it reconnects it to its backend.
+#[derive(Component, Debug)]
+pub struct Reconnecting {
+    backend: String,
+    attempts: u32,
+    next_attempt: Instant,
+    attempting: bool,
+}
+
+impl Reconnecting {
+    pub(crate) fn new(backend: &str, policy: &ReconnectPolicy, now: Instant) -> Self {
+        Self {
+            backend: backend.to_string(),
+            attempts: 0,
+            next_attempt: now + policy.backoff(0),
+            attempting: false,
+        }
+    }
+
+    /// Returns the backend the client is being reconnected to.
+    pub fn backend(&self) -> &str {
+        &self.backend
+    }
+
+    /// Returns the number of attempts made so far.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Returns whether the backoff before the next attempt has elapsed at the provided time and no attempt is still in
+    /// progress.
+    pub(crate) fn is_due(&self, now: Instant) -> bool {
+        !self.attempting && now >= self.next_attempt
+    }
+
+    /// Counts an attempt and schedules the next one.
+    pub(crate) fn schedule(&mut self, policy: &ReconnectPolicy, now: Instant) {
+        self.attempts += 1;
+        self.attempting = true;
+        self.next_attempt = now + policy.backoff(self.attempts);
+    }
+
+    /// Ends the attempt in progress, which failed, and schedules the next one after the backoff.
+    pub(crate) fn retry(&mut self, policy: &ReconnectPolicy, now: Instant) {
+        self.attempting = false;
+        self.next_attempt = now + policy.backoff(self.attempts);
+    }
+}
+
+/// UpstreamKind is the reason an upstream connection of the proxy is connected, which decides what happens once its
+/// handshake with the backend completes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum UpstreamKind {
+    /// A new downstream client is connected to its backend.
+    Connect,
+    /// A logged in downstream client is moved to another backend.
+    Transfer,
+    /// A held downstream client is reconnected to its backend.
+    Reconnect,
+}
+
+/// PendingUpstream is the component of an upstream connection of the proxy whose offline handshake with the backend
+/// runs on the HandshakePool, so that the other clients of the proxy are not blocked meanwhile. The entity is already
+/// linked to the downstream client so that the batches of the client are queued on it until the handshake completes.
+#[derive(Component)]
+pub struct PendingUpstream {
+    kind: UpstreamKind,
+    outcome: Option<Receiver<Result<Handshake>>>,
+}
+
+impl PendingUpstream {
+    pub(crate) fn new(kind: UpstreamKind, outcome: Receiver<Result<Handshake>>) -> Self {
+        Self {
+            kind,
+            outcome: Some(outcome),
+        }
+    }
+
+    pub(crate) fn kind(&self) -> UpstreamKind {
+        self.kind
+    }
+
+    /// Returns the outcome of the handshake the first time it is called once the handshake has completed.
+    pub(crate) fn poll(&mut self) -> Option<Result<Handshake>> {
+        poll_handshake(&mut self.outcome)
+    }
+}
+
+/// StatusPassthrough makes the proxy advertise the status of its backends, which are pinged on the provided interval.
+/// The MOTD, version and gamemode of the first healthy backend are mirrored into the ServerBundle of the proxy along
+/// with the player counts of all the healthy backends added up, unless they are overridden.
+#[derive(Resource, Debug, Clone)]
+pub struct StatusPassthrough {
+    interval: Duration,
+    motd: Option<String>,
+    level_name: Option<String>,
+    max_players: Option<u32>,
+    pub(crate) server: Entity,
+    pub(crate) addrs: HashMap<String, SocketAddr>,
+}
+
+impl StatusPassthrough {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            motd: None,
+            level_name: None,
+            max_players: None,
+            server: Entity::PLACEHOLDER,
+            addrs: HashMap::new(),
+        }
+    }
+
+    /// Advertises the provided MOTD instead of the one of the backends.
+    pub fn with_motd(mut self, motd: &str) -> Self {
+        self.motd = Some(motd.to_string());
+        self
+    }
+
+    /// Advertises the provided level name instead of the one of the backends.
+    pub fn with_level_name(mut self, level_name: &str) -> Self {
+        self.level_name = Some(level_name.to_string());
+        self
+    }
+
+    /// Advertises the provided maximum number of players instead of the sum of the backends.
+    pub fn with_max_players(mut self, max_players: u32) -> Self {
+        self.max_players = Some(max_players);
+        self
+    }
+
+    /// Returns how often the backends are pinged.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Merges the provided statuses of the backends into the status advertised by the proxy. Returns None if none of
+    /// the backends has responded yet.
+    pub(crate) fn merge<'a>(
+        &self,
+        mut statuses: impl Iterator<Item = &'a ServerStatus>,
+    ) -> Option<ServerStatus> {
+        let mut merged = statuses.next()?.clone();
+
+        for status in statuses {
+            merged.online += status.online;
+            merged.max += status.max;
+        }
+
+        if let Some(motd) = &self.motd {
+            merged.motd = motd.clone();
+        }
+
+        if let Some(level_name) = &self.level_name {
+            merged.level_name = level_name.clone();
+        }
+
+        if let Some(max_players) = self.max_players {
+            merged.max = max_players;
+        }
+
+        Some(merged)
+    }
+}
+
+/// ProxyLink pairs a downstream client connection of the proxy with its upstream connection to the backend. Both
+/// entities carry a link to the other, the batches of one side are forwarded to the other side as they are and both
+/// are disconnected together. The downstream side records the login batches of the client so that the client can be
+/// transferred to another backend later.
+#[derive(Component, Debug)]
+pub struct ProxyLink {
+    peer: Entity,
+    backend: String,
+    upstream: bool,
+    pending: Vec<Vec<u8>>,
+    login: Vec<Vec<u8>>,
+    replay: Option<Replay>,
+    algorithm: Option<CompressionAlgorithm>,
+    header: bool,
+    window: (Option<Instant>, u32, usize),
+}
+
+impl ProxyLink {
+    /// Creates the link of a downstream client that is connected to the provided backend through the peer entity.
+    pub fn downstream(peer: Entity, backend: &str) -> Self {
+        Self {
+            peer,
+            backend: backend.to_string(),
+            upstream: false,
+            pending: Vec::new(),
+            login: Vec::new(),
+            replay: None,
+            algorithm: None,
+            header: false,
+            window: (None, 0, 0),
+        }
+    }
+
+    /// Creates the link of an upstream connection to the provided backend for the downstream client of the peer entity.
+    pub fn upstream(peer: Entity, backend: &str) -> Self {
+        Self {
+            upstream: true,
+            ..Self::downstream(peer, backend)
+        }
+    }
+
+    /// Returns the entity on the other side of the proxy.
+    pub fn peer(&self) -> Entity {
+        self.peer
+    }
+
+    /// Returns the address of the backend the link goes to.
+    pub fn backend(&self) -> &str {
+        &self.backend
+    }
+
+    /// Returns whether this side of the link is the connection to the backend.
+    pub fn is_upstream(&self) -> bool {
+        self.upstream
+    }
+
+    /// Returns whether the login batches of the downstream client have been recorded, which is required for a transfer.
+    pub fn can_transfer(&self) -> bool {
+        !self.upstream && self.login.len() == LOGIN_BATCHES
+    }
+
+    /// Queues a batch for this side of the proxy until its connection has been established. The compression algorithm
+    /// of a downstream client is learnt from the NetworkSettings its backend sends in between its login batches.
+    pub(crate) fn queue(&mut self, batch: Vec<u8>) {
+        if !self.upstream && self.algorithm.is_none() && self.login.len() == 1 {
+            self.algorithm = read_network_settings(&batch).ok();
+        }
+
+        self.pending.push(batch);
+    }
+
+    /// Returns the compression algorithm the downstream client negotiated with its backend, if it is known.
+    pub fn algorithm(&self) -> Option<CompressionAlgorithm> {
+        self.algorithm
+    }
+
+    /// Returns whether the batches of the downstream client carry the compression header, which is learnt from the
+    /// protocol version in its RequestNetworkSettings.
+    pub fn compression_header(&self) -> bool {
+        self.header
+    }
+
+    /// Returns all the batches queued while the connection was being established.
+    pub(crate) fn drain(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// Counts a batch of the provided size received at the provided time towards the current one second window of the
+    /// link. Returns false if the batch exceeds either limit of the provided rate limit.
+    pub(crate) fn allow(&mut self, len: usize, limit: &ProxyRateLimit, now: Instant) -> bool {
+        let (instant, batches, bytes) = &mut self.window;
+
+        if instant.map_or(true, |i| {
+            now.saturating_duration_since(i).as_millis() >= 1000
+        }) {
+            *instant = Some(now);
+            *batches = 0;
+            *bytes = 0;
+        }
+
+        *batches += 1;
+        *bytes += len;
+
+        *batches <= limit.max_batches_per_sec && *bytes <= limit.max_bytes_per_sec
+    }
+
+    /// Records a batch sent by the downstream client if it is one of its login batches.
+    pub(crate) fn record(&mut self, batch: &[u8]) {
+        if !self.upstream && self.login.len() < LOGIN_BATCHES {
+            if self.login.is_empty() {
+                self.header = uses_compression_header(batch);
+            }
+
+            self.login.push(batch.to_vec());
+        }
+    }
+
+    /// Points the downstream link to the upstream connection of the backend the client was transferred to.
+    pub(crate) fn retarget(&mut self, peer: Entity, backend: &str) {
+        self.peer = peer;
+        self.backend = backend.to_string();
+    }
+
+    /// Creates the upstream link of a transfer, which replays the provided login batches to the backend.
+    pub(crate) fn transfer(peer: Entity, backend: &str, login: Vec<Vec<u8>>) -> Self {
+        Self {
+            replay: Some(Replay::new(login)),
+            ..Self::upstream(peer, backend)
+        }
+    }
+
+    /// Returns the login batches recorded from the downstream client.
+    pub(crate) fn login(&self) -> Vec<Vec<u8>> {
+        self.login.clone()
+    }
+
+    /// Returns the replay of the login if the upstream connection is still replaying it.
+    pub(crate) fn replay(&mut self) -> Option<&mut Replay> {
+        self.replay.as_mut()
+    }
+
+    /// Ends the replay of the login, the batches of both sides are forwarded from now on.
+    pub(crate) fn finish_replay(&mut self) {
+        self.replay = None;
+    }
+}
+
+/// ReplayOutcome contains the batches that result from a batch the backend sent during the replay of a login.
+#[derive(Debug, Default)]
+pub(crate) struct ReplayOutcome {
+    /// The batch that is sent to the backend next.
+    pub upstream: Option<Vec<u8>>,
+    /// The packets of the backend that are not part of the login, which are forwarded to the client.
+    pub downstream: Option<Vec<u8>>,
+    /// Whether the backend has logged the client in.
+    pub done: bool,
+}
+
+/// Replay is the state of an upstream connection that replays the login of an already logged in client to a new
+/// backend. The login responses of the backend are swallowed since the client has already seen them, which only works
+/// with backends that do not encrypt the connections and that share the compression settings.
+#[derive(Debug)]
+pub(crate) struct Replay {
+    login: Vec<Vec<u8>>,
+    algorithm: Option<CompressionAlgorithm>,
+    header: bool,
+    started: bool,
+}
+
+impl Replay {
+    fn new(login: Vec<Vec<u8>>) -> Self {
+        Self {
+            header: login
+                .first()
+                .is_some_and(|batch| uses_compression_header(batch)),
+            login,
+            algorithm: None,
+            started: false,
+        }
+    }
+
+    /// Returns the RequestNetworkSettings batch the first time it is called once the connection is established.
+    pub(crate) fn start(&mut self) -> Option<Vec<u8>> {
+        if self.started {
+            return None;
+        }
+
+        self.started = true;
+        self.login.first().cloned()
+    }
+
+    /// Handles a batch that the backend sent during the replay. The Login is replayed once the NetworkSettings are
+    /// received and the replay is done once the PlayStatus LoginSuccess is received.
+    pub(crate) fn handle(&mut self, batch: &[u8], config: &RakNetConfig) -> Result<ReplayOutcome> {
+        let Some(algorithm) = self.algorithm else {
+            self.algorithm = Some(read_network_settings(batch)?);
+
+            return Ok(ReplayOutcome {
+                upstream: self.login.get(1).cloned(),
+                ..Default::default()
+            });
+        };
+
+        let batch = decompress_batch(batch, algorithm, config.max_batch_size, self.header)?;
+        let mut forward = Vec::new();
+        let mut done = false;
+
+        for packet in read_sub_packets(&batch)? {
+            match packet_id(packet) {
+                Some(SERVER_TO_CLIENT_HANDSHAKE) => {
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        "The backend requires encryption",
+                    ))
+                }
+                Some(PLAY_STATUS) => {
+                    match PlayStatus::deserialize(&mut Cursor::new(packet))?.status {
+                        Status::LoginSuccess => done = true,
+                        status => {
+                            return Err(Error::new(ErrorKind::Other, format!("{:?}", status)))
+                        }
+                    }
+                }
+                _ => write_sub_packet(&mut forward, packet),
+            }
+        }
+
+        let downstream = match forward.is_empty() {
+            true => None,
+            false => Some(compress_batch(
+                &forward,
+                algorithm,
+                config.compression_level,
+                self.header,
+            )?),
+        };
+
+        Ok(ReplayOutcome {
+            upstream: None,
+            downstream,
+            done,
+        })
+    }
+}
+
+/// Returns whether the client that sent the provided batch with its RequestNetworkSettings uses the compression header,
+/// the batch is sent uncompressed as the compression is only negotiated afterwards.
+fn uses_compression_header(batch: &[u8]) -> bool {
+    let Ok(packets) = read_sub_packets(batch) else {
+        return false;
+    };
+
+    packets
+        .into_iter()
+        .find(|packet| packet_id(packet) == Some(REQUEST_NETWORK_SETTINGS))
+        .and_then(|packet| RequestNetworkSettings::deserialize(&mut Cursor::new(packet)).ok())
+        .is_some_and(|request| request.protocol >= COMPRESSION_HEADER_PROTOCOL)
+}
+
+/// Reads the compression algorithm from the NetworkSettings in the provided batch, which is sent uncompressed as the
+/// compression is only negotiated by it.
+fn read_network_settings(batch: &[u8]) -> Result<CompressionAlgorithm> {
+    let packets = read_sub_packets(batch)?;
+    let packet = packets
+        .into_iter()
+        .find(|packet| packet_id(packet) == Some(NETWORK_SETTINGS))
+        .ok_or(Error::new(ErrorKind::Other, "Expected NetworkSettings"))?;
+
+    let settings = NetworkSettings::deserialize(&mut Cursor::new(packet))?;
+    CompressionAlgorithm::from_id(settings.compression_algorithm).ok_or(Error::new(
+        ErrorKind::Other,
+        "Unknown compression algorithm",
+    ))
+}
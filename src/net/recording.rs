@@ -0,0 +1,274 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{Cursor, Error, ErrorKind, Read, Result, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use binary::Binary;
+use byteorder::{ReadBytesExt, WriteBytesExt, BE};
+use tracing::debug;
+
+use crate::{
+    generic::time::TimeSource,
+    protocol::{binary::UDPAddress, RECORDING_MAGIC, RECORDING_VERSION},
+};
+
+use super::transport::Transport;
+
+/// Direction is whether a recorded datagram was received or sent by the transport it was recorded on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+/// Record is a single datagram of a recording along with the time it was recorded at since the recording started
+/// and the address of the other end of the connection.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub direction: Direction,
+    pub timestamp: Duration,
+    pub local_addr: SocketAddr,
+    pub addr: SocketAddr,
+    pub datagram: Vec<u8>,
+}
+
+impl Record {
+    fn serialize(&self, buf: &mut Vec<u8>) {
+        buf.write_u8(self.direction as u8).unwrap();
+        buf.write_u64::<BE>(self.timestamp.as_micros() as u64)
+            .unwrap();
+        UDPAddress(self.local_addr).serialize(buf);
+        UDPAddress(self.addr).serialize(buf);
+        buf.write_u32::<BE>(self.datagram.len() as u32).unwrap();
+        buf.extend_from_slice(&self.datagram);
+    }
+
+    fn deserialize(reader: &mut Cursor<&[u8]>) -> Result<Self> {
+        let direction = match reader.read_u8()? {
+            0 => Direction::Incoming,
+            1 => Direction::Outgoing,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "The direction of the record is invalid",
+                ))
+            }
+        };
+
+        let timestamp = Duration::from_micros(reader.read_u64::<BE>()?);
+        let local_addr = UDPAddress::deserialize(reader)?.0;
+        let addr = UDPAddress::deserialize(reader)?.0;
+
+        let len = reader.read_u32::<BE>()? as usize;
+        if len > reader.get_ref().len() - reader.position() as usize {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "The length of the record exceeds the recording",
+            ));
+        }
+
+        let mut datagram = vec![0u8; len];
+        reader.read_exact(&mut datagram)?;
+
+        Ok(Self {
+            direction,
+            timestamp,
+            local_addr,
+            addr,
+            datagram,
+        })
+    }
+}
+
+/// TrafficRecorder writes the datagrams of the transports it is attached to into a recording file, so that a session
+/// can be reproduced later with a ReplayTransport. Every record is written as soon as it is made. Clones write to
+/// the same file.
+#[derive(Clone)]
+pub struct TrafficRecorder {
+    file: Arc<Mutex<File>>,
+    started: Instant,
+}
+
+impl TrafficRecorder {
+    /// Creates the recording file at the provided path, truncating it if it already exists.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let mut file = File::create(path)?;
+        file.write_all(RECORDING_MAGIC)?;
+        file.write_u8(RECORDING_VERSION)?;
+
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+            started: Instant::now(),
+        })
+    }
+
+    /// Wraps the provided transport so that all the datagrams sent and received through it are recorded.
+    pub fn attach(&self, inner: Arc<dyn Transport>) -> Result<RecordingTransport> {
+        Ok(RecordingTransport {
+            local_addr: inner.local_addr()?,
+            inner,
+            recorder: self.clone(),
+        })
+    }
+
+    fn record(
+        &self,
+        direction: Direction,
+        local_addr: SocketAddr,
+        addr: SocketAddr,
+        datagram: &[u8],
+    ) {
+        let record = Record {
+            direction,
+            timestamp: self.started.elapsed(),
+            local_addr,
+            addr,
+            datagram: datagram.to_vec(),
+        };
+
+        let mut buf = Vec::with_capacity(datagram.len() + 64);
+        record.serialize(&mut buf);
+
+        if let Err(e) = self.file.lock().unwrap().write_all(&buf) {
+            debug!("[Recording Error]: {}", e.to_string());
+        }
+    }
+}
+
+/// Reads all the records of the recording file at the provided path in the order they were recorded.
+pub fn read_recording(path: impl AsRef<Path>) -> Result<Vec<Record>> {
+    let bytes = fs::read(path)?;
+    let mut reader = Cursor::new(&bytes[..]);
+
+    let mut magic = [0u8; RECORDING_MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+
+    if &magic != RECORDING_MAGIC || reader.read_u8()? != RECORDING_VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "The file is not a recording of a supported version",
+        ));
+    }
+
+    let mut records = Vec::new();
+    while (reader.position() as usize) < bytes.len() {
+        records.push(Record::deserialize(&mut reader)?);
+    }
+
+    Ok(records)
+}
+
+/// RecordingTransport is a Transport that records all the datagrams going through the transport it wraps.
+pub struct RecordingTransport {
+    inner: Arc<dyn Transport>,
+    local_addr: SocketAddr,
+    recorder: TrafficRecorder,
+}
+
+impl Transport for RecordingTransport {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize> {
+        let len = self.inner.send_to(buf, addr)?;
+        self.recorder
+            .record(Direction::Outgoing, self.local_addr, addr, buf);
+
+        Ok(len)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let (len, addr) = self.inner.recv_from(buf)?;
+        self.recorder
+            .record(Direction::Incoming, self.local_addr, addr, &buf[..len]);
+
+        Ok((len, addr))
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// ReplayTransport is a Transport that receives the incoming datagrams of a recording again, which feeds them through
+/// the same decode path as they went through when they were recorded. Every datagram is received once the provided
+/// clock reaches the time it was recorded at since the replay started, a mock clock lets a test step through them.
+/// The datagrams sent through it are discarded.
+pub struct ReplayTransport {
+    local_addr: SocketAddr,
+    clock: TimeSource,
+    started: Instant,
+    records: Mutex<VecDeque<Record>>,
+}
+
+impl ReplayTransport {
+    /// Opens the recording file at the provided path. Only the datagrams received on the provided local address are
+    /// replayed, or the ones of the first transport in the recording if it is None.
+    pub fn open(
+        path: impl AsRef<Path>,
+        local_addr: Option<SocketAddr>,
+        clock: TimeSource,
+    ) -> Result<Self> {
+        let records = read_recording(path)?;
+
+        let Some(local_addr) = local_addr.or(records.first().map(|record| record.local_addr))
+        else {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "The recording does not contain any records",
+            ));
+        };
+
+        let records = records
+            .into_iter()
+            .filter(|record| {
+                record.direction == Direction::Incoming && record.local_addr == local_addr
+            })
+            .collect();
+
+        Ok(Self {
+            local_addr,
+            started: clock.now(),
+            clock,
+            records: Mutex::new(records),
+        })
+    }
+
+    /// Returns the number of datagrams that have not been replayed yet.
+    pub fn remaining(&self) -> usize {
+        self.records.lock().unwrap().len()
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn send_to(&self, buf: &[u8], _addr: SocketAddr) -> Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let mut records = self.records.lock().unwrap();
+        let elapsed = self.clock.since(self.started);
+
+        if !records
+            .front()
+            .is_some_and(|record| record.timestamp <= elapsed)
+        {
+            return Err(Error::new(
+                ErrorKind::WouldBlock,
+                "There is no datagram to receive",
+            ));
+        }
+
+        let record = records.pop_front().unwrap();
+        let len = record.datagram.len().min(buf.len());
+        buf[..len].copy_from_slice(&record.datagram[..len]);
+
+        Ok((len, record.addr))
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    collections::VecDeque,
+    io::{Error, ErrorKind, Result},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::protocol::{MAX_MTU_SIZE, SIMULATOR_REORDER_DELAY};
+
+use super::transport::Transport;
+
+/// NetworkConditions are the conditions that a SimulatedTransport applies to the datagrams in both directions. The
+/// probabilities range from 0 to 1 and the defaults describe a perfect network.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkConditions {
+    /// The probability of a datagram being lost.
+    pub loss: f32,
+    /// The delay added to every datagram.
+    pub latency: Duration,
+    /// The upper bound of the random delay added to every datagram on top of the latency.
+    pub jitter: Duration,
+    /// The probability of a datagram being delivered twice.
+    pub duplication: f32,
+    /// The probability of a datagram being held back for SIMULATOR_REORDER_DELAY so the ones after it overtake it.
+    pub reordering: f32,
+    /// The seed of the random decisions so that a run can be reproduced, a random one is used if None.
+    pub seed: Option<u64>,
+}
+
+/// Delayed is a datagram held by a SimulatedTransport until it is due.
+struct Delayed {
+    due: Instant,
+    datagram: Vec<u8>,
+    addr: SocketAddr,
+}
+
+/// SimulatorState is the random generator and the datagrams held by a SimulatedTransport in either direction.
+struct SimulatorState {
+    rng: StdRng,
+    outgoing: VecDeque<Delayed>,
+    incoming: VecDeque<Delayed>,
+}
+
+/// SimulatedTransport wraps another Transport and applies the provided NetworkConditions to the datagrams sent and
+/// received through it, so the retransmission and ordering logic can be exercised under a bad network. The delayed
+/// datagrams are released whenever the transport is used, which the socket systems do on every run.
+pub struct SimulatedTransport {
+    inner: Arc<dyn Transport>,
+    conditions: NetworkConditions,
+    state: Mutex<SimulatorState>,
+}
+
+impl SimulatedTransport {
+    pub fn new(inner: Arc<dyn Transport>, conditions: NetworkConditions) -> Self {
+        let rng = match conditions.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+
+        Self {
+            inner,
+            conditions,
+            state: Mutex::new(SimulatorState {
+                rng,
+                outgoing: VecDeque::new(),
+                incoming: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Returns the conditions applied by the transport.
+    pub fn conditions(&self) -> &NetworkConditions {
+        &self.conditions
+    }
+
+    /// Drops, duplicates or delays the provided datagram according to the conditions and queues the copies that
+    /// survive in the provided queue ordered by the time they are due.
+    fn schedule(
+        &self,
+        rng: &mut StdRng,
+        queue: &mut VecDeque<Delayed>,
+        datagram: &[u8],
+        addr: SocketAddr,
+    ) {
+        let copies = match rng.gen::<f32>() < self.conditions.duplication {
+            true => 2,
+            false => 1,
+        };
+
+        for _ in 0..copies {
+            if rng.gen::<f32>() < self.conditions.loss {
+                continue;
+            }
+
+            let mut delay = self.conditions.latency;
+
+            if !self.conditions.jitter.is_zero() {
+                delay += self.conditions.jitter.mul_f32(rng.gen::<f32>());
+            }
+
+            if rng.gen::<f32>() < self.conditions.reordering {
+                delay += SIMULATOR_REORDER_DELAY;
+            }
+
+            let due = Instant::now() + delay;
+            let index = queue.partition_point(|delayed| delayed.due <= due);
+
+            queue.insert(
+                index,
+                Delayed {
+                    due,
+                    datagram: datagram.to_vec(),
+                    addr,
+                },
+            );
+        }
+    }
+
+    /// Sends all the outgoing datagrams that are due through the wrapped transport.
+    fn flush(&self, outgoing: &mut VecDeque<Delayed>) -> Result<()> {
+        let now = Instant::now();
+
+        while outgoing.front().is_some_and(|delayed| delayed.due <= now) {
+            let delayed = outgoing.pop_front().unwrap();
+            self.inner.send_to(&delayed.datagram, delayed.addr)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Transport for SimulatedTransport {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+
+        self.schedule(&mut state.rng, &mut state.outgoing, buf, addr);
+        self.flush(&mut state.outgoing)?;
+
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+
+        // The datagrams that are due are sent from here too, so they go out even if nothing else is sent.
+        let _ = self.flush(&mut state.outgoing);
+
+        let mut read_buf = [0u8; MAX_MTU_SIZE];
+        while let Ok((len, addr)) = self.inner.recv_from(&mut read_buf) {
+            self.schedule(&mut state.rng, &mut state.incoming, &read_buf[..len], addr);
+        }
+
+        let now = Instant::now();
+        if !state
+            .incoming
+            .front()
+            .is_some_and(|delayed| delayed.due <= now)
+        {
+            return Err(Error::new(
+                ErrorKind::WouldBlock,
+                "There is no datagram to receive",
+            ));
+        }
+
+        let delayed = state.incoming.pop_front().unwrap();
+        let len = delayed.datagram.len().min(buf.len());
+        buf[..len].copy_from_slice(&delayed.datagram[..len]);
+
+        Ok((len, delayed.addr))
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
@@ -1,54 +1,203 @@
+use bevy::core::Name;
 use bevy::ecs::bundle::Bundle;
 use bevy::ecs::component::Component;
-use bevy::ecs::entity::Entity;
+use bevy::ecs::entity::{Entities, Entity};
 use bevy::ecs::event::EventWriter;
-use bevy::ecs::system::{Commands, Query};
+use bevy::ecs::reflect::ReflectComponent;
+use bevy::ecs::system::{Commands, Query, Resource};
 use bevy::ecs::world::World;
+use bevy::reflect::Reflect;
 use binary::datatypes::{Bool, I64, U16, U8};
 use binary::prefixed::{Str, UnsizedBytes};
 use binary::Binary;
 use bytes::BytesMut;
 use commons::utils::unix_timestamp;
-use log::{debug, info, trace};
+use tracing::{debug, debug_span, info, trace};
 
-use crate::generic::events::RakNetEvent;
+use crate::generic::events::{DisconnectReason, RakNetEvent};
+use crate::net::blocklist::{BlockList, BlockReason};
+use crate::net::maintenance::Maintenance;
 use crate::net::stream::{RakStream, StreamBundle};
-use crate::protocol::binary::{Magic, UDPAddress};
+use crate::net::transport::Transport;
+use crate::net::whitelist::{Refusal, Whitelist};
+use crate::net::{ConnectionFilters, MessageInterceptors};
+use crate::protocol::binary::{Magic, ProxySignature, UDPAddress};
+use crate::protocol::compression::CompressionAlgorithm;
+use crate::protocol::config::{DuplicateLoginPolicy, RakNetConfig};
+use crate::protocol::encryption::{proxy_signature, verify_proxy_signature};
 use crate::protocol::mcpe::{
-    BroadcastGamemode, MaxPlayers, MinecraftProtocol, MinecraftVersion, OnlinePlayers, PrimaryMotd,
-    SecondaryMotd,
+    login::LoginState, BroadcastGamemode, LevelName, MaxPlayers, MinecraftProtocol,
+    MinecraftVersion, OnlinePlayers, PongStatus, PortV6, PrimaryMotd, SecondaryMotd, ServerStatus,
 };
 use crate::protocol::message::Message;
 use crate::protocol::{
-    CLIENT_PADDING_DECREASE, MAX_INVALID_MSGS, MAX_MSGS_PER_SEC, MAX_MTU_SIZE, PROTOCOL_VERSION,
-    RAKNET_BLOCK_DUR, UDP_HEADER_SIZE,
+    CLIENT_MTU_RETRIES, CLIENT_MTU_TIERS, FLAG_DATAGRAM, HANDSHAKE_POLL_INTERVAL,
+    HANDSHAKE_READ_TIMEOUT, MAX_MTU_SIZE, MAX_OFFLINE_DATA_LENGTH, MIN_MTU_SIZE,
+    NAT_ESTABLISH_PACKET_ID, PROTOCOL_VERSION, PROXY_HEADER_MAX_AGE, UDP_HEADER_SIZE,
 };
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::{Cursor, Error, ErrorKind, Result};
 use std::net::{SocketAddr, UdpSocket};
+use std::panic::{self, AssertUnwindSafe};
 use std::str::FromStr;
-use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use super::stream::{NetworkInfo, NetworkStatus};
+use super::ping::unspecified;
+use super::stream::{ConnectionState, NetworkInfo, NetworkStatus};
 
 /// Mappings contains all the useful maps that store data such as the connections <-> Entity map, and various other maps
 /// that help in preventing packet spamming, corrupt packets, etc.
 #[derive(Component, Default)]
 pub struct Mappings {
     connections: HashMap<SocketAddr, Entity>,
-    blocked: HashMap<SocketAddr, u64>,
-    packets_per_sec: HashMap<SocketAddr, (Instant, u8)>,
-    invalid_packets: HashMap<SocketAddr, u8>,
+    closed: HashMap<SocketAddr, Instant>,
+    packets_per_sec: HashMap<SocketAddr, (Instant, u32)>,
+    unconnected_per_sec: HashMap<SocketAddr, (Instant, u32)>,
+    pongs_per_sec: HashMap<SocketAddr, (Instant, u32)>,
+    pongs_this_sec: (Option<Instant>, u32),
+    invalid_packets: HashMap<SocketAddr, (Instant, u32)>,
+    forwarded: HashMap<SocketAddr, (Instant, SocketAddr)>,
+    proxy_nonces: HashSet<(i64, i64)>,
+    approvals: HashMap<SocketAddr, PendingConnection>,
+    guids: HashMap<i64, Entity>,
+}
+
+/// PendingConnection is an OpenConnectionRequest2 that has not been answered yet as it is waiting for the approval
+/// of the App.
+pub struct PendingConnection {
+    pub requested_at: Instant,
+    pub local_addr: SocketAddr,
+    pub remote_addr: SocketAddr,
+    pub mtu_size: usize,
+    pub guid: i64,
+}
+
+impl Mappings {
+    /// Returns the entity of the connection with the provided address if there is any.
+    pub fn connection(&self, addr: &SocketAddr) -> Option<Entity> {
+        self.connections.get(addr).copied()
+    }
+
+    /// Returns whether the connection of the provided address was despawned recently and the other end of it may
+    /// still be sending its datagrams.
+    pub fn is_closed(&self, addr: &SocketAddr) -> bool {
+        self.closed.contains_key(addr)
+    }
+
+    /// Returns an iterator over the addresses and the entities of all the connections.
+    pub fn connections(&self) -> impl Iterator<Item = (&SocketAddr, &Entity)> {
+        self.connections.iter()
+    }
+
+    /// Removes the connection of the provided address that is waiting for the approval of the App if there is any.
+    pub fn take_approval(&mut self, addr: &SocketAddr) -> Option<PendingConnection> {
+        self.approvals.remove(addr)
+    }
+
+    /// Removes the connections that have waited for the approval of the App for longer than the provided timeout and
+    /// returns their addresses.
+    pub fn expired_approvals(&mut self, now: Instant, timeout: Duration) -> Vec<SocketAddr> {
+        let expired: Vec<SocketAddr> = self
+            .approvals
+            .iter()
+            .filter(|(_, pending)| now.saturating_duration_since(pending.requested_at) >= timeout)
+            .map(|(addr, _)| *addr)
+            .collect();
+
+        for addr in expired.iter() {
+            self.approvals.remove(addr);
+        }
+
+        expired
+    }
+
+    /// Evicts the mappings of the despawned connections, which are recorded as closed like the ones removed when their
+    /// datagrams are read, the finished packet and pong rate windows, the invalid packet counters of addresses that
+    /// have been idle for a while and the nonces of the expired proxy headers. The counter maps are reset entirely if
+    /// they still exceed the maximum number of tracked addresses so that a flood of spoofed addresses cannot grow them
+    /// without bounds.
+    pub fn cleanup(&mut self, config: &RakNetConfig, exists: impl Fn(Entity) -> bool) {
+        let clock = &config.clock;
+        let now = clock.now();
+
+        self.connections.retain(|addr, entity| {
+            if exists(*entity) {
+                return true;
+            }
+
+            self.closed.insert(*addr, now);
+            false
+        });
+        self.closed
+            .retain(|_, instant| clock.since(*instant) < config.timeout);
+        self.guids.retain(|_, entity| exists(*entity));
+        self.packets_per_sec
+            .retain(|_, (instant, _)| clock.since(*instant).as_millis() < 1000);
+        self.unconnected_per_sec
+            .retain(|_, (instant, _)| clock.since(*instant).as_millis() < 1000);
+        self.pongs_per_sec
+            .retain(|_, (instant, _)| clock.since(*instant).as_millis() < 1000);
+        self.invalid_packets
+            .retain(|_, (instant, _)| clock.since(*instant) < config.invalid_packets_idle);
+        self.forwarded
+            .retain(|_, (instant, _)| clock.since(*instant) < config.timeout);
+        self.proxy_nonces
+            .retain(|(timestamp, _)| !proxy_header_expired(*timestamp));
+
+        if self.packets_per_sec.len() > config.max_tracked_addresses {
+            self.packets_per_sec.clear();
+        }
+
+        if self.unconnected_per_sec.len() > config.max_tracked_addresses {
+            self.unconnected_per_sec.clear();
+        }
+
+        if self.pongs_per_sec.len() > config.max_tracked_addresses {
+            self.pongs_per_sec.clear();
+        }
+
+        if self.invalid_packets.len() > config.max_tracked_addresses {
+            self.invalid_packets.clear();
+        }
+
+        if self.forwarded.len() > config.max_tracked_addresses {
+            self.forwarded.clear();
+        }
+    }
+}
+
+/// ReadBudget bounds the amount of work the read systems do in a single run. The systems keep reading datagrams
+/// from the UdpSocket until it has no more datagrams or until either of the limits is reached, the remaining
+/// datagrams are read in the next run.
+#[derive(Resource)]
+pub struct ReadBudget {
+    pub max_datagrams: usize,
+    pub max_bytes: usize,
+}
+
+impl Default for ReadBudget {
+    fn default() -> Self {
+        Self {
+            max_datagrams: 1024,
+            max_bytes: 1024 * MAX_MTU_SIZE,
+        }
+    }
 }
 
 /// ServerBundle is the bundle used to spawn a RakNet server. A RakNet server has multiple extra components from a client such
 /// as various components used for building the unconnected pong message.
 #[derive(Bundle)]
 pub struct ServerBundle {
+    pub name: Name,
     pub socket: RakSocket,
     pub info: SocketInfo,
     pub mappings: Mappings,
+    pub block_list: BlockList,
+    pub whitelist: Whitelist,
+    pub maintenance: Maintenance,
     pub primary_motd: PrimaryMotd,
     pub secondary_motd: SecondaryMotd,
     pub online_players: OnlinePlayers,
@@ -56,18 +205,33 @@ pub struct ServerBundle {
     pub gamemode: BroadcastGamemode,
     pub protocol: MinecraftProtocol,
     pub version: MinecraftVersion,
+    pub level_name: LevelName,
+    pub port_v6: PortV6,
+    pub status: PongStatus,
 }
 
 impl ServerBundle {
     pub fn new(addr: &str) -> Self {
-        let socket = RakSocket::new(addr, true).unwrap();
-        let addr = socket.udp.local_addr().unwrap();
+        Self::with_socket(RakSocket::new(addr, true).unwrap())
+    }
+
+    /// Creates the bundle of a server that sends and receives its datagrams on the provided transport.
+    pub fn with_transport(transport: Arc<dyn Transport>) -> Self {
+        Self::with_socket(RakSocket::with_transport(transport))
+    }
+
+    fn with_socket(socket: RakSocket) -> Self {
+        let addr = socket.transport.local_addr().unwrap();
         let guid = rand::random();
 
         Self {
+            name: Name::new(format!("raknet server {}", addr)),
             socket,
             info: SocketInfo { addr, guid },
             mappings: Mappings::default(),
+            block_list: BlockList::default(),
+            whitelist: Whitelist::default(),
+            maintenance: Maintenance::default(),
             primary_motd: PrimaryMotd::new("RakNet"),
             secondary_motd: SecondaryMotd::new("blazingly fast!"),
             online_players: OnlinePlayers::new(0),
@@ -75,6 +239,9 @@ impl ServerBundle {
             gamemode: BroadcastGamemode::new("Survival"),
             protocol: MinecraftProtocol::new(600),
             version: MinecraftVersion::new("1.20.51"),
+            level_name: LevelName::default(),
+            port_v6: PortV6::new(addr.port()),
+            status: PongStatus::new(),
         }
     }
 }
@@ -88,18 +255,204 @@ pub struct ClientBundle {
     pub stream: StreamBundle,
 }
 
-/// SocketInfo contains information about a RakSocket such as the address it's bound to, it's guid.
-#[derive(Component)]
+/// SocketInfo contains information about a RakSocket such as the address it's bound to, it's guid. The address is not
+/// reflected as SocketAddr does not implement Reflect.
+#[derive(Component, Reflect)]
+#[reflect(Component, from_reflect = false)]
 pub struct SocketInfo {
+    #[reflect(ignore)]
     pub addr: SocketAddr,
     pub guid: i64,
 }
 
-/// RakSocket is built on top of the UdpSocket and handles the reading and writing of unconnected messages from/to the other end of the
-/// connection. It handles the login sequence of clients (logging into a server) and server (for clients logging into it).
+impl Default for SocketInfo {
+    fn default() -> Self {
+        Self {
+            addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+            guid: 0,
+        }
+    }
+}
+
+/// Handshake is the outcome of a completed offline handshake with a RakNet server, which the entity of the client
+/// connection is built from. It does not need the World so that the handshake can be performed on another thread.
+pub(crate) struct Handshake {
+    socket: RakSocket,
+    local_addr: SocketAddr,
+    remote_addr: SocketAddr,
+    guid: i64,
+    server_guid: i64,
+    mtu_size: usize,
+    status: Option<ServerStatus>,
+}
+
+impl Handshake {
+    /// Spawns the entity of the client connection and returns its ID.
+    pub(crate) fn spawn(self, world: &mut World) -> Entity {
+        let id = world.spawn_empty().id();
+        self.insert(id, world);
+        id
+    }
+
+    /// Inserts the client connection on the provided entity and requests the connection from the server.
+    pub(crate) fn insert(self, id: Entity, world: &mut World) {
+        let config = world
+            .get_resource_or_insert_with(RakNetConfig::default)
+            .clone();
+
+        let mut stream = RakStream::new(self.remote_addr, self.mtu_size, &config);
+        let state_changed = stream.request_connection(self.guid, id);
+
+        world.entity_mut(id).insert(ClientBundle {
+            socket: self.socket,
+            info: SocketInfo {
+                addr: self.local_addr,
+                guid: self.guid,
+            },
+            stream: StreamBundle {
+                name: Name::new(format!("raknet {}", self.remote_addr)),
+                info: NetworkInfo {
+                    local_addr: self.local_addr,
+                    remote_addr: self.remote_addr,
+                    mtu_size: self.mtu_size,
+                    guid: self.server_guid,
+                },
+                status: NetworkStatus {
+                    ping: 0,
+                    latency: Duration::from_secs(0),
+                    last_activity: config.clock.now(),
+                    state_changed_at: config.clock.now(),
+                    last_game_packet: config.clock.now(),
+                    high_latency_since: None,
+                },
+                state: stream.state(),
+                compression: CompressionAlgorithm::default(),
+                rakstream: stream,
+            },
+        });
+
+        if let Some(status) = self.status {
+            world.entity_mut(id).insert(status.clone());
+            world.send_event(RakNetEvent::PongReceived(self.remote_addr, status));
+        }
+
+        if let Some(event) = state_changed {
+            world.send_event(event);
+        }
+    }
+}
+
+/// HandshakePool performs the offline handshakes of the upstream connections of the proxy and of the punched
+/// connections on a fixed number of threads, so that the App is not blocked while the other ends are waited for and a
+/// burst of downstream clients cannot spawn a thread each. The handshakes that find every thread busy wait for one of
+/// them to be free.
+#[derive(Resource)]
+pub(crate) struct HandshakePool {
+    jobs: Sender<HandshakeJob>,
+}
+
+/// HandshakeJob is a handshake queued on the HandshakePool along with the channel that its outcome is sent on.
+struct HandshakeJob {
+    addr: String,
+    transport: Option<Arc<dyn Transport>>,
+    forwarded: Option<(SocketAddr, Vec<u8>)>,
+    outcome: Sender<Result<Handshake>>,
+}
+
+impl HandshakePool {
+    /// Creates a new HandshakePool and spawns the provided number of threads, at least one, for it.
+    pub(crate) fn new(threads: usize) -> Self {
+        let (jobs, queue) = mpsc::channel::<HandshakeJob>();
+        let queue = Arc::new(Mutex::new(queue));
+
+        for _ in 0..threads.max(1) {
+            let queue = queue.clone();
+
+            // The threads exit once the pool is dropped and its queue has been drained.
+            thread::spawn(move || loop {
+                let Ok(job) = queue.lock().unwrap().recv() else {
+                    break;
+                };
+
+                let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+                    let socket = match job.transport {
+                        Some(transport) => RakSocket::with_transport(transport),
+                        None => RakSocket::bind_client(&job.addr)?,
+                    };
+
+                    RakSocket::handshake(socket, &job.addr, job.forwarded)
+                }))
+                .unwrap_or_else(|_| Err(Error::new(ErrorKind::Other, "The handshake panicked")));
+
+                let _ = job.outcome.send(outcome);
+            });
+        }
+
+        Self { jobs }
+    }
+
+    /// Queues the offline handshake with the specified address, forwarding the provided client address signed with
+    /// the provided secret if there is one. The entity of the connection is built from the Handshake received on the
+    /// returned channel once it has completed.
+    pub(crate) fn handshake(
+        &self,
+        addr: &str,
+        forwarded: Option<(SocketAddr, Vec<u8>)>,
+    ) -> Receiver<Result<Handshake>> {
+        let (outcome, receiver) = mpsc::channel();
+
+        let _ = self.jobs.send(HandshakeJob {
+            addr: addr.to_string(),
+            transport: None,
+            forwarded,
+            outcome,
+        });
+
+        receiver
+    }
+
+    /// Queues the offline handshake with the provided address over the provided transport instead of a new socket.
+    pub(crate) fn handshake_with(
+        &self,
+        transport: Arc<dyn Transport>,
+        addr: SocketAddr,
+    ) -> Receiver<Result<Handshake>> {
+        let (outcome, receiver) = mpsc::channel();
+
+        let _ = self.jobs.send(HandshakeJob {
+            addr: addr.to_string(),
+            transport: Some(transport),
+            forwarded: None,
+            outcome,
+        });
+
+        receiver
+    }
+}
+
+/// Returns the outcome of the handshake queued on the HandshakePool the first time it is called once the handshake
+/// has completed, the channel is dropped afterwards.
+pub(crate) fn poll_handshake(
+    outcome: &mut Option<Receiver<Result<Handshake>>>,
+) -> Option<Result<Handshake>> {
+    let result = match outcome.as_ref()?.try_recv() {
+        Ok(result) => result,
+        Err(TryRecvError::Empty) => return None,
+        Err(TryRecvError::Disconnected) => {
+            Err(Error::new(ErrorKind::Other, "The handshake was abandoned"))
+        }
+    };
+
+    *outcome = None;
+    Some(result)
+}
+
+/// RakSocket is built on top of a Transport, the UdpSocket by default, and handles the reading and writing of unconnected messages
+/// from/to the other end of the connection. It handles the login sequence of clients (logging into a server) and server (for clients
+/// logging into it).
 #[derive(Component)]
 pub struct RakSocket {
-    pub udp: Arc<UdpSocket>,
+    pub transport: Arc<dyn Transport>,
     pub read_buf: BytesMut,
     pub write_buf: BytesMut,
 }
@@ -111,31 +464,86 @@ impl RakSocket {
             Ok(socket) => {
                 socket.set_nonblocking(non_blocking).unwrap();
 
-                Ok(Self {
-                    udp: socket.into(),
-                    read_buf: BytesMut::zeroed(MAX_MTU_SIZE),
-                    write_buf: BytesMut::with_capacity(MAX_MTU_SIZE),
-                })
+                Ok(Self::with_transport(Arc::new(socket)))
             }
             Err(e) => Err(e),
         }
     }
 
+    /// Creates a RakSocket that sends and receives its datagrams on the provided transport.
+    pub fn with_transport(transport: Arc<dyn Transport>) -> Self {
+        Self {
+            transport,
+            read_buf: BytesMut::zeroed(MAX_MTU_SIZE),
+            write_buf: BytesMut::with_capacity(MAX_MTU_SIZE),
+        }
+    }
+
     /// Connects to the specified address running a RakNet server. If successful, it spawns an entity from the StreamBundle
     /// and returns it'd ID.
     pub fn connect(addr: &str, world: &mut World) -> Result<Entity> {
-        // Creates a new RakSocket and binds it on any random port with blocking mode.
-        let mut socket = RakSocket::new("127.0.0.1:0", false)?;
-        let local_addr = socket.udp.local_addr().unwrap();
-        let remote_addr: SocketAddr = SocketAddr::from_str(addr).unwrap();
-
-        // Configure the socket to have a read delay of 1 second so it could be useful when discovering the MTU size of
-        // the connection later and in general is helpful.
-        socket.udp.connect(remote_addr)?;
-        socket
-            .udp
-            .set_read_timeout(Some(Duration::from_secs(1)))
-            .unwrap();
+        Ok(Self::handshake(Self::bind_client(addr)?, addr, None)?.spawn(world))
+    }
+
+    /// Connects to the specified address running a RakNet server on behalf of the provided client address, which is
+    /// sent in a ProxyHeader message signed with the proxy secret of the RakNetConfig ahead of the handshake. The
+    /// server only uses it if it trusts this proxy and shares its secret.
+    pub fn connect_forwarded(addr: &str, client: SocketAddr, world: &mut World) -> Result<Entity> {
+        let secret = world
+            .get_resource::<RakNetConfig>()
+            .and_then(|config| config.proxy_secret.clone())
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    "Forwarding the client address requires a proxy secret",
+                )
+            })?;
+
+        Ok(Self::handshake(Self::bind_client(addr)?, addr, Some((client, secret)))?.spawn(world))
+    }
+
+    /// Connects to the specified address running a RakNet server over the provided transport instead of a UdpSocket.
+    pub fn connect_with(
+        transport: Arc<dyn Transport>,
+        addr: &str,
+        world: &mut World,
+    ) -> Result<Entity> {
+        Ok(Self::handshake(RakSocket::with_transport(transport), addr, None)?.spawn(world))
+    }
+
+    /// Sends an AdvertiseSystem message with the provided payload to the provided address, which does not have to be
+    /// connected. The payload cannot exceed MAX_OFFLINE_DATA_LENGTH.
+    pub fn advertise(&mut self, addr: SocketAddr, data: &[u8]) -> Result<()> {
+        if data.len() > MAX_OFFLINE_DATA_LENGTH {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "The advertised payload exceeds the maximum offline data length",
+            ));
+        }
+
+        self.write_to(
+            addr,
+            Message::AdvertiseSystem {
+                data: UnsizedBytes::new(data),
+            },
+        )
+    }
+
+    /// Creates a new RakSocket for a client connection to the provided address, bound on any random port of the
+    /// unspecified address of its family so that it can reach servers on other hosts as well.
+    pub(crate) fn bind_client(addr: &str) -> Result<Self> {
+        RakSocket::new(&unspecified(parse_addr(addr)?).to_string(), true)
+    }
+
+    /// Performs the offline handshake with the specified address, which blocks until the server has answered. The
+    /// forwarded client address is signed with the secret that comes along with it.
+    fn handshake(
+        mut socket: RakSocket,
+        addr: &str,
+        forwarded: Option<(SocketAddr, Vec<u8>)>,
+    ) -> Result<Handshake> {
+        let local_addr = socket.transport.local_addr()?;
+        let remote_addr = parse_addr(addr)?;
 
         // We try to send a Unconnected Ping message to the other end of the connection to get it's status, MOTD, and to check if it's alive.
         let guid = rand::random();
@@ -145,10 +553,11 @@ impl RakSocket {
             client_guid: I64::new(guid),
         };
 
-        socket.write(msg)?;
+        socket.write_to(remote_addr, msg)?;
 
-        // Wait for an UnconnectedPong message from the other end, return if no message is received
-        match socket.read()? {
+        // Wait for an UnconnectedPong message from the other end, return if no message is received. Servers that are
+        // not Minecraft servers may send any payload, so a status that fails to parse does not abort the connection.
+        let status = match socket.read_from(remote_addr)? {
             Message::UnconnectedPong {
                 send_timestamp: _,
                 server_guid: _,
@@ -156,6 +565,14 @@ impl RakSocket {
                 data,
             } => {
                 debug!("Connecting to {:?}", data);
+
+                match ServerStatus::parse(data.0) {
+                    Ok(status) => Some(status),
+                    Err(e) => {
+                        debug!("[Status Error]: {}", e.to_string());
+                        None
+                    }
+                }
             }
             _ => {
                 return Err(Error::new(
@@ -163,26 +580,52 @@ impl RakSocket {
                     "Expected UnconnectedPong message from the other end of the connection",
                 ))
             }
-        }
+        };
 
         // We try to discuss the MTU size of the other end of the connection. In order to do that, we send an
-        // empty buffer of size equivalent to the MAX_MTU_SIZE - 46 (28 UDP Overhead, 1 packet ID, 16 magic, 1 protocol version).
-        // This padding is decreased every second by cpnfigured rate to be able to discover the maximum size of datagram the server can handle.
-        let mut mtu_size = MAX_MTU_SIZE;
+        // empty buffer of size equivalent to the MTU size of the tier - 46 (28 UDP Overhead, 1 packet ID, 16 magic,
+        // 1 protocol version). Every tier is retried a few times before falling back to the next smaller tier so
+        // that we discover the maximum size of datagram the server can handle.
+        let mut discovered = None;
 
-        loop {
-            let size = mtu_size - UDP_HEADER_SIZE - 16 - 1 - 1;
-            let emptybytes = BytesMut::zeroed(size);
+        'discovery: for tier in CLIENT_MTU_TIERS {
+            for _ in 0..CLIENT_MTU_RETRIES {
+                let size = tier - UDP_HEADER_SIZE - 16 - 1 - 1;
+                let emptybytes = BytesMut::zeroed(size);
 
-            let msg = Message::OpenConnectionRequest1 {
-                magic: Magic,
-                protocol: U8::new(PROTOCOL_VERSION),
-                emptybuf: UnsizedBytes::new(&emptybytes),
-            };
+                // The header is sent ahead of every attempt as the server drops the ones that are lost.
+                if let Some((client, secret)) = &forwarded {
+                    let timestamp = proxy_timestamp();
+                    let nonce = rand::random();
+
+                    socket.write_to(
+                        remote_addr,
+                        Message::ProxyHeader {
+                            magic: Magic,
+                            client_address: UDPAddress(*client),
+                            timestamp: I64::new(timestamp),
+                            nonce: I64::new(nonce),
+                            signature: ProxySignature(proxy_signature(
+                                secret, *client, timestamp, nonce,
+                            )),
+                        },
+                    )?;
+                }
+
+                let msg = Message::OpenConnectionRequest1 {
+                    magic: Magic,
+                    protocol: U8::new(PROTOCOL_VERSION),
+                    emptybuf: UnsizedBytes::new(&emptybytes),
+                };
 
-            socket.write(msg)?;
+                socket.write_to(remote_addr, msg)?;
+
+                // No response within the read timeout means the datagram was too large for the route to the
+                // server or was lost, either way we try again.
+                let Ok(msg) = socket.read_from(remote_addr) else {
+                    continue;
+                };
 
-            if let Ok(msg) = socket.read() {
                 match msg {
                     Message::OpenConnectionReply1 {
                         magic,
@@ -190,7 +633,16 @@ impl RakSocket {
                         secure: _,
                         server_mtu,
                     } => {
-                        mtu_size = server_mtu.0 as usize;
+                        // The datagrams of an MTU size below the minimum would not even fit their own headers.
+                        let mtu_size = server_mtu.0 as usize;
+                        if !(MIN_MTU_SIZE..=MAX_MTU_SIZE).contains(&mtu_size) {
+                            return Err(Error::new(
+                                ErrorKind::InvalidData,
+                                "The other end of the connection replied with an invalid MTU size",
+                            ));
+                        }
+
+                        discovered = Some(mtu_size);
 
                         // Write the OpenConnectionRequest2 message to the other end of the connection.
                         let msg = Message::OpenConnectionRequest2 {
@@ -199,9 +651,15 @@ impl RakSocket {
                             client_mtu: server_mtu,
                             client_guid: I64::new(guid),
                         };
-                        socket.write(msg)?;
+                        socket.write_to(remote_addr, msg)?;
 
-                        break;
+                        break 'discovery;
+                    }
+                    Message::AlreadyConnected { .. } => {
+                        return Err(Error::new(
+                            ErrorKind::ConnectionRefused,
+                            "The other end of the connection already has a connection from this address",
+                        ))
                     }
                     _ => {
                         return Err(Error::new(
@@ -210,111 +668,185 @@ impl RakSocket {
                         ))
                     }
                 }
-            };
-
-            mtu_size -= CLIENT_PADDING_DECREASE;
+            }
         }
 
-        // Expect a OpenConnectionReply2 message from the other end of the connection.
-        match socket.read()? {
-            Message::OpenConnectionReply2 {
-                magic: _,
-                server_guid: _,
-                client_address: _,
-                mtu_size: _,
-                secure: _,
-            } => {}
-            _ => {
+        let Some(mtu_size) = discovered else {
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                "The other end of the connection did not respond to any of the MTU discovery tiers",
+            ));
+        };
+
+        // Expect a OpenConnectionReply2 message from the other end of the connection, which may refuse the
+        // connection instead. The late replies to the earlier OpenConnectionRequest1 retries are skipped.
+        let deadline = Instant::now() + HANDSHAKE_READ_TIMEOUT;
+        let reply = loop {
+            match socket.read_until(remote_addr, deadline)? {
+                Message::OpenConnectionReply1 { .. } => continue,
+                reply => break reply,
+            }
+        };
+
+        match reply {
+            Message::ConnectionBanned { .. } => {
                 return Err(Error::new(
-                    ErrorKind::Other,
-                    "Expected OpenConnectionReply2 message from the other end of the connection",
+                    ErrorKind::ConnectionRefused,
+                    "The other end of the connection refused the connection",
+                ))
+            }
+            Message::NoFreeIncomingConnections { .. } => {
+                return Err(Error::new(
+                    ErrorKind::ConnectionRefused,
+                    "The other end of the connection has no free incoming connections",
+                ))
+            }
+            Message::AlreadyConnected { .. } => {
+                return Err(Error::new(
+                    ErrorKind::ConnectionRefused,
+                    "The other end of the connection already has a connection with the same GUID",
                 ))
             }
+            _ => {}
         }
 
-        let udp = socket.udp.clone();
-        let id = world
-            .spawn(ClientBundle {
-                socket,
-                info: SocketInfo {
-                    addr: local_addr,
-                    guid,
-                },
-                stream: StreamBundle {
-                    info: NetworkInfo {
-                        local_addr,
-                        remote_addr,
-                    },
-                    status: NetworkStatus {
-                        ping: 0,
-                        latency: Duration::from_secs(0),
-                        last_activity: Instant::now(),
-                    },
-                    rakstream: RakStream::new(remote_addr, udp, mtu_size),
-                },
-            })
-            .id();
+        let Message::OpenConnectionReply2 {
+            magic: _,
+            server_guid,
+            client_address: _,
+            mtu_size: _,
+            secure: _,
+        } = reply
+        else {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "Expected OpenConnectionReply2 message from the other end of the connection",
+            ));
+        };
 
-        Ok(id)
+        Ok(Handshake {
+            socket,
+            local_addr,
+            remote_addr,
+            guid,
+            server_guid: server_guid.0,
+            mtu_size,
+            status,
+        })
     }
 
-    /// Check if the sender is blocked or not. Unblocks the sender if the block duration has been achieved.
-    /// Returns true if the sender is still blocked.
-    pub fn is_blocked(&mut self, addr: SocketAddr, mappings: &mut Mappings) -> bool {
-        if let Some(expiry) = mappings.blocked.get(&addr) {
-            if expiry > &unix_timestamp() {
-                return true;
-            }
+    /// Checks if the connected sender does not exceed the maximum number of packets per second. Returns true
+    /// if the number of packets exceed the allowed, the sender is blocked then.
+    pub fn check_packet_spam(
+        &mut self,
+        addr: SocketAddr,
+        mappings: &mut Mappings,
+        block_list: &mut BlockList,
+        config: &RakNetConfig,
+    ) -> bool {
+        let now = config.clock.now();
 
-            mappings.blocked.remove(&addr);
+        if count(&mut mappings.packets_per_sec, addr, now) == config.max_msgs_per_sec {
+            mappings.packets_per_sec.remove(&addr);
+            block_list.block_offender(
+                addr,
+                config.block_duration,
+                &config.block_escalation,
+                BlockReason::PacketSpam,
+            );
+            return true;
         }
 
         return false;
     }
 
-    /// Checks if the sender does not exceed the maximum number of packets per second. Returns true
-    /// if the number of packets exceed the allowed.
-    pub fn check_packet_spam(&mut self, addr: SocketAddr, mappings: &mut Mappings) -> bool {
-        let (mut instant, mut packets) = mappings
-            .packets_per_sec
-            .remove(&addr)
-            .unwrap_or((Instant::now(), 0));
-
-        if instant.elapsed().as_millis() < 1000 {
-            packets += 1;
+    /// Checks if the sender that is not connected does not exceed the maximum number of unconnected messages per
+    /// second, which are counted apart so that being scanned cannot get the address of a player blocked. Returns true
+    /// if the number of messages exceed the allowed, the sender is blocked then.
+    pub fn check_unconnected_spam(
+        &mut self,
+        addr: SocketAddr,
+        mappings: &mut Mappings,
+        block_list: &mut BlockList,
+        config: &RakNetConfig,
+    ) -> bool {
+        let now = config.clock.now();
 
-            if packets == MAX_MSGS_PER_SEC {
-                self.block(addr, mappings);
-                return true;
-            }
-        } else {
-            instant = Instant::now();
-            packets = 0;
+        if count(&mut mappings.unconnected_per_sec, addr, now) == config.max_unconnected_per_sec {
+            mappings.unconnected_per_sec.remove(&addr);
+            block_list.block_offender(
+                addr,
+                config.unconnected_block_duration,
+                &config.block_escalation,
+                BlockReason::UnconnectedFlood,
+            );
+            return true;
         }
 
-        mappings.packets_per_sec.insert(addr, (instant, packets));
         return false;
     }
 
+    /// Checks if the sender can be sent another Unconnected Pong within the current second. Returns false if the
+    /// sender or all the senders together have already been sent the maximum number of pongs, the ping is dropped
+    /// without blocking the sender then, as the pongs are larger than the pings they answer.
+    pub fn allow_pong(
+        &mut self,
+        addr: SocketAddr,
+        mappings: &mut Mappings,
+        config: &RakNetConfig,
+    ) -> bool {
+        let now = config.clock.now();
+        let (mut instant, mut pongs) = mappings.pongs_per_sec.remove(&addr).unwrap_or((now, 0));
+
+        if now.saturating_duration_since(instant).as_millis() >= 1000 {
+            instant = now;
+            pongs = 0;
+        }
+
+        let (global_instant, global_pongs) = &mut mappings.pongs_this_sec;
+        if global_instant.map_or(true, |i| {
+            now.saturating_duration_since(i).as_millis() >= 1000
+        }) {
+            *global_instant = Some(now);
+            *global_pongs = 0;
+        }
+
+        let allowed =
+            pongs < config.max_pongs_per_sec && *global_pongs < config.max_global_pongs_per_sec;
+        if allowed {
+            pongs += 1;
+            *global_pongs += 1;
+        }
+
+        mappings.pongs_per_sec.insert(addr, (instant, pongs));
+        allowed
+    }
+
     /// Checks if the sender exceeds the maximum number of invalid packets. Blocks the sender if it exceeds
     /// the allowed limit.
-    pub fn check_invalid_packets(&mut self, addr: SocketAddr, mappings: &mut Mappings) {
-        let invalid_packets = mappings.invalid_packets.get(&addr).unwrap_or(&0) + 1;
+    pub fn check_invalid_packets(
+        &mut self,
+        addr: SocketAddr,
+        mappings: &mut Mappings,
+        block_list: &mut BlockList,
+        config: &RakNetConfig,
+    ) {
+        let now = config.clock.now();
+        let (_, invalid_packets) = mappings.invalid_packets.remove(&addr).unwrap_or((now, 0));
 
-        if invalid_packets == MAX_INVALID_MSGS {
-            mappings.invalid_packets.remove(&addr);
-            self.block(addr, mappings);
+        if invalid_packets + 1 == config.max_invalid_msgs {
+            block_list.block_offender(
+                addr,
+                config.block_duration,
+                &config.block_escalation,
+                BlockReason::InvalidPackets,
+            );
             return;
         }
 
-        mappings.invalid_packets.insert(addr, invalid_packets);
-    }
-
-    /// Blocks a provided IP address for the specified reason and writes an event to the Bevy Runtime.
-    pub fn block(&mut self, addr: SocketAddr, mappings: &mut Mappings) {
         mappings
-            .blocked
-            .insert(addr, unix_timestamp() + RAKNET_BLOCK_DUR.as_secs());
+            .invalid_packets
+            .insert(addr, (now, invalid_packets + 1));
     }
 
     /// Checks if the message received on the buffer is a Connected Message. Returns whether the message was a connected
@@ -325,25 +857,59 @@ impl RakSocket {
         len: usize,
         query: &mut Query<&mut RakStream>,
         ev: &mut EventWriter<RakNetEvent>,
+        interceptors: &mut MessageInterceptors,
         mappings: &mut Mappings,
+        entities: &Entities,
+        config: &RakNetConfig,
     ) -> bool {
-        if let Some(entity) = mappings.connections.get(&addr) {
-            if let Ok(mut stream) = query.get_mut(*entity) {
-                if let Err(e) = stream.decode(&self.read_buf[..len], ev, *entity) {
-                    debug!("[Network Error] {}", e.to_string());
+        let Some(entity) = mappings.connection(&addr) else {
+            // The other end of a connection that was despawned recently has most likely not noticed it yet, its
+            // datagrams are dropped until it times out while its offline messages are still handled.
+            return len > 0 && self.read_buf[0] & FLAG_DATAGRAM != 0 && mappings.is_closed(&addr);
+        };
+
+        if let Ok(mut stream) = query.get_mut(entity) {
+            if let Err(e) = stream.decode(&self.read_buf[..len], ev, interceptors, entity) {
+                debug!("[Network Error] {}", e.to_string());
 
-                    ev.send(RakNetEvent::MalformedPackets(*entity));
+                if config.dump_malformed_packets {
+                    ev.send(RakNetEvent::MalformedPacketDump(
+                        entity,
+                        self.read_buf[..len].to_vec(),
+                        e.to_string(),
+                    ));
                 }
 
-                return true;
+                ev.send(RakNetEvent::MalformedPackets(entity));
             }
 
-            // Remove the entry because the entity did not exist.
-            mappings.connections.remove(&addr);
             return true;
         }
 
-        false
+        // The entity is either despawned or it was spawned during this run and does not exist yet. The message is
+        // dropped either way rather than handled as an unconnected one, which would count it as invalid and could
+        // spawn a second entity for the same address. The mapping of a despawned entity is removed right away so
+        // that the address can connect again.
+        if !entities.contains(entity) {
+            mappings.connections.remove(&addr);
+            mappings.closed.insert(addr, config.clock.now());
+        }
+
+        true
+    }
+
+    /// Writes all the datagrams queued by the connections of this socket to the other end of the
+    /// respective connections.
+    pub fn flush_connections(&mut self, query: &mut Query<&mut RakStream>, mappings: &Mappings) {
+        for (addr, entity) in mappings.connections.iter() {
+            if let Ok(mut stream) = query.get_mut(*entity) {
+                while let Some(datagram) = stream.poll_outgoing() {
+                    if let Err(e) = self.transport.send_to(&datagram, *addr) {
+                        debug!("[Network Error] {}", e.to_string());
+                    }
+                }
+            }
+        }
     }
 
     /// Handles an unconnected message received on the buffer.
@@ -351,42 +917,47 @@ impl RakSocket {
         &mut self,
         addr: SocketAddr,
         len: usize,
-        status: &str,
+        status: &PongStatus,
         commands: &mut Commands,
         ev: &mut EventWriter<RakNetEvent>,
         info: &SocketInfo,
         mappings: &mut Mappings,
+        filters: &mut ConnectionFilters,
+        whitelist: &Whitelist,
+        maintenance: &Maintenance,
+        config: &RakNetConfig,
     ) -> Result<()> {
         let mut reader = Cursor::new(&self.read_buf[..len]);
         let message = Message::deserialize(&mut reader)?;
 
-        trace!("[+] {:?} {:?}", addr, message);
+        let _span = debug_span!("raknet", remote = %addr).entered();
+        trace!(?message, "Received unconnected message");
 
         match message {
             Message::UnconnectedPing {
                 send_timestamp,
                 magic,
                 client_guid: _,
-            } => {
-                let resp = Message::UnconnectedPong {
-                    send_timestamp,
-                    server_guid: I64::new(info.guid),
-                    magic,
-                    data: Str::new(status),
-                };
-
-                self.write_to(addr, resp)?;
             }
-            Message::UnconnectedPingOpenConnections {
+            | Message::UnconnectedPingOpenConnections {
                 send_timestamp,
                 magic,
                 client_guid: _,
             } => {
+                if !self.allow_pong(addr, mappings, config) {
+                    trace!("Dropped unconnected ping above the pong rate limit");
+                    return Ok(());
+                }
+
+                let Some(status) = status.payload(addr) else {
+                    return Ok(());
+                };
+
                 let resp = Message::UnconnectedPong {
                     send_timestamp,
                     server_guid: I64::new(info.guid),
                     magic,
-                    data: Str::new(status),
+                    data: Str::new(&status),
                 };
 
                 self.write_to(addr, resp)?;
@@ -396,9 +967,27 @@ impl RakSocket {
                 protocol,
                 emptybuf: _,
             } => {
-                let mut server_mtu = reader.get_ref().len() + UDP_HEADER_SIZE;
-                if server_mtu > MAX_MTU_SIZE {
-                    server_mtu = MAX_MTU_SIZE;
+                if !filters.allow(addr) {
+                    ev.send(RakNetEvent::ConnectionFiltered(addr));
+                    return Ok(());
+                }
+
+                // Packet ID (u8), Magic (16 bytes) and Protocol Version (u8) are followed by the padding. The replies
+                // are larger than a request without padding, so the undersized requests are dropped without a reply
+                // as they are likely spoofed to use the server for amplification.
+                let padding = reader.get_ref().len().saturating_sub(1 + 16 + 1);
+                if padding == 0 || padding < config.min_request_padding {
+                    trace!(padding, "Dropped undersized OpenConnectionRequest1");
+                    return Ok(());
+                }
+
+                let server_mtu = reader.get_ref().len() + UDP_HEADER_SIZE;
+
+                if !(config.min_mtu_size.get()..=MAX_MTU_SIZE).contains(&server_mtu) {
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        "OpenConnectionRequest1 is padded outside of the allowed MTU size range",
+                    ));
                 }
 
                 if protocol.0 != PROTOCOL_VERSION {
@@ -423,41 +1012,159 @@ impl RakSocket {
                 ev.send(RakNetEvent::ConnectionRequest(addr));
             }
             Message::OpenConnectionRequest2 {
-                magic,
+                magic: _,
                 server_address,
                 client_mtu,
-                client_guid: _,
+                client_guid,
             } => {
                 let mut mtu_size = client_mtu.0 as usize;
                 if mtu_size > MAX_MTU_SIZE {
                     mtu_size = MAX_MTU_SIZE
                 }
 
-                let resp = Message::OpenConnectionReply2 {
-                    magic,
-                    server_guid: I64::new(info.guid),
-                    client_address: UDPAddress(addr),
-                    mtu_size: U16::new(mtu_size as u16),
-                    secure: Bool::new(false),
+                if mtu_size < config.min_mtu_size.get() {
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        "Client MTU size is below the minimum MTU size",
+                    ));
+                }
+
+                // The clients connecting through a trusted proxy are filtered by their forwarded address, the proxy
+                // itself is never taken for the client.
+                let remote_addr = match mappings.forwarded.get(&addr) {
+                    Some((_, client)) => *client,
+                    None if config.trusted_proxies.contains(&addr.ip()) => {
+                        return Err(Error::new(
+                            ErrorKind::Other,
+                            "Handshake of a trusted proxy received without a ProxyHeader",
+                        ));
+                    }
+                    None => addr,
                 };
 
-                self.write_to(addr, resp)?;
+                if !filters.allow(remote_addr) {
+                    ev.send(RakNetEvent::ConnectionFiltered(remote_addr));
+                    return Ok(());
+                }
 
-                let entity = commands.spawn(StreamBundle {
-                    info: NetworkInfo {
-                        local_addr: server_address.0,
-                        remote_addr: addr,
-                    },
-                    status: NetworkStatus {
-                        ping: 0,
-                        latency: Duration::from_secs(0),
-                        last_activity: Instant::now(),
-                    },
-                    rakstream: RakStream::new(addr, self.udp.clone(), mtu_size),
-                });
+                if maintenance.is_enabled() {
+                    ev.send(RakNetEvent::RefusedForMaintenance(
+                        remote_addr,
+                        client_guid.0,
+                    ));
+                    return self.refuse(addr, info, maintenance.refusal());
+                }
+
+                if !whitelist.allows(remote_addr, client_guid.0) {
+                    ev.send(RakNetEvent::NotWhitelisted(remote_addr, client_guid.0));
+                    return self.refuse(addr, info, whitelist.refusal());
+                }
+
+                // The GUIDs of the connections that were despawned since the last cleanup are free again, the ones
+                // spawned during this run are already reserved.
+                let existing = mappings
+                    .guids
+                    .get(&client_guid.0)
+                    .copied()
+                    .filter(|entity| commands.get_entity(*entity).is_some());
+
+                if let Some(existing) = existing {
+                    ev.send(RakNetEvent::DuplicateGuid(
+                        remote_addr,
+                        client_guid.0,
+                        existing,
+                    ));
+
+                    match config.duplicate_login_policy {
+                        DuplicateLoginPolicy::RejectNew => {
+                            return self.refuse(addr, info, Refusal::AlreadyConnected);
+                        }
+                        DuplicateLoginPolicy::KickExisting => {
+                            ev.send(RakNetEvent::DisconnectRequest(
+                                existing,
+                                DisconnectReason::DuplicateLogin,
+                            ));
+                        }
+                        DuplicateLoginPolicy::AllowBoth => {}
+                    }
+                }
+
+                let pending = PendingConnection {
+                    requested_at: config.clock.now(),
+                    local_addr: server_address.0,
+                    remote_addr,
+                    mtu_size,
+                    guid: client_guid.0,
+                };
+
+                if !config.connection_approval {
+                    return self.accept(addr, pending, commands, ev, info, mappings, config);
+                }
 
-                mappings.connections.insert(addr, entity.id());
-                info!("Spawned Entity: {:?}", entity.id().index());
+                // The retries of the request are ignored while the approval of the first one is pending.
+                if !mappings.approvals.contains_key(&addr) {
+                    ev.send(RakNetEvent::ApprovalRequested(
+                        addr,
+                        remote_addr,
+                        client_guid.0,
+                    ));
+                    mappings.approvals.insert(addr, pending);
+                }
+            }
+            Message::ProxyHeader {
+                magic: _,
+                client_address,
+                timestamp,
+                nonce,
+                signature,
+            } => {
+                if !config.trusted_proxies.contains(&addr.ip()) {
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        "ProxyHeader received from an untrusted address",
+                    ));
+                }
+
+                // The source address of a UDP datagram is easily spoofed, so the header must also be signed with the
+                // secret shared with the proxy.
+                let Some(secret) = &config.proxy_secret else {
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        "ProxyHeader received without a proxy secret to verify it",
+                    ));
+                };
+
+                if !verify_proxy_signature(
+                    secret,
+                    client_address.0,
+                    timestamp.0,
+                    nonce.0,
+                    &signature.0,
+                ) {
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        "ProxyHeader has an invalid signature",
+                    ));
+                }
+
+                if proxy_header_expired(timestamp.0) {
+                    return Err(Error::new(ErrorKind::Other, "ProxyHeader has expired"));
+                }
+
+                // Every header carries its own nonce, so one that was already seen within its lifetime is a replay.
+                if !mappings.proxy_nonces.insert((timestamp.0, nonce.0)) {
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        "ProxyHeader has already been used",
+                    ));
+                }
+
+                mappings
+                    .forwarded
+                    .insert(addr, (config.clock.now(), client_address.0));
+            }
+            Message::AdvertiseSystem { data } => {
+                ev.send(RakNetEvent::SystemAdvertised(addr, data.to_vec()));
             }
             _ => {}
         }
@@ -465,28 +1172,268 @@ impl RakSocket {
         Ok(())
     }
 
-    /// Reads an unconnected message from the connected stream.
-    fn read(&mut self) -> Result<Message> {
-        let len = self.udp.recv(&mut self.read_buf)?;
-        let mut reader = Cursor::new(&self.read_buf[..len]);
-        Message::deserialize(&mut reader)
+    /// Reads an unconnected message sent by the provided address. It waits up to HANDSHAKE_READ_TIMEOUT for the
+    /// message and discards the datagrams received from any other address and the NAT punches meanwhile.
+    fn read_from(&mut self, addr: SocketAddr) -> Result<Message> {
+        self.read_until(addr, Instant::now() + HANDSHAKE_READ_TIMEOUT)
     }
 
-    /// Writes an unconnected message to the connected stream.
-    fn write(&mut self, message: Message) -> Result<()> {
-        message.serialize(&mut self.write_buf);
-        self.udp.send(&self.write_buf)?;
-        self.write_buf.clear();
+    /// Reads an unconnected message sent by the provided address, waiting for it until the provided deadline.
+    fn read_until(&mut self, addr: SocketAddr, deadline: Instant) -> Result<Message> {
+        loop {
+            let discarded = match self.transport.recv_from(&mut self.read_buf) {
+                Ok((len, _)) if len > 0 && self.read_buf[0] == NAT_ESTABLISH_PACKET_ID => true,
+                Ok((len, from)) if from == addr => {
+                    let mut reader = Cursor::new(&self.read_buf[..len]);
+                    return Message::deserialize(&mut reader);
+                }
+                Ok(_) => true,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => false,
+                Err(e) => return Err(e),
+            };
+
+            if Instant::now() >= deadline {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    "The other end of the connection did not respond in time",
+                ));
+            }
+
+            if !discarded {
+                thread::sleep(HANDSHAKE_POLL_INTERVAL);
+            }
+        }
+    }
+
+    /// Answers the OpenConnectionRequest2 of the provided address and spawns the entity of its connection.
+    pub(crate) fn accept(
+        &mut self,
+        addr: SocketAddr,
+        pending: PendingConnection,
+        commands: &mut Commands,
+        ev: &mut EventWriter<RakNetEvent>,
+        info: &SocketInfo,
+        mappings: &mut Mappings,
+        config: &RakNetConfig,
+    ) -> Result<()> {
+        let resp = Message::OpenConnectionReply2 {
+            magic: Magic,
+            server_guid: I64::new(info.guid),
+            client_address: UDPAddress(addr),
+            mtu_size: U16::new(pending.mtu_size as u16),
+            secure: Bool::new(false),
+        };
+
+        self.write_to(addr, resp)?;
+        mappings.forwarded.remove(&addr);
+
+        let mut entity = commands.spawn(StreamBundle {
+            name: Name::new(format!("raknet {}", pending.remote_addr)),
+            info: NetworkInfo {
+                local_addr: pending.local_addr,
+                remote_addr: pending.remote_addr,
+                mtu_size: pending.mtu_size,
+                guid: pending.guid,
+            },
+            status: NetworkStatus {
+                ping: 0,
+                latency: Duration::from_secs(0),
+                last_activity: config.clock.now(),
+                state_changed_at: config.clock.now(),
+                last_game_packet: config.clock.now(),
+                high_latency_since: None,
+            },
+            state: ConnectionState::AwaitingConnectionRequest,
+            compression: CompressionAlgorithm::default(),
+            rakstream: RakStream::new(addr, pending.mtu_size, config),
+        });
+
+        // The MCPE login is opt-in, the connections of a server that does not speak it never wait for a login.
+        if config.mcpe_login {
+            entity.insert(LoginState::default());
+        }
+
+        ev.send(RakNetEvent::StateChanged(
+            entity.id(),
+            ConnectionState::AwaitingConnectionRequest,
+        ));
+        mappings.connections.insert(addr, entity.id());
+        mappings.closed.remove(&addr);
+        mappings.guids.insert(pending.guid, entity.id());
+        info!(entity = ?entity.id(), "Spawned connection");
 
         Ok(())
     }
 
+    /// Refuses the OpenConnectionRequest2 of the provided address with the message of the provided Refusal.
+    pub(crate) fn refuse(
+        &mut self,
+        addr: SocketAddr,
+        info: &SocketInfo,
+        refusal: Refusal,
+    ) -> Result<()> {
+        let resp = match refusal {
+            Refusal::Banned => Message::ConnectionBanned {
+                magic: Magic,
+                server_guid: I64::new(info.guid),
+            },
+            Refusal::ServerFull => Message::NoFreeIncomingConnections {
+                magic: Magic,
+                server_guid: I64::new(info.guid),
+            },
+            Refusal::AlreadyConnected => Message::AlreadyConnected {
+                magic: Magic,
+                server_guid: I64::new(info.guid),
+            },
+        };
+
+        self.write_to(addr, resp)
+    }
+
     /// Writes an unconnected message to the provided address and flushes it immediately.
     fn write_to(&mut self, addr: SocketAddr, message: Message) -> Result<()> {
         message.serialize(&mut self.write_buf);
-        self.udp.send_to(&self.write_buf, addr)?;
+        self.transport.send_to(&self.write_buf, addr)?;
         self.write_buf.clear();
 
         Ok(())
     }
 }
+
+/// Counts another message of the provided address within its current one second window and returns the number of
+/// messages it has sent within the window.
+fn count(window: &mut HashMap<SocketAddr, (Instant, u32)>, addr: SocketAddr, now: Instant) -> u32 {
+    let (instant, count) = window.entry(addr).or_insert((now, 0));
+
+    if now.saturating_duration_since(*instant).as_millis() >= 1000 {
+        *instant = now;
+        *count = 0;
+    }
+
+    *count += 1;
+    *count
+}
+
+/// Parses the provided address of a RakNet server.
+fn parse_addr(addr: &str) -> Result<SocketAddr> {
+    SocketAddr::from_str(addr).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            "The address is not a valid socket address",
+        )
+    })
+}
+
+/// Returns the current wall-clock time in milliseconds as used in the timestamp of a ProxyHeader message.
+fn proxy_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as i64)
+}
+
+/// Returns true if the provided ProxyHeader timestamp lies too far from the current time to be accepted.
+fn proxy_header_expired(timestamp: i64) -> bool {
+    proxy_timestamp().abs_diff(timestamp) > PROXY_HEADER_MAX_AGE.as_millis() as u64
+}
+
+/// Reads the AdvertiseSystem message on the provided buffer and writes an event for it. The message is sent outside
+/// of any connection, so it may come from the address of a connection as well as from any other address.
+pub fn read_advertisement(
+    addr: SocketAddr,
+    buf: &[u8],
+    ev: &mut EventWriter<RakNetEvent>,
+) -> Result<()> {
+    let mut reader = Cursor::new(buf);
+
+    match Message::deserialize(&mut reader)? {
+        Message::AdvertiseSystem { data } => {
+            ev.send(RakNetEvent::SystemAdvertised(addr, data.to_vec()));
+            Ok(())
+        }
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            "Expected AdvertiseSystem message",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::loopback::LoopbackNetwork;
+
+    #[test]
+    fn reply_with_tiny_mtu_is_rejected() {
+        let network = LoopbackNetwork::new();
+        let server = network.bind("127.0.0.1:19132").unwrap();
+        let client = network.bind_any().unwrap();
+
+        // The server answers the ping and then replies to the first OpenConnectionRequest1 with an MTU size that
+        // leaves no room for the datagram headers.
+        let handle = thread::spawn(move || {
+            let mut buf = [0u8; MAX_MTU_SIZE];
+            let deadline = Instant::now() + HANDSHAKE_READ_TIMEOUT;
+
+            while Instant::now() < deadline {
+                let Ok((len, addr)) = server.recv_from(&mut buf) else {
+                    thread::sleep(HANDSHAKE_POLL_INTERVAL);
+                    continue;
+                };
+
+                let resp = match Message::deserialize(&mut Cursor::new(&buf[..len])).unwrap() {
+                    Message::UnconnectedPing { send_timestamp, .. } => Message::UnconnectedPong {
+                        send_timestamp,
+                        server_guid: I64::new(0),
+                        magic: Magic,
+                        data: Str::new(""),
+                    },
+                    Message::OpenConnectionRequest1 { .. } => Message::OpenConnectionReply1 {
+                        magic: Magic,
+                        server_guid: I64::new(0),
+                        secure: Bool::new(false),
+                        server_mtu: U16::new(20),
+                    },
+                    _ => continue,
+                };
+
+                let mut out = BytesMut::new();
+                resp.serialize(&mut out);
+                server.send_to(&out, addr).unwrap();
+
+                if matches!(resp, Message::OpenConnectionReply1 { .. }) {
+                    break;
+                }
+            }
+        });
+
+        let result =
+            RakSocket::handshake(RakSocket::with_transport(client), "127.0.0.1:19132", None);
+        handle.join().unwrap();
+
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn cleanup_records_the_reaped_connections_as_closed() {
+        let config = RakNetConfig::default();
+        let mut mappings = Mappings::default();
+        let addr: SocketAddr = "127.0.0.1:19133".parse().unwrap();
+
+        mappings.connections.insert(addr, Entity::from_raw(7));
+        mappings.cleanup(&config, |_| false);
+
+        assert!(mappings.connection(&addr).is_none());
+        assert!(mappings.is_closed(&addr));
+    }
+
+    #[test]
+    fn proxy_headers_outside_the_max_age_are_expired() {
+        let now = proxy_timestamp();
+        let max_age = PROXY_HEADER_MAX_AGE.as_millis() as i64;
+
+        assert!(!proxy_header_expired(now));
+        assert!(!proxy_header_expired(now - max_age / 2));
+        assert!(proxy_header_expired(now - 2 * max_age));
+        assert!(proxy_header_expired(now + 2 * max_age));
+    }
+}
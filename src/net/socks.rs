@@ -0,0 +1,236 @@
+use std::{
+    io::{Cursor, Error, ErrorKind, Read, Result, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket},
+};
+
+use byteorder::{ReadBytesExt, WriteBytesExt, BE};
+
+use crate::protocol::{MAX_MTU_SIZE, SOCKS5_HANDSHAKE_TIMEOUT};
+
+use super::transport::Transport;
+
+/// SOCKS_VERSION is the version of the SOCKS protocol sent in every message of the handshake.
+const SOCKS_VERSION: u8 = 0x05;
+
+/// These are the authentication methods that the client offers to the proxy.
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_PASSWORD: u8 = 0x02;
+
+/// UDP_ASSOCIATE is the command that asks the proxy to relay the UDP datagrams of the client.
+const UDP_ASSOCIATE: u8 = 0x03;
+
+/// These are the types of the addresses in the requests, replies and the headers of the relayed datagrams.
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// Socks5Transport is a Transport for clients that relays the RakNet datagrams through a SOCKS5 proxy with UDP
+/// ASSOCIATE. Every datagram is sent to the relay of the proxy with the address of its destination prepended, and
+/// the address of the sender is read back from the datagrams received, so the connections see the addresses of the
+/// servers as if they were reached directly. The association lasts as long as the transport is alive.
+pub struct Socks5Transport {
+    // The association is released by the proxy once its control connection is closed.
+    _control: TcpStream,
+    udp: UdpSocket,
+    relay: SocketAddr,
+}
+
+impl Socks5Transport {
+    /// Connects to the SOCKS5 proxy at the provided address and associates a UDP relay with it, authenticating with
+    /// the provided username and password if any. The handshake blocks until it completes.
+    pub fn connect(proxy: &str, credentials: Option<(&str, &str)>) -> Result<Self> {
+        let mut control = TcpStream::connect(proxy)?;
+        control.set_read_timeout(Some(SOCKS5_HANDSHAKE_TIMEOUT))?;
+        control.set_write_timeout(Some(SOCKS5_HANDSHAKE_TIMEOUT))?;
+        control.set_nodelay(true)?;
+
+        authenticate(&mut control, credentials)?;
+
+        // The datagrams are sent from the same interface as the control connection, the proxy may only accept them
+        // from the address that the association was requested for.
+        let udp = UdpSocket::bind(SocketAddr::new(control.local_addr()?.ip(), 0))?;
+        udp.set_nonblocking(true)?;
+
+        let mut request = vec![SOCKS_VERSION, UDP_ASSOCIATE, 0x00];
+        write_address(&mut request, udp.local_addr()?)?;
+        control.write_all(&request)?;
+
+        let mut reply = [0u8; 3];
+        control.read_exact(&mut reply)?;
+
+        if reply[0] != SOCKS_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "The proxy did not reply with SOCKS version 5",
+            ));
+        }
+
+        if reply[1] != 0x00 {
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                format!(
+                    "The proxy refused the UDP association with code {}",
+                    reply[1]
+                ),
+            ));
+        }
+
+        // A relay bound on all the interfaces of the proxy is reached at the address of the proxy itself.
+        let mut relay = read_address(&mut control)?;
+        if relay.ip().is_unspecified() {
+            relay.set_ip(control.peer_addr()?.ip());
+        }
+
+        Ok(Self {
+            _control: control,
+            udp,
+            relay,
+        })
+    }
+
+    /// Returns the address of the UDP relay of the proxy that the datagrams are sent to.
+    pub fn relay_addr(&self) -> SocketAddr {
+        self.relay
+    }
+}
+
+impl Transport for Socks5Transport {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize> {
+        // RSV (u16) and FRAG (u8) are always zero, the datagrams are never fragmented.
+        let mut datagram = Vec::with_capacity(buf.len() + 22);
+        datagram.extend_from_slice(&[0x00, 0x00, 0x00]);
+        write_address(&mut datagram, addr)?;
+        datagram.extend_from_slice(buf);
+
+        self.udp.send_to(&datagram, self.relay)?;
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let mut datagram = [0u8; MAX_MTU_SIZE + 22];
+
+        loop {
+            let (len, from) = self.udp.recv_from(&mut datagram)?;
+
+            // The datagrams that do not come from the relay or that are fragmented are dropped.
+            if from != self.relay || len < 3 || datagram[2] != 0x00 {
+                continue;
+            }
+
+            let mut reader = Cursor::new(&datagram[3..len]);
+            let Ok(addr) = read_address(&mut reader) else {
+                continue;
+            };
+
+            let payload = &datagram[3 + reader.position() as usize..len];
+            let len = payload.len().min(buf.len());
+            buf[..len].copy_from_slice(&payload[..len]);
+
+            return Ok((len, addr));
+        }
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.udp.local_addr()
+    }
+}
+
+/// Negotiates the authentication method with the proxy and authenticates with the provided username and password if
+/// the proxy asks for them.
+fn authenticate(control: &mut TcpStream, credentials: Option<(&str, &str)>) -> Result<()> {
+    match credentials {
+        Some(_) => control.write_all(&[SOCKS_VERSION, 2, METHOD_NO_AUTH, METHOD_PASSWORD])?,
+        None => control.write_all(&[SOCKS_VERSION, 1, METHOD_NO_AUTH])?,
+    }
+
+    let mut reply = [0u8; 2];
+    control.read_exact(&mut reply)?;
+
+    if reply[0] != SOCKS_VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "The proxy did not reply with SOCKS version 5",
+        ));
+    }
+
+    match (reply[1], credentials) {
+        (METHOD_NO_AUTH, _) => Ok(()),
+        (METHOD_PASSWORD, Some((username, password))) => {
+            if username.len() > 255 || password.len() > 255 {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "The SOCKS5 username and password cannot exceed 255 bytes",
+                ));
+            }
+
+            let mut request = vec![0x01, username.len() as u8];
+            request.extend_from_slice(username.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            control.write_all(&request)?;
+
+            let mut reply = [0u8; 2];
+            control.read_exact(&mut reply)?;
+
+            if reply[1] != 0x00 {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    "The proxy rejected the SOCKS5 username and password",
+                ));
+            }
+
+            Ok(())
+        }
+        _ => Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "The proxy did not accept any of the offered authentication methods",
+        )),
+    }
+}
+
+/// Writes the provided address in the format of the SOCKS5 messages, its type followed by the address and the port.
+fn write_address(buf: &mut Vec<u8>, addr: SocketAddr) -> Result<()> {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+
+    buf.write_u16::<BE>(addr.port())
+}
+
+/// Reads an address in the format of the SOCKS5 messages. The domain names are rejected as they cannot be mapped to
+/// the addresses of the connections without resolving them.
+fn read_address(reader: &mut impl Read) -> Result<SocketAddr> {
+    let ip = match reader.read_u8()? {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            reader.read_exact(&mut octets)?;
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            reader.read_exact(&mut octets)?;
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        ATYP_DOMAIN => {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "Domain names are not supported in the SOCKS5 addresses",
+            ))
+        }
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Unknown SOCKS5 address type",
+            ))
+        }
+    };
+
+    Ok(SocketAddr::new(ip, reader.read_u16::<BE>()?))
+}
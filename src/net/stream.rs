@@ -1,69 +1,190 @@
 use std::{
     collections::{HashMap, VecDeque},
-    io::{Cursor, Error, ErrorKind, Result, Write},
-    net::{SocketAddr, UdpSocket},
-    sync::Arc,
+    io::{Cursor, Error, ErrorKind, Result},
+    net::SocketAddr,
     time::{Duration, Instant},
 };
 
-use bevy::ecs::{bundle::Bundle, component::Component, entity::Entity, event::EventWriter};
+use bevy::core::Name;
+use bevy::ecs::{
+    bundle::Bundle, component::Component, entity::Entity, event::EventWriter,
+    reflect::ReflectComponent,
+};
+use bevy::reflect::Reflect;
 use binary::{
-    datatypes::{I16, I64, U16, U24, U32},
+    datatypes::{Bool, I16, I64, U24},
     Binary,
 };
-use byteorder::{ReadBytesExt, WriteBytesExt, BE, LE};
-use bytes::{Buf, BufMut, BytesMut};
+use byteorder::{WriteBytesExt, LE};
+use bytes::{BufMut, Bytes, BytesMut};
 use commons::utils::unix_timestamp;
-use log::{info, trace};
+use tracing::{debug_span, Span};
 
 use crate::{
     generic::{
-        events::RakNetEvent,
+        events::{DisconnectReason, NatFailure, QualityIssue, RakNetEvent},
+        time::TimeSource,
         window::{MessageWindow, RecoveryWindow, SequenceWindow, SplitWindow},
     },
     protocol::{
         binary::{SystemAddresses, UDPAddress},
+        compression::CompressionAlgorithm,
+        config::RakNetConfig,
+        frame::{decode_datagram_bytes, AckRecord, Datagram, Frame, Packet, Split},
         message::Message,
         reliability::Reliability,
-        DATAGRAM_HEADER_SIZE, FLAG_ACK, FLAG_DATAGRAM, FLAG_FRAGMENTED, FLAG_NACK,
-        FLAG_NEEDS_B_AND_AS, FRAME_ADDITIONAL_SIZE, FRAME_HEADER_SIZE, LOGIN_PACKET_ID,
-        MAX_BATCHED_PACKETS, MAX_MESSAGE_SIZE, MAX_MTU_SIZE, MAX_RECEIPT_SIZE, MAX_SPLIT_PACKETS,
-        UDP_HEADER_SIZE,
+        ADVERTISE_SYSTEM_PACKET_ID, DATAGRAM_HEADER_SIZE, FLAG_ACK, FLAG_DATAGRAM, FLAG_NACK,
+        FLAG_NEEDS_B_AND_AS, LOGIN_PACKET_ID, MAX_MESSAGE_SIZE, MAX_MTU_SIZE, MAX_RECEIPT_SIZE,
+        QUALITY_SAMPLE_SIZE, UDP_HEADER_SIZE,
     },
 };
 
+use super::{socket::read_advertisement, MessageInterceptors};
+
+/// Logs a datagram-level message of a RakStream. The message is logged at the info level if the stream is traced
+/// by the TraceFilter so that it is visible regardless of the global level, and at the trace level otherwise.
+macro_rules! trace_datagram {
+    ($stream:expr, $($arg:tt)+) => {
+        if $stream.traced {
+            tracing::info!($($arg)+)
+        } else {
+            tracing::trace!($($arg)+)
+        }
+    };
+}
+
 /// StreamBundle contains components that are required to be spawned for an entity representing
 /// an established RakNet connection.
 #[derive(Bundle)]
 pub struct StreamBundle {
+    pub name: Name,
     pub info: NetworkInfo,
     pub status: NetworkStatus,
+    pub state: ConnectionState,
+    pub compression: CompressionAlgorithm,
     pub rakstream: RakStream,
 }
 
-/// NetworkInfo contains the local and the remote address of the established RakNet Connection.
-#[derive(Component)]
+/// NetworkInfo contains the local and the remote address of the established RakNet Connection along with
+/// the MTU size that was discovered for it during the handshake and the GUID of the other end. The addresses are not reflected as SocketAddr
+/// does not implement Reflect.
+#[derive(Component, Reflect)]
+#[reflect(Component, from_reflect = false)]
 pub struct NetworkInfo {
+    #[reflect(ignore)]
     pub local_addr: SocketAddr,
+    #[reflect(ignore)]
     pub remote_addr: SocketAddr,
+    pub mtu_size: usize,
+    pub guid: i64,
+}
+
+impl Default for NetworkInfo {
+    fn default() -> Self {
+        Self {
+            local_addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+            remote_addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+            mtu_size: 0,
+            guid: 0,
+        }
+    }
 }
 
 /// NetworkStatus contains the current status information of the network such as the ping, latency or last activity
 /// of the other end of the connection.
-#[derive(Component)]
+#[derive(Component, Reflect)]
+#[reflect(Component)]
 pub struct NetworkStatus {
     pub ping: u64,
     pub latency: Duration,
     pub last_activity: Instant,
+    /// The time the ConnectionState of the connection last changed at.
+    pub state_changed_at: Instant,
+    /// The time the last game packet batch was received at, which the keep-alives of RakNet do not refresh.
+    pub last_game_packet: Instant,
+    /// The time since which the latency has stayed above the maximum latency, if it is above it.
+    pub high_latency_since: Option<Instant>,
+}
+
+impl Default for NetworkStatus {
+    fn default() -> Self {
+        Self {
+            ping: 0,
+            latency: Duration::from_secs(0),
+            last_activity: Instant::now(),
+            state_changed_at: Instant::now(),
+            last_game_packet: Instant::now(),
+            high_latency_since: None,
+        }
+    }
+}
+
+/// ConnectionState is the stage of the lifecycle a RakNet connection is currently in. It is kept in sync with the
+/// RakStream of the connection through the StateChanged events so that systems can filter their queries by it.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConnectionState {
+    /// The connection request is being exchanged with the other end of the connection.
+    Handshaking,
+    /// The server has completed the offline handshake and waits for the connection request of the client.
+    AwaitingConnectionRequest,
+    /// The connection has been established and game packets can be sent over it.
+    Connected,
+    /// A disconnect notification has been queued for the other end of the connection.
+    Disconnecting,
+    /// The other end of the connection has closed it.
+    Closed,
+}
+
+/// ConnectionQuality is the quality of a RakNet connection measured over the datagrams exchanged since it was last
+/// measured.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectionQuality {
+    /// The fraction of the datagrams sent by the other end of the connection that never reached us, if enough of them
+    /// were received to measure it.
+    pub packet_loss: Option<f32>,
+    /// The fraction of the datagrams sent to the other end of the connection that had to be retransmitted, if enough of
+    /// them were sent to measure it.
+    pub retransmissions: Option<f32>,
+    /// The average deviation of the round trip times of the connection from its latency.
+    pub jitter: Duration,
+}
+
+/// StreamConfig is the part of the RakNetConfig that a RakStream needs once it has been created, which are the limits
+/// it enforces on the received messages and the clock it reads the time from.
+struct StreamConfig {
+    clock: TimeSource,
+    max_batched_packets: usize,
+    max_split_packets: u32,
+    max_game_packet_size: usize,
+    max_concurrent_splits: usize,
+    max_split_bytes: usize,
+    split_timeout: Duration,
+}
+
+impl From<&RakNetConfig> for StreamConfig {
+    fn from(config: &RakNetConfig) -> Self {
+        Self {
+            clock: config.clock.clone(),
+            max_batched_packets: config.max_batched_packets,
+            max_split_packets: config.max_split_packets,
+            max_game_packet_size: config.max_game_packet_size,
+            max_concurrent_splits: config.max_concurrent_splits,
+            max_split_bytes: config.max_split_bytes,
+            split_timeout: config.split_timeout,
+        }
+    }
 }
 
 /// RakStream represents a component that handles reliable encoding and decoding of messages, receiepts from the
-/// other end of the connection.
+/// other end of the connection. It does not perform any IO itself, the encoded datagrams are queued and drained
+/// by the socket systems which write them to the underlying transport.
 #[derive(Component)]
 pub struct RakStream {
     addr: SocketAddr,
-    socket: Arc<UdpSocket>,
     mtu_size: usize,
+    config: StreamConfig,
+    state: ConnectionState,
+    traced: bool,
 
     sequence_number: u32,
     message_index: u32,
@@ -74,9 +195,17 @@ pub struct RakStream {
     sequence_window: SequenceWindow,
     message_window: MessageWindow,
     split_window: HashMap<u16, SplitWindow>,
+    split_bytes: usize,
     recovery_window: RecoveryWindow,
+    lost_datagrams: u64,
+    received_datagrams: u64,
+    missing_datagrams: u64,
+    measured_sequence: u32,
+    measured_lost: u64,
+    quality_issues: u8,
 
     receipts: VecDeque<u32>,
+    outgoing: VecDeque<Bytes>,
 
     receiptbuf: BytesMut,
     msgbuf: BytesMut,
@@ -85,21 +214,31 @@ pub struct RakStream {
 
 impl RakStream {
     /// Creates and returns a new RakStream.
-    pub fn new(addr: SocketAddr, socket: Arc<UdpSocket>, mtu_size: usize) -> Self {
+    pub fn new(addr: SocketAddr, mtu_size: usize, config: &RakNetConfig) -> Self {
         Self {
             addr,
-            socket,
             mtu_size,
+            config: config.into(),
+            state: ConnectionState::AwaitingConnectionRequest,
+            traced: false,
             sequence_number: 0,
             message_index: 0,
             sequence_index: 0,
             order_index: 0,
             split_id: 0,
-            sequence_window: SequenceWindow::new(),
-            message_window: MessageWindow::new(),
+            sequence_window: SequenceWindow::new(config.window_size),
+            message_window: MessageWindow::new(config.window_size),
             split_window: HashMap::new(),
+            split_bytes: 0,
             recovery_window: RecoveryWindow::new(),
+            lost_datagrams: 0,
+            received_datagrams: 0,
+            missing_datagrams: 0,
+            measured_sequence: 0,
+            measured_lost: 0,
+            quality_issues: 0,
             receipts: VecDeque::new(),
+            outgoing: VecDeque::new(),
             receiptbuf: BytesMut::with_capacity(MAX_RECEIPT_SIZE),
             msgbuf: BytesMut::with_capacity(MAX_MESSAGE_SIZE),
             buffer: BytesMut::with_capacity(MAX_MTU_SIZE),
@@ -109,9 +248,27 @@ impl RakStream {
     /// Encodes the provided message with the specified Reliability and batches it for transmission
     /// to the other end of the connection whenever possible.
     pub fn encode(&mut self, message: Message, reliability: Reliability) {
-        message.serialize(&mut self.msgbuf);
-        let fragments = self.split(&self.msgbuf);
+        // The message buffer is taken out of the stream while encoding so that the fragments borrowing it
+        // can be written while the datagrams are being flushed.
+        let mut msgbuf = std::mem::take(&mut self.msgbuf);
+        message.serialize(&mut msgbuf);
+        self.encode_bytes(&msgbuf, reliability);
+
+        msgbuf.clear();
+        self.msgbuf = msgbuf;
+    }
+
+    /// Encodes an already serialized message with the specified Reliability and batches it for transmission to
+    /// the other end of the connection. This allows the same message to be serialized once and sent over many
+    /// connections.
+    pub fn encode_bytes(&mut self, message: &[u8], reliability: Reliability) {
+        let fragments = split(message, self.mtu_size, &reliability);
+        self.encode_fragments(&fragments, reliability);
+    }
 
+    /// Encodes the fragments of an already serialized and split message with the specified Reliability. The
+    /// fragments must have been split for the MTU size of this connection.
+    pub fn encode_fragments(&mut self, fragments: &[&[u8]], reliability: Reliability) {
         let order_index = self.order_index;
         self.order_index += 1;
 
@@ -124,90 +281,55 @@ impl RakStream {
         }
 
         for split_index in 0..split_count {
-            let content = fragments[split_index as usize];
-            let max_len = self.buffer.capacity() - self.buffer.len() - FRAME_HEADER_SIZE;
-
-            if content.len() > max_len {
-                self.flush(&self.buffer);
-                self.recovery_window
-                    .add(self.sequence_number, self.buffer.clone().into());
-                self.sequence_number += 1;
-                self.buffer.clear();
-            }
-
-            let mut header = (reliability.clone() as u8) << 5;
-            if split {
-                header |= FLAG_FRAGMENTED;
-            }
-
-            self.buffer.put_u8(header);
-            self.buffer.put_u16((content.len() as u16) << 3);
+            let mut frame = Frame {
+                reliability: reliability.clone(),
+                message_index: 0,
+                sequence_index: 0,
+                order_index,
+                order_channel: 0,
+                split: None,
+                content: fragments[split_index as usize],
+            };
 
             if reliability.reliable() {
-                U24::<LE>::new(self.message_index).serialize(&mut self.buffer);
+                frame.message_index = self.message_index;
                 self.message_index += 1;
             }
 
             if reliability.sequenced() {
-                U24::<LE>::new(self.sequence_index).serialize(&mut self.buffer);
+                frame.sequence_index = self.sequence_index;
                 self.sequence_index += 1;
             }
 
-            if reliability.sequenced_or_ordered() {
-                U24::<LE>::new(order_index).serialize(&mut self.buffer);
-                self.buffer.put_u8(0); // order index
+            if split {
+                frame.split = Some(Split {
+                    count: split_count,
+                    id: split_id,
+                    index: split_index,
+                });
             }
 
-            if split {
-                self.buffer.put_u32(split_count);
-                self.buffer.put_u16(split_id);
-                self.buffer.put_u32(split_index);
+            if self.buffer.len() + frame.size() > self.datagram_capacity() {
+                self.flush_buffer();
             }
 
-            self.buffer.write_all(&content).unwrap();
+            frame.serialize(&mut self.buffer);
 
             if reliability != Reliability::ReliableOrdered {
-                self.flush(&self.buffer);
-                self.recovery_window
-                    .add(self.sequence_number, self.buffer.clone().into());
-                self.sequence_number += 1;
-                self.buffer.clear();
+                self.flush_buffer();
             }
         }
-
-        self.msgbuf.clear();
     }
 
-    /// Splits the encoded message into multiple fragments if it exceeds the maximum size of a datagram.
-    /// It should return atleast one fragment.
-    fn split<'a>(&self, bytes: &'a [u8]) -> Vec<&'a [u8]> {
-        let mut max_size =
-            self.mtu_size - UDP_HEADER_SIZE - DATAGRAM_HEADER_SIZE - FRAME_HEADER_SIZE;
-
-        let len = bytes.len();
-
-        if len > max_size {
-            max_size -= FRAME_ADDITIONAL_SIZE;
-        }
-
-        let mut count = len / max_size;
-        if len % max_size != 0 {
-            count += 1;
-        }
-
-        let mut fragments = Vec::with_capacity(count);
-        for i in 0..count {
-            let start = i * max_size;
-            let mut end = start + max_size;
-
-            if end > len {
-                end = len;
-            }
-
-            fragments.insert(i, &bytes[start..end]);
-        }
+    /// Returns the number of bytes of frames that fit in a single datagram without exceeding the
+    /// negotiated MTU size of the connection.
+    fn datagram_capacity(&self) -> usize {
+        datagram_capacity(self.mtu_size)
+    }
 
-        fragments
+    /// Returns the MTU size negotiated for the connection.
+    pub fn mtu_size(&self) -> usize {
+        self.mtu_size
     }
 
     /// Decodes an ACK, NACK or a Datagram present in the provided buffer and handles it appropriately by
@@ -216,142 +338,175 @@ impl RakStream {
         &mut self,
         buffer: &[u8],
         ev: &mut EventWriter<RakNetEvent>,
+        interceptors: &mut MessageInterceptors,
         entity: Entity,
     ) -> Result<()> {
-        let mut reader = Cursor::new(buffer);
-        let header = reader.read_u8()?;
+        let _span = self.span(entity).entered();
+
+        let header = *buffer.first().ok_or(Error::new(
+            ErrorKind::UnexpectedEof,
+            "Received an empty datagram",
+        ))?;
+        trace_datagram!(self, len = buffer.len(), header, "Received datagram");
 
         if header == LOGIN_PACKET_ID {
             ev.send(RakNetEvent::DuplicateLogin(entity));
             return Ok(());
         }
 
-        if header & FLAG_DATAGRAM == 0 {
-            return Err(Error::new(
-                ErrorKind::Other,
-                "Buffer does not have a valid FLAG_DATAGRAM",
-            ));
+        if header == ADVERTISE_SYSTEM_PACKET_ID {
+            return read_advertisement(self.addr, buffer, ev);
         }
 
-        ev.send(RakNetEvent::LastActivity(entity, Instant::now()));
+        let packet = decode_datagram_bytes(buffer)?;
+        ev.send(RakNetEvent::LastActivity(entity, self.config.clock.now()));
 
-        if header & FLAG_ACK != 0 {
-            return self.decode_ack(&mut reader, entity, ev);
+        match packet {
+            Packet::Ack(records) => self.decode_ack(records, entity, ev),
+            Packet::Nack(records) => self.decode_nack(records, entity, ev),
+            Packet::Datagram(datagram) => self.decode_datagram(datagram, ev, interceptors, entity),
         }
-
-        if header & FLAG_NACK != 0 {
-            return self.decode_nack(&mut reader, entity, ev);
-        }
-
-        self.decode_datagram(&mut reader, ev, entity)
     }
 
-    /// This decodes a datagram from the provided buffer reader and returns any error whilst decoding it if any.
+    /// This handles the frames of a decoded datagram and returns any error whilst handling them if any.
     /// If it contains a fragmented message, it tries to unsplit the message, it also handles fully processed packets.
     fn decode_datagram(
         &mut self,
-        reader: &mut Cursor<&[u8]>,
+        datagram: Datagram,
         ev: &mut EventWriter<RakNetEvent>,
+        interceptors: &mut MessageInterceptors,
         entity: Entity,
     ) -> Result<()> {
-        let seq = U24::<LE>::deserialize(reader)?.0;
+        if datagram.frames.len() > self.config.max_batched_packets {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "The datagram sent by the connection contains high number of batched messages",
+            ));
+        }
 
-        if !self.sequence_window.receive(seq) {
+        if !self.sequence_window.receive(datagram.sequence) {
             return Ok(());
         }
+        self.received_datagrams += 1;
 
-        let mut count = 0;
-
-        while reader.remaining() != 0 {
-            let header = reader.read_u8()?;
-            let split = (header & FLAG_FRAGMENTED) != 0;
-            let reliability = Reliability::try_from((header & 224) >> 5)?;
-
-            let mut length = U16::<BE>::deserialize(reader)?.0;
-            length >>= 3;
-
-            if length == 0 {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    "RakNet Message content length cannot be 0",
-                ));
-            }
-
-            let mut message_index = 0;
-
-            if reliability.reliable() {
-                message_index = U24::<LE>::deserialize(reader)?.0;
-            }
-
-            if reliability.sequenced() {
-                reader.advance(3); // sequence index; this probably wouldn't happen for MCPE.
-            }
-
-            if reliability.sequenced_or_ordered() {
-                reader.advance(4); // order index & order channel; we don't care about this
+        for frame in datagram.frames {
+            if frame.reliability.reliable() && !self.message_window.receive(frame.message_index) {
+                continue;
             }
 
-            let mut split_count = 0;
-            let mut split_id = 0;
-            let mut split_index = 0;
+            match frame.split {
+                Some(split) => {
+                    if split.count >= self.config.max_split_packets {
+                        return Err(Error::new(
+                            ErrorKind::Other,
+                            "Maximum number of split packets reached",
+                        ));
+                    }
 
-            if split {
-                split_count = U32::<BE>::deserialize(reader)?.0;
-                split_id = U16::<BE>::deserialize(reader)?.0;
-                split_index = U32::<BE>::deserialize(reader)?.0;
-            }
+                    let mut splits = match self.split_window.remove(&split.id) {
+                        Some(splits) => splits,
+                        None => {
+                            if self.split_window.len() >= self.config.max_concurrent_splits {
+                                return self.exceed_split_limit(ev, entity);
+                            }
+
+                            SplitWindow::new(split.count, self.config.clock.now())
+                        }
+                    };
+
+                    if splits.count != split.count {
+                        self.split_bytes -= splits.size;
+                        return Err(Error::new(
+                            ErrorKind::Other,
+                            "Frame split count mismatch with the stored value for the given split ID.",
+                        ));
+                    }
 
-            let start = reader.position() as usize;
-            let end = start + length as usize;
+                    if split.index >= split.count {
+                        self.split_bytes -= splits.size;
+                        return Err(Error::new(
+                            ErrorKind::Other,
+                            "Frame split index exceeds the split count for the given split ID.",
+                        ));
+                    }
 
-            reader.advance(length as usize);
+                    if splits.discarded {
+                        self.split_window.insert(split.id, splits);
+                        continue;
+                    }
 
-            let content = &reader.get_ref()[start..end];
+                    if self.split_bytes + frame.content.len() > self.config.max_split_bytes {
+                        return self.exceed_split_limit(ev, entity);
+                    }
 
-            if !self.message_window.receive(message_index) {
-                continue;
-            }
+                    // The message is discarded as soon as it grows too large instead of once it is reassembled.
+                    let size = splits.size + frame.content.len();
+                    if size > self.config.max_game_packet_size {
+                        self.split_bytes -= splits.size;
+                        splits.discard();
+                        self.split_window.insert(split.id, splits);
+                        ev.send(RakNetEvent::PacketTooLarge(entity, size));
+
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "Split message exceeds the maximum game packet size",
+                        ));
+                    }
 
-            if split {
-                if split_count >= MAX_SPLIT_PACKETS {
-                    return Err(Error::new(
-                        ErrorKind::Other,
-                        "Maximum number of split packets reached",
-                    ));
-                }
+                    let size = splits.size;
+                    let fragment = Bytes::copy_from_slice(frame.content);
 
-                let mut splits = self
-                    .split_window
-                    .remove(&split_id)
-                    .unwrap_or(SplitWindow::new(split_count));
+                    if let Some(bytes) =
+                        splits.receive(split.index, fragment, self.config.clock.now())
+                    {
+                        self.split_bytes -= size;
+                        self.handle_message(&bytes, ev, interceptors, entity)?;
+                        continue;
+                    }
 
-                if splits.count != split_count {
-                    return Err(Error::new(
-                        ErrorKind::Other,
-                        "Frame split count mismatch with the stored value for the given split ID.",
-                    ));
+                    self.split_bytes += splits.size - size;
+                    self.split_window.insert(split.id, splits);
                 }
-
-                if let Some(bytes) = splits.receive(split_index, content.to_vec()) {
-                    self.handle_message(&bytes, ev, entity)?;
-                    continue;
-                }
-
-                self.split_window.insert(split_id, splits);
-            } else {
-                self.handle_message(&content, ev, entity)?;
+                None => self.handle_message(frame.content, ev, interceptors, entity)?,
             }
+        }
 
-            count += 1;
+        Ok(())
+    }
 
-            if count > MAX_BATCHED_PACKETS {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    "The datagram sent by the connection contains high number of batched messages",
-                ));
+    /// Discards all the split messages for which the other end of the connection has not sent any fragment
+    /// within the configured split timeout.
+    pub fn evict_splits(&mut self) {
+        let split_bytes = &mut self.split_bytes;
+        let split_timeout = self.config.split_timeout;
+        let now = self.config.clock.now();
+
+        self.split_window.retain(|_, splits| {
+            if splits.expired(split_timeout, now) {
+                *split_bytes -= splits.size;
+                return false;
             }
-        }
 
+            true
+        });
+    }
+
+    /// Disconnects the other end of the connection because it exceeded the limits on the number of split
+    /// messages or the amount of fragment bytes that can be buffered at once.
+    fn exceed_split_limit(
+        &mut self,
+        ev: &mut EventWriter<RakNetEvent>,
+        entity: Entity,
+    ) -> Result<()> {
+        self.split_window.clear();
+        self.split_bytes = 0;
+        self.disconnect(ev, entity);
+
+        ev.send(RakNetEvent::SplitLimitExceeded(entity));
+        ev.send(RakNetEvent::Disconnect(
+            entity,
+            DisconnectReason::SplitLimitExceeded,
+        ));
         Ok(())
     }
 
@@ -359,18 +514,20 @@ impl RakStream {
     /// from the recovery queue.
     fn decode_ack(
         &mut self,
-        reader: &mut Cursor<&[u8]>,
+        records: Vec<AckRecord>,
         entity: Entity,
         ev: &mut EventWriter<RakNetEvent>,
     ) -> Result<()> {
-        self.read_receipts(reader)?;
-        trace!("[+] {:?} Received ACKs: {:?}", self.addr, self.receipts);
+        self.receipts
+            .extend(records.iter().flat_map(AckRecord::sequences));
+        trace_datagram!(self, acks = ?self.receipts, "Received ACKs");
 
+        let now = self.config.clock.now();
         while let Some(sequence) = self.receipts.pop_front() {
-            self.recovery_window.acknowledge(sequence);
+            self.recovery_window.acknowledge(sequence, now);
         }
 
-        ev.send(RakNetEvent::Latency(entity, self.recovery_window.rtt()));
+        ev.send(RakNetEvent::Latency(entity, self.recovery_window.rtt(now)));
         Ok(())
     }
 
@@ -378,56 +535,28 @@ impl RakStream {
     /// the packet from the recovery queue.
     fn decode_nack(
         &mut self,
-        reader: &mut Cursor<&[u8]>,
+        records: Vec<AckRecord>,
         entity: Entity,
         ev: &mut EventWriter<RakNetEvent>,
     ) -> Result<()> {
-        self.read_receipts(reader)?;
-        trace!("[+] {:?} Received NACKs: {:?}", self.addr, self.receipts);
+        self.receipts
+            .extend(records.iter().flat_map(AckRecord::sequences));
+        trace_datagram!(self, nacks = ?self.receipts, "Received NACKs");
 
+        let now = self.config.clock.now();
         while let Some(sequence) = self.receipts.pop_front() {
-            if let Some(bytes) = self.recovery_window.retransmit(sequence) {
-                self.flush(&bytes[..]);
+            if let Some(bytes) = self.recovery_window.retransmit(sequence, now) {
+                self.lost_datagrams += 1;
 
-                self.recovery_window.add(self.sequence_number, bytes);
-                self.sequence_number += 1;
-            }
-        }
-
-        ev.send(RakNetEvent::Latency(entity, self.recovery_window.rtt()));
-        Ok(())
-    }
+                let datagram = self.datagram(&bytes[..]);
+                self.outgoing.push_back(datagram);
 
-    /// This function reads Receipts from the other end of the connection. These receipts may be an ACK
-    /// or a NACK but this function does not need to know as it stores them in the same buffer.
-    fn read_receipts(&mut self, reader: &mut Cursor<&[u8]>) -> Result<()> {
-        let record_count = I16::<BE>::deserialize(reader)?.0;
-
-        for _ in 0..record_count {
-            let record_type = reader.read_u8()?;
-
-            match record_type {
-                0 => {
-                    let start = U24::<LE>::deserialize(reader)?.0;
-                    let end = U24::<LE>::deserialize(reader)?.0;
-
-                    for seq in start..end {
-                        self.receipts.push_back(seq);
-                    }
-                }
-                1 => {
-                    let seq = U24::<LE>::deserialize(reader)?.0;
-                    self.receipts.push_back(seq);
-                }
-                _ => {
-                    return Err(Error::new(
-                        ErrorKind::Other,
-                        "Record Type can either be Single (1) or Range (0)",
-                    ));
-                }
+                self.recovery_window.add(self.sequence_number, bytes, now);
+                self.sequence_number += 1;
             }
         }
 
+        ev.send(RakNetEvent::Latency(entity, self.recovery_window.rtt(now)));
         Ok(())
     }
 
@@ -435,6 +564,7 @@ impl RakStream {
     /// and we didn't receive respectively.
     pub fn flush_receipts(&mut self) {
         self.sequence_window.shift();
+        self.missing_datagrams += self.sequence_window.nacks.len() as u64;
 
         if self.sequence_window.acks.len() > 0 {
             self.write_ack();
@@ -448,11 +578,7 @@ impl RakStream {
     /// Writes a Positive Acknowledgement Receipt to the other end of the connection containing all the
     /// sequence numbers that we received.
     fn write_ack(&mut self) {
-        trace!(
-            "[-] {:?} Sending ACKs {:?}",
-            self.addr,
-            &self.sequence_window.acks
-        );
+        trace_datagram!(self, acks = ?self.sequence_window.acks, "Sending ACKs");
         let _ = self.receiptbuf.write_u8(FLAG_DATAGRAM | FLAG_ACK);
         self.write_receipts(false);
     }
@@ -460,17 +586,13 @@ impl RakStream {
     /// Writes a Negative Acknowledgement Receipt to the other end of the connection containing all the
     /// sequence numbers that we did not receive.
     fn write_nack(&mut self) {
-        trace!(
-            "[-] {:?} Sending NACKs {:?}",
-            self.addr,
-            &self.sequence_window.nacks
-        );
+        trace_datagram!(self, nacks = ?self.sequence_window.nacks, "Sending NACKs");
         let _ = self.receiptbuf.write_u8(FLAG_DATAGRAM | FLAG_NACK);
         self.write_receipts(true);
     }
 
-    /// This function contains all the logic for serializing a Receipt packet in RakNet. It immediately flushes
-    /// the encoded
+    /// This function contains all the logic for serializing a Receipt packet in RakNet. It immediately queues
+    /// the encoded receipt for transmission.
     fn write_receipts(&mut self, nack: bool) {
         let sequences = if nack {
             &mut self.sequence_window.nacks
@@ -497,12 +619,9 @@ impl RakStream {
             }
 
             if first == last {
-                self.receiptbuf.put_u8(1);
-                U24::<LE>::new(first).serialize(&mut self.receiptbuf);
+                AckRecord::Single(first).serialize(&mut self.receiptbuf);
             } else {
-                self.receiptbuf.put_u8(0);
-                U24::<LE>::new(first).serialize(&mut self.receiptbuf);
-                U24::<LE>::new(last).serialize(&mut self.receiptbuf);
+                AckRecord::Range(first, last).serialize(&mut self.receiptbuf);
             }
 
             first = sequence;
@@ -513,23 +632,28 @@ impl RakStream {
         let mut reserved = &mut self.receiptbuf[1..3];
         reserved.put_i16(record_count);
 
-        self.socket.send_to(&self.receiptbuf, self.addr).unwrap();
-        self.receiptbuf.clear();
         sequences.clear();
+        self.outgoing.push_back(self.receiptbuf.split().freeze());
     }
 
     /// Decodes a RakNet Message from the provided buffer and flushes it's response if required
-    /// (for mostly Internal Packets) immediately.
+    /// (for mostly Internal Packets) immediately. The message is ignored if one of the interceptors vetoes it.
     fn handle_message(
         &mut self,
         buffer: &[u8],
         ev: &mut EventWriter<RakNetEvent>,
+        interceptors: &mut MessageInterceptors,
         entity: Entity,
     ) -> Result<()> {
         let mut reader = Cursor::new(buffer);
         let message = Message::deserialize(&mut reader)?;
 
-        trace!("[+] {:?} {:?}", self.addr, message);
+        trace_datagram!(self, ?message, "Received message");
+
+        if !interceptors.intercept(entity, &message) {
+            trace_datagram!(self, "Message vetoed by an interceptor");
+            return Ok(());
+        }
 
         match message {
             Message::ConnectedPing { client_timestamp } => {
@@ -544,7 +668,7 @@ impl RakStream {
                 client_timestamp,
                 server_timestamp,
             } => {
-                let ping = server_timestamp.0 - client_timestamp.0;
+                let ping = server_timestamp.0.wrapping_sub(client_timestamp.0);
                 ev.send(RakNetEvent::Ping(entity, ping as u64));
             }
             Message::ConnectionRequest {
@@ -561,6 +685,7 @@ impl RakStream {
                 };
 
                 self.encode(resp, Reliability::Unreliable);
+                self.transition(ConnectionState::Handshaking, ev, entity);
             }
             Message::ConnectionRequestAccepted {
                 client_address: _,
@@ -577,6 +702,7 @@ impl RakStream {
                 };
 
                 self.encode(resp, Reliability::Unreliable);
+                self.transition(ConnectionState::Connected, ev, entity);
                 ev.send(RakNetEvent::ConnectionEstablished(self.addr, entity));
             }
             Message::NewIncomingConnection {
@@ -585,14 +711,19 @@ impl RakStream {
                 request_timestamp: _,
                 accept_timestamp: _,
             } => {
+                self.transition(ConnectionState::Connected, ev, entity);
                 ev.send(RakNetEvent::ConnectionEstablished(self.addr, entity));
             }
             Message::GamePacket { data } => {
                 ev.send(RakNetEvent::IncomingBatch(entity, data.to_vec()));
-                info!("{:?} {:?}", self.addr, data);
+                trace_datagram!(self, ?data, "Received game packet");
             }
             Message::DisconnectNotification {} => {
-                ev.send(RakNetEvent::Disconnect(entity));
+                self.transition(ConnectionState::Closed, ev, entity);
+                ev.send(RakNetEvent::Disconnect(
+                    entity,
+                    DisconnectReason::ClosedByPeer,
+                ));
             }
             Message::DetectLostConnections {} => {
                 let resp = Message::ConnectedPing {
@@ -606,6 +737,33 @@ impl RakStream {
                 magic: _,
                 server_guid: _,
             } => ev.send(RakNetEvent::IncompatibleProtocol(entity, server_protocol.0)),
+            Message::NatPunchthroughRequest { target_guid } => {
+                ev.send(RakNetEvent::NatPunchthroughRequest(entity, target_guid.0))
+            }
+            Message::NatConnectAtTime {
+                delay,
+                peer_address,
+                peer_guid,
+            } => ev.send(RakNetEvent::NatConnectAtTime(
+                entity,
+                peer_address.0,
+                peer_guid.0,
+                Duration::from_millis(delay.0 as u64),
+            )),
+            Message::NatTargetNotConnected { target_guid } => {
+                ev.send(RakNetEvent::NatPunchthroughFailed(
+                    entity,
+                    target_guid.0,
+                    NatFailure::TargetNotConnected,
+                ))
+            }
+            Message::NatAlreadyInProgress { target_guid } => {
+                ev.send(RakNetEvent::NatPunchthroughFailed(
+                    entity,
+                    target_guid.0,
+                    NatFailure::AlreadyInProgress,
+                ))
+            }
             _ => {}
         }
 
@@ -615,35 +773,378 @@ impl RakStream {
     /// Tries to flush the packets written so far to the other end of the connection if the buffer
     /// is not empty.
     pub fn try_flush(&mut self) {
-        if self.buffer.len() == 0 {
+        self.flush_buffer();
+    }
+
+    /// Queues the frames written to the buffer so far as a datagram with a new sequence number and keeps
+    /// it in the recovery window until it is acknowledged. It does nothing if the buffer is empty.
+    fn flush_buffer(&mut self) {
+        if self.buffer.is_empty() {
             return;
         }
 
-        self.flush(&self.buffer);
+        let datagram = self.datagram(&self.buffer);
+        trace_datagram!(
+            self,
+            len = datagram.len(),
+            sequence = self.sequence_number,
+            "Sending datagram"
+        );
+        self.outgoing.push_back(datagram);
+
+        let payload = self.buffer.split().freeze();
+        self.recovery_window
+            .add(self.sequence_number, payload, self.config.clock.now());
         self.sequence_number += 1;
-        self.buffer.clear();
     }
 
-    /// Flushes the provided encoded datagram message by appending the header of the datagram with
-    /// a new sequence number and flushes it immediately to the socket connection.
-    fn flush(&self, buffer: &[u8]) {
-        let mut header = [0u8; 4];
-        let mut writer = header.as_mut_slice();
+    /// Builds a datagram from the provided encoded frames by prepending the header of the datagram with
+    /// the current sequence number.
+    fn datagram(&self, buffer: &[u8]) -> Bytes {
+        let mut datagram = BytesMut::with_capacity(DATAGRAM_HEADER_SIZE + buffer.len());
+
+        datagram.put_u8(FLAG_DATAGRAM | FLAG_NEEDS_B_AND_AS);
+        U24::<LE>::new(self.sequence_number).serialize(&mut datagram);
+        datagram.put_slice(buffer);
+
+        datagram.freeze()
+    }
+
+    /// Returns the next datagram that is queued for transmission to the other end of the connection
+    /// if there is any.
+    pub fn poll_outgoing(&mut self) -> Option<Bytes> {
+        self.outgoing.pop_front()
+    }
+
+    /// Returns the remote address of the other end of the connection.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the fraction of the datagrams sent so far that were reported lost by the other end of the connection.
+    pub fn packet_loss(&self) -> f32 {
+        if self.sequence_number == 0 {
+            return 0.0;
+        }
+
+        self.lost_datagrams as f32 / self.sequence_number as f32
+    }
+
+    /// Measures the quality of the connection over the datagrams exchanged since it was last measured. The packet loss
+    /// and the retransmissions are only measured once enough datagrams were exchanged for them to tell anything.
+    pub fn measure_quality(&mut self) -> ConnectionQuality {
+        let mut quality = ConnectionQuality {
+            jitter: self.recovery_window.jitter(self.config.clock.now()),
+            ..Default::default()
+        };
+
+        let incoming = self.received_datagrams + self.missing_datagrams;
+        if incoming >= QUALITY_SAMPLE_SIZE {
+            quality.packet_loss = Some(self.missing_datagrams as f32 / incoming as f32);
+            self.received_datagrams = 0;
+            self.missing_datagrams = 0;
+        }
 
-        writer.put_u8(FLAG_DATAGRAM | FLAG_NEEDS_B_AND_AS);
-        U24::<LE>::new(self.sequence_number).serialize(&mut writer);
+        let outgoing = (self.sequence_number - self.measured_sequence) as u64;
+        if outgoing >= QUALITY_SAMPLE_SIZE {
+            quality.retransmissions =
+                Some((self.lost_datagrams - self.measured_lost) as f32 / outgoing as f32);
+            self.measured_sequence = self.sequence_number;
+            self.measured_lost = self.lost_datagrams;
+        }
 
-        let buffer: &[u8] = &[&header[..], &buffer[..]].concat();
-        self.socket.send_to(&buffer, self.addr).unwrap();
+        quality
+    }
+
+    /// Records whether the connection currently has the provided kind of quality issue and returns true if it did not
+    /// have it on the previous check, so that an issue is only reported once until the connection recovers from it.
+    pub fn track_issue(&mut self, issue: QualityIssue, present: bool) -> bool {
+        let flag = match issue {
+            QualityIssue::PacketLoss(_) => 1,
+            QualityIssue::Retransmissions(_) => 2,
+            QualityIssue::Jitter(_) => 4,
+        };
+
+        let started = present && self.quality_issues & flag == 0;
+        if present {
+            self.quality_issues |= flag;
+        } else {
+            self.quality_issues &= !flag;
+        }
+
+        started
+    }
+
+    /// Returns the number of bytes sent to the other end of the connection that have not been acknowledged yet.
+    pub fn in_flight_bytes(&self) -> usize {
+        self.recovery_window.in_flight_bytes()
+    }
+
+    /// Returns the number of bytes held by the connection in its split messages, unacknowledged datagrams, queued
+    /// datagrams and receipts and in the frames that are not flushed yet.
+    pub fn buffered_bytes(&self) -> usize {
+        let outgoing: usize = self.outgoing.iter().map(Bytes::len).sum();
+
+        self.split_bytes
+            + self.in_flight_bytes()
+            + outgoing
+            + self.buffer.len()
+            + self.receipts.len() * std::mem::size_of::<u32>()
+    }
+
+    /// Returns true if every datagram sent so far has been acknowledged by the other end of the connection.
+    pub fn is_acknowledged(&self) -> bool {
+        self.recovery_window.unacknowledged.is_empty()
     }
 
     /// Handles graceful disconnection of the client, it flushes all the remaining packets we have written so far
-    /// and also sends the Disconnect Notification to the client.
-    pub fn disconnect(&mut self) {
+    /// and also queues the Disconnect Notification for the client.
+    pub fn disconnect(&mut self, ev: &mut EventWriter<RakNetEvent>, entity: Entity) {
         self.encode(
             Message::DisconnectNotification {},
             Reliability::ReliableOrdered,
         );
         self.try_flush();
+        self.transition(ConnectionState::Disconnecting, ev, entity);
+    }
+
+    /// Sends a connection request to the other end of the connection. This is used by the clients once the
+    /// offline handshake has been completed. It returns the StateChanged event of the connection moving to the
+    /// Handshaking state, which the caller sends once the entity has been spawned.
+    pub fn request_connection(&mut self, guid: i64, entity: Entity) -> Option<RakNetEvent> {
+        let msg = Message::ConnectionRequest {
+            client_guid: I64::new(guid),
+            request_timestamp: I64::new(unix_timestamp() as i64),
+            secure: Bool::new(false),
+        };
+
+        self.encode(msg, Reliability::Reliable);
+        self.set_state(ConnectionState::Handshaking)
+            .then_some(RakNetEvent::StateChanged(
+                entity,
+                ConnectionState::Handshaking,
+            ))
+    }
+
+    /// Returns a span carrying the remote address, the entity and the state of the connection which the logs
+    /// of the connection are recorded in.
+    pub fn span(&self, entity: Entity) -> Span {
+        debug_span!("raknet", remote = %self.addr, ?entity, state = ?self.state)
+    }
+
+    /// Returns true if the datagram-level logs of the connection are enabled by the TraceFilter.
+    pub fn is_traced(&self) -> bool {
+        self.traced
+    }
+
+    /// Enables or disables the datagram-level logs of the connection regardless of the global level.
+    pub fn set_traced(&mut self, traced: bool) {
+        self.traced = traced;
+    }
+
+    /// Returns the current state of the connection.
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// Moves the connection to the provided state and sends a StateChanged event if it differs from the
+    /// current one.
+    fn transition(
+        &mut self,
+        state: ConnectionState,
+        ev: &mut EventWriter<RakNetEvent>,
+        entity: Entity,
+    ) {
+        if self.set_state(state) {
+            ev.send(RakNetEvent::StateChanged(entity, state));
+        }
+    }
+
+    /// Moves the connection to the provided state and returns true if it differs from the current one.
+    fn set_state(&mut self, state: ConnectionState) -> bool {
+        if self.state == state {
+            return false;
+        }
+
+        self.state = state;
+        true
+    }
+}
+
+/// Returns the number of bytes of frames that fit in a single datagram of the provided MTU size.
+fn datagram_capacity(mtu_size: usize) -> usize {
+    mtu_size.saturating_sub(UDP_HEADER_SIZE + DATAGRAM_HEADER_SIZE)
+}
+
+/// Splits the encoded message into multiple fragments if it exceeds the maximum size of a datagram of the
+/// provided MTU size. It should return atleast one fragment.
+fn split<'a>(bytes: &'a [u8], mtu_size: usize, reliability: &Reliability) -> Vec<&'a [u8]> {
+    let capacity = datagram_capacity(mtu_size);
+    let mut max_size = capacity.saturating_sub(Frame::header_size(reliability, false));
+
+    if bytes.len() <= max_size {
+        return vec![bytes];
+    }
+
+    max_size = capacity.saturating_sub(Frame::header_size(reliability, true));
+    bytes.chunks(max_size.max(1)).collect()
+}
+
+/// Multicast encodes the same serialized message on multiple connections. The message is split only once for
+/// every distinct MTU size of the connections and the resulting fragments are reused, so only the frame headers
+/// are written separately for each connection.
+pub struct Multicast<'a> {
+    message: &'a [u8],
+    reliability: Reliability,
+    fragments: HashMap<usize, Vec<&'a [u8]>>,
+}
+
+impl<'a> Multicast<'a> {
+    /// Creates a new Multicast of the provided serialized message.
+    pub fn new(message: &'a [u8], reliability: Reliability) -> Self {
+        Self {
+            message,
+            reliability,
+            fragments: HashMap::new(),
+        }
+    }
+
+    /// Encodes the message on the provided connection.
+    pub fn encode(&mut self, stream: &mut RakStream) {
+        let fragments = self
+            .fragments
+            .entry(stream.mtu_size())
+            .or_insert_with(|| split(self.message, stream.mtu_size(), &self.reliability));
+
+        stream.encode_fragments(fragments, self.reliability.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use binary::prefixed::UnsizedBytes;
+
+    use super::*;
+    use crate::protocol::frame::Datagram;
+
+    const RELIABILITIES: [Reliability; 5] = [
+        Reliability::Unreliable,
+        Reliability::UnreliableSequenced,
+        Reliability::Reliable,
+        Reliability::ReliableOrdered,
+        Reliability::ReliableSequenced,
+    ];
+
+    fn stream(mtu_size: usize) -> RakStream {
+        RakStream::new(
+            "127.0.0.1:19132".parse().unwrap(),
+            mtu_size,
+            &RakNetConfig::default(),
+        )
+    }
+
+    /// Encodes the provided data in a GamePacket on the stream and returns the serialized message.
+    fn encode(stream: &mut RakStream, data: &[u8], reliability: Reliability) -> Vec<u8> {
+        let message = Message::GamePacket {
+            data: UnsizedBytes::new(data),
+        };
+
+        let mut serialized = Vec::new();
+        message.serialize(&mut serialized);
+        stream.encode(message, reliability);
+
+        serialized
+    }
+
+    /// Drains the datagrams queued on the stream and returns them after checking that none exceeds the MTU size.
+    fn drain(stream: &mut RakStream) -> Vec<Bytes> {
+        stream.try_flush();
+
+        let datagrams: Vec<Bytes> = std::iter::from_fn(|| stream.poll_outgoing()).collect();
+
+        for datagram in datagrams.iter() {
+            assert!(datagram.len() + UDP_HEADER_SIZE <= stream.mtu_size);
+        }
+
+        datagrams
+    }
+
+    /// Decodes the drained datagrams and joins the content of their frames in order.
+    fn reassemble(datagrams: &[Bytes]) -> Vec<u8> {
+        let mut content = Vec::new();
+
+        for datagram in datagrams {
+            let datagram = Datagram::deserialize(&mut Cursor::new(&datagram[..])).unwrap();
+
+            for frame in datagram.frames {
+                content.extend_from_slice(frame.content);
+            }
+        }
+
+        content
+    }
+
+    #[test]
+    fn datagrams_never_exceed_mtu() {
+        for mtu_size in [576, 1400, MAX_MTU_SIZE] {
+            for reliability in RELIABILITIES {
+                for len in [mtu_size - 1, mtu_size, 5 * mtu_size] {
+                    let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+                    let mut stream = stream(mtu_size);
+
+                    let message = encode(&mut stream, &data, reliability.clone());
+                    let datagrams = drain(&mut stream);
+
+                    assert!(datagrams.len() > message.len() / mtu_size);
+                    assert_eq!(reassemble(&datagrams), message);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn batched_messages_never_exceed_mtu() {
+        for mtu_size in [576, MAX_MTU_SIZE] {
+            for reliability in RELIABILITIES {
+                let mut stream = stream(mtu_size);
+                let mut expected = Vec::new();
+
+                for len in [1, mtu_size - 1, 100, mtu_size, 3 * mtu_size, 7] {
+                    let data = vec![len as u8; len];
+                    expected.extend(encode(&mut stream, &data, reliability.clone()));
+                }
+
+                assert_eq!(reassemble(&drain(&mut stream)), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn quality_issues_are_reported_once_until_recovered() {
+        let mut stream = stream(MAX_MTU_SIZE);
+        let jitter = QualityIssue::Jitter(Duration::from_millis(150));
+
+        assert!(stream.track_issue(jitter, true));
+        assert!(!stream.track_issue(jitter, true));
+        assert!(stream.track_issue(QualityIssue::PacketLoss(0.2), true));
+
+        assert!(!stream.track_issue(jitter, false));
+        assert!(stream.track_issue(jitter, true));
+    }
+
+    #[test]
+    fn split_never_underflows_below_the_frame_header() {
+        let data = vec![0u8; 64];
+
+        for mtu_size in [
+            0,
+            UDP_HEADER_SIZE,
+            UDP_HEADER_SIZE + DATAGRAM_HEADER_SIZE + 1,
+        ] {
+            for reliability in RELIABILITIES {
+                let fragments = split(&data, mtu_size, &reliability);
+                assert_eq!(fragments.concat(), data);
+            }
+        }
     }
 }
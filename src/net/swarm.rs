@@ -0,0 +1,265 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use bevy::{
+    app::{App, Plugin, Update},
+    ecs::{
+        entity::Entity,
+        event::{EventReader, EventWriter},
+        schedule::IntoSystemConfigs,
+        system::{Local, Query, Res, ResMut, Resource},
+    },
+    time::{common_conditions::on_timer, Time, Timer, TimerMode},
+};
+use tracing::{info, warn};
+
+use crate::{
+    build_client, build_common,
+    generic::events::{NetworkEvent, RakNetEvent},
+    protocol::mcpe::login::{LoginState, OfflineLogin},
+};
+
+use super::{socket::RakSocket, stream::NetworkStatus};
+
+/// TrafficPattern is the game traffic that every bot of a swarm sends once it has logged in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TrafficPattern {
+    /// The bots do not send anything after logging in.
+    #[default]
+    Idle,
+    /// The bots send a single packet of the provided size on every interval.
+    Constant { size: usize, interval: Duration },
+    /// The bots send the provided number of packets of the provided size at once on every interval.
+    Burst {
+        size: usize,
+        count: usize,
+        interval: Duration,
+    },
+}
+
+impl TrafficPattern {
+    /// Returns the interval on which the packets are sent, None if the bots are idle.
+    fn interval(&self) -> Option<Duration> {
+        match self {
+            TrafficPattern::Idle => None,
+            TrafficPattern::Constant { interval, .. } => Some(*interval),
+            TrafficPattern::Burst { interval, .. } => Some(*interval),
+        }
+    }
+}
+
+/// BotSwarm is the plugin that load tests a RakNet server by connecting the provided number of bots to it. Every
+/// bot logs in with its own OfflineLogin and then sends the configured traffic, the connect success rate and the
+/// latency percentiles are logged on the report interval. It is only available with the swarm feature.
+pub struct BotSwarm {
+    addr: String,
+    count: usize,
+    traffic: TrafficPattern,
+    report_interval: Duration,
+}
+
+impl BotSwarm {
+    pub fn new(addr: &str, count: usize) -> Self {
+        Self {
+            addr: addr.to_string(),
+            count,
+            traffic: TrafficPattern::Idle,
+            report_interval: Duration::from_secs(5),
+        }
+    }
+
+    /// Sets the traffic that the bots send once they have logged in.
+    pub fn with_traffic(mut self, traffic: TrafficPattern) -> Self {
+        self.traffic = traffic;
+        self
+    }
+
+    /// Sets how often the statistics of the swarm are logged.
+    pub fn with_report_interval(mut self, interval: Duration) -> Self {
+        self.report_interval = interval;
+        self
+    }
+}
+
+impl Plugin for BotSwarm {
+    fn build(&self, app: &mut App) {
+        build_common(app);
+        build_client(app);
+
+        let mut stats = SwarmStats {
+            traffic: self.traffic,
+            ..Default::default()
+        };
+
+        for i in 0..self.count {
+            let started = Instant::now();
+
+            match RakSocket::connect(&self.addr, &mut app.world) {
+                Ok(entity) => {
+                    let login = OfflineLogin::new(&format!("Bot{}", i));
+                    app.world
+                        .entity_mut(entity)
+                        .insert((login, LoginState::default()));
+                    stats.connecting.insert(entity, started.elapsed());
+                }
+                Err(e) => {
+                    warn!("[Swarm Error]: {}", e.to_string());
+                    stats.failed += 1;
+                }
+            }
+        }
+
+        stats.bots = self.count;
+        app.insert_resource(stats);
+        app.add_systems(
+            Update,
+            (
+                record_swarm,
+                send_traffic,
+                report_swarm.run_if(on_timer(self.report_interval)),
+            )
+                .chain(),
+        );
+    }
+}
+
+/// SwarmStats contains the connect times of the bots of a swarm that have logged in and the number of bots that
+/// failed to. The bots are connected one after another while the plugin is built, so the connect time of a bot is
+/// the duration of its own offline handshake added to the time it took to log in once the App started running.
+#[derive(Resource, Default)]
+pub struct SwarmStats {
+    bots: usize,
+    failed: usize,
+    traffic: TrafficPattern,
+    started: Option<Instant>,
+    connecting: HashMap<Entity, Duration>,
+    established: HashMap<Entity, Duration>,
+}
+
+impl SwarmStats {
+    /// Returns the ratio of the bots that have logged in to all the bots of the swarm.
+    pub fn success_rate(&self) -> f32 {
+        if self.bots == 0 {
+            return 0.0;
+        }
+
+        self.established.len() as f32 / self.bots as f32
+    }
+
+    /// Returns the time it took the bots that have logged in to do so, sorted from the fastest.
+    pub fn connect_times(&self) -> Vec<Duration> {
+        let mut times: Vec<Duration> = self.established.values().copied().collect();
+        times.sort();
+        times
+    }
+}
+
+/// Returns the value at the provided percentile of the provided sorted durations.
+fn percentile(sorted: &[Duration], percentile: f32) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+
+    let index = ((sorted.len() - 1) as f32 * percentile / 100.0).round() as usize;
+    sorted[index]
+}
+
+/// This system is responsible for recording the bots that have logged in and the ones that were disconnected
+/// before doing so.
+fn record_swarm(
+    mut network: EventReader<NetworkEvent>,
+    mut ev: EventReader<RakNetEvent>,
+    mut stats: ResMut<SwarmStats>,
+) {
+    let started = *stats.started.get_or_insert_with(Instant::now);
+
+    for event in network.read() {
+        if let NetworkEvent::ConnectionEstablished(entity) = event {
+            if let Some(offline) = stats.connecting.remove(entity) {
+                stats
+                    .established
+                    .insert(*entity, offline + started.elapsed());
+            }
+        }
+    }
+
+    for event in ev.read() {
+        if let RakNetEvent::Disconnect(entity, _) = event {
+            if stats.connecting.remove(entity).is_some() {
+                stats.failed += 1;
+            }
+        }
+    }
+}
+
+/// This system is responsible for sending the configured traffic from all the bots that have logged in.
+fn send_traffic(
+    mut network: EventWriter<NetworkEvent>,
+    stats: Res<SwarmStats>,
+    time: Res<Time>,
+    mut timer: Local<Option<Timer>>,
+) {
+    let traffic = stats.traffic;
+
+    let Some(interval) = traffic.interval() else {
+        return;
+    };
+
+    let timer = timer.get_or_insert_with(|| Timer::new(interval, TimerMode::Repeating));
+
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    let (size, count) = match traffic {
+        TrafficPattern::Idle => return,
+        TrafficPattern::Constant { size, .. } => (size, 1),
+        TrafficPattern::Burst { size, count, .. } => (size, count),
+    };
+
+    for entity in stats.established.keys() {
+        for _ in 0..count {
+            network.send(NetworkEvent::OutgoingPacket(
+                *entity,
+                vec![0u8; size].into(),
+            ));
+        }
+    }
+}
+
+/// This system is responsible for logging the connect success rate of the swarm and the percentiles of the connect
+/// times and of the latencies of the bots.
+fn report_swarm(stats: Res<SwarmStats>, query: Query<&NetworkStatus>) {
+    let connect_times = stats.connect_times();
+
+    let mut latencies: Vec<Duration> = stats
+        .established
+        .keys()
+        .filter_map(|entity| query.get(*entity).ok())
+        .map(|status| status.latency)
+        .collect();
+    latencies.sort();
+
+    info!(
+        "[Swarm]: {}/{} logged in ({:.1}%), {} failed, {} pending",
+        stats.established.len(),
+        stats.bots,
+        stats.success_rate() * 100.0,
+        stats.failed,
+        stats.connecting.len()
+    );
+    info!(
+        "[Swarm]: connect p50 {:?} p90 {:?} p99 {:?}",
+        percentile(&connect_times, 50.0),
+        percentile(&connect_times, 90.0),
+        percentile(&connect_times, 99.0)
+    );
+    info!(
+        "[Swarm]: latency p50 {:?} p90 {:?} p99 {:?}",
+        percentile(&latencies, 50.0),
+        percentile(&latencies, 90.0),
+        percentile(&latencies, 99.0)
+    );
+}
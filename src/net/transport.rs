@@ -0,0 +1,53 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    net::{SocketAddr, UdpSocket},
+};
+
+/// Transport is the datagram transport that a RakSocket sends and receives the RakNet datagrams on. It is implemented
+/// by the UdpSocket, other implementations let the reliability layer run over loopback or simulated networks.
+pub trait Transport: Send + Sync + 'static {
+    /// Sends the provided datagram to the provided address and returns the number of bytes sent.
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize>;
+
+    /// Receives a single datagram into the provided buffer and returns its length and the address it came from. It
+    /// must not block, an error of the WouldBlock kind is returned if there is no datagram to receive.
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)>;
+
+    /// Returns the address the transport is bound to.
+    fn local_addr(&self) -> Result<SocketAddr>;
+}
+
+impl Transport for UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+/// ClosedTransport is the Transport left behind on a connection whose transport has been handed over to another
+/// socket. The datagrams sent on it are discarded and it never receives any.
+pub struct ClosedTransport;
+
+impl Transport for ClosedTransport {
+    fn send_to(&self, buf: &[u8], _addr: SocketAddr) -> Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, _buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        Err(Error::new(
+            ErrorKind::WouldBlock,
+            "The transport has been handed over",
+        ))
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(SocketAddr::from(([0, 0, 0, 0], 0)))
+    }
+}
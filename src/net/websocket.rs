@@ -0,0 +1,253 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{Error, ErrorKind, Result},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Mutex,
+    time::Instant,
+};
+
+use tracing::debug;
+use tungstenite::{
+    handshake::{
+        server::{NoCallback, ServerHandshake},
+        HandshakeError, MidHandshake,
+    },
+    Message, WebSocket,
+};
+
+use crate::protocol::WEBSOCKET_HANDSHAKE_TIMEOUT;
+
+use super::transport::Transport;
+
+/// Handshake is a WebSocket connection accepted by a WebSocketListener whose upgrade has not completed yet.
+struct Handshake {
+    addr: SocketAddr,
+    accepted: Instant,
+    handshake: MidHandshake<ServerHandshake<TcpStream, NoCallback>>,
+}
+
+/// ListenerState is the connections of a WebSocketListener along with the datagrams read from them that have not
+/// been received yet.
+struct ListenerState {
+    handshakes: Vec<Handshake>,
+    sockets: HashMap<SocketAddr, WebSocket<TcpStream>>,
+    incoming: VecDeque<(Vec<u8>, SocketAddr)>,
+}
+
+/// WebSocketListener is a Transport for servers that tunnels the RakNet datagrams over WebSocket connections, for the
+/// clients on the web or behind networks that block UDP. Every binary message carries a single datagram and every
+/// connection is addressed by the address of its TCP peer, so the connections are handled by the same RakStreams
+/// as over UDP.
+pub struct WebSocketListener {
+    listener: TcpListener,
+    state: Mutex<ListenerState>,
+}
+
+impl WebSocketListener {
+    /// Binds a WebSocketListener on the provided address.
+    pub fn bind(addr: &str) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Self {
+            listener,
+            state: Mutex::new(ListenerState {
+                handshakes: Vec::new(),
+                sockets: HashMap::new(),
+                incoming: VecDeque::new(),
+            }),
+        })
+    }
+
+    /// Accepts the pending connections and advances their handshakes without blocking. The handshakes that do not
+    /// complete within WEBSOCKET_HANDSHAKE_TIMEOUT are dropped.
+    fn accept(&self, state: &mut ListenerState) {
+        while let Ok((stream, addr)) = self.listener.accept() {
+            if let Err(e) = stream.set_nonblocking(true) {
+                debug!("[WebSocket Error]: {}", e.to_string());
+                continue;
+            }
+
+            let _ = stream.set_nodelay(true);
+            complete(state, addr, Instant::now(), tungstenite::accept(stream));
+        }
+
+        for pending in std::mem::take(&mut state.handshakes) {
+            if pending.accepted.elapsed() >= WEBSOCKET_HANDSHAKE_TIMEOUT {
+                debug!("[WebSocket Error]: Handshake of {} timed out", pending.addr);
+                continue;
+            }
+
+            complete(
+                state,
+                pending.addr,
+                pending.accepted,
+                pending.handshake.handshake(),
+            );
+        }
+    }
+}
+
+/// Stores the connection of a handshake that completed, or keeps the handshake for later if it would block.
+fn complete(
+    state: &mut ListenerState,
+    addr: SocketAddr,
+    accepted: Instant,
+    result: std::result::Result<
+        WebSocket<TcpStream>,
+        HandshakeError<ServerHandshake<TcpStream, NoCallback>>,
+    >,
+) {
+    match result {
+        Ok(socket) => {
+            state.sockets.insert(addr, socket);
+        }
+        Err(HandshakeError::Interrupted(handshake)) => state.handshakes.push(Handshake {
+            addr,
+            accepted,
+            handshake,
+        }),
+        Err(HandshakeError::Failure(e)) => debug!("[WebSocket Error]: {}", e.to_string()),
+    }
+}
+
+impl Transport for WebSocketListener {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+
+        // The datagrams sent to an address without a connection are dropped like they would be over UDP.
+        let Some(socket) = state.sockets.get_mut(&addr) else {
+            return Ok(buf.len());
+        };
+
+        if let Err(e) = write_datagram(socket, buf) {
+            state.sockets.remove(&addr);
+            return Err(e);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.incoming.is_empty() {
+            self.accept(&mut state);
+
+            let state = &mut *state;
+            state.sockets.retain(|addr, socket| loop {
+                match read_datagram(socket) {
+                    Ok(Some(datagram)) => state.incoming.push_back((datagram, *addr)),
+                    Ok(None) => break true,
+                    Err(e) => {
+                        debug!("[WebSocket Error]: {}", e.to_string());
+                        break false;
+                    }
+                }
+            });
+        }
+
+        let Some((datagram, addr)) = state.incoming.pop_front() else {
+            return Err(Error::new(
+                ErrorKind::WouldBlock,
+                "There is no datagram to receive",
+            ));
+        };
+
+        let len = datagram.len().min(buf.len());
+        buf[..len].copy_from_slice(&datagram[..len]);
+
+        Ok((len, addr))
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+/// WebSocketTransport is a Transport for clients that tunnels the RakNet datagrams over a WebSocket connection to a
+/// server bound with a WebSocketListener.
+pub struct WebSocketTransport {
+    socket: Mutex<WebSocket<TcpStream>>,
+    local_addr: SocketAddr,
+    remote_addr: SocketAddr,
+}
+
+impl WebSocketTransport {
+    /// Connects to the WebSocketListener bound on the provided address. The handshake blocks until it completes.
+    pub fn connect(addr: &str) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+
+        let local_addr = stream.local_addr()?;
+        let remote_addr = stream.peer_addr()?;
+
+        let (socket, _) = tungstenite::client(format!("ws://{}/", addr), stream)
+            .map_err(|e| Error::new(ErrorKind::ConnectionRefused, e.to_string()))?;
+        socket.get_ref().set_nonblocking(true)?;
+
+        Ok(Self {
+            socket: Mutex::new(socket),
+            local_addr,
+            remote_addr,
+        })
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize> {
+        if addr == self.remote_addr {
+            write_datagram(&mut self.socket.lock().unwrap(), buf)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let Some(datagram) = read_datagram(&mut self.socket.lock().unwrap())? else {
+            return Err(Error::new(
+                ErrorKind::WouldBlock,
+                "There is no datagram to receive",
+            ));
+        };
+
+        let len = datagram.len().min(buf.len());
+        buf[..len].copy_from_slice(&datagram[..len]);
+
+        Ok((len, self.remote_addr))
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// Sends the provided datagram as a binary message. The message stays queued in the socket if the stream would block
+/// and is written by the next read or write.
+fn write_datagram(socket: &mut WebSocket<TcpStream>, datagram: &[u8]) -> Result<()> {
+    match socket.send(Message::Binary(datagram.to_vec())) {
+        Ok(()) => Ok(()),
+        Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+        Err(e) => Err(Error::new(ErrorKind::ConnectionReset, e.to_string())),
+    }
+}
+
+/// Reads the next binary message of the socket, or None if the stream would block. The control messages are answered
+/// by the socket itself and skipped, an error is returned once the connection is closed.
+fn read_datagram(socket: &mut WebSocket<TcpStream>) -> Result<Option<Vec<u8>>> {
+    // The messages queued while the stream would block are written before anything is read.
+    match socket.flush() {
+        Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => {}
+        Err(e) => return Err(Error::new(ErrorKind::ConnectionReset, e.to_string())),
+        Ok(()) => {}
+    }
+
+    loop {
+        match socket.read() {
+            Ok(Message::Binary(datagram)) => return Ok(Some(datagram)),
+            Ok(_) => continue,
+            Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+            Err(e) => return Err(Error::new(ErrorKind::ConnectionReset, e.to_string())),
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+};
+
+use bevy::ecs::component::Component;
+
+/// Refusal is the RakNet message that the clients refused by a server are answered with, which the clients show to
+/// the player in their own words.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Refusal {
+    /// The client is told that it is banned from the server.
+    #[default]
+    Banned,
+    /// The client is told that the server has no free slots.
+    ServerFull,
+    /// The client is told that it is already connected to the server.
+    AlreadyConnected,
+}
+
+/// Whitelist is the component of a server that only lets the listed IP addresses and client GUIDs connect while it is
+/// enabled. The other clients are refused with the Refusal of the whitelist, the pings are still answered. It can be
+/// switched on and off at any time.
+#[derive(Component, Debug, Clone, Default)]
+pub struct Whitelist {
+    enabled: bool,
+    addresses: HashSet<IpAddr>,
+    guids: HashSet<i64>,
+    refusal: Refusal,
+}
+
+impl Whitelist {
+    /// Creates an empty whitelist that is enabled, so no client can connect until it is allowed.
+    pub fn new() -> Self {
+        Self {
+            enabled: true,
+            ..Default::default()
+        }
+    }
+
+    /// Allows the clients with the provided IP address to connect.
+    pub fn with_address(mut self, ip: IpAddr) -> Self {
+        self.addresses.insert(ip);
+        self
+    }
+
+    /// Allows the clients with the provided GUID to connect.
+    pub fn with_guid(mut self, guid: i64) -> Self {
+        self.guids.insert(guid);
+        self
+    }
+
+    /// Sets the message that the refused clients are answered with.
+    pub fn with_refusal(mut self, refusal: Refusal) -> Self {
+        self.refusal = refusal;
+        self
+    }
+
+    /// Enables or disables the whitelist, every client can connect while it is disabled.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Returns whether the whitelist is enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Allows the clients with the provided IP address to connect. Returns false if it was already allowed.
+    pub fn add_address(&mut self, ip: IpAddr) -> bool {
+        self.addresses.insert(ip)
+    }
+
+    /// Stops allowing the clients with the provided IP address. Returns false if it was not allowed.
+    pub fn remove_address(&mut self, ip: IpAddr) -> bool {
+        self.addresses.remove(&ip)
+    }
+
+    /// Allows the clients with the provided GUID to connect. Returns false if it was already allowed.
+    pub fn add_guid(&mut self, guid: i64) -> bool {
+        self.guids.insert(guid)
+    }
+
+    /// Stops allowing the clients with the provided GUID. Returns false if it was not allowed.
+    pub fn remove_guid(&mut self, guid: i64) -> bool {
+        self.guids.remove(&guid)
+    }
+
+    /// Sets the message that the refused clients are answered with.
+    pub fn set_refusal(&mut self, refusal: Refusal) {
+        self.refusal = refusal;
+    }
+
+    /// Returns the message that the refused clients are answered with.
+    pub fn refusal(&self) -> Refusal {
+        self.refusal
+    }
+
+    /// Returns whether the client with the provided address and GUID can connect, which is always the case while the
+    /// whitelist is disabled.
+    pub fn allows(&self, addr: SocketAddr, guid: i64) -> bool {
+        !self.enabled || self.addresses.contains(&addr.ip()) || self.guids.contains(&guid)
+    }
+}
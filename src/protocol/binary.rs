@@ -51,13 +51,13 @@ impl<'a> Binary<'a> for UDPAddress {
             }
             6 => {
                 let mut bytes = [0u8; 16];
-                buf.advance(2);
+                skip(buf, 2)?;
 
                 let port = U16::<BE>::deserialize(buf)?.0;
-                buf.advance(4);
+                skip(buf, 4)?;
 
-                buf.read_exact(&mut bytes).unwrap();
-                buf.advance(4);
+                buf.read_exact(&mut bytes)?;
+                skip(buf, 4)?;
 
                 let ip = IpAddr::V6(Ipv6Addr::from(bytes));
 
@@ -71,6 +71,23 @@ impl<'a> Binary<'a> for UDPAddress {
     }
 }
 
+/// ProxySignature is the HMAC-SHA256 that authenticates the forwarded address of a ProxyHeader.
+#[derive(Debug)]
+pub struct ProxySignature(pub [u8; 32]);
+
+impl<'a> Binary<'a> for ProxySignature {
+    fn serialize(&self, buf: &mut impl Write) {
+        buf.write_all(&self.0).unwrap();
+    }
+
+    fn deserialize(buf: &mut Cursor<&'a [u8]>) -> Result<Self> {
+        let mut bytes = [0u8; 32];
+        buf.read_exact(&mut bytes)?;
+
+        Ok(ProxySignature(bytes))
+    }
+}
+
 #[derive(Debug)]
 pub struct SystemAddresses;
 
@@ -103,12 +120,10 @@ impl<'a> Binary<'a> for Magic {
     }
 
     fn deserialize(buf: &mut Cursor<&'a [u8]>) -> Result<Self> {
-        let start = buf.position() as usize;
-        let end = start + 16;
+        let mut sequence = [0u8; 16];
+        buf.read_exact(&mut sequence)?;
 
-        buf.advance(16);
-
-        if &buf.get_ref()[start..end] != UNCONNECTED_MESSAGE_SEQUENCE {
+        if sequence != UNCONNECTED_MESSAGE_SEQUENCE {
             return Err(Error::new(
                 ErrorKind::Other,
                 "Unconnected Message Sequence mismatch",
@@ -118,3 +133,185 @@ impl<'a> Binary<'a> for Magic {
         Ok(Magic)
     }
 }
+
+/// VarU32 is an unsigned 32 bit integer encoded in 1 to 5 bytes, each of which carries 7 bits of the value and
+/// whether another byte follows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct VarU32(pub u32);
+
+impl<'a> Binary<'a> for VarU32 {
+    fn serialize(&self, buf: &mut impl Write) {
+        write_varint(buf, self.0 as u64);
+    }
+
+    fn deserialize(buf: &mut Cursor<&'a [u8]>) -> Result<Self> {
+        Ok(VarU32(read_varint(buf, 32)? as u32))
+    }
+}
+
+/// VarI32 is a signed 32 bit integer zig-zag encoded into a VarU32, so that small negative values are encoded in as
+/// few bytes as small positive ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct VarI32(pub i32);
+
+impl<'a> Binary<'a> for VarI32 {
+    fn serialize(&self, buf: &mut impl Write) {
+        VarU32(((self.0 << 1) ^ (self.0 >> 31)) as u32).serialize(buf);
+    }
+
+    fn deserialize(buf: &mut Cursor<&'a [u8]>) -> Result<Self> {
+        let value = VarU32::deserialize(buf)?.0;
+        Ok(VarI32((value >> 1) as i32 ^ -((value & 1) as i32)))
+    }
+}
+
+/// VarU64 is an unsigned 64 bit integer encoded in 1 to 10 bytes, each of which carries 7 bits of the value and
+/// whether another byte follows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct VarU64(pub u64);
+
+impl<'a> Binary<'a> for VarU64 {
+    fn serialize(&self, buf: &mut impl Write) {
+        write_varint(buf, self.0);
+    }
+
+    fn deserialize(buf: &mut Cursor<&'a [u8]>) -> Result<Self> {
+        Ok(VarU64(read_varint(buf, 64)?))
+    }
+}
+
+/// VarI64 is a signed 64 bit integer zig-zag encoded into a VarU64.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct VarI64(pub i64);
+
+impl<'a> Binary<'a> for VarI64 {
+    fn serialize(&self, buf: &mut impl Write) {
+        VarU64(((self.0 << 1) ^ (self.0 >> 63)) as u64).serialize(buf);
+    }
+
+    fn deserialize(buf: &mut Cursor<&'a [u8]>) -> Result<Self> {
+        let value = VarU64::deserialize(buf)?.0;
+        Ok(VarI64((value >> 1) as i64 ^ -((value & 1) as i64)))
+    }
+}
+
+/// Writes the provided value in groups of 7 bits, starting from the least significant ones.
+fn write_varint(buf: &mut impl Write, mut value: u64) {
+    while value >= 0x80 {
+        buf.write_u8(value as u8 | 0x80).unwrap();
+        value >>= 7;
+    }
+
+    buf.write_u8(value as u8).unwrap();
+}
+
+/// Reads a value of the provided width in bits written in groups of 7 bits. The last byte the value can span may only
+/// carry the bits that are left of its width, the values that overflow the type are rejected instead of truncated.
+fn read_varint(buf: &mut Cursor<&[u8]>, bits: u32) -> Result<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+
+    while shift < bits {
+        let byte = buf.read_u8()?;
+
+        if bits - shift < 7 && byte >> (bits - shift) != 0 {
+            break;
+        }
+
+        value |= ((byte & 0x7f) as u64) << shift;
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+
+        shift += 7;
+    }
+
+    Err(Error::new(
+        ErrorKind::Other,
+        "VarInt exceeds the width of its type",
+    ))
+}
+
+/// Skips the provided number of bytes of the buffer, returning an error instead of moving past its end if there are
+/// not enough of them left.
+fn skip(buf: &mut Cursor<&[u8]>, len: usize) -> Result<()> {
+    if buf.remaining() < len {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "Buffer does not have enough bytes left to skip",
+        ));
+    }
+
+    buf.advance(len);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use binary::Binary;
+
+    use super::*;
+
+    fn encode<'a>(value: &impl Binary<'a>) -> Vec<u8> {
+        let mut buf = Vec::new();
+        value.serialize(&mut buf);
+        buf
+    }
+
+    fn decode<'a, T: Binary<'a>>(bytes: &'a [u8]) -> Result<T> {
+        T::deserialize(&mut Cursor::new(bytes))
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        for value in [0, 1, 127, 128, 300, 16_384, u32::MAX] {
+            assert_eq!(decode::<VarU32>(&encode(&VarU32(value))).unwrap().0, value);
+        }
+
+        for value in [0, 1, -1, 63, -64, 64, i32::MIN, i32::MAX] {
+            assert_eq!(decode::<VarI32>(&encode(&VarI32(value))).unwrap().0, value);
+        }
+
+        for value in [0, 1, 128, u32::MAX as u64 + 1, u64::MAX] {
+            assert_eq!(decode::<VarU64>(&encode(&VarU64(value))).unwrap().0, value);
+        }
+
+        for value in [0, -1, i64::MIN, i64::MAX] {
+            assert_eq!(decode::<VarI64>(&encode(&VarI64(value))).unwrap().0, value);
+        }
+    }
+
+    #[test]
+    fn varints_are_zigzag_encoded() {
+        assert_eq!(encode(&VarI32(0)), [0x00]);
+        assert_eq!(encode(&VarI32(-1)), [0x01]);
+        assert_eq!(encode(&VarI32(1)), [0x02]);
+        assert_eq!(encode(&VarI32(-64)), [0x7f]);
+        assert_eq!(encode(&VarI64(i64::MIN)), encode(&VarU64(u64::MAX)));
+    }
+
+    #[test]
+    fn varints_use_the_maximum_length() {
+        assert_eq!(encode(&VarU32(u32::MAX)), [0xff, 0xff, 0xff, 0xff, 0x0f]);
+        assert_eq!(encode(&VarU64(u64::MAX)).len(), 10);
+        assert_eq!(encode(&VarU64(u64::MAX))[9], 0x01);
+    }
+
+    #[test]
+    fn varints_overflowing_their_type_are_rejected() {
+        assert!(decode::<VarU32>(&[0xff, 0xff, 0xff, 0xff, 0x1f]).is_err());
+        assert!(decode::<VarU32>(&[0xff, 0xff, 0xff, 0xff, 0x8f, 0x00]).is_err());
+        assert!(decode::<VarU32>(&[0x80, 0x80]).is_err());
+
+        let mut overflow = vec![0xff; 9];
+        overflow.push(0x02);
+        assert!(decode::<VarU64>(&overflow).is_err());
+
+        let mut too_long = vec![0x80; 10];
+        too_long.push(0x00);
+        assert!(decode::<VarU64>(&too_long).is_err());
+    }
+}
@@ -0,0 +1,206 @@
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+use bevy::ecs::component::Component;
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+
+/// CompressionAlgorithm is the algorithm the game packet batches of a connection are compressed with. It is
+/// negotiated through the NetworkSettings packet, the batches are not compressed until then.
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionAlgorithm {
+    /// The raw DEFLATE algorithm, referred to as zlib by MCPE.
+    Zlib,
+    /// The raw snappy algorithm supported since 1.19.30.
+    Snappy,
+    /// The batches are not compressed at all.
+    #[default]
+    None,
+}
+
+impl CompressionAlgorithm {
+    /// Returns the compression algorithm with the provided ID as sent in the NetworkSettings packet.
+    pub fn from_id(id: u16) -> Option<Self> {
+        match id {
+            0 => Some(CompressionAlgorithm::Zlib),
+            1 => Some(CompressionAlgorithm::Snappy),
+            0xffff => Some(CompressionAlgorithm::None),
+            _ => None,
+        }
+    }
+
+    /// Returns the ID of the compression algorithm as sent in the NetworkSettings packet.
+    pub fn id(&self) -> u16 {
+        match self {
+            CompressionAlgorithm::Zlib => 0,
+            CompressionAlgorithm::Snappy => 1,
+            CompressionAlgorithm::None => 0xffff,
+        }
+    }
+
+    /// Returns the ID of the compression algorithm as written in the header of the batches.
+    pub fn header(&self) -> u8 {
+        match self {
+            CompressionAlgorithm::Zlib => 0x00,
+            CompressionAlgorithm::Snappy => 0x01,
+            CompressionAlgorithm::None => 0xff,
+        }
+    }
+}
+
+/// CompressionHeader is inserted on the connections whose batches start with the one byte header of their
+/// compression algorithm, which is the case once the NetworkSettings of a client of protocol 649 or above were sent.
+#[derive(Component, Debug, Default, Clone, Copy)]
+pub struct CompressionHeader;
+
+/// Compresses the provided game packet batch with the provided algorithm. The compression level is only used
+/// by the zlib algorithm.
+pub fn compress(bytes: &[u8], algorithm: CompressionAlgorithm, level: u32) -> Result<Vec<u8>> {
+    match algorithm {
+        CompressionAlgorithm::Zlib => {
+            let mut encoder =
+                DeflateEncoder::new(Vec::with_capacity(bytes.len()), Compression::new(level));
+            encoder.write_all(bytes)?;
+            encoder.finish()
+        }
+        CompressionAlgorithm::Snappy => snap::raw::Encoder::new()
+            .compress_vec(bytes)
+            .map_err(|e| Error::new(ErrorKind::Other, e)),
+        CompressionAlgorithm::None => Ok(bytes.to_vec()),
+    }
+}
+
+/// Compresses the provided game packet batch like compress and writes the header of the algorithm in front of it if
+/// the connection uses the compression header.
+pub fn compress_batch(
+    bytes: &[u8],
+    algorithm: CompressionAlgorithm,
+    level: u32,
+    header: bool,
+) -> Result<Vec<u8>> {
+    let batch = compress(bytes, algorithm, level)?;
+
+    if !header {
+        return Ok(batch);
+    }
+
+    let mut buf = Vec::with_capacity(batch.len() + 1);
+    buf.push(algorithm.header());
+    buf.extend_from_slice(&batch);
+    Ok(buf)
+}
+
+/// Decompresses the provided game packet batch like decompress after reading the header of its algorithm if the
+/// connection uses the compression header. A batch is either compressed with the negotiated algorithm or not at all.
+pub fn decompress_batch(
+    bytes: &[u8],
+    algorithm: CompressionAlgorithm,
+    max_size: usize,
+    header: bool,
+) -> Result<Vec<u8>> {
+    if !header {
+        return decompress(bytes, algorithm, max_size);
+    }
+
+    let Some((&id, bytes)) = bytes.split_first() else {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "Batch is missing its compression header",
+        ));
+    };
+
+    match id {
+        id if id == CompressionAlgorithm::None.header() => {
+            decompress(bytes, CompressionAlgorithm::None, max_size)
+        }
+        id if id == algorithm.header() => decompress(bytes, algorithm, max_size),
+        _ => Err(Error::new(
+            ErrorKind::Other,
+            "Batch is compressed with an algorithm that was not negotiated",
+        )),
+    }
+}
+
+/// Decompresses the provided game packet batch with the provided algorithm. It fails if the decompressed batch
+/// exceeds the provided maximum size so that a small batch cannot inflate without bounds.
+pub fn decompress(
+    bytes: &[u8],
+    algorithm: CompressionAlgorithm,
+    max_size: usize,
+) -> Result<Vec<u8>> {
+    let buf = match algorithm {
+        CompressionAlgorithm::Zlib => {
+            let mut decoder = DeflateDecoder::new(bytes).take(max_size as u64 + 1);
+            let mut buf = Vec::with_capacity(bytes.len() * 2);
+            decoder.read_to_end(&mut buf)?;
+            buf
+        }
+        CompressionAlgorithm::Snappy => {
+            let len =
+                snap::raw::decompress_len(bytes).map_err(|e| Error::new(ErrorKind::Other, e))?;
+
+            if len > max_size {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    "Decompressed batch exceeds the maximum batch size",
+                ));
+            }
+
+            snap::raw::Decoder::new()
+                .decompress_vec(bytes)
+                .map_err(|e| Error::new(ErrorKind::Other, e))?
+        }
+        CompressionAlgorithm::None => bytes.to_vec(),
+    };
+
+    if buf.len() > max_size {
+        return Err(Error::new(
+            ErrorKind::Other,
+            "Decompressed batch exceeds the maximum batch size",
+        ));
+    }
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_SIZE: usize = 1024;
+
+    #[test]
+    fn batch_round_trip() {
+        for algorithm in [
+            CompressionAlgorithm::Zlib,
+            CompressionAlgorithm::Snappy,
+            CompressionAlgorithm::None,
+        ] {
+            for header in [false, true] {
+                let batch = compress_batch(b"game packets", algorithm, 7, header).unwrap();
+
+                if header {
+                    assert_eq!(batch[0], algorithm.header());
+                }
+
+                let decompressed = decompress_batch(&batch, algorithm, MAX_SIZE, header).unwrap();
+                assert_eq!(decompressed, b"game packets");
+            }
+        }
+    }
+
+    #[test]
+    fn uncompressed_batch_is_accepted_with_header() {
+        let batch = [&[0xff][..], b"game packets"].concat();
+        let decompressed =
+            decompress_batch(&batch, CompressionAlgorithm::Zlib, MAX_SIZE, true).unwrap();
+
+        assert_eq!(decompressed, b"game packets");
+    }
+
+    #[test]
+    fn invalid_header_is_rejected() {
+        let batch = compress_batch(b"game packets", CompressionAlgorithm::Snappy, 7, true).unwrap();
+
+        assert!(decompress_batch(&batch, CompressionAlgorithm::Zlib, MAX_SIZE, true).is_err());
+        assert!(decompress_batch(&[], CompressionAlgorithm::Zlib, MAX_SIZE, true).is_err());
+    }
+}
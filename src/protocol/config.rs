@@ -0,0 +1,285 @@
+use std::{net::IpAddr, num::NonZeroU32, path::PathBuf, time::Duration};
+
+use bevy::{
+    app::PreUpdate,
+    ecs::{
+        schedule::{InternedScheduleLabel, ScheduleLabel},
+        system::Resource,
+    },
+};
+
+use crate::generic::time::TimeSource;
+
+use super::{
+    compression::CompressionAlgorithm, APPROVAL_TIMEOUT, BACKEND_COOLDOWN, BLOCK_ESCALATION,
+    COMPRESSION_LEVEL, HIGH_LATENCY_GRACE, INVALID_PACKETS_IDLE, JITTER_THRESHOLD, LOGIN_TIMEOUT,
+    MAX_BATCHED_PACKETS, MAX_BATCH_SIZE, MAX_BUFFERED_BYTES, MAX_CONCURRENT_SPLITS,
+    MAX_GAME_PACKET_SIZE, MAX_GLOBAL_PONGS_PER_SEC, MAX_INVALID_MSGS, MAX_MSGS_PER_SEC,
+    MAX_MTU_SIZE, MAX_PONGS_PER_SEC, MAX_SPLIT_BYTES, MAX_SPLIT_PACKETS, MAX_TRACKED_ADDRESSES,
+    MAX_UNCONNECTED_PER_SEC, MIN_MTU_SIZE, MIN_REQUEST_PADDING, OFFENSE_MEMORY,
+    PACKET_LOSS_THRESHOLD, PROXY_HANDSHAKE_THREADS, RAKNET_BLOCK_DUR, RAKNET_CHECK_TIMEOUT,
+    RAKNET_CLEANUP_INTERVAL, RAKNET_EXIT_GRACE, RAKNET_TIMEOUT, RAKNET_TPS,
+    RETRANSMISSION_THRESHOLD, SPLIT_TIMEOUT, UNCONNECTED_BLOCK_DUR, WINDOW_SIZE,
+};
+
+/// DuplicateLoginPolicy decides which session is kept when a client logs into a server while another session of it is
+/// still connected, either with the same GUID or from the same address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicateLoginPolicy {
+    /// The new session is refused and the existing one is kept.
+    #[default]
+    RejectNew,
+    /// The existing session is disconnected and the new one is accepted.
+    KickExisting,
+    /// Both sessions are kept and only the events are written. A new handshake from the address of a live connection
+    /// is ignored, as the sessions are told apart by their addresses.
+    AllowBoth,
+}
+
+/// MinMtuSize is the floor of the MTU sizes the connections can negotiate. It cannot be lower than MIN_MTU_SIZE nor
+/// higher than MAX_MTU_SIZE, as the frames of a smaller datagram would not fit in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MinMtuSize(usize);
+
+impl MinMtuSize {
+    /// Creates a MinMtuSize of the provided size if it lies between MIN_MTU_SIZE and MAX_MTU_SIZE.
+    pub fn new(size: usize) -> Option<Self> {
+        (MIN_MTU_SIZE..=MAX_MTU_SIZE)
+            .contains(&size)
+            .then_some(Self(size))
+    }
+
+    /// Returns the MTU size.
+    pub fn get(self) -> usize {
+        self.0
+    }
+}
+
+impl Default for MinMtuSize {
+    fn default() -> Self {
+        Self(MIN_MTU_SIZE)
+    }
+}
+
+/// RakNetConfig contains all the tunable settings of the RakNet systems, sockets and streams. It can be inserted
+/// into the App before adding the network plugins to override the defaults, which are the values of the respective
+/// constants in the protocol module.
+#[derive(Resource, Clone, Debug)]
+pub struct RakNetConfig {
+    /// The schedule the network systems are added to by the plugins, PreUpdate by default.
+    pub schedule: InternedScheduleLabel,
+    /// Whether the schedule runs on a fixed timestep (e.g. FixedUpdate). The flush systems then run on every
+    /// tick of the schedule instead of on their own timers.
+    pub fixed_tick: bool,
+    /// How often the outgoing datagrams and receipts are flushed.
+    pub flush_interval: Duration,
+    /// How often the outlived connections and the abandoned split messages are checked.
+    pub check_interval: Duration,
+    /// How often the expired entries are evicted from the server mappings.
+    pub cleanup_interval: Duration,
+    /// The duration after which a connection that has not sent anything is timed out.
+    pub timeout: Duration,
+    /// Whether the connections of a server go through the MCPE login sequence once they are connected. It is disabled
+    /// by default so that the servers that do not speak the MCPE login receive the batches of their clients as they are.
+    pub mcpe_login: bool,
+    /// The duration within which the connections of a server must complete the MCPE login once they are connected,
+    /// the ones that do not are disconnected. It only applies if the MCPE login is enabled.
+    pub login_timeout: Option<Duration>,
+    /// The duration after which a connection of a server that has not sent any game packet is disconnected, even if
+    /// it is still alive, so that the clients stuck on a dead screen are removed. It is disabled by default.
+    pub idle_timeout: Option<Duration>,
+    /// The latency above which the connections of a server are disconnected once it has lasted for the high latency
+    /// grace, so that the servers that cannot tolerate lagging players can remove them. It is disabled by default.
+    pub max_latency: Option<Duration>,
+    /// The duration for which the latency of a connection can stay above the maximum latency before it is disconnected.
+    pub high_latency_grace: Duration,
+    /// The packet loss above which a QualityWarning is written for a connection, so that the App can reduce its update
+    /// rates or warn the player before the connection times out. The warnings are disabled if it is None.
+    pub packet_loss_threshold: Option<f32>,
+    /// The fraction of the datagrams sent to a connection that can be retransmitted before a QualityWarning is written.
+    pub retransmission_threshold: Option<f32>,
+    /// The jitter of a connection above which a QualityWarning is written.
+    pub jitter_threshold: Option<Duration>,
+    /// The maximum time the App waits for the connections to acknowledge their disconnection when it exits.
+    pub exit_grace: Duration,
+    /// The duration for which an address that exceeds either of the abuse thresholds is blocked.
+    pub block_duration: Duration,
+    /// The durations for which the addresses blocked again by the spam checks are blocked, the second block of an
+    /// address lasts for the first of them and so on. The blocks past the last of them are permanent.
+    pub block_escalation: Vec<Duration>,
+    /// The duration after which the offenses of an address that has not been blocked again are forgotten.
+    pub offense_memory: Duration,
+    /// The JSON file that the BlockList of the server spawned by a plugin is loaded from at startup and written back to
+    /// whenever it changes, so that the blocks survive restarts. The blocks are not persisted by default.
+    pub block_list_path: Option<PathBuf>,
+    /// The maximum number of datagrams an address can send in one second before being blocked. It should be tuned to
+    /// the traffic of the deployment, as the clients send more datagrams on busy servers.
+    pub max_msgs_per_sec: u32,
+    /// The maximum number of unconnected messages, such as pings, an address that is not connected can send in one
+    /// second before being blocked. They are counted apart from the datagrams of the connections.
+    pub max_unconnected_per_sec: u32,
+    /// The duration for which an address exceeding the maximum number of unconnected messages is blocked.
+    pub unconnected_block_duration: Duration,
+    /// The maximum number of malformed messages an address can send before being blocked.
+    pub max_invalid_msgs: u32,
+    /// The maximum number of Unconnected Pongs sent to an address in one second, the pings above it are dropped so
+    /// that the server cannot be used as a reflector.
+    pub max_pongs_per_sec: u32,
+    /// The maximum number of Unconnected Pongs a server sends to all the addresses together in one second, the pings
+    /// above it are dropped as well.
+    pub max_global_pongs_per_sec: u32,
+    /// The duration after which the invalid packets counter of an idle address is forgotten.
+    pub invalid_packets_idle: Duration,
+    /// The maximum number of addresses for which the packet counters are tracked at once.
+    pub max_tracked_addresses: usize,
+    /// The size of the sequence and message windows of a connection, which cannot be zero.
+    pub window_size: NonZeroU32,
+    /// The minimum MTU size that a connection can negotiate, which cannot be below MIN_MTU_SIZE.
+    pub min_mtu_size: MinMtuSize,
+    /// The minimum number of padding bytes an OpenConnectionRequest1 must carry, the smaller requests are dropped
+    /// without a reply.
+    pub min_request_padding: usize,
+    /// The maximum number of encapsulated frames a single datagram can carry.
+    pub max_batched_packets: usize,
+    /// The maximum number of fragments a single message can be split into.
+    pub max_split_packets: u32,
+    /// The maximum size of a game packet received once its fragments are reassembled, or sent in an OutgoingBatch.
+    pub max_game_packet_size: usize,
+    /// The maximum number of split messages a connection can have partially received at once.
+    pub max_concurrent_splits: usize,
+    /// The maximum amount of fragment bytes buffered for the split messages of a connection.
+    pub max_split_bytes: usize,
+    /// The maximum number of bytes a connection can buffer in its split messages, unacknowledged datagrams and
+    /// outgoing queues before it is disconnected.
+    pub max_buffered_bytes: usize,
+    /// The duration after which a split message with no new fragments is discarded.
+    pub split_timeout: Duration,
+    /// The compression level used for the outgoing game packet batches.
+    pub compression_level: u32,
+    /// The compression algorithm that is negotiated with the clients through the NetworkSettings packet.
+    pub compression_algorithm: CompressionAlgorithm,
+    /// The maximum size a game packet batch can have once it is decompressed.
+    pub max_batch_size: usize,
+    /// Whether the connections are encrypted once the Login packet of the client is received. It only applies if the
+    /// MCPE login is enabled.
+    pub encryption: bool,
+    /// Whether the login chains of the clients must be signed by the Mojang root key. Clients that fail the
+    /// verification are disconnected.
+    pub online_mode: bool,
+    /// Whether the datagrams that the connections fail to decode are written as MalformedPacketDump events along with
+    /// their errors. It is meant for debugging as it copies every malformed datagram.
+    pub dump_malformed_packets: bool,
+    /// Whether the server answers the Unconnected Pings that are broadcast on the LAN discovery port, which is only
+    /// needed when the server is not itself bound to that port on all interfaces.
+    pub lan_discovery: bool,
+    /// The duration for which a proxy backend that could not be reached is skipped when pairing new clients.
+    pub backend_cooldown: Duration,
+    /// The number of threads that perform the offline handshakes of the proxy with its backends and those of the
+    /// connections to punched peers. The handshakes of further connections wait for one of them to be free.
+    pub proxy_handshake_threads: usize,
+    /// Whether the proxy sends the address of its downstream clients to the backends in a ProxyHeader message ahead
+    /// of the handshake of every upstream connection.
+    pub proxy_forwarding: bool,
+    /// The addresses of the proxies whose ProxyHeader messages are trusted by the server. The remote address of the
+    /// connections made through them is the forwarded one, the header is rejected from any other address and their
+    /// handshakes are refused without one.
+    pub trusted_proxies: Vec<IpAddr>,
+    /// The secret shared by the proxy and its backends that the ProxyHeader messages are signed with. The proxy does
+    /// not forward the addresses of its clients and the server rejects every header without one.
+    pub proxy_secret: Option<Vec<u8>>,
+    /// Whether the OpenConnectionRequest2 of the clients is only answered once the App approves it through the
+    /// ApproveConnection event, which lets games implement whitelists and maintenance gates.
+    pub connection_approval: bool,
+    /// The duration after which a connection that is still waiting for the approval of the App is refused. It should
+    /// stay below the time the clients wait for the answer of their request.
+    pub approval_timeout: Duration,
+    /// Which session is kept when a client connects with the GUID of a connection that is still alive or starts another
+    /// handshake from the address of one.
+    pub duplicate_login_policy: DuplicateLoginPolicy,
+    /// The clock that the connections read the current time from, the real time by default.
+    pub clock: TimeSource,
+}
+
+impl Default for RakNetConfig {
+    fn default() -> Self {
+        Self {
+            schedule: PreUpdate.intern(),
+            fixed_tick: false,
+            flush_interval: RAKNET_TPS,
+            check_interval: RAKNET_CHECK_TIMEOUT,
+            cleanup_interval: RAKNET_CLEANUP_INTERVAL,
+            timeout: RAKNET_TIMEOUT,
+            mcpe_login: false,
+            login_timeout: Some(LOGIN_TIMEOUT),
+            idle_timeout: None,
+            max_latency: None,
+            high_latency_grace: HIGH_LATENCY_GRACE,
+            packet_loss_threshold: Some(PACKET_LOSS_THRESHOLD),
+            retransmission_threshold: Some(RETRANSMISSION_THRESHOLD),
+            jitter_threshold: Some(JITTER_THRESHOLD),
+            exit_grace: RAKNET_EXIT_GRACE,
+            block_duration: RAKNET_BLOCK_DUR,
+            block_escalation: BLOCK_ESCALATION.to_vec(),
+            offense_memory: OFFENSE_MEMORY,
+            block_list_path: None,
+            max_msgs_per_sec: MAX_MSGS_PER_SEC,
+            max_unconnected_per_sec: MAX_UNCONNECTED_PER_SEC,
+            unconnected_block_duration: UNCONNECTED_BLOCK_DUR,
+            max_invalid_msgs: MAX_INVALID_MSGS,
+            max_pongs_per_sec: MAX_PONGS_PER_SEC,
+            max_global_pongs_per_sec: MAX_GLOBAL_PONGS_PER_SEC,
+            invalid_packets_idle: INVALID_PACKETS_IDLE,
+            max_tracked_addresses: MAX_TRACKED_ADDRESSES,
+            window_size: NonZeroU32::new(WINDOW_SIZE).unwrap(),
+            min_mtu_size: MinMtuSize::default(),
+            min_request_padding: MIN_REQUEST_PADDING,
+            max_batched_packets: MAX_BATCHED_PACKETS,
+            max_split_packets: MAX_SPLIT_PACKETS,
+            max_game_packet_size: MAX_GAME_PACKET_SIZE,
+            max_concurrent_splits: MAX_CONCURRENT_SPLITS,
+            max_split_bytes: MAX_SPLIT_BYTES,
+            max_buffered_bytes: MAX_BUFFERED_BYTES,
+            split_timeout: SPLIT_TIMEOUT,
+            compression_level: COMPRESSION_LEVEL,
+            compression_algorithm: CompressionAlgorithm::Zlib,
+            max_batch_size: MAX_BATCH_SIZE,
+            encryption: true,
+            online_mode: false,
+            dump_malformed_packets: false,
+            lan_discovery: false,
+            backend_cooldown: BACKEND_COOLDOWN,
+            proxy_handshake_threads: PROXY_HANDSHAKE_THREADS,
+            proxy_forwarding: false,
+            trusted_proxies: Vec::new(),
+            proxy_secret: None,
+            connection_approval: false,
+            approval_timeout: APPROVAL_TIMEOUT,
+            duplicate_login_policy: DuplicateLoginPolicy::default(),
+            clock: TimeSource::default(),
+        }
+    }
+}
+
+impl RakNetConfig {
+    /// Makes the plugins add the network systems to the provided schedule. If the schedule runs on a fixed
+    /// timestep, the flush systems run on every tick of it rather than on the flush interval.
+    pub fn in_schedule(mut self, schedule: impl ScheduleLabel, fixed_tick: bool) -> Self {
+        self.schedule = schedule.intern();
+        self.fixed_tick = fixed_tick;
+        self
+    }
+
+    /// Sets the abuse thresholds of the servers, the maximum number of datagrams an address can send in one second and
+    /// of malformed messages, and the duration for which the addresses exceeding either of them are blocked.
+    pub fn with_abuse_thresholds(
+        mut self,
+        max_msgs_per_sec: u32,
+        max_invalid_msgs: u32,
+        block_duration: Duration,
+    ) -> Self {
+        self.max_msgs_per_sec = max_msgs_per_sec;
+        self.max_invalid_msgs = max_invalid_msgs;
+        self.block_duration = block_duration;
+        self
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    net::SocketAddr,
+};
+
+use aes::{
+    cipher::{KeyIvInit, StreamCipher},
+    Aes256,
+};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use bevy::ecs::component::Component;
+use binary::Binary;
+use hmac::{Hmac, Mac};
+use p384::{
+    ecdh::diffie_hellman,
+    ecdsa::{signature::Signer, Signature, SigningKey},
+    pkcs8::{DecodePublicKey, EncodePublicKey},
+    PublicKey, SecretKey,
+};
+use rand::{rngs::OsRng, RngCore};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use super::{
+    binary::UDPAddress,
+    mcpe::login::{jwt_claims, jwt_header, verify_jwt},
+};
+
+/// MCPE encrypts the batches with AES-256-GCM without ever verifying the tag, which is equivalent to AES-256-CTR
+/// with a 32 bit big endian counter starting at 2.
+type Aes256Ctr = ctr::Ctr32BE<Aes256>;
+
+/// This is the size of the checksum appended to every batch before it is encrypted.
+const CHECKSUM_SIZE: usize = 8;
+
+/// EncryptionKeys holds the key and the cipher states of an encrypted connection. Once this component is inserted
+/// on a connection entity, all of its game packet batches are encrypted after compression and decrypted before
+/// decompression.
+#[derive(Component)]
+pub struct EncryptionKeys {
+    key: [u8; 32],
+    send_counter: u64,
+    recv_counter: u64,
+    encryptor: Aes256Ctr,
+    decryptor: Aes256Ctr,
+    failed: bool,
+}
+
+impl EncryptionKeys {
+    /// Creates the encryption keys of a connection from the key derived during the handshake. The IV is the first
+    /// 12 bytes of the key followed by the initial GCM counter.
+    pub fn new(key: [u8; 32]) -> Self {
+        let mut iv = [0u8; 16];
+        iv[..12].copy_from_slice(&key[..12]);
+        iv[15] = 2;
+
+        Self {
+            key,
+            send_counter: 0,
+            recv_counter: 0,
+            encryptor: Aes256Ctr::new(&key.into(), &iv.into()),
+            decryptor: Aes256Ctr::new(&key.into(), &iv.into()),
+            failed: false,
+        }
+    }
+
+    /// Creates the encryption keys of a connection on the client side from the JWT of the ServerToClientHandshake
+    /// packet and the private key of the client. The token must be signed by the key in its x5u header.
+    pub fn from_handshake(secret: &SecretKey, token: &str) -> Result<Self> {
+        let server_key = jwt_header(token)?["x5u"]
+            .as_str()
+            .ok_or(Error::new(
+                ErrorKind::Other,
+                "Handshake token has no x5u header",
+            ))?
+            .to_string();
+        verify_jwt(token, &server_key)?;
+
+        let salt = jwt_claims(token)?["salt"]
+            .as_str()
+            .ok_or(Error::new(ErrorKind::Other, "Handshake token has no salt"))
+            .and_then(|salt| {
+                STANDARD
+                    .decode(salt)
+                    .map_err(|e| Error::new(ErrorKind::Other, e))
+            })?;
+
+        shared_key(secret, &server_key, &salt)
+    }
+
+    /// Appends the checksum of the provided batch to it and encrypts it in place.
+    pub fn encrypt(&mut self, mut batch: Vec<u8>) -> Vec<u8> {
+        let checksum = self.checksum(self.send_counter, &batch);
+        self.send_counter += 1;
+
+        batch.extend_from_slice(&checksum);
+        self.encryptor.apply_keystream(&mut batch);
+        batch
+    }
+
+    /// Decrypts the provided batch in place and verifies the checksum appended to it. The checksum is removed from
+    /// the returned batch. The keystream and the counter cannot be rewound, so once a batch fails every following
+    /// batch is refused as well.
+    pub fn decrypt(&mut self, mut batch: Vec<u8>) -> Result<Vec<u8>> {
+        if self.failed {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "Encryption is out of sync after a failed batch",
+            ));
+        }
+
+        if batch.len() < CHECKSUM_SIZE {
+            self.failed = true;
+            return Err(Error::new(
+                ErrorKind::Other,
+                "Encrypted batch is too short to carry a checksum",
+            ));
+        }
+
+        self.decryptor.apply_keystream(&mut batch);
+
+        let offset = batch.len() - CHECKSUM_SIZE;
+        let checksum = self.checksum(self.recv_counter, &batch[..offset]);
+        self.recv_counter += 1;
+
+        if checksum != batch[offset..] {
+            self.failed = true;
+            return Err(Error::new(
+                ErrorKind::Other,
+                "Encrypted batch checksum mismatch",
+            ));
+        }
+
+        batch.truncate(offset);
+        Ok(batch)
+    }
+
+    /// Returns whether a batch failed to decrypt, after which the connection can no longer be decrypted.
+    pub fn has_failed(&self) -> bool {
+        self.failed
+    }
+
+    /// Returns the checksum of a batch which is the first 8 bytes of the SHA-256 hash of the counter, the batch and
+    /// the key.
+    fn checksum(&self, counter: u64, batch: &[u8]) -> [u8; CHECKSUM_SIZE] {
+        let mut hasher = Sha256::new();
+        hasher.update(counter.to_le_bytes());
+        hasher.update(batch);
+        hasher.update(self.key);
+
+        let mut checksum = [0u8; CHECKSUM_SIZE];
+        checksum.copy_from_slice(&hasher.finalize()[..CHECKSUM_SIZE]);
+        checksum
+    }
+}
+
+/// KeyExchange is the server side of the ECDH handshake of an MCPE connection. It holds the P-384 keypair of the
+/// server and the salt that the shared key is derived with.
+pub struct KeyExchange {
+    secret: SecretKey,
+    salt: [u8; 16],
+}
+
+impl Default for KeyExchange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyExchange {
+    /// Generates a new P-384 keypair and salt for the handshake of a single connection.
+    pub fn new() -> Self {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+
+        Self {
+            secret: SecretKey::random(&mut OsRng),
+            salt,
+        }
+    }
+
+    /// Derives the encryption keys of the connection from the identity public key of the client, which is the
+    /// base64 encoded DER key found in its login chain.
+    pub fn derive(&self, client_key: &str) -> Result<EncryptionKeys> {
+        shared_key(&self.secret, client_key, &self.salt)
+    }
+
+    /// Returns the JWT of the ServerToClientHandshake packet. It is signed with the private key of the server and
+    /// carries its public key in the header and the salt in the claims.
+    pub fn token(&self) -> Result<String> {
+        sign_jwt(&self.secret, &json!({ "salt": STANDARD.encode(self.salt) }))
+    }
+}
+
+/// Derives the encryption keys from the provided private key and the base64 encoded DER public key of the other end
+/// of the connection. The key is the SHA-256 hash of the salt and the shared secret.
+fn shared_key(secret: &SecretKey, public_key: &str, salt: &[u8]) -> Result<EncryptionKeys> {
+    let der = STANDARD
+        .decode(public_key)
+        .map_err(|e| Error::new(ErrorKind::Other, e))?;
+    let public_key =
+        PublicKey::from_public_key_der(&der).map_err(|e| Error::new(ErrorKind::Other, e))?;
+
+    let shared = diffie_hellman(secret.to_nonzero_scalar(), public_key.as_affine());
+
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(shared.raw_secret_bytes());
+
+    Ok(EncryptionKeys::new(hasher.finalize().into()))
+}
+
+/// Returns the base64 encoded DER public key of the provided private key.
+pub(crate) fn public_key_der(secret: &SecretKey) -> Result<String> {
+    let public_key = secret
+        .public_key()
+        .to_public_key_der()
+        .map_err(|e| Error::new(ErrorKind::Other, e))?;
+
+    Ok(STANDARD.encode(public_key.as_bytes()))
+}
+
+/// Returns a JWT with the provided claims signed with ES384 by the provided private key, whose public key is carried
+/// in the x5u header.
+pub(crate) fn sign_jwt(secret: &SecretKey, claims: &Value) -> Result<String> {
+    let header = json!({ "alg": "ES384", "x5u": public_key_der(secret)? });
+
+    let message = format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(header.to_string()),
+        URL_SAFE_NO_PAD.encode(claims.to_string())
+    );
+
+    let signature: Signature = SigningKey::from(secret).sign(message.as_bytes());
+
+    Ok(format!(
+        "{}.{}",
+        message,
+        URL_SAFE_NO_PAD.encode(signature.to_bytes())
+    ))
+}
+
+/// Returns the signature of a ProxyHeader, which is the HMAC-SHA256 of the forwarded client address, the timestamp
+/// and the nonce of the header keyed with the secret shared by the proxy and its backends.
+pub fn proxy_signature(secret: &[u8], client: SocketAddr, timestamp: i64, nonce: i64) -> [u8; 32] {
+    proxy_mac(secret, client, timestamp, nonce)
+        .finalize()
+        .into_bytes()
+        .into()
+}
+
+/// Returns whether the provided signature of a ProxyHeader was made with the secret, it is compared in constant time.
+pub fn verify_proxy_signature(
+    secret: &[u8],
+    client: SocketAddr,
+    timestamp: i64,
+    nonce: i64,
+    signature: &[u8; 32],
+) -> bool {
+    proxy_mac(secret, client, timestamp, nonce)
+        .verify_slice(signature)
+        .is_ok()
+}
+
+/// Returns the HMAC-SHA256 keyed with the provided secret that was fed the fields of a ProxyHeader.
+fn proxy_mac(secret: &[u8], client: SocketAddr, timestamp: i64, nonce: i64) -> Hmac<Sha256> {
+    // HMAC accepts keys of any length, so this cannot fail.
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+
+    let mut buf = Vec::new();
+    UDPAddress(client).serialize(&mut buf);
+    mac.update(&buf);
+    mac.update(&timestamp.to_be_bytes());
+    mac.update(&nonce.to_be_bytes());
+
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decrypt_round_trip() {
+        let mut sender = EncryptionKeys::new([7; 32]);
+        let mut receiver = EncryptionKeys::new([7; 32]);
+
+        for batch in [b"first".to_vec(), b"second".to_vec()] {
+            let encrypted = sender.encrypt(batch.clone());
+            assert_eq!(receiver.decrypt(encrypted).unwrap(), batch);
+        }
+    }
+
+    #[test]
+    fn failed_batch_refuses_the_following_ones() {
+        let mut sender = EncryptionKeys::new([7; 32]);
+        let mut receiver = EncryptionKeys::new([7; 32]);
+
+        let mut tampered = sender.encrypt(b"first".to_vec());
+        tampered[0] ^= 1;
+
+        assert!(receiver.decrypt(tampered).is_err());
+        assert!(receiver.has_failed());
+
+        let valid = sender.encrypt(b"second".to_vec());
+        assert!(receiver.decrypt(valid).is_err());
+    }
+
+    #[test]
+    fn proxy_signature_covers_every_field() {
+        let client = "10.0.0.1:19132".parse().unwrap();
+        let signature = proxy_signature(b"secret", client, 1000, 7);
+
+        assert!(verify_proxy_signature(
+            b"secret", client, 1000, 7, &signature
+        ));
+        assert!(!verify_proxy_signature(
+            b"other", client, 1000, 7, &signature
+        ));
+        assert!(!verify_proxy_signature(
+            b"secret",
+            "10.0.0.2:19132".parse().unwrap(),
+            1000,
+            7,
+            &signature
+        ));
+        assert!(!verify_proxy_signature(
+            b"secret", client, 1001, 7, &signature
+        ));
+        assert!(!verify_proxy_signature(
+            b"secret", client, 1000, 8, &signature
+        ));
+    }
+}
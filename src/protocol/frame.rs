@@ -0,0 +1,501 @@
+use std::io::{Cursor, Error, ErrorKind, Result, Write};
+
+use binary::{
+    datatypes::{I16, U16, U24, U32},
+    Binary,
+};
+use byteorder::{ReadBytesExt, WriteBytesExt, BE, LE};
+use bytes::Buf;
+
+use super::{
+    reliability::Reliability, FLAG_ACK, FLAG_DATAGRAM, FLAG_FRAGMENTED, FLAG_NACK,
+    FRAME_ADDITIONAL_SIZE, MAX_RECEIPT_SEQUENCES,
+};
+
+/// Packet is a datagram received from the other end of a connection decoded without any connection state. It either
+/// carries the records of an ACK or a NACK receipt or the frames of a Datagram.
+#[derive(Debug)]
+pub enum Packet<'a> {
+    Ack(Vec<AckRecord>),
+    Nack(Vec<AckRecord>),
+    Datagram(Datagram<'a>),
+}
+
+/// Decodes the provided bytes of a datagram into a Packet. Malformed bytes are reported as an error rather than a
+/// panic, so this is the entry point for fuzzing the decoding of the datagrams.
+pub fn decode_datagram_bytes(bytes: &[u8]) -> Result<Packet<'_>> {
+    let mut reader = Cursor::new(bytes);
+    let header = reader.read_u8()?;
+
+    if header & FLAG_DATAGRAM == 0 {
+        return Err(Error::new(
+            ErrorKind::Other,
+            "Buffer does not have a valid FLAG_DATAGRAM",
+        ));
+    }
+
+    if header & FLAG_ACK != 0 {
+        return Ok(Packet::Ack(read_receipts(&mut reader)?));
+    }
+
+    if header & FLAG_NACK != 0 {
+        return Ok(Packet::Nack(read_receipts(&mut reader)?));
+    }
+
+    reader.set_position(0);
+    Ok(Packet::Datagram(Datagram::deserialize(&mut reader)?))
+}
+
+/// Reads the records of an ACK or a NACK receipt. The receipts covering more than MAX_RECEIPT_SEQUENCES sequence
+/// numbers are rejected.
+fn read_receipts(reader: &mut Cursor<&[u8]>) -> Result<Vec<AckRecord>> {
+    let record_count = I16::<BE>::deserialize(reader)?.0;
+
+    let mut records = Vec::new();
+    let mut sequences = 0;
+
+    for _ in 0..record_count {
+        let record = AckRecord::deserialize(reader)?;
+        sequences += record.count();
+
+        if sequences > MAX_RECEIPT_SEQUENCES {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "Receipt covers too many sequence numbers",
+            ));
+        }
+
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+/// Datagram is the unit of transmission of a RakNet connection. It carries a sequence number that is
+/// acknowledged by the other end of the connection and one or more encapsulated frames.
+#[derive(Debug)]
+pub struct Datagram<'a> {
+    pub header: u8,
+    pub sequence: u32,
+    pub frames: Vec<Frame<'a>>,
+}
+
+impl<'a> Binary<'a> for Datagram<'a> {
+    fn serialize(&self, buf: &mut impl Write) {
+        buf.write_u8(self.header).unwrap();
+        U24::<LE>::new(self.sequence).serialize(buf);
+
+        for frame in self.frames.iter() {
+            frame.serialize(buf);
+        }
+    }
+
+    fn deserialize(buf: &mut Cursor<&'a [u8]>) -> Result<Self> {
+        let header = buf.read_u8()?;
+
+        if header & FLAG_DATAGRAM == 0 {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "Buffer does not have a valid FLAG_DATAGRAM",
+            ));
+        }
+
+        let sequence = U24::<LE>::deserialize(buf)?.0;
+        let mut frames = Vec::new();
+
+        while buf.remaining() != 0 {
+            frames.push(Frame::deserialize(buf)?);
+        }
+
+        Ok(Self {
+            header,
+            sequence,
+            frames,
+        })
+    }
+}
+
+/// Split contains the information about a fragment of a RakNet message that was too large to fit
+/// in a single datagram.
+#[derive(Debug, Clone, Copy)]
+pub struct Split {
+    pub count: u32,
+    pub id: u16,
+    pub index: u32,
+}
+
+/// Frame is an encapsulated RakNet message (or a fragment of it) along with the reliability information
+/// that is required to process it on the other end of the connection.
+#[derive(Debug)]
+pub struct Frame<'a> {
+    pub reliability: Reliability,
+    pub message_index: u32,
+    pub sequence_index: u32,
+    pub order_index: u32,
+    pub order_channel: u8,
+    pub split: Option<Split>,
+    pub content: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    /// Returns the size of the header of a frame with the provided reliability, including the additional
+    /// split information if the frame is a fragment of a split message.
+    pub fn header_size(reliability: &Reliability, split: bool) -> usize {
+        let mut size = 1 + 2;
+
+        if reliability.reliable() {
+            size += 3;
+        }
+
+        if reliability.sequenced() {
+            size += 3;
+        }
+
+        if reliability.sequenced_or_ordered() {
+            size += 3 + 1;
+        }
+
+        if split {
+            size += FRAME_ADDITIONAL_SIZE;
+        }
+
+        size
+    }
+
+    /// Returns the exact number of bytes this frame takes when it is serialized.
+    pub fn size(&self) -> usize {
+        Self::header_size(&self.reliability, self.split.is_some()) + self.content.len()
+    }
+}
+
+impl<'a> Binary<'a> for Frame<'a> {
+    fn serialize(&self, buf: &mut impl Write) {
+        let mut header = (self.reliability.clone() as u8) << 5;
+        if self.split.is_some() {
+            header |= FLAG_FRAGMENTED;
+        }
+
+        buf.write_u8(header).unwrap();
+        U16::<BE>::new((self.content.len() as u16) << 3).serialize(buf);
+
+        if self.reliability.reliable() {
+            U24::<LE>::new(self.message_index).serialize(buf);
+        }
+
+        if self.reliability.sequenced() {
+            U24::<LE>::new(self.sequence_index).serialize(buf);
+        }
+
+        if self.reliability.sequenced_or_ordered() {
+            U24::<LE>::new(self.order_index).serialize(buf);
+            buf.write_u8(self.order_channel).unwrap();
+        }
+
+        if let Some(split) = &self.split {
+            U32::<BE>::new(split.count).serialize(buf);
+            U16::<BE>::new(split.id).serialize(buf);
+            U32::<BE>::new(split.index).serialize(buf);
+        }
+
+        buf.write_all(self.content).unwrap();
+    }
+
+    fn deserialize(buf: &mut Cursor<&'a [u8]>) -> Result<Self> {
+        let header = buf.read_u8()?;
+        let reliability = Reliability::try_from((header & 224) >> 5)?;
+
+        let length = (U16::<BE>::deserialize(buf)?.0 >> 3) as usize;
+
+        if length == 0 {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "RakNet Message content length cannot be 0",
+            ));
+        }
+
+        let mut frame = Frame {
+            reliability,
+            message_index: 0,
+            sequence_index: 0,
+            order_index: 0,
+            order_channel: 0,
+            split: None,
+            content: &[],
+        };
+
+        if frame.reliability.reliable() {
+            frame.message_index = U24::<LE>::deserialize(buf)?.0;
+        }
+
+        if frame.reliability.sequenced() {
+            frame.sequence_index = U24::<LE>::deserialize(buf)?.0;
+        }
+
+        if frame.reliability.sequenced_or_ordered() {
+            frame.order_index = U24::<LE>::deserialize(buf)?.0;
+            frame.order_channel = buf.read_u8()?;
+        }
+
+        if header & FLAG_FRAGMENTED != 0 {
+            frame.split = Some(Split {
+                count: U32::<BE>::deserialize(buf)?.0,
+                id: U16::<BE>::deserialize(buf)?.0,
+                index: U32::<BE>::deserialize(buf)?.0,
+            });
+        }
+
+        if buf.remaining() < length {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "RakNet Message content length exceeds the remaining datagram",
+            ));
+        }
+
+        let start = buf.position() as usize;
+        let inner: &'a [u8] = buf.get_ref();
+
+        frame.content = &inner[start..start + length];
+        buf.advance(length);
+
+        Ok(frame)
+    }
+}
+
+/// AckRecord is a single record of an ACK or a NACK receipt. It either acknowledges a single sequence
+/// number or an inclusive range of sequence numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckRecord {
+    Range(u32, u32),
+    Single(u32),
+}
+
+impl AckRecord {
+    /// Returns an iterator over all the sequence numbers that are covered by this record.
+    pub fn sequences(&self) -> std::ops::RangeInclusive<u32> {
+        match *self {
+            AckRecord::Range(start, end) => start..=end,
+            AckRecord::Single(seq) => seq..=seq,
+        }
+    }
+
+    /// Returns the number of sequence numbers that are covered by this record.
+    pub fn count(&self) -> usize {
+        match *self {
+            AckRecord::Range(start, end) => (end - start) as usize + 1,
+            AckRecord::Single(_) => 1,
+        }
+    }
+}
+
+impl<'a> Binary<'a> for AckRecord {
+    fn serialize(&self, buf: &mut impl Write) {
+        match *self {
+            AckRecord::Range(start, end) => {
+                buf.write_u8(0).unwrap();
+                U24::<LE>::new(start).serialize(buf);
+                U24::<LE>::new(end).serialize(buf);
+            }
+            AckRecord::Single(seq) => {
+                buf.write_u8(1).unwrap();
+                U24::<LE>::new(seq).serialize(buf);
+            }
+        }
+    }
+
+    fn deserialize(buf: &mut Cursor<&'a [u8]>) -> Result<Self> {
+        match buf.read_u8()? {
+            0 => {
+                let start = U24::<LE>::deserialize(buf)?.0;
+                let end = U24::<LE>::deserialize(buf)?.0;
+
+                if end < start {
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        "Record Range cannot end before it starts",
+                    ));
+                }
+
+                Ok(AckRecord::Range(start, end))
+            }
+            1 => Ok(AckRecord::Single(U24::<LE>::deserialize(buf)?.0)),
+            _ => Err(Error::new(
+                ErrorKind::Other,
+                "Record Type can either be Single (1) or Range (0)",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use binary::Binary;
+
+    use super::*;
+    use crate::protocol::FLAG_NEEDS_B_AND_AS;
+
+    fn frame(reliability: Reliability, split: Option<Split>, content: &[u8]) -> Frame<'_> {
+        Frame {
+            reliability,
+            message_index: 0x0a0b0c,
+            sequence_index: 0x010203,
+            order_index: 0x040506,
+            order_channel: 7,
+            split,
+            content,
+        }
+    }
+
+    fn encode<'a>(value: &impl Binary<'a>) -> Vec<u8> {
+        let mut buf = Vec::new();
+        value.serialize(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let split = Split {
+            count: 3,
+            id: 9,
+            index: 2,
+        };
+
+        for reliability in 0..5 {
+            for split in [None, Some(split)] {
+                let reliability = Reliability::try_from(reliability).unwrap();
+                let original = frame(reliability, split, b"hello");
+                let bytes = encode(&original);
+
+                assert_eq!(bytes.len(), original.size());
+
+                let decoded = Frame::deserialize(&mut Cursor::new(&bytes[..])).unwrap();
+
+                assert_eq!(decoded.reliability, original.reliability);
+                assert_eq!(decoded.content, original.content);
+                assert_eq!(decoded.split.is_some(), split.is_some());
+
+                if original.reliability.reliable() {
+                    assert_eq!(decoded.message_index, original.message_index);
+                }
+
+                if original.reliability.sequenced() {
+                    assert_eq!(decoded.sequence_index, original.sequence_index);
+                }
+
+                if original.reliability.sequenced_or_ordered() {
+                    assert_eq!(decoded.order_index, original.order_index);
+                    assert_eq!(decoded.order_channel, original.order_channel);
+                }
+
+                if let (Some(decoded), Some(original)) = (decoded.split, split) {
+                    assert_eq!(decoded.count, original.count);
+                    assert_eq!(decoded.id, original.id);
+                    assert_eq!(decoded.index, original.index);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn datagram_round_trip() {
+        let original = Datagram {
+            header: FLAG_DATAGRAM | FLAG_NEEDS_B_AND_AS,
+            sequence: 0xfffffe,
+            frames: vec![
+                frame(Reliability::ReliableOrdered, None, b"first"),
+                frame(Reliability::Unreliable, None, b"second"),
+            ],
+        };
+        let bytes = encode(&original);
+
+        let Packet::Datagram(decoded) = decode_datagram_bytes(&bytes).unwrap() else {
+            panic!("expected a datagram");
+        };
+
+        assert_eq!(decoded.header, original.header);
+        assert_eq!(decoded.sequence, original.sequence);
+        assert_eq!(decoded.frames.len(), 2);
+        assert_eq!(decoded.frames[0].content, b"first");
+        assert_eq!(decoded.frames[1].content, b"second");
+    }
+
+    #[test]
+    fn ack_record_round_trip() {
+        for record in [AckRecord::Single(42), AckRecord::Range(10, 20)] {
+            let bytes = encode(&record);
+            let decoded = AckRecord::deserialize(&mut Cursor::new(&bytes[..])).unwrap();
+
+            assert_eq!(decoded, record);
+        }
+    }
+
+    #[test]
+    fn receipts_round_trip() {
+        let records = [AckRecord::Range(0, 4), AckRecord::Single(8)];
+
+        for flag in [FLAG_ACK, FLAG_NACK] {
+            let mut bytes = vec![FLAG_DATAGRAM | flag];
+            I16::<BE>::new(records.len() as i16).serialize(&mut bytes);
+
+            for record in records.iter() {
+                record.serialize(&mut bytes);
+            }
+
+            match decode_datagram_bytes(&bytes).unwrap() {
+                Packet::Ack(decoded) if flag == FLAG_ACK => assert_eq!(decoded, records),
+                Packet::Nack(decoded) if flag == FLAG_NACK => assert_eq!(decoded, records),
+                packet => panic!("unexpected packet {:?}", packet),
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_inputs_are_rejected() {
+        let datagram = Datagram {
+            header: FLAG_DATAGRAM,
+            sequence: 1,
+            frames: vec![frame(Reliability::ReliableSequenced, None, b"payload")],
+        };
+        let bytes = encode(&datagram);
+
+        // A datagram cut right after its header is still valid, it just carries no frames.
+        for end in (0..4).chain(5..bytes.len()) {
+            assert!(
+                decode_datagram_bytes(&bytes[..end]).is_err(),
+                "{} bytes were accepted",
+                end
+            );
+        }
+
+        let record = encode(&AckRecord::Range(1, 2));
+
+        for end in 0..record.len() {
+            assert!(AckRecord::deserialize(&mut Cursor::new(&record[..end])).is_err());
+        }
+    }
+
+    #[test]
+    fn malformed_inputs_are_rejected() {
+        assert!(decode_datagram_bytes(&[0x00, 0x01, 0x00, 0x00]).is_err());
+
+        let invalid_reliability = [FLAG_DATAGRAM, 0, 0, 0, 7 << 5, 0x00, 0x08, 0xff];
+        assert!(decode_datagram_bytes(&invalid_reliability).is_err());
+
+        let empty_content = [FLAG_DATAGRAM, 0, 0, 0, 0x00, 0x00, 0x00];
+        assert!(decode_datagram_bytes(&empty_content).is_err());
+
+        let oversized_content = [FLAG_DATAGRAM, 0, 0, 0, 0x00, 0x00, 0x50, 0xff];
+        assert!(decode_datagram_bytes(&oversized_content).is_err());
+
+        let backwards_range = encode(&AckRecord::Range(20, 10));
+        assert!(AckRecord::deserialize(&mut Cursor::new(&backwards_range[..])).is_err());
+
+        assert!(AckRecord::deserialize(&mut Cursor::new(&[2, 0, 0, 0][..])).is_err());
+
+        let mut oversized_receipt = vec![FLAG_DATAGRAM | FLAG_ACK];
+        I16::<BE>::new(1).serialize(&mut oversized_receipt);
+        AckRecord::Range(0, MAX_RECEIPT_SEQUENCES as u32).serialize(&mut oversized_receipt);
+        assert!(decode_datagram_bytes(&oversized_receipt).is_err());
+    }
+}
@@ -0,0 +1,595 @@
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use bevy::ecs::component::Component;
+use binary::Binary;
+use byteorder::{ReadBytesExt, WriteBytesExt, BE, LE};
+use p384::{
+    ecdsa::{signature::Verifier, Signature, VerifyingKey},
+    pkcs8::DecodePublicKey,
+    SecretKey,
+};
+use rand::{rngs::OsRng, RngCore};
+use serde_json::{json, Value};
+use std::{
+    io::{Cursor, Error, ErrorKind, Read, Result, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use uuid::{Builder, Uuid};
+
+use crate::protocol::{
+    binary::VarU32,
+    encryption::{public_key_der, sign_jwt},
+};
+
+/// LoginState is the state of the MCPE login sequence of a connection. The server advances it as the packets of the
+/// sequence are received, the connection is established once it reaches LoggedIn.
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LoginState {
+    /// The client has not requested the network settings yet.
+    #[default]
+    AwaitingNetworkSettings,
+    /// The network settings were sent and the Login packet of the client is awaited.
+    AwaitingLogin,
+    /// The ServerToClientHandshake was sent and the ClientToServerHandshake of the client is awaited.
+    AwaitingHandshake,
+    /// The PlayStatus LoginSuccess was sent and the client is ready to be spawned.
+    LoggedIn,
+}
+
+/// This is the ID of the PlayStatus packet.
+pub const PLAY_STATUS: u32 = 0x02;
+
+/// This is the ID of the ClientToServerHandshake packet.
+pub const CLIENT_TO_SERVER_HANDSHAKE: u32 = 0x04;
+
+/// Status is the status carried by the PlayStatus packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    LoginSuccess = 0,
+    LoginFailedClient = 1,
+    LoginFailedServer = 2,
+    PlayerSpawn = 3,
+    LoginFailedInvalidTenant = 4,
+    LoginFailedVanillaEdu = 5,
+    LoginFailedEduVanilla = 6,
+    LoginFailedServerFull = 7,
+}
+
+impl Status {
+    /// Returns the status with the provided value as sent in the PlayStatus packet.
+    pub fn from_i32(value: i32) -> Option<Self> {
+        match value {
+            0 => Some(Status::LoginSuccess),
+            1 => Some(Status::LoginFailedClient),
+            2 => Some(Status::LoginFailedServer),
+            3 => Some(Status::PlayerSpawn),
+            4 => Some(Status::LoginFailedInvalidTenant),
+            5 => Some(Status::LoginFailedVanillaEdu),
+            6 => Some(Status::LoginFailedEduVanilla),
+            7 => Some(Status::LoginFailedServerFull),
+            _ => None,
+        }
+    }
+}
+
+/// PlayStatus is sent by the server to notify the client of the result of its login or that it can be spawned.
+#[derive(Debug, Clone)]
+pub struct PlayStatus {
+    pub status: Status,
+}
+
+impl<'a> Binary<'a> for PlayStatus {
+    fn serialize(&self, buf: &mut impl Write) {
+        VarU32(PLAY_STATUS).serialize(buf);
+        buf.write_i32::<BE>(self.status as i32).unwrap();
+    }
+
+    fn deserialize(buf: &mut Cursor<&'a [u8]>) -> Result<Self> {
+        if VarU32::deserialize(buf)?.0 != PLAY_STATUS {
+            return Err(Error::new(ErrorKind::Other, "Packet is not a PlayStatus"));
+        }
+
+        let status = Status::from_i32(buf.read_i32::<BE>()?)
+            .ok_or(Error::new(ErrorKind::Other, "Unknown PlayStatus status"))?;
+
+        Ok(Self { status })
+    }
+}
+
+/// ClientToServerHandshake is sent encrypted by the client once it has derived the keys from the
+/// ServerToClientHandshake. It does not carry any data.
+#[derive(Debug, Clone)]
+pub struct ClientToServerHandshake;
+
+impl<'a> Binary<'a> for ClientToServerHandshake {
+    fn serialize(&self, buf: &mut impl Write) {
+        VarU32(CLIENT_TO_SERVER_HANDSHAKE).serialize(buf);
+    }
+
+    fn deserialize(buf: &mut Cursor<&'a [u8]>) -> Result<Self> {
+        if VarU32::deserialize(buf)?.0 != CLIENT_TO_SERVER_HANDSHAKE {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "Packet is not a ClientToServerHandshake",
+            ));
+        }
+
+        Ok(Self)
+    }
+}
+
+/// This is the ID of the NetworkSettings packet.
+pub const NETWORK_SETTINGS: u32 = 0x8f;
+
+/// This is the ID of the RequestNetworkSettings packet.
+pub const REQUEST_NETWORK_SETTINGS: u32 = 0xc1;
+
+/// RequestNetworkSettings is the first packet sent by the client after the RakNet connection is established. It is
+/// sent uncompressed and carries the protocol version of the client.
+#[derive(Debug, Clone)]
+pub struct RequestNetworkSettings {
+    pub protocol: i32,
+}
+
+impl<'a> Binary<'a> for RequestNetworkSettings {
+    fn serialize(&self, buf: &mut impl Write) {
+        VarU32(REQUEST_NETWORK_SETTINGS).serialize(buf);
+        buf.write_i32::<BE>(self.protocol).unwrap();
+    }
+
+    fn deserialize(buf: &mut Cursor<&'a [u8]>) -> Result<Self> {
+        if VarU32::deserialize(buf)?.0 != REQUEST_NETWORK_SETTINGS {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "Packet is not a RequestNetworkSettings",
+            ));
+        }
+
+        Ok(Self {
+            protocol: buf.read_i32::<BE>()?,
+        })
+    }
+}
+
+/// NetworkSettings is sent by the server in response to RequestNetworkSettings. All the batches following it are
+/// compressed with the algorithm it carries.
+#[derive(Debug, Clone)]
+pub struct NetworkSettings {
+    pub compression_threshold: u16,
+    pub compression_algorithm: u16,
+    pub client_throttle: bool,
+    pub client_throttle_threshold: u8,
+    pub client_throttle_scalar: f32,
+}
+
+impl<'a> Binary<'a> for NetworkSettings {
+    fn serialize(&self, buf: &mut impl Write) {
+        VarU32(NETWORK_SETTINGS).serialize(buf);
+        buf.write_u16::<LE>(self.compression_threshold).unwrap();
+        buf.write_u16::<LE>(self.compression_algorithm).unwrap();
+        buf.write_u8(self.client_throttle as u8).unwrap();
+        buf.write_u8(self.client_throttle_threshold).unwrap();
+        buf.write_f32::<LE>(self.client_throttle_scalar).unwrap();
+    }
+
+    fn deserialize(buf: &mut Cursor<&'a [u8]>) -> Result<Self> {
+        if VarU32::deserialize(buf)?.0 != NETWORK_SETTINGS {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "Packet is not a NetworkSettings",
+            ));
+        }
+
+        Ok(Self {
+            compression_threshold: buf.read_u16::<LE>()?,
+            compression_algorithm: buf.read_u16::<LE>()?,
+            client_throttle: buf.read_u8()? != 0,
+            client_throttle_threshold: buf.read_u8()?,
+            client_throttle_scalar: buf.read_f32::<LE>()?,
+        })
+    }
+}
+
+/// This is the ID of the ServerToClientHandshake packet.
+pub const SERVER_TO_CLIENT_HANDSHAKE: u32 = 0x03;
+
+/// ServerToClientHandshake is sent by the server to start the encryption of the connection. It carries the JWT
+/// signed by the server which holds its public key and the salt of the shared key.
+#[derive(Debug, Clone)]
+pub struct ServerToClientHandshake {
+    pub token: String,
+}
+
+impl<'a> Binary<'a> for ServerToClientHandshake {
+    fn serialize(&self, buf: &mut impl Write) {
+        VarU32(SERVER_TO_CLIENT_HANDSHAKE).serialize(buf);
+        VarU32(self.token.len() as u32).serialize(buf);
+        buf.write_all(self.token.as_bytes()).unwrap();
+    }
+
+    fn deserialize(buf: &mut Cursor<&'a [u8]>) -> Result<Self> {
+        if VarU32::deserialize(buf)?.0 != SERVER_TO_CLIENT_HANDSHAKE {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "Packet is not a ServerToClientHandshake",
+            ));
+        }
+
+        let len = VarU32::deserialize(buf)?.0 as usize;
+        if len > buf.get_ref().len() - buf.position() as usize {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "Token length exceeds the packet",
+            ));
+        }
+
+        let mut token = vec![0u8; len];
+        buf.read_exact(&mut token)?;
+
+        let token = String::from_utf8(token).map_err(|e| Error::new(ErrorKind::Other, e))?;
+
+        Ok(Self { token })
+    }
+}
+
+/// This is the public key of Mojang that signs the login chains of the clients authenticated with Xbox Live.
+pub const MOJANG_PUBLIC_KEY: &str = "MHYwEAYHKoZIzj0CAQYFK4EEACIDYgAECRXueJeTDqNRRgJi/vlRufByu/2G0i2Ebt6YMar5QX/R0DIIyrJMcUpruK4QveTfJSTp3Shlq4Gk34cD/4GUWwkv0DVuzeuB+tXija7HBxii03NHDbPAD0AKnLr2wdAp";
+
+/// This is the maximum number of tokens in the certificate chain of a Login packet. The chains of the clients
+/// authenticated with Xbox Live hold the self-signed root, the token of Mojang and the token of the client.
+pub const MAX_CHAIN_LENGTH: usize = 3;
+
+/// This is the ID of the Login packet.
+pub const LOGIN: u32 = 0x01;
+
+/// Login is the first packet sent by the client after the network settings are negotiated. It carries the
+/// certificate chain of the client and the JWT holding the data of its device.
+#[derive(Debug, Clone)]
+pub struct Login {
+    pub protocol: i32,
+    pub chain: Vec<String>,
+    pub client_data: String,
+}
+
+impl<'a> Binary<'a> for Login {
+    fn serialize(&self, buf: &mut impl Write) {
+        let chain = json!({ "chain": self.chain }).to_string();
+
+        VarU32(LOGIN).serialize(buf);
+        buf.write_i32::<BE>(self.protocol).unwrap();
+        VarU32((4 + chain.len() + 4 + self.client_data.len()) as u32).serialize(buf);
+        buf.write_i32::<LE>(chain.len() as i32).unwrap();
+        buf.write_all(chain.as_bytes()).unwrap();
+        buf.write_i32::<LE>(self.client_data.len() as i32).unwrap();
+        buf.write_all(self.client_data.as_bytes()).unwrap();
+    }
+
+    fn deserialize(buf: &mut Cursor<&'a [u8]>) -> Result<Self> {
+        if VarU32::deserialize(buf)?.0 != LOGIN {
+            return Err(Error::new(ErrorKind::Other, "Packet is not a Login"));
+        }
+
+        let protocol = buf.read_i32::<BE>()?;
+        VarU32::deserialize(buf)?;
+
+        let chain: Value = serde_json::from_str(&read_i32_string(buf)?)?;
+        let chain = chain["chain"]
+            .as_array()
+            .ok_or(Error::new(ErrorKind::Other, "Login chain is not an array"))?
+            .iter()
+            .map(|token| token.as_str().map(str::to_string))
+            .collect::<Option<Vec<String>>>()
+            .ok_or(Error::new(
+                ErrorKind::Other,
+                "Login chain contains a non string token",
+            ))?;
+
+        let client_data = read_i32_string(buf)?;
+
+        Ok(Self {
+            protocol,
+            chain,
+            client_data,
+        })
+    }
+}
+
+impl Login {
+    /// Verifies the signatures of the certificate chain and the client data. Every token of the chain must be signed
+    /// by the identity public key of the previous one, the first of them by the key in its own header. Returns
+    /// whether the token right after the self-signed root was signed by the Mojang root key.
+    pub fn verify(&self) -> Result<bool> {
+        let first = checked_chain(&self.chain)?;
+
+        let mut key = jwt_header(first)?["x5u"]
+            .as_str()
+            .ok_or(Error::new(
+                ErrorKind::Other,
+                "Login chain has no x5u header",
+            ))?
+            .to_string();
+        let mut authenticated = false;
+
+        for (index, token) in self.chain.iter().enumerate() {
+            verify_jwt(token, &key)?;
+
+            // Only the token signed by the root can be signed by Mojang, a Mojang key claimed further down the chain
+            // would let a self-signed token in front of a valid chain pass for it.
+            if index == 1 {
+                authenticated = key == MOJANG_PUBLIC_KEY;
+            }
+
+            key = jwt_claims(token)?["identityPublicKey"]
+                .as_str()
+                .ok_or(Error::new(
+                    ErrorKind::Other,
+                    "Login chain has no identity public key",
+                ))?
+                .to_string();
+        }
+
+        verify_jwt(&self.client_data, &key)?;
+
+        Ok(authenticated)
+    }
+}
+
+/// LoginData is the component holding the decoded identity and device of the client that was sent in its Login
+/// packet. It is inserted on the connection entity once the Login packet is parsed.
+#[derive(Component, Debug, Clone)]
+pub struct LoginData {
+    pub protocol: i32,
+    pub display_name: String,
+    pub uuid: Uuid,
+    pub xuid: String,
+    pub identity_public_key: String,
+    pub device_os: i64,
+    pub device_model: String,
+    pub device_id: String,
+    pub game_version: String,
+    pub language_code: String,
+}
+
+impl LoginData {
+    /// Decodes the claims of the certificate chain and the client data of the provided Login packet. The signatures
+    /// of the tokens are not verified. The identity is only read from the last token of the chain, which is the one
+    /// verified last.
+    pub fn parse(login: &Login) -> Result<Self> {
+        checked_chain(&login.chain)?;
+
+        let claims = jwt_claims(login.chain.last().unwrap())?;
+
+        let identity = claims.get("extraData").ok_or(Error::new(
+            ErrorKind::Other,
+            "Login chain has no identity data",
+        ))?;
+
+        let identity_public_key = claims["identityPublicKey"].as_str().ok_or(Error::new(
+            ErrorKind::Other,
+            "Login chain has no identity public key",
+        ))?;
+
+        let client_data = jwt_claims(&login.client_data)?;
+
+        Ok(Self {
+            protocol: login.protocol,
+            display_name: json_string(identity, "displayName")?,
+            uuid: Uuid::parse_str(&json_string(identity, "identity")?)
+                .map_err(|e| Error::new(ErrorKind::Other, e))?,
+            xuid: identity["XUID"].as_str().unwrap_or_default().to_string(),
+            identity_public_key: identity_public_key.to_string(),
+            device_os: client_data["DeviceOS"].as_i64().unwrap_or_default(),
+            device_model: client_data["DeviceModel"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            device_id: client_data["DeviceId"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            game_version: client_data["GameVersion"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            language_code: client_data["LanguageCode"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+        })
+    }
+}
+
+/// Returns the first token of the provided certificate chain after checking that it is neither empty nor longer than
+/// the maximum chain length.
+fn checked_chain(chain: &[String]) -> Result<&String> {
+    if chain.len() > MAX_CHAIN_LENGTH {
+        return Err(Error::new(ErrorKind::Other, "Login chain is too long"));
+    }
+
+    chain
+        .first()
+        .ok_or(Error::new(ErrorKind::Other, "Login chain is empty"))
+}
+
+/// Decodes the claims of the provided JWT without verifying its signature.
+pub(crate) fn jwt_claims(token: &str) -> Result<Value> {
+    jwt_part(token, 1)
+}
+
+/// Decodes the header of the provided JWT.
+pub(crate) fn jwt_header(token: &str) -> Result<Value> {
+    jwt_part(token, 0)
+}
+
+/// Decodes the JSON of the part of the provided JWT at the provided index.
+fn jwt_part(token: &str, index: usize) -> Result<Value> {
+    let part = token
+        .split('.')
+        .nth(index)
+        .ok_or(Error::new(ErrorKind::Other, "Token is not a JWT"))?;
+    let part = URL_SAFE_NO_PAD
+        .decode(part.trim_end_matches('='))
+        .map_err(|e| Error::new(ErrorKind::Other, e))?;
+
+    Ok(serde_json::from_slice(&part)?)
+}
+
+/// Verifies the ES384 signature of the provided JWT with the provided base64 encoded DER public key and checks that
+/// the token has not expired.
+pub(crate) fn verify_jwt(token: &str, key: &str) -> Result<()> {
+    let (message, signature) = token
+        .rsplit_once('.')
+        .ok_or(Error::new(ErrorKind::Other, "Token is not a JWT"))?;
+
+    let key = STANDARD
+        .decode(key)
+        .map_err(|e| Error::new(ErrorKind::Other, e))?;
+    let key =
+        VerifyingKey::from_public_key_der(&key).map_err(|e| Error::new(ErrorKind::Other, e))?;
+
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature.trim_end_matches('='))
+        .map_err(|e| Error::new(ErrorKind::Other, e))?;
+    let signature =
+        Signature::from_slice(&signature).map_err(|e| Error::new(ErrorKind::Other, e))?;
+
+    key.verify(message.as_bytes(), &signature)
+        .map_err(|e| Error::new(ErrorKind::Other, e))?;
+
+    let claims = jwt_claims(token)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs() as i64)
+        .unwrap_or_default();
+
+    if claims["exp"].as_i64().is_some_and(|exp| exp < now)
+        || claims["nbf"].as_i64().is_some_and(|nbf| nbf > now)
+    {
+        return Err(Error::new(ErrorKind::Other, "Token is expired"));
+    }
+
+    Ok(())
+}
+
+/// Returns the string with the provided key in the provided JSON object.
+fn json_string(value: &Value, key: &str) -> Result<String> {
+    value[key].as_str().map(str::to_string).ok_or(Error::new(
+        ErrorKind::Other,
+        format!("Missing {} in login", key),
+    ))
+}
+
+/// Reads a string prefixed by its length as a little endian i32.
+fn read_i32_string(buf: &mut Cursor<&[u8]>) -> Result<String> {
+    let len = buf.read_i32::<LE>()?;
+    let remaining = buf.get_ref().len() - buf.position() as usize;
+
+    if len < 0 || len as usize > remaining {
+        return Err(Error::new(
+            ErrorKind::Other,
+            "String length exceeds the packet",
+        ));
+    }
+
+    let mut bytes = vec![0u8; len as usize];
+    buf.read_exact(&mut bytes)?;
+
+    String::from_utf8(bytes).map_err(|e| Error::new(ErrorKind::Other, e))
+}
+
+/// OfflineLogin builds the Login packet of a client in offline mode, whose certificate chain is signed by the
+/// client itself instead of Mojang. When it is inserted on a client connection, the client is driven through the
+/// login sequence automatically once the RakNet connection is established.
+#[derive(Component, Clone)]
+pub struct OfflineLogin {
+    display_name: String,
+    uuid: Uuid,
+    protocol: i32,
+    game_version: String,
+    secret: SecretKey,
+}
+
+impl OfflineLogin {
+    /// Creates the login of a client with the provided display name, a random UUID and a new P-384 keypair.
+    pub fn new(display_name: &str) -> Self {
+        let mut uuid = [0u8; 16];
+        OsRng.fill_bytes(&mut uuid);
+
+        Self {
+            display_name: display_name.to_string(),
+            uuid: Builder::from_random_bytes(uuid).into_uuid(),
+            protocol: 630,
+            game_version: "1.20.51".to_string(),
+            secret: SecretKey::random(&mut OsRng),
+        }
+    }
+
+    /// Sets the UUID of the client instead of a random one.
+    pub fn with_uuid(mut self, uuid: Uuid) -> Self {
+        self.uuid = uuid;
+        self
+    }
+
+    /// Sets the protocol version and the game version the client claims to be running.
+    pub fn with_version(mut self, protocol: i32, game_version: &str) -> Self {
+        self.protocol = protocol;
+        self.game_version = game_version.to_string();
+        self
+    }
+
+    /// Returns the protocol version the client claims to be running.
+    pub fn protocol(&self) -> i32 {
+        self.protocol
+    }
+
+    /// Returns the private key of the client, which the encryption keys are derived from.
+    pub fn secret(&self) -> &SecretKey {
+        &self.secret
+    }
+
+    /// Builds the Login packet with a certificate chain of a single token signed by the key of the client.
+    pub fn login(&self) -> Result<Login> {
+        let public_key = public_key_der(&self.secret)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs() as i64)
+            .unwrap_or_default();
+
+        let chain = sign_jwt(
+            &self.secret,
+            &json!({
+                "certificateAuthority": true,
+                "extraData": {
+                    "displayName": self.display_name,
+                    "identity": self.uuid.to_string(),
+                    "XUID": "",
+                },
+                "identityPublicKey": public_key,
+                "nbf": now - 60,
+                "exp": now + 24 * 60 * 60,
+            }),
+        )?;
+
+        let client_data = sign_jwt(
+            &self.secret,
+            &json!({
+                "DeviceOS": 7,
+                "DeviceModel": "",
+                "DeviceId": self.uuid.to_string(),
+                "GameVersion": self.game_version,
+                "LanguageCode": "en_US",
+                "ClientRandomId": OsRng.next_u64() as i64,
+                "SelfSignedId": self.uuid.to_string(),
+                "ServerAddress": "",
+            }),
+        )?;
+
+        Ok(Login {
+            protocol: self.protocol,
+            chain: vec![chain],
+            client_data,
+        })
+    }
+}
@@ -0,0 +1,360 @@
+use bevy::{
+    ecs::{component::Component, reflect::ReflectComponent, system::Resource},
+    reflect::Reflect,
+};
+use binary::Binary;
+use bytes::BytesMut;
+use std::{
+    borrow::Cow,
+    io::{Cursor, Error, ErrorKind, Result},
+    net::SocketAddr,
+    str::FromStr,
+    sync::Arc,
+};
+
+use super::binary::VarU32;
+
+pub mod login;
+
+/// StatusProvider produces the payload of the Unconnected Pong message on every Unconnected Ping, which allows
+/// servers that are not Minecraft servers or that have per-ping data to customize it. It is implemented for closures.
+pub trait StatusProvider: Send + Sync + 'static {
+    /// Returns the payload of the pong sent to the provided address, the MCPE status built from the server components
+    /// is provided as the default. Returning None suppresses the pong for the address.
+    fn status(&self, addr: SocketAddr, default: &str) -> Option<String>;
+}
+
+impl<F> StatusProvider for F
+where
+    F: Fn(SocketAddr, &str) -> Option<String> + Send + Sync + 'static,
+{
+    fn status(&self, addr: SocketAddr, default: &str) -> Option<String> {
+        self(addr, default)
+    }
+}
+
+/// PongStatus is the payload of the Unconnected Pong advertised by a server entity, so that every server advertises
+/// its own status independently. The MCPE status is rebuilt from the components of the server.
+#[derive(Component)]
+pub struct PongStatus {
+    pub bytes: BytesMut,
+    provider: Option<Arc<dyn StatusProvider>>,
+}
+
+impl PongStatus {
+    pub fn new() -> Self {
+        let mut bytes = BytesMut::with_capacity(300);
+        let status =
+            "MCPE;RakNet;390;1.14.60;0;10;13253860892328930865;Blazingly fast;Survival;1;19132;";
+        bytes.extend_from_slice(&status.as_bytes());
+
+        Self {
+            bytes,
+            provider: None,
+        }
+    }
+
+    /// Sets the StatusProvider that produces the payload of the pongs instead of the MCPE status.
+    pub fn set_provider(&mut self, provider: Arc<dyn StatusProvider>) {
+        self.provider = Some(provider);
+    }
+
+    /// Returns the payload of the pong sent to the provided address, None if the pong is suppressed.
+    pub fn payload(&self, addr: SocketAddr) -> Option<Cow<'_, str>> {
+        let status = String::from_utf8_lossy(&self.bytes);
+
+        match &self.provider {
+            Some(provider) => provider.status(addr, &status).map(Cow::Owned),
+            None => Some(status),
+        }
+    }
+}
+
+/// ServerStatus is the status of a server parsed from the payload of its Unconnected Pong. It is inserted on the
+/// client entity when connecting and is carried by the RakNetEvent::PongReceived event.
+#[derive(Component, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerStatus {
+    pub edition: String,
+    pub motd: String,
+    pub protocol: u32,
+    pub version: String,
+    pub online: u32,
+    pub max: u32,
+    pub server_guid: i64,
+    pub level_name: String,
+    pub gamemode: String,
+    pub gamemode_id: u32,
+    pub port_v4: Option<u16>,
+    pub port_v6: Option<u16>,
+}
+
+impl ServerStatus {
+    /// Parses the provided MCPE status string. Only the edition, MOTD, protocol, version and player counts are
+    /// required, the remaining fields are left to their defaults when absent.
+    pub fn parse(status: &str) -> Result<Self> {
+        let fields: Vec<&str> = status.split(';').collect();
+
+        if fields.len() < 6 {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "Status has fewer fields than required",
+            ));
+        }
+
+        let field = |index: usize| fields.get(index).copied().unwrap_or_default();
+
+        Ok(Self {
+            edition: field(0).to_string(),
+            motd: field(1).to_string(),
+            protocol: parse_field(field(2))?,
+            version: field(3).to_string(),
+            online: parse_field(field(4))?,
+            max: parse_field(field(5))?,
+            server_guid: field(6)
+                .parse::<i64>()
+                .or_else(|_| field(6).parse::<u64>().map(|guid| guid as i64))
+                .unwrap_or_default(),
+            level_name: field(7).to_string(),
+            gamemode: field(8).to_string(),
+            gamemode_id: field(9).parse().unwrap_or_default(),
+            port_v4: field(10).parse().ok(),
+            port_v6: field(11).parse().ok(),
+        })
+    }
+}
+
+/// Parses a required numeric field of the status string.
+fn parse_field<T: FromStr>(field: &str) -> Result<T> {
+    field
+        .parse()
+        .map_err(|_| Error::new(ErrorKind::Other, "Invalid numeric field in the status"))
+}
+
+#[derive(Component, Reflect, Default)]
+#[reflect(Component)]
+pub struct PrimaryMotd(String);
+
+impl PrimaryMotd {
+    pub fn new(value: &str) -> Self {
+        Self(value.to_string())
+    }
+
+    pub fn get<'a>(&'a self) -> &'a str {
+        &self.0
+    }
+
+    pub fn set(&mut self, value: &str) {
+        self.0 = value.to_string()
+    }
+}
+
+#[derive(Component, Reflect, Default)]
+#[reflect(Component)]
+pub struct SecondaryMotd(String);
+
+impl SecondaryMotd {
+    pub fn new(value: &str) -> Self {
+        Self(value.to_string())
+    }
+
+    pub fn get<'a>(&'a self) -> &'a str {
+        &self.0
+    }
+
+    pub fn set(&mut self, value: &str) {
+        self.0 = value.to_string()
+    }
+}
+
+#[derive(Component, Reflect, Default)]
+#[reflect(Component)]
+pub struct OnlinePlayers(u32);
+
+impl OnlinePlayers {
+    pub fn new(value: u32) -> Self {
+        Self(value)
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0
+    }
+
+    pub fn set(&mut self, value: u32) {
+        self.0 = value
+    }
+}
+
+/// OnlinePlayersSync is the resource that keeps the OnlinePlayers of the server equal to the number of established
+/// connections. The offset is added to the count, so the advertised number can be adjusted at runtime.
+#[derive(Resource, Default)]
+pub struct OnlinePlayersSync {
+    pub offset: i32,
+}
+
+impl OnlinePlayersSync {
+    pub fn new(offset: i32) -> Self {
+        Self { offset }
+    }
+
+    /// Returns the number of players advertised for the provided number of established connections.
+    pub fn players(&self, connections: usize) -> u32 {
+        (connections as i64 + self.offset as i64).clamp(0, u32::MAX as i64) as u32
+    }
+}
+
+#[derive(Component, Reflect, Default)]
+#[reflect(Component)]
+pub struct MaxPlayers(u32);
+
+impl MaxPlayers {
+    pub fn new(value: u32) -> Self {
+        Self(value)
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0
+    }
+
+    pub fn set(&mut self, value: u32) {
+        self.0 = value
+    }
+}
+
+#[derive(Component, Reflect, Default)]
+#[reflect(Component)]
+pub struct MinecraftProtocol(u32);
+
+impl MinecraftProtocol {
+    pub fn new(value: u32) -> Self {
+        Self(value)
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0
+    }
+
+    pub fn set(&mut self, value: u32) {
+        self.0 = value
+    }
+}
+
+#[derive(Component, Reflect, Default)]
+#[reflect(Component)]
+pub struct MinecraftVersion(String);
+
+impl MinecraftVersion {
+    pub fn new(value: &str) -> Self {
+        Self(value.to_string())
+    }
+
+    pub fn get<'a>(&'a self) -> &'a str {
+        &self.0
+    }
+
+    pub fn set(&mut self, value: &str) {
+        self.0 = value.to_string()
+    }
+}
+
+#[derive(Component, Reflect, Default)]
+#[reflect(Component)]
+pub struct BroadcastGamemode(String);
+
+impl BroadcastGamemode {
+    pub fn new(value: &str) -> Self {
+        Self(value.to_string())
+    }
+
+    pub fn get<'a>(&'a self) -> &'a str {
+        &self.0
+    }
+
+    pub fn set(&mut self, value: &str) {
+        self.0 = value.to_string()
+    }
+}
+
+/// LevelName is the world name advertised in the status, the SecondaryMotd is advertised in its place when it is empty.
+#[derive(Component, Reflect, Default)]
+#[reflect(Component)]
+pub struct LevelName(String);
+
+impl LevelName {
+    pub fn new(value: &str) -> Self {
+        Self(value.to_string())
+    }
+
+    pub fn get<'a>(&'a self) -> &'a str {
+        &self.0
+    }
+
+    pub fn set(&mut self, value: &str) {
+        self.0 = value.to_string()
+    }
+}
+
+/// PortV6 is the IPv6 port advertised in the status next to the port the server is bound on.
+#[derive(Component, Reflect, Default)]
+#[reflect(Component)]
+pub struct PortV6(u16);
+
+impl PortV6 {
+    pub fn new(value: u16) -> Self {
+        Self(value)
+    }
+
+    pub fn get(&self) -> u16 {
+        self.0
+    }
+
+    pub fn set(&mut self, value: u16) {
+        self.0 = value
+    }
+}
+
+/// Returns the ID of the provided sub-packet, which is the lowest 10 bits of its varint header.
+pub fn packet_id(packet: &[u8]) -> Option<u32> {
+    VarU32::deserialize(&mut Cursor::new(packet))
+        .ok()
+        .map(|header| header.0 & 0x3ff)
+}
+
+/// Appends the provided sub-packet to the provided game packet batch, prefixed by its length as an unsigned varint.
+pub fn write_sub_packet(batch: &mut Vec<u8>, packet: &[u8]) {
+    VarU32(packet.len() as u32).serialize(batch);
+    batch.extend_from_slice(packet);
+}
+
+/// Returns a game packet batch holding only the provided packet.
+pub fn encode_packet<'a>(packet: &impl Binary<'a>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    packet.serialize(&mut buf);
+
+    let mut batch = Vec::with_capacity(buf.len() + 5);
+    write_sub_packet(&mut batch, &buf);
+    batch
+}
+
+/// Returns the sub-packets of the provided game packet batch, each of which is prefixed by its length as an
+/// unsigned varint.
+pub fn read_sub_packets(batch: &[u8]) -> Result<Vec<&[u8]>> {
+    let mut buf = Cursor::new(batch);
+    let mut packets = Vec::new();
+
+    while (buf.position() as usize) < batch.len() {
+        let len = VarU32::deserialize(&mut buf)?.0 as usize;
+        let start = buf.position() as usize;
+
+        if start + len > batch.len() {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "Sub-packet length exceeds the batch",
+            ));
+        }
+
+        packets.push(&batch[start..start + len]);
+        buf.set_position((start + len) as u64);
+    }
+
+    Ok(packets)
+}
@@ -4,7 +4,7 @@ use binary::{
 };
 use byteorder::BE;
 
-use super::binary::{Magic, SystemAddresses, UDPAddress};
+use super::binary::{Magic, ProxySignature, SystemAddresses, UDPAddress};
 
 macro_rules! build_message {
     (
@@ -113,6 +113,18 @@ build_message! {
         mtu_size: U16<BE>,
         secure: Bool
     };
+    0x12; AlreadyConnected {
+        magic: Magic,
+        server_guid: I64<BE>
+    };
+    0x14; NoFreeIncomingConnections {
+        magic: Magic,
+        server_guid: I64<BE>
+    };
+    0x17; ConnectionBanned {
+        magic: Magic,
+        server_guid: I64<BE>
+    };
     0x19; IncompatibleProtocolVersion {
         server_protocol: U8,
         magic: Magic,
@@ -148,6 +160,35 @@ build_message! {
     };
     0x15; DisconnectNotification {
 
+    };
+    0x86; ProxyHeader {
+        magic: Magic,
+        client_address: UDPAddress,
+        timestamp: I64<BE>,
+        nonce: I64<BE>,
+        signature: ProxySignature
+    };
+    0x3a; NatPunchthroughRequest {
+        target_guid: I64<BE>
+    };
+    0x3b; NatConnectAtTime {
+        delay: U16<BE>,
+        peer_address: UDPAddress,
+        peer_guid: I64<BE>
+    };
+    0x3e; NatTargetNotConnected {
+        target_guid: I64<BE>
+    };
+    0x41; NatAlreadyInProgress {
+        target_guid: I64<BE>
+    };
+    0x0d; NatEstablish {
+        magic: Magic,
+        sender_guid: I64<BE>,
+        reply: Bool
+    };
+    0x1d; AdvertiseSystem {
+        data: UnsizedBytes<'a>
     };
     0xfe; GamePacket {
         data: UnsizedBytes<'a>
@@ -1,6 +1,10 @@
 use std::time::Duration;
 
 pub mod binary;
+pub mod compression;
+pub mod config;
+pub mod encryption;
+pub mod frame;
 pub mod mcpe;
 pub mod message;
 pub mod reliability;
@@ -13,18 +17,62 @@ pub const PROTOCOL_VERSION: u8 = 11;
 /// into smaller encapsulated frames.
 pub const MAX_MTU_SIZE: usize = 1500;
 
+/// RakNet connections cannot negotiate an MTU size smaller than this, the handshake of clients claiming
+/// a smaller MTU size is refused.
+pub const MIN_MTU_SIZE: usize = 576;
+
+/// This is the minimum number of padding bytes an OpenConnectionRequest1 must carry to be answered, the requests that
+/// are not much larger than the replies are dropped as they could be spoofed to amplify traffic.
+pub const MIN_REQUEST_PADDING: usize = 400;
+
 /// RakNet Messages cannot exceed this size. If they do, they are rejected.
 pub const MAX_MESSAGE_SIZE: usize = 8000;
 
+/// This is the compression level used for the outgoing game packet batches.
+pub const COMPRESSION_LEVEL: u32 = 7;
+
+/// This is the compression threshold sent in the NetworkSettings packet. The clients compress every batch that is at
+/// least this large.
+pub const COMPRESSION_THRESHOLD: u16 = 1;
+
+/// This is the first MCPE protocol version whose batches carry the ID of their compression algorithm in a one byte
+/// header once the NetworkSettings have been sent.
+pub const COMPRESSION_HEADER_PROTOCOL: i32 = 649;
+
+/// Game packet batches cannot exceed this size once they are decompressed. If they do, they are rejected.
+pub const MAX_BATCH_SIZE: usize = 8 * 1024 * 1024;
+
 /// Max Receipt Size of the buffer used to write the receipts.
 pub const MAX_RECEIPT_SIZE: usize = 256;
 
+/// This is the number of sequence numbers that a single ACK or NACK receipt can cover at most. Receipts covering more
+/// are rejected.
+pub const MAX_RECEIPT_SEQUENCES: usize = 8192;
+
 /// Regular Raknet uses 10 by default. MCPE uses 20. Configure this as appropriate.
 pub const SYSTEM_ADDRESS_COUNT: usize = 20;
 
 /// This is the number of times a single RakNet message can be split into encapsulated frames.
 pub const MAX_SPLIT_PACKETS: u32 = 250;
 
+/// This is the maximum size of a game packet once its fragments are reassembled. The split messages growing past it are
+/// discarded before they are complete and the larger outgoing batches are dropped.
+pub const MAX_GAME_PACKET_SIZE: usize = 256 * 1024;
+
+/// This is the number of split messages that a single connection can have partially received at once.
+pub const MAX_CONCURRENT_SPLITS: usize = 16;
+
+/// This is the maximum amount of fragment bytes that are buffered for all the partially received split
+/// messages of a single connection.
+pub const MAX_SPLIT_BYTES: usize = 4 * 1024 * 1024;
+
+/// This is the maximum number of bytes a single connection can buffer in its split messages, unacknowledged datagrams
+/// and outgoing queues before it is disconnected.
+pub const MAX_BUFFERED_BYTES: usize = 32 * 1024 * 1024;
+
+/// This is the duration after which a split message is discarded if no new fragment for it has been received.
+pub const SPLIT_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// This is the number of maximum encapsulated frames a single RakNet Datagram can carry.
 pub const MAX_BATCHED_PACKETS: usize = 100;
 
@@ -38,9 +86,23 @@ pub const UDP_HEADER_SIZE: usize = 20 + 8;
 /// Sequence Number (u24)
 pub const DATAGRAM_HEADER_SIZE: usize = 1 + 3;
 
-/// This is the configured rate at which the MTU_SIZE of the client is decreased every second to discover
-/// the MTU size of the server.
-pub const CLIENT_PADDING_DECREASE: usize = 40;
+/// These are the MTU sizes that the client tries in order to discover the MTU size of the server, starting from
+/// the largest one.
+pub const CLIENT_MTU_TIERS: [usize; 3] = [1492, 1200, 576];
+
+/// This is the number of times the client retries discovering the MTU size of the server with a single tier before
+/// falling back to the next one.
+pub const CLIENT_MTU_RETRIES: usize = 4;
+
+/// This is the maximum age of the timestamp of a ProxyHeader, the older ones are rejected and the nonces of the newer
+/// ones are remembered so that a header that was captured on the way cannot be replayed.
+pub const PROXY_HEADER_MAX_AGE: Duration = Duration::from_secs(10);
+
+/// This is how long the client waits for every response of the server during the offline handshake.
+pub const HANDSHAKE_READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// This is how often the client checks its transport for a response of the server during the offline handshake.
+pub const HANDSHAKE_POLL_INTERVAL: Duration = Duration::from_millis(1);
 
 /// This contains the size of the Raknet Frame Header.
 /// Frame Header (u8)
@@ -82,29 +144,166 @@ pub const WINDOW_SIZE: u32 = 2048;
 /// establishing a RakNet connection.
 pub const INTERNAL_ADDRESS: &str = "255.255.255.255:19132";
 
+/// LAN_DISCOVERY_PORT is the port that the Unconnected Pings are broadcast to by the clients looking for LAN servers.
+pub const LAN_DISCOVERY_PORT: u16 = 19132;
+
+/// LOOPBACK_EPHEMERAL_PORT is the first port that is handed out to the transports bound on any port of a
+/// LoopbackNetwork.
+pub const LOOPBACK_EPHEMERAL_PORT: u16 = 49152;
+
+/// SIMULATOR_REORDER_DELAY is how long a SimulatedTransport holds back the datagrams that it reorders.
+pub const SIMULATOR_REORDER_DELAY: Duration = Duration::from_millis(50);
+
+/// RECORDING_MAGIC is written at the start of every file recorded by a TrafficRecorder.
+pub const RECORDING_MAGIC: &[u8; 6] = b"RAKREC";
+
+/// RECORDING_VERSION is the version of the format of the recorded files, which follows the magic.
+pub const RECORDING_VERSION: u8 = 1;
+
+/// WEBSOCKET_HANDSHAKE_TIMEOUT is how long a WebSocketListener waits for the upgrade of an accepted connection before
+/// dropping it.
+pub const WEBSOCKET_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// TEST_STEP_INTERVAL is how long a TestHarness sleeps between the updates of its Apps, so that the timers of the
+/// network schedule elapse.
+pub const TEST_STEP_INTERVAL: Duration = Duration::from_millis(5);
+
+/// TEST_TIMEOUT is how long a TestHarness waits for an expected event before the test fails.
+pub const TEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// PCAP_MAGIC is the magic of the pcap files written by a PcapWriter, whose timestamps are in microseconds.
+pub const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+
+/// PCAP_SNAPSHOT_LENGTH is the maximum length of the packets captured in the pcap files, which is never reached.
+pub const PCAP_SNAPSHOT_LENGTH: u32 = 65535;
+
+/// PCAP_LINKTYPE_RAW is the link type of the pcap files, whose packets start directly with their IP header.
+pub const PCAP_LINKTYPE_RAW: u32 = 101;
+
+/// NAT_PUNCHTHROUGH_DELAY is how long after being introduced by the NatCoordinator both peers start punching, so that
+/// they punch at about the same time.
+pub const NAT_PUNCHTHROUGH_DELAY: Duration = Duration::from_millis(100);
+
+/// NAT_PUNCH_INTERVAL is how often a peer sends a punch to the other peer until one of its punches gets through.
+pub const NAT_PUNCH_INTERVAL: Duration = Duration::from_millis(50);
+
+/// NAT_PUNCH_ATTEMPTS is the number of punches a peer sends before the punchthrough fails.
+pub const NAT_PUNCH_ATTEMPTS: u32 = 40;
+
+/// NAT_COORDINATOR_TIMEOUT is how long the NatCoordinator refuses another punchthrough between the same peers.
+pub const NAT_COORDINATOR_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// SOCKS5_HANDSHAKE_TIMEOUT is how long a Socks5Transport waits for every reply of the proxy while it associates the
+/// UDP relay.
+pub const SOCKS5_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// HEARTBEAT_INTERVAL is how often the servers are reported to the master server by default.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// HEARTBEAT_TIMEOUT is how long a heartbeat waits for the master server to accept its HTTP request.
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// RAKNET_TPS is the duration of how often in milliseconds should we flush outgoing packets and datagrams.
 pub const RAKNET_TPS: Duration = Duration::from_millis(100);
 
 /// This specifies the duration of how often we should be checking the outlived connections.
 pub const RAKNET_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
 
-/// This value is the maximum amount of allowed RakNet messages in one second. If the number exceeds this value, the
-/// stream gets disconnected.
-pub const MAX_MSGS_PER_SEC: u8 = 100;
+/// This value is the default maximum number of datagrams an address can send in one second before it is blocked. The
+/// Bedrock clients easily send a few hundred datagrams every second while they play, counting the receipts.
+pub const MAX_MSGS_PER_SEC: u32 = 2000;
+
+/// This value is the default maximum number of unconnected messages, such as pings, an address that is not connected
+/// can send in one second before it is blocked.
+pub const MAX_UNCONNECTED_PER_SEC: u32 = 50;
 
-/// This value is the maximum number of malformed messages that the other side of the connection can send during its lifetime.
-pub const MAX_INVALID_MSGS: u8 = 20;
+/// This value is the default duration for which an address flooding the server with unconnected messages is blocked.
+pub const UNCONNECTED_BLOCK_DUR: Duration = Duration::from_secs(30);
 
-/// This value is the time in milliseconds for which a spammy or a bad connection is blocked from the RakListener for.
+/// This value is the default maximum number of malformed messages an address can send before it is blocked. The
+/// counter is forgotten once the address has not sent any for INVALID_PACKETS_IDLE.
+pub const MAX_INVALID_MSGS: u32 = 50;
+
+/// This value is the duration for which a proxy backend that could not be reached is skipped when pairing clients.
+pub const BACKEND_COOLDOWN: Duration = Duration::from_secs(10);
+
+/// This value is the default number of threads that perform the offline handshakes of the proxy with its backends.
+pub const PROXY_HANDSHAKE_THREADS: usize = 4;
+
+/// This value is the maximum number of Unconnected Pongs sent to a single address in one second.
+pub const MAX_PONGS_PER_SEC: u32 = 5;
+
+/// This value is the maximum number of Unconnected Pongs sent by a server to all the addresses together in one second,
+/// so that spoofed pings from many addresses cannot use the server for amplification either.
+pub const MAX_GLOBAL_PONGS_PER_SEC: u32 = 1000;
+
+/// This value is the default duration for which a spammy or a bad address is blocked from the server.
 pub const RAKNET_BLOCK_DUR: Duration = Duration::from_secs(10);
 
-/// If a RakStream is not responding for more than this time in milliseconds then we assume it is a timeout.
-pub const RAKNET_TIMEOUT: u128 = 5000;
+/// These values are the default durations for which the repeat offenders are blocked by their second and third block,
+/// their later blocks are permanent.
+pub const BLOCK_ESCALATION: [Duration; 2] = [Duration::from_secs(60), Duration::from_secs(60 * 60)];
+
+/// This is the duration after which the offenses of an address that has not been blocked again are forgotten.
+pub const OFFENSE_MEMORY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// This specifies the duration of how often we should be evicting the expired entries from the server mappings.
+pub const RAKNET_CLEANUP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// This is the duration after which the invalid packets counter of an address that has not sent any more malformed
+/// messages is forgotten.
+pub const INVALID_PACKETS_IDLE: Duration = Duration::from_secs(60);
+
+/// This is the maximum number of addresses for which the packet rate and invalid packets counters are tracked at once.
+pub const MAX_TRACKED_ADDRESSES: usize = 65536;
+
+/// This is the duration after which a connection that is still waiting for the approval of the App is refused, which
+/// is below the time the clients wait for the answer of their OpenConnectionRequest2.
+pub const APPROVAL_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// If a RakStream is not responding for more than this time then we assume it is a timeout.
+pub const RAKNET_TIMEOUT: Duration = Duration::from_millis(5000);
+
+/// This is the default duration within which a connection must complete the MCPE login once the RakNet connection is
+/// established, which is far longer than the clients need even when they are slow to sign in.
+pub const LOGIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// This is the default duration for which the latency of a connection can stay above the maximum latency before it is
+/// disconnected.
+pub const HIGH_LATENCY_GRACE: Duration = Duration::from_secs(10);
+
+/// This is the minimum number of datagrams a connection must exchange before its packet loss and retransmissions are
+/// measured, as a few datagrams lost out of a handful do not tell anything about the quality of the connection.
+pub const QUALITY_SAMPLE_SIZE: u64 = 20;
+
+/// This is the default fraction of the datagrams of a connection that can be lost before it is warned about.
+pub const PACKET_LOSS_THRESHOLD: f32 = 0.05;
+
+/// This is the default fraction of the datagrams sent to a connection that can be retransmitted before it is warned
+/// about.
+pub const RETRANSMISSION_THRESHOLD: f32 = 0.1;
+
+/// This is the default jitter of a connection above which it is warned about.
+pub const JITTER_THRESHOLD: Duration = Duration::from_millis(100);
+
+/// This is the maximum time the App waits for the disconnect notifications to be acknowledged when it exits.
+pub const RAKNET_EXIT_GRACE: Duration = Duration::from_millis(250);
 
 /// Login Packet ID corresponds to the ID of the OpenConnectionRequest1 packet sent by the client to check
 /// whether we have a duplicate login.
 pub const LOGIN_PACKET_ID: u8 = 0x05;
 
+/// NAT Establish Packet ID corresponds to the ID of the NatEstablish punches, which may still be in flight from a peer
+/// when a handshake is performed over a punched transport.
+pub const NAT_ESTABLISH_PACKET_ID: u8 = 0x0d;
+
+/// Advertise System Packet ID corresponds to the ID of the AdvertiseSystem message, which can be received from any
+/// address whether it is connected or not.
+pub const ADVERTISE_SYSTEM_PACKET_ID: u8 = 0x1d;
+
+/// This is the maximum size of the payload of an AdvertiseSystem message.
+pub const MAX_OFFLINE_DATA_LENGTH: usize = 400;
+
 /// Unconnected Message Sequence is a sequence of bytes found in every Unconnected RakNet message.
 pub const UNCONNECTED_MESSAGE_SEQUENCE: [u8; 16] = [
     0x00, 0xff, 0xff, 0x00, 0xfe, 0xfe, 0xfe, 0xfe, 0xfd, 0xfd, 0xfd, 0xfd, 0x12, 0x34, 0x56, 0x78,
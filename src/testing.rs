@@ -0,0 +1,634 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+
+use crate::{
+    generic::events::{NetworkEvent, RakNetEvent},
+    net::{loopback::LoopbackNetwork, socket::RakSocket},
+    protocol::{config::RakNetConfig, TEST_STEP_INTERVAL, TEST_TIMEOUT},
+    NetworkClient, NetworkServer,
+};
+
+/// EventLog is the resource that keeps every RakNetEvent and NetworkEvent emitted in an App of a TestHarness, so
+/// that a test can assert on them after they were read by the systems of the App.
+#[derive(Resource, Default)]
+pub struct EventLog {
+    raknet: Vec<RakNetEvent>,
+    network: Vec<NetworkEvent>,
+}
+
+impl EventLog {
+    /// Returns the RakNetEvents emitted so far in the order they were emitted.
+    pub fn raknet(&self) -> &[RakNetEvent] {
+        &self.raknet
+    }
+
+    /// Returns the NetworkEvents emitted so far in the order they were emitted.
+    pub fn network(&self) -> &[NetworkEvent] {
+        &self.network
+    }
+
+    /// Returns the first RakNetEvent matching the provided predicate.
+    pub fn find_raknet(&self, predicate: impl Fn(&RakNetEvent) -> bool) -> Option<&RakNetEvent> {
+        self.raknet.iter().find(|event| predicate(event))
+    }
+
+    /// Returns the first NetworkEvent matching the provided predicate.
+    pub fn find_network(&self, predicate: impl Fn(&NetworkEvent) -> bool) -> Option<&NetworkEvent> {
+        self.network.iter().find(|event| predicate(event))
+    }
+
+    /// Panics unless a RakNetEvent matching the provided predicate was emitted.
+    pub fn assert_raknet(&self, predicate: impl Fn(&RakNetEvent) -> bool) {
+        if self.find_raknet(predicate).is_none() {
+            panic!("No matching RakNetEvent was emitted in {:?}", self.raknet);
+        }
+    }
+
+    /// Panics if a RakNetEvent matching the provided predicate was emitted.
+    pub fn assert_no_raknet(&self, predicate: impl Fn(&RakNetEvent) -> bool) {
+        if let Some(event) = self.find_raknet(predicate) {
+            panic!("Unexpected RakNetEvent {:?} was emitted", event);
+        }
+    }
+
+    /// Panics unless a NetworkEvent matching the provided predicate was emitted.
+    pub fn assert_network(&self, predicate: impl Fn(&NetworkEvent) -> bool) {
+        if self.find_network(predicate).is_none() {
+            panic!("No matching NetworkEvent was emitted in {:?}", self.network);
+        }
+    }
+
+    /// Panics if a NetworkEvent matching the provided predicate was emitted.
+    pub fn assert_no_network(&self, predicate: impl Fn(&NetworkEvent) -> bool) {
+        if let Some(event) = self.find_network(predicate) {
+            panic!("Unexpected NetworkEvent {:?} was emitted", event);
+        }
+    }
+
+    /// Forgets all the events emitted so far.
+    pub fn clear(&mut self) {
+        self.raknet.clear();
+        self.network.clear();
+    }
+}
+
+/// This system is responsible for keeping the events emitted in the App of a TestHarness in its EventLog.
+fn record_events(
+    mut log: ResMut<EventLog>,
+    mut raknet: EventReader<RakNetEvent>,
+    mut network: EventReader<NetworkEvent>,
+) {
+    log.raknet.extend(raknet.read().cloned());
+    log.network.extend(network.read().cloned());
+}
+
+/// TestHarness runs a server App and a client App that is connected to it, either over a LoopbackNetwork or over
+/// UDP on an ephemeral port, so that a connection can be tested end to end in a few lines. Both Apps are updated
+/// together and the events emitted in each of them are kept in their EventLog.
+pub struct TestHarness {
+    pub server: App,
+    pub client: App,
+}
+
+impl TestHarness {
+    /// Connects a client to a server over a new LoopbackNetwork.
+    pub fn loopback() -> Self {
+        Self::loopback_with(|server| server, |client| client)
+    }
+
+    /// Connects a client to a server over a new LoopbackNetwork, the plugins of both are configured by the provided
+    /// functions before they are added.
+    pub fn loopback_with(
+        server: impl FnOnce(NetworkServer) -> NetworkServer,
+        client: impl FnOnce(NetworkClient) -> NetworkClient,
+    ) -> Self {
+        Self::loopback_with_config(RakNetConfig::default(), server, client)
+    }
+
+    /// Connects a client to a server over a new LoopbackNetwork, both Apps use the provided RakNetConfig so that they
+    /// can share a mock TimeSource.
+    pub fn loopback_with_config(
+        config: RakNetConfig,
+        server: impl FnOnce(NetworkServer) -> NetworkServer,
+        client: impl FnOnce(NetworkClient) -> NetworkClient,
+    ) -> Self {
+        let network = LoopbackNetwork::new();
+        let addr = "127.0.0.1:19132";
+
+        Self::connect(
+            config,
+            server(NetworkServer::new(addr).with_loopback(&network)),
+            client(NetworkClient::new(addr).with_loopback(&network)),
+        )
+    }
+
+    /// Connects a client to a server bound on an ephemeral UDP port of the local host.
+    pub fn udp() -> Self {
+        Self::udp_with(|server| server, |client| client)
+    }
+
+    /// Connects a client to a server bound on an ephemeral UDP port of the local host, the plugins of both are
+    /// configured by the provided functions before they are added. The socket of the server is bound before the
+    /// plugins so that the client is pointed at the port it was actually given.
+    pub fn udp_with(
+        server: impl FnOnce(NetworkServer) -> NetworkServer,
+        client: impl FnOnce(NetworkClient) -> NetworkClient,
+    ) -> Self {
+        let transport = RakSocket::new("127.0.0.1:0", true).unwrap().transport;
+        let addr = transport.local_addr().unwrap().to_string();
+
+        Self::connect(
+            RakNetConfig::default(),
+            server(NetworkServer::new(&addr).with_transport(transport)),
+            client(NetworkClient::new(&addr)),
+        )
+    }
+
+    /// Creates the Apps and adds the provided plugins to them. The handshake of the client blocks while its plugin is
+    /// built, so the server App is updated on another thread meanwhile.
+    fn connect(config: RakNetConfig, server: NetworkServer, client: NetworkClient) -> Self {
+        let mut server_app = Self::app(&config);
+        server_app.add_plugins(server);
+
+        let running = Arc::new(AtomicBool::new(true));
+        let handle = {
+            let running = running.clone();
+
+            thread::spawn(move || {
+                while running.load(Ordering::Relaxed) {
+                    server_app.update();
+                    thread::sleep(TEST_STEP_INTERVAL);
+                }
+
+                server_app
+            })
+        };
+
+        let mut client_app = Self::app(&config);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            client_app.add_plugins(client);
+        }));
+
+        running.store(false, Ordering::Relaxed);
+        let server_app = handle.join().unwrap();
+
+        if let Err(e) = result {
+            panic::resume_unwind(e);
+        }
+
+        Self {
+            server: server_app,
+            client: client_app,
+        }
+    }
+
+    fn app(config: &RakNetConfig) -> App {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(config.clone());
+        app.init_resource::<EventLog>();
+        app.add_systems(Last, record_events);
+        app
+    }
+
+    /// Updates the server App and then the client App once.
+    pub fn update(&mut self) {
+        self.server.update();
+        self.client.update();
+    }
+
+    /// Updates both Apps for the provided duration, sleeping TEST_STEP_INTERVAL between the updates.
+    pub fn run_for(&mut self, duration: Duration) {
+        let deadline = Instant::now() + duration;
+
+        while Instant::now() < deadline {
+            self.update();
+            thread::sleep(TEST_STEP_INTERVAL);
+        }
+    }
+
+    /// Updates both Apps until the provided predicate returns true for their EventLogs, the server one first. Panics
+    /// if it does not within TEST_TIMEOUT.
+    pub fn wait_for(&mut self, predicate: impl Fn(&EventLog, &EventLog) -> bool) {
+        let deadline = Instant::now() + TEST_TIMEOUT;
+
+        loop {
+            self.update();
+
+            if predicate(self.server_events(), self.client_events()) {
+                return;
+            }
+
+            if Instant::now() >= deadline {
+                panic!(
+                    "The expected events were not emitted within {:?}",
+                    TEST_TIMEOUT
+                );
+            }
+
+            thread::sleep(TEST_STEP_INTERVAL);
+        }
+    }
+
+    /// Updates both Apps until the server emits a RakNetEvent matching the provided predicate and returns it.
+    pub fn expect_server_raknet(
+        &mut self,
+        predicate: impl Fn(&RakNetEvent) -> bool,
+    ) -> RakNetEvent {
+        self.wait_for(|server, _| server.find_raknet(&predicate).is_some());
+        self.server_events()
+            .find_raknet(&predicate)
+            .cloned()
+            .unwrap()
+    }
+
+    /// Updates both Apps until the client emits a RakNetEvent matching the provided predicate and returns it.
+    pub fn expect_client_raknet(
+        &mut self,
+        predicate: impl Fn(&RakNetEvent) -> bool,
+    ) -> RakNetEvent {
+        self.wait_for(|_, client| client.find_raknet(&predicate).is_some());
+        self.client_events()
+            .find_raknet(&predicate)
+            .cloned()
+            .unwrap()
+    }
+
+    /// Updates both Apps until the server emits a NetworkEvent matching the provided predicate and returns it.
+    pub fn expect_server_network(
+        &mut self,
+        predicate: impl Fn(&NetworkEvent) -> bool,
+    ) -> NetworkEvent {
+        self.wait_for(|server, _| server.find_network(&predicate).is_some());
+        self.server_events()
+            .find_network(&predicate)
+            .cloned()
+            .unwrap()
+    }
+
+    /// Updates both Apps until the client emits a NetworkEvent matching the provided predicate and returns it.
+    pub fn expect_client_network(
+        &mut self,
+        predicate: impl Fn(&NetworkEvent) -> bool,
+    ) -> NetworkEvent {
+        self.wait_for(|_, client| client.find_network(&predicate).is_some());
+        self.client_events()
+            .find_network(&predicate)
+            .cloned()
+            .unwrap()
+    }
+
+    /// Returns the events emitted in the server App so far.
+    pub fn server_events(&self) -> &EventLog {
+        self.server.world.resource::<EventLog>()
+    }
+
+    /// Returns the events emitted in the client App so far.
+    pub fn client_events(&self) -> &EventLog {
+        self.client.world.resource::<EventLog>()
+    }
+
+    /// Forgets the events emitted in both Apps so far, so that the following expectations only match new events.
+    pub fn clear_events(&mut self) {
+        self.server.world.resource_mut::<EventLog>().clear();
+        self.client.world.resource_mut::<EventLog>().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use binary::{datatypes::I64, prefixed::UnsizedBytes, Binary};
+
+    use super::*;
+    use crate::{
+        generic::time::TimeSource,
+        net::{
+            socket::{Mappings, RakSocket},
+            stream::{NetworkInfo, RakStream},
+        },
+        protocol::{
+            binary::{Magic, ProxySignature, UDPAddress},
+            compression::CompressionHeader,
+            encryption::proxy_signature,
+            mcpe::login::OfflineLogin,
+            message::Message,
+            reliability::Reliability,
+            COMPRESSION_HEADER_PROTOCOL,
+        },
+    };
+
+    /// Connects a client to a server over a LoopbackNetwork driven by the returned mock clock and returns the harness
+    /// along with the client and server entities of the connection.
+    fn connect_mocked() -> (TestHarness, TimeSource, Entity, Entity) {
+        let clock = TimeSource::mock();
+        let config = RakNetConfig {
+            clock: clock.clone(),
+            ..Default::default()
+        };
+
+        let mut harness =
+            TestHarness::loopback_with_config(config, |server| server, |client| client);
+
+        let RakNetEvent::ConnectionEstablished(_, client) = harness
+            .expect_client_raknet(|event| matches!(event, RakNetEvent::ConnectionEstablished(..)))
+        else {
+            unreachable!()
+        };
+
+        let RakNetEvent::ConnectionEstablished(_, server) = harness
+            .expect_server_raknet(|event| matches!(event, RakNetEvent::ConnectionEstablished(..)))
+        else {
+            unreachable!()
+        };
+
+        (harness, clock, client, server)
+    }
+
+    #[test]
+    fn connection_times_out_on_the_mock_clock() {
+        let (mut harness, clock, client, _) = connect_mocked();
+        let timeout = RakNetConfig::default().timeout;
+
+        // The clock is stopped, so the connection cannot time out however long the Apps run.
+        harness.run_for(Duration::from_millis(200));
+        harness
+            .client_events()
+            .assert_no_raknet(|event| matches!(event, RakNetEvent::Timeout(_)));
+
+        // The server goes silent while the clock moves past the timeout, only the client App is updated from now on.
+        clock.advance(timeout + Duration::from_millis(1));
+
+        let deadline = Instant::now() + TEST_TIMEOUT;
+        while harness
+            .client_events()
+            .find_raknet(|event| matches!(event, RakNetEvent::Timeout(entity) if *entity == client))
+            .is_none()
+        {
+            assert!(Instant::now() < deadline, "The connection did not time out");
+
+            harness.client.update();
+            thread::sleep(TEST_STEP_INTERVAL);
+        }
+    }
+
+    #[test]
+    fn lost_datagram_is_retransmitted() {
+        let (mut harness, _, client, server) = connect_mocked();
+        harness.run_for(Duration::from_millis(100));
+        harness.clear_events();
+
+        // The first batch is encoded on the stream of the client but its datagrams are dropped before they are sent,
+        // as if they were lost on the network.
+        {
+            let mut stream = harness.client.world.get_mut::<RakStream>(client).unwrap();
+            stream.try_flush();
+            while stream.poll_outgoing().is_some() {}
+
+            stream.encode(
+                Message::GamePacket {
+                    data: UnsizedBytes::new(b"lost"),
+                },
+                Reliability::ReliableOrdered,
+            );
+            stream.try_flush();
+
+            assert!(stream.poll_outgoing().is_some());
+            while stream.poll_outgoing().is_some() {}
+        }
+
+        harness
+            .client
+            .world
+            .send_event(RakNetEvent::OutgoingBatch(client, b"delivered".to_vec()));
+
+        // The server NACKs the missing sequence once the second batch arrives and the client retransmits the first one.
+        harness.wait_for(|server_log, _| {
+            server_log
+                .raknet()
+                .iter()
+                .filter(|event| matches!(event, RakNetEvent::IncomingBatch(entity, _) if *entity == server))
+                .count()
+                >= 2
+        });
+
+        let mut batches: Vec<&[u8]> = harness
+            .server_events()
+            .raknet()
+            .iter()
+            .filter_map(|event| match event {
+                RakNetEvent::IncomingBatch(entity, bytes) if *entity == server => {
+                    Some(bytes.as_slice())
+                }
+                _ => None,
+            })
+            .collect();
+
+        batches.sort();
+        assert_eq!(batches, [&b"delivered"[..], &b"lost"[..]]);
+
+        let stream = harness.client.world.get::<RakStream>(client).unwrap();
+        assert!(stream.packet_loss() > 0.0);
+    }
+
+    #[test]
+    fn datagrams_of_despawned_connection_are_dropped() {
+        let config = RakNetConfig {
+            max_invalid_msgs: 1,
+            ..Default::default()
+        };
+        let mut harness =
+            TestHarness::loopback_with_config(config, |server| server, |client| client);
+
+        let RakNetEvent::ConnectionEstablished(addr, server) = harness
+            .expect_server_raknet(|event| matches!(event, RakNetEvent::ConnectionEstablished(..)))
+        else {
+            unreachable!()
+        };
+        let RakNetEvent::ConnectionEstablished(_, client) = harness
+            .expect_client_raknet(|event| matches!(event, RakNetEvent::ConnectionEstablished(..)))
+        else {
+            unreachable!()
+        };
+
+        harness.server.world.despawn(server);
+        harness
+            .client
+            .world
+            .send_event(RakNetEvent::OutgoingBatch(client, b"late".to_vec()));
+        harness.run_for(Duration::from_millis(200));
+
+        // The datagrams are neither counted as invalid unconnected messages nor mapped to the despawned entity.
+        harness
+            .server_events()
+            .assert_no_raknet(|event| matches!(event, RakNetEvent::AddressBlocked(..)));
+
+        let mut mappings = harness.server.world.query::<&Mappings>();
+        assert_eq!(
+            mappings.single(&harness.server.world).connection(&addr),
+            None
+        );
+    }
+
+    #[test]
+    fn login_negotiates_compression_header() {
+        for protocol in [COMPRESSION_HEADER_PROTOCOL - 1, COMPRESSION_HEADER_PROTOCOL] {
+            let login = OfflineLogin::new("Steve").with_version(protocol, "1.20.80");
+            let config = RakNetConfig {
+                mcpe_login: true,
+                ..Default::default()
+            };
+            let mut harness = TestHarness::loopback_with_config(
+                config,
+                |server| server,
+                |client| client.with_login(login),
+            );
+
+            harness.expect_server_network(|event| {
+                matches!(event, NetworkEvent::ConnectionEstablished(_))
+            });
+            harness.expect_client_network(|event| {
+                matches!(event, NetworkEvent::ConnectionEstablished(_))
+            });
+
+            let expected = (protocol >= COMPRESSION_HEADER_PROTOCOL) as usize;
+
+            for app in [&mut harness.server, &mut harness.client] {
+                let mut headers = app.world.query::<&CompressionHeader>();
+                assert_eq!(headers.iter(&app.world).count(), expected);
+            }
+        }
+    }
+
+    /// Adds a server bound on an ephemeral UDP port of the local host to the provided App and returns its address.
+    fn bind_server(app: &mut App) -> SocketAddr {
+        let transport = RakSocket::new("127.0.0.1:0", true).unwrap().transport;
+        let addr = transport.local_addr().unwrap();
+
+        app.add_plugins(NetworkServer::new(&addr.to_string()).with_transport(transport));
+        addr
+    }
+
+    /// The client address that the proxy forwards to the server.
+    const FORWARDED: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 19132);
+
+    /// Starts a connection to a server that trusts the proxies of the local host, through a proxy that signs the
+    /// forwarded address with the provided secret or without a ProxyHeader if there is none. Returns the remote
+    /// addresses of the connections spawned by the server, whose names are checked against them.
+    fn forward_through_proxy(secret: Option<&'static [u8]>) -> Vec<SocketAddr> {
+        let mut server = TestHarness::app(&RakNetConfig {
+            trusted_proxies: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            proxy_secret: Some(b"secret".to_vec()),
+            ..Default::default()
+        });
+        let addr = bind_server(&mut server).to_string();
+
+        // The handshakes that are refused only fail once the client has given up, so they are not waited for.
+        thread::spawn(move || {
+            let mut client = World::new();
+            client.insert_resource(RakNetConfig {
+                proxy_secret: secret.map(<[u8]>::to_vec),
+                ..Default::default()
+            });
+
+            match secret {
+                Some(_) => RakSocket::connect_forwarded(&addr, FORWARDED, &mut client),
+                None => RakSocket::connect(&addr, &mut client),
+            }
+        });
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let mut infos = server.world.query::<(&NetworkInfo, &Name)>();
+
+        while Instant::now() < deadline && infos.iter(&server.world).next().is_none() {
+            server.update();
+            thread::sleep(TEST_STEP_INTERVAL);
+        }
+
+        infos
+            .iter(&server.world)
+            .map(|(info, name)| {
+                // The connection is named after the client rather than after the proxy.
+                assert_eq!(name.as_str(), format!("raknet {}", info.remote_addr));
+                info.remote_addr
+            })
+            .collect()
+    }
+
+    #[test]
+    fn signed_proxy_header_forwards_the_address() {
+        assert_eq!(forward_through_proxy(Some(b"secret")), [FORWARDED]);
+    }
+
+    #[test]
+    fn forged_proxy_header_is_refused() {
+        assert_eq!(forward_through_proxy(Some(b"forged")), []);
+    }
+
+    #[test]
+    fn trusted_proxy_without_header_is_refused() {
+        assert_eq!(forward_through_proxy(None), []);
+    }
+
+    #[test]
+    fn replayed_proxy_header_is_rejected() {
+        // A single invalid message blocks the address, which tells the rejected header apart from the accepted one.
+        let mut server = TestHarness::app(&RakNetConfig {
+            trusted_proxies: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            proxy_secret: Some(b"secret".to_vec()),
+            max_invalid_msgs: 1,
+            ..Default::default()
+        });
+        let addr = bind_server(&mut server);
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        let header = Message::ProxyHeader {
+            magic: Magic,
+            client_address: UDPAddress(FORWARDED),
+            timestamp: I64::new(timestamp),
+            nonce: I64::new(7),
+            signature: ProxySignature(proxy_signature(b"secret", FORWARDED, timestamp, 7)),
+        };
+        let mut buf = Vec::new();
+        header.serialize(&mut buf);
+
+        let proxy = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let blocked = |server: &App| {
+            server
+                .world
+                .resource::<EventLog>()
+                .find_raknet(|event| matches!(event, RakNetEvent::AddressBlocked(..)))
+                .is_some()
+        };
+
+        proxy.send_to(&buf, addr).unwrap();
+        for _ in 0..20 {
+            server.update();
+            thread::sleep(TEST_STEP_INTERVAL);
+        }
+        assert!(!blocked(&server));
+
+        proxy.send_to(&buf, addr).unwrap();
+        for _ in 0..20 {
+            server.update();
+            thread::sleep(TEST_STEP_INTERVAL);
+        }
+        assert!(blocked(&server));
+    }
+}